@@ -0,0 +1,37 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use musicserver1::analyzer::{Melody, MelodyMaker};
+
+/// The opening phrase of a folk tune, as recorded from a keyboard (pitch, duration, velocity).
+const EXAMPLE_MELODY: &str = "55,0.39,0.91,55,0.04,0.0,59,0.33,0.73,60,0.06,0.44,62,0.02,0.87,59,0.05,0.0,60,0.16,0.0,62,0.2,0.0,55,0.39,0.61,55,0.01,0.0,57,0.34,0.98,57,0.05,0.0,55,0.39,0.78,54,0.02,0.98,55,0.19,0.0,54,0.12,0.0,52,0.11,0.74,52,0.0,0.0,54,0.12,0.46,54,0.03,0.0,50,0.1,0.84,50,0.27,0.0,55,0.27,0.74,55,0.1,0.0,59,0.27,0.44,60,0.07,0.54,62,0.04,0.91,59,0.09,0.0,60,0.11,0.0,62,0.19,0.0,55,0.29,0.67,55,0.07,0.0,57,0.32,0.76,57,0.06,0.0,55,0.23,0.7,55,0.05,0.0,54,0.12,0.93,54,0.07,0.0,50,0.37,0.8,50,0.5,0.0";
+
+fn variations(c: &mut Criterion) {
+    let maker = MelodyMaker::new();
+    let melody = Melody::from(EXAMPLE_MELODY);
+    c.bench_function("motive variation", |b| {
+        b.iter(|| maker.create_motive_variation(black_box(&melody), 0.5))
+    });
+    c.bench_function("whimsical variation", |b| {
+        b.iter(|| maker.create_whimsical_variation(black_box(&melody), 0.5))
+    });
+    c.bench_function("wandering variation", |b| {
+        b.iter(|| maker.create_wandering_variation(black_box(&melody), 0.5))
+    });
+    c.bench_function("rhythmic variation", |b| {
+        b.iter(|| maker.create_rhythmic_variation(black_box(&melody), 0.5))
+    });
+}
+
+fn analysis(c: &mut Criterion) {
+    let maker = MelodyMaker::new();
+    let melody = Melody::from(EXAMPLE_MELODY);
+    c.bench_function("figure matches", |b| {
+        b.iter(|| maker.all_figure_matches(black_box(&melody)))
+    });
+    c.bench_function("best scale", |b| {
+        b.iter(|| black_box(&melody).best_scale_for())
+    });
+    c.bench_function("maker setup", |b| b.iter(MelodyMaker::new));
+}
+
+criterion_group!(benches, variations, analysis);
+criterion_main!(benches);
@@ -1,181 +1,656 @@
-use crate::analyzer::{Melody, MelodyMaker, MidiByte, Note};
+use crate::analyzer::{Melody, MelodyMaker, MidiByte, Note, PhraseSegmentation, VariationReport};
+use crate::backing::BackingTransport;
 use crate::database::{FromAiMsg, MelodyInfo, VariationStats};
+use crate::harmonizer::{Harmonizer, HarmonyInterval};
+use crate::midi_clock::ClockSync;
+use crate::queue::BlockingQueue;
 use crate::runtime::{
-    send_recorded_melody, ChooserTable, MelodyRunStatus, SliderValue, VariationControls,
-    HUMAN_SPEAKER, VARIATION_SPEAKER,
+    pedal_replay_slider, replay_slider, send_recorded_melody, ChooserTable, MelodyRunStatus,
+    SliderValue, VariationControls, HUMAN_SPEAKER, VARIATION_SPEAKER,
 };
+use crate::scheduler::Scheduler;
+use crate::tempo::BeatMelody;
+use crate::threads::{spawn_named, AI_THREAD, MAX_PLAYBACK_THREADS, PLAYBACK_THREAD, SINGLETON};
+use crate::training::PracticeTarget;
 use crate::{analyzer, arc_vec};
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::atomic::AtomicCell;
 use eframe::emath::Numeric;
 use midi_fundsp::io::SynthMsg;
-use midi_msg::{ChannelVoiceMsg, MidiMsg};
+use midi_msg::{Channel, ChannelVoiceMsg, ControlChange, MidiMsg};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info, warn};
 
 pub type AIFuncType = dyn Fn(&MelodyMaker, &Melody, f64) -> Melody + Send + Sync;
 pub type AITable = ChooserTable<Arc<AIFuncType>>;
 pub const NO_AI_NAME: &str = "Bypass";
 pub const DEFAULT_AI_NAME: &str = "Motive Mapper";
+pub const PERCUSSION_CHANNEL: Channel = Channel::Ch10;
+/// How long the recorder waits for input before checking for melodies from the GUI and
+/// for the end of the player's phrase.
+const INPUT_WAIT_MILLIS: u64 = 5;
+const SUSTAIN_PEDAL_CONTROL: u8 = 64;
+/// Pedal values this high or higher mean the pedal is held.
+const PEDAL_DOWN_VALUE: u8 = 64;
+const MIDDLE_C: u8 = 60;
+
+/// Divides the keyboard at `point`: notes below it form the bass zone and the rest the
+/// treble zone. Every note is still heard, but only the zones that respond are recorded
+/// for the AI to vary, so that a left-hand accompaniment can be kept out of its melodies.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyboardSplit {
+    pub enabled: bool,
+    pub point: u8,
+    pub bass_responds: bool,
+    pub treble_responds: bool,
+}
+
+impl Default for KeyboardSplit {
+    fn default() -> Self {
+        KeyboardSplit {
+            enabled: false,
+            point: MIDDLE_C,
+            bass_responds: false,
+            treble_responds: true,
+        }
+    }
+}
+
+impl KeyboardSplit {
+    pub fn records(&self, note: u8) -> bool {
+        if !self.enabled {
+            true
+        } else if note < self.point {
+            self.bass_responds
+        } else {
+            self.treble_responds
+        }
+    }
+}
 
 pub fn make_ai_table() -> AITable {
     let ai_funcs: Vec<(String, Arc<AIFuncType>)> = arc_vec![
         (NO_AI_NAME, |_, _, _| Melody::new()),
         (DEFAULT_AI_NAME, MelodyMaker::create_motive_variation),
-        ("Wanderer", MelodyMaker::create_wandering_variation)
+        ("Wanderer", MelodyMaker::create_wandering_variation),
+        ("Rhythm Shifter", MelodyMaker::create_rhythmic_variation),
+        ("Motive Developer", MelodyMaker::create_motive_development),
+        ("Contour", MelodyMaker::create_contour_variation),
+        ("Retrograde", retrograde),
+        ("Inversion", inversion),
+        ("Retrograde Inversion", retrograde_inversion)
     ];
     ChooserTable::from(&ai_funcs)
 }
 
+fn retrograde(_: &MelodyMaker, melody: &Melody, _: f64) -> Melody {
+    melody.retrograde()
+}
+
+/// Inverts around the first pitch heard, leaving a melody of rests as it is.
+fn inversion(_: &MelodyMaker, melody: &Melody, _: f64) -> Melody {
+    match first_sounding_pitch(melody) {
+        Some(axis) => melody.inverted(axis),
+        None => melody.clone(),
+    }
+}
+
+fn retrograde_inversion(_: &MelodyMaker, melody: &Melody, _: f64) -> Melody {
+    match first_sounding_pitch(melody) {
+        Some(axis) => melody.retrograde_inverted(axis),
+        None => melody.clone(),
+    }
+}
+
+fn first_sounding_pitch(melody: &Melody) -> Option<MidiByte> {
+    melody.iter().find(|n| !n.is_rest()).map(|n| n.pitch())
+}
+
+/// An additional AI voice that answers each phrase along with the main one, using its own
+/// algorithm and variation settings. Its variations play at the same time as the main
+/// variation, through the variation synthesizer. A voice set to `NO_AI_NAME` stays silent.
+#[derive(Clone)]
+pub struct Personality {
+    pub ai_table: Arc<Mutex<AITable>>,
+    pub variation_controls: VariationControls,
+}
+
 pub fn start_ai_thread(
     ai_table: Arc<Mutex<AITable>>,
-    input2ai: Arc<SegQueue<SynthMsg>>,
+    personalities: Vec<Personality>,
+    input2ai: Arc<BlockingQueue<SynthMsg>>,
     gui2ai: Arc<SegQueue<MelodyInfo>>,
-    ai2output: Arc<SegQueue<SynthMsg>>,
-    ai2dbase: Arc<SegQueue<FromAiMsg>>,
+    scheduler: Scheduler,
+    ai2dbase: Arc<BlockingQueue<FromAiMsg>>,
     variation_controls: VariationControls,
     replay_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pedal_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    phrase_segmentation: Arc<AtomicCell<PhraseSegmentation>>,
+    mpe: Arc<AtomicCell<bool>>,
+    split: Arc<AtomicCell<KeyboardSplit>>,
+    harmony: Arc<AtomicCell<HarmonyInterval>>,
+    practice: Arc<Mutex<Option<PracticeTarget>>>,
+    backing: BackingTransport,
+    clock: ClockSync,
     melody_progress: Arc<AtomicCell<Option<f32>>>,
     melody_run_status: MelodyRunStatus,
-) {
-    std::thread::spawn(move || {
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(AI_THREAD, SINGLETON, move || {
         let mut recorder = PlayerRecorder::new(
             input2ai,
             gui2ai,
-            ai2output.clone(),
+            scheduler.output(),
             replay_delay_slider.clone(),
+            pedal_delay_slider,
+            phrase_segmentation,
+            mpe,
+            split,
+            harmony,
+            variation_controls.clone(),
+            quit.clone(),
         );
         let performer = Performer::new(variation_controls.clone(), ai_table);
+        let voices = personalities
+            .into_iter()
+            .map(|p| Performer::new(p.variation_controls, p.ai_table))
+            .collect::<Vec<_>>();
         let min_melody_pitches = *analyzer::FIGURE_LENGTHS.iter().max().unwrap();
 
         loop {
-            let incoming = recorder.record();
+            let incoming = debug_span!("recording").in_scope(|| recorder.record());
+            if quit.load() {
+                // Keep whatever the player was in the middle of, rather than losing it.
+                if incoming.is_new()
+                    && long_enough(
+                        incoming.melody(),
+                        min_melody_pitches,
+                        replay_delay_slider.load().current(),
+                    )
+                {
+                    ai2dbase.push(FromAiMsg::MelodyOnly(incoming.melody().clone()));
+                }
+                ai2dbase.push(FromAiMsg::Shutdown);
+                break;
+            }
+            // While practicing, each phrase is an attempt at the target, with no response.
+            let target = practice.lock().unwrap().clone();
+            if let Some(target) = target {
+                if incoming.is_new() && incoming.melody().onset_indices().len() > 1 {
+                    let attempt = target.attempt(incoming.melody());
+                    info!("Scored {:.2} on {}", attempt.score, attempt.figure);
+                    ai2dbase.push(FromAiMsg::Practice(attempt));
+                }
+                continue;
+            }
             if long_enough(
                 incoming.melody(),
                 min_melody_pitches,
                 replay_delay_slider.load().current(),
             ) {
-                let melody = incoming
-                    .melody()
-                    .without_brief_notes(variation_controls.shortest_note_slider.load().current());
-                let variation = performer.create_variation(&melody);
+                if incoming.is_new() && !performer.will_respond() {
+                    incoming.push_bar_position(&backing, &ai2dbase);
+                    ai2dbase.push(FromAiMsg::MelodyOnly(incoming.melody().clone()));
+                    continue;
+                }
+                let (variation, report) = performer.respond_in_beats(incoming.melody());
                 if long_enough(
-                    &variation,
+                    &performed(&variation_controls, &variation),
                     min_melody_pitches,
                     replay_delay_slider.load().current(),
                 ) {
-                    let stats = variation_controls.stats(performer.current_name());
-                    ai2dbase.push(incoming.database_msg(&variation, stats));
+                    let mut stats = variation_controls.stats(performer.current_name());
+                    stats.report = report;
+                    let mut voice_variations = vec![];
+                    for voice in voices.iter() {
+                        let (voice_variation, report) = voice.respond_in_beats(incoming.melody());
+                        if long_enough(
+                            &performed(&voice.variation_controls, &voice_variation),
+                            min_melody_pitches,
+                            replay_delay_slider.load().current(),
+                        ) {
+                            let mut stats = voice.variation_controls.stats(voice.current_name());
+                            stats.report = report;
+                            voice_variations.push((voice_variation, voice, stats));
+                        }
+                    }
+                    incoming.push_bar_position(&backing, &ai2dbase);
                     melody_run_status.send_stop();
-                    while melody_run_status.is_stopping() {}
-                    send_recorded_melody(
-                        &variation,
-                        VARIATION_SPEAKER,
-                        ai2output.clone(),
-                        melody_progress.clone(),
-                        melody_run_status.clone(),
-                    );
+                    melody_run_status.wait_until_stopped();
+                    // Along with a backing track, responses begin on its next bar, and in time
+                    // with MIDI clock, on the next beat.
+                    // Every voice starts at the same moment.
+                    let wait = backing
+                        .until_next_bar()
+                        .or_else(|| clock.until_next_beat())
+                        .unwrap_or(0.0);
+                    let start = Instant::now() + Duration::from_secs_f64(wait);
+                    // Only now are the variations timed in seconds, at the tempo set as
+                    // they start.
+                    let variation = performed(&variation_controls, &variation);
+                    let (voice_variations, voice_playback): (Vec<_>, Vec<_>) = voice_variations
+                        .into_iter()
+                        .map(|(v, voice, stats)| {
+                            let v = performed(&voice.variation_controls, &v);
+                            let performance = voice.humanized(&v);
+                            ((v, stats), performance)
+                        })
+                        .unzip();
+                    for msg in incoming.database_msgs(&variation, stats, &voice_variations) {
+                        ai2dbase.push(msg);
+                    }
+                    let performance = performer.humanized(&variation);
+                    for voice_performance in voice_playback {
+                        let scheduler = scheduler.clone();
+                        let melody_run_status = melody_run_status.clone();
+                        let started =
+                            spawn_named(PLAYBACK_THREAD, MAX_PLAYBACK_THREADS, move || {
+                                send_recorded_melody(
+                                    &voice_performance,
+                                    VARIATION_SPEAKER,
+                                    start,
+                                    scheduler,
+                                    // Only the main variation's progress is shown.
+                                    Arc::new(AtomicCell::new(None)),
+                                    melody_run_status,
+                                );
+                            });
+                        if let Err(e) = started {
+                            warn!("A voice's variation was not played: {e}");
+                        }
+                    }
+                    if variation_controls.overlap.load() {
+                        // Play in the background so that the recorder can keep capturing
+                        // the player while the variation is heard.
+                        let scheduler = scheduler.clone();
+                        let melody_progress = melody_progress.clone();
+                        let melody_run_status = melody_run_status.clone();
+                        let started =
+                            spawn_named(PLAYBACK_THREAD, MAX_PLAYBACK_THREADS, move || {
+                                send_recorded_melody(
+                                    &performance,
+                                    VARIATION_SPEAKER,
+                                    start,
+                                    scheduler,
+                                    melody_progress,
+                                    melody_run_status,
+                                );
+                            });
+                        if let Err(e) = started {
+                            warn!("Variation not played: {e}");
+                        }
+                    } else {
+                        send_recorded_melody(
+                            &performance,
+                            VARIATION_SPEAKER,
+                            start,
+                            scheduler.clone(),
+                            melody_progress.clone(),
+                            melody_run_status.clone(),
+                        );
+                    }
                 }
             }
         }
-    });
+    })
 }
 
 fn _print_debug(melody: &Melody, label: &str) {
-    println!("{label} ({} s): {melody:?}", melody.duration());
+    debug!("{label} ({} s): {melody:?}", melody.duration());
     melody.tuple_print();
 }
 
-fn long_enough(melody: &Melody, min_melody_pitches: usize, min_duration: f64) -> bool {
+/// `variation` in seconds at the playback tempo, cut or padded to a turn when trading bars.
+fn performed(variation_controls: &VariationControls, variation: &BeatMelody) -> Melody {
+    let performed = variation_controls.performed(variation);
+    match variation_controls.turn_seconds() {
+        Some(turn_seconds) => performed.fitted_to(turn_seconds),
+        None => performed,
+    }
+}
+
+pub(crate) fn long_enough(melody: &Melody, min_melody_pitches: usize, min_duration: f64) -> bool {
     melody.num_pitch_changes() >= min_melody_pitches && melody.duration() > min_duration
 }
 
+/// Settings that decide how the player's MIDI becomes melodies. The live recorder reads
+/// them from the GUI's controls as each message arrives.
+#[derive(Copy, Clone, Debug)]
+pub struct RecordingSettings {
+    pub mpe: bool,
+    pub split: KeyboardSplit,
+    pub phrase_segmentation: PhraseSegmentation,
+    pub replay_delay: f64,
+    pub pedal_delay: f64,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        RecordingSettings {
+            mpe: false,
+            split: KeyboardSplit::default(),
+            phrase_segmentation: PhraseSegmentation::SilenceDelay,
+            replay_delay: replay_slider().current(),
+            pedal_delay: pedal_replay_slider().current(),
+        }
+    }
+}
+
+/// A MIDI message from the player, `seconds` after recording began.
+#[derive(Clone, Debug)]
+pub struct TimedMidi {
+    pub seconds: f64,
+    pub msg: MidiMsg,
+}
+
+/// Turns the player's notes into melodies one phrase at a time, given the time at which
+/// each message arrived. It knows nothing of queues or clocks, so that the live recorder
+/// and `record_from_events` behave alike.
+pub struct PhraseRecorder {
+    waiting: Option<PendingNote>,
+    pedal_held: bool,
+    sounding: Option<(Channel, u8)>,
+    melody: Melody,
+}
+
+impl PhraseRecorder {
+    pub fn new() -> Self {
+        PhraseRecorder {
+            waiting: None,
+            pedal_held: false,
+            sounding: None,
+            melody: Melody::new(),
+        }
+    }
+
+    /// Records `msg`, which arrived on `channel` at `seconds`. Returns whether it was a
+    /// note that is being recorded, rather than percussion, a note in a zone that does not
+    /// respond, or some other message.
+    pub fn receive(
+        &mut self,
+        seconds: f64,
+        channel: Channel,
+        msg: ChannelVoiceMsg,
+        settings: &RecordingSettings,
+    ) -> bool {
+        match msg {
+            // Drum pads send General MIDI percussion on channel 10; those hits are
+            // not pitches, so they are played but kept out of the recorded melody.
+            // In MPE mode, channel 10 is just another member channel.
+            _ if channel == PERCUSSION_CHANNEL && !settings.mpe => false,
+            ChannelVoiceMsg::NoteOff { note, .. } | ChannelVoiceMsg::NoteOn { note, .. }
+                if !settings.split.records(note) =>
+            {
+                false
+            }
+            ChannelVoiceMsg::NoteOff { note, velocity }
+            | ChannelVoiceMsg::NoteOn { note, velocity } => {
+                let released = matches!(msg, ChannelVoiceMsg::NoteOff { .. }) || velocity == 0;
+                if !settings.mpe || self.continues_stream(channel, note, released) {
+                    if let Some(pending_note) = self.waiting {
+                        self.melody.add(pending_note.ended_at(seconds));
+                    }
+                    self.waiting = Some(PendingNote::new(note, velocity, seconds));
+                }
+                true
+            }
+            ChannelVoiceMsg::ControlChange {
+                control: ControlChange::Hold(value),
+            }
+            | ChannelVoiceMsg::ControlChange {
+                control:
+                    ControlChange::CC {
+                        control: SUSTAIN_PEDAL_CONTROL,
+                        value,
+                    },
+            } => {
+                self.pedal_held = value >= PEDAL_DOWN_VALUE;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// In MPE mode every note arrives on its own member channel, so notes overlap freely.
+    /// Only a new note or the release of the latest one changes the melody; releasing an
+    /// earlier, overlapped note does not.
+    fn continues_stream(&mut self, channel: Channel, note: u8, released: bool) -> bool {
+        if !released {
+            self.sounding = Some((channel, note));
+            true
+        } else if self.sounding == Some((channel, note)) {
+            self.sounding = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the player has been silent long enough by `seconds` to have finished the
+    /// phrase. If so, the silence becomes the phrase's final rest.
+    pub fn phrase_over(&mut self, seconds: f64, settings: &RecordingSettings) -> bool {
+        match self.waiting {
+            Some(pending_note) if pending_note.is_rest() => {
+                let replay_delay = if self.pedal_held {
+                    settings.pedal_delay
+                } else {
+                    settings.replay_delay
+                };
+                let silence = settings
+                    .phrase_segmentation
+                    .phrase_end_silence(&self.melody, replay_delay);
+                if pending_note.held_at(seconds) > silence {
+                    self.melody
+                        .add(pending_note.ended_at(pending_note.started + silence));
+                    self.waiting = None;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the phrase recorded so far, cutting off any note still held at `seconds`,
+    /// and starts a new one.
+    pub fn finish(&mut self, seconds: f64) -> Melody {
+        if let Some(pending_note) = self.waiting.take() {
+            self.melody.add(pending_note.ended_at(seconds));
+        }
+        let mut result = Melody::new();
+        std::mem::swap(&mut result, &mut self.melody);
+        if result.len() > 0 {
+            result.synchronize_rests();
+        }
+        result
+    }
+}
+
+/// Divides `events` into phrases as the live recorder would, ending the last phrase at
+/// the last event.
+pub fn record_from_events(events: &[TimedMidi], settings: &RecordingSettings) -> Vec<Melody> {
+    let mut recorder = PhraseRecorder::new();
+    let mut phrases = vec![];
+    for event in events {
+        if recorder.phrase_over(event.seconds, settings) {
+            phrases.push(recorder.finish(event.seconds));
+        }
+        if let MidiMsg::ChannelVoice { channel, msg } = event.msg {
+            recorder.receive(event.seconds, channel, msg, settings);
+        }
+    }
+    if let Some(last) = events.last() {
+        let phrase = recorder.finish(last.seconds);
+        if phrase.len() > 0 {
+            phrases.push(phrase);
+        }
+    }
+    phrases
+}
+
 struct PlayerRecorder {
-    input2ai: Arc<SegQueue<SynthMsg>>,
+    input2ai: Arc<BlockingQueue<SynthMsg>>,
     gui2ai: Arc<SegQueue<MelodyInfo>>,
-    ai2output: Arc<SegQueue<SynthMsg>>,
+    ai2output: Arc<BlockingQueue<SynthMsg>>,
     replay_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
-    waiting: Option<PendingNote>,
-    player_melody: Melody,
+    pedal_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    phrase_segmentation: Arc<AtomicCell<PhraseSegmentation>>,
+    mpe: Arc<AtomicCell<bool>>,
+    split: Arc<AtomicCell<KeyboardSplit>>,
+    harmony: Arc<AtomicCell<HarmonyInterval>>,
+    harmonizer: Harmonizer,
+    variation_controls: VariationControls,
+    quit: Arc<AtomicCell<bool>>,
+    phrase: PhraseRecorder,
+    started: Instant,
+    turn_start: Option<Instant>,
 }
 
 impl PlayerRecorder {
     fn new(
-        input2ai: Arc<SegQueue<SynthMsg>>,
+        input2ai: Arc<BlockingQueue<SynthMsg>>,
         gui2ai: Arc<SegQueue<MelodyInfo>>,
-        ai2output: Arc<SegQueue<SynthMsg>>,
+        ai2output: Arc<BlockingQueue<SynthMsg>>,
         replay_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+        pedal_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+        phrase_segmentation: Arc<AtomicCell<PhraseSegmentation>>,
+        mpe: Arc<AtomicCell<bool>>,
+        split: Arc<AtomicCell<KeyboardSplit>>,
+        harmony: Arc<AtomicCell<HarmonyInterval>>,
+        variation_controls: VariationControls,
+        quit: Arc<AtomicCell<bool>>,
     ) -> Self {
         PlayerRecorder {
             input2ai,
             gui2ai,
             ai2output,
             replay_delay_slider,
-            waiting: None,
-            player_melody: Melody::new(),
+            pedal_delay_slider,
+            phrase_segmentation,
+            mpe,
+            split,
+            harmony,
+            harmonizer: Harmonizer::new(),
+            variation_controls,
+            quit,
+            phrase: PhraseRecorder::new(),
+            started: Instant::now(),
+            turn_start: None,
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    fn settings(&self) -> RecordingSettings {
+        RecordingSettings {
+            mpe: self.mpe.load(),
+            split: self.split.load(),
+            phrase_segmentation: self.phrase_segmentation.load(),
+            replay_delay: self.replay_delay_slider.load().current(),
+            pedal_delay: self.pedal_delay_slider.load().current(),
         }
     }
 
     fn record(&mut self) -> IncomingMelody {
-        self.waiting = None;
         let mut player_finished = false;
-        while !player_finished {
+        while !player_finished && !self.quit.load() {
             if let Some(melody) = self.gui2ai.pop() {
                 return IncomingMelody::Preexisting(melody);
             }
-            if let Some(mut synth_msg) = self.input2ai.pop() {
+            if let Some(mut synth_msg) = self
+                .input2ai
+                .pop_timeout(Duration::from_millis(INPUT_WAIT_MILLIS))
+            {
                 synth_msg.speaker = HUMAN_SPEAKER;
                 self.handle_incoming(synth_msg);
             }
 
-            if let Some(pending_note) = self.waiting {
-                player_finished = self.check_if_finished(pending_note);
+            if let Some(turn_seconds) = self.variation_controls.turn_seconds() {
+                player_finished = self.turn_over(turn_seconds);
+            } else {
+                self.turn_start = None;
+                player_finished = self.phrase.phrase_over(self.now(), &self.settings());
             }
         }
-        let mut result = Melody::new();
-        std::mem::swap(&mut result, &mut self.player_melody);
-        result.synchronize_rests();
-        IncomingMelody::New(result)
+        IncomingMelody::New(self.phrase.finish(self.now()))
     }
 
     fn handle_incoming(&mut self, synth_msg: SynthMsg) {
-        if let MidiMsg::ChannelVoice { channel: _, msg } = synth_msg.msg {
-            match msg {
-                ChannelVoiceMsg::NoteOff { note, velocity }
-                | ChannelVoiceMsg::NoteOn { note, velocity } => {
-                    if let Some(pending_note) = self.waiting {
-                        self.player_melody.add(pending_note.into());
-                    }
-                    self.waiting = Some(PendingNote::new(note, velocity));
-                }
-                _ => {}
+        let mut harmony = None;
+        if let MidiMsg::ChannelVoice { channel, msg } = synth_msg.msg {
+            let settings = self.settings();
+            if self.phrase.receive(self.now(), channel, msg, &settings) {
+                harmony = self
+                    .harmonizer
+                    .harmonize(self.harmony.load(), channel, msg)
+                    .map(|msg| SynthMsg {
+                        msg: MidiMsg::ChannelVoice { channel, msg },
+                        speaker: VARIATION_SPEAKER,
+                    });
             }
         }
         self.ai2output.push(synth_msg);
+        if let Some(harmony) = harmony {
+            self.ai2output.push(harmony);
+        }
     }
 
-    fn check_if_finished(&mut self, pending_note: PendingNote) -> bool {
-        let replay_delay = self.replay_delay_slider.load();
-        if pending_note.is_rest() && pending_note.elapsed() > replay_delay.current() {
-            self.player_melody.add(pending_note.into());
-            true
-        } else {
-            false
+    /// When trading bars, the player's turn starts with their first note and ends
+    /// `turn_seconds` later, however they phrase it. The next turn begins once the AI
+    /// has had a turn of the same length. A turn in which the player plays nothing
+    /// stops the trading until they play again.
+    fn turn_over(&mut self, turn_seconds: f64) -> bool {
+        let now = Instant::now();
+        let turn = Duration::from_secs_f64(turn_seconds);
+        match (self.turn_start, self.phrase.waiting) {
+            (None, waiting) => {
+                if waiting.is_some() {
+                    self.turn_start = Some(now);
+                }
+                false
+            }
+            (Some(start), _) if now < start + turn => false,
+            (Some(_), None) => {
+                self.turn_start = None;
+                false
+            }
+            (Some(start), Some(pending_note)) => {
+                self.phrase.melody.add(pending_note.ended_at(self.now()));
+                self.phrase.waiting = None;
+                self.turn_start = Some(start + turn * 2);
+                true
+            }
         }
     }
 }
 
-struct Performer {
+/// Creates variations using whichever algorithm and settings are currently chosen.
+pub struct Performer {
     maker: MelodyMaker,
     variation_controls: VariationControls,
     ai_table: Arc<Mutex<AITable>>,
 }
 
 impl Performer {
-    fn new(variation_controls: VariationControls, ai_table: Arc<Mutex<AITable>>) -> Self {
+    pub fn new(variation_controls: VariationControls, ai_table: Arc<Mutex<AITable>>) -> Self {
+        Self::with_maker(variation_controls, ai_table, MelodyMaker::new())
+    }
+
+    /// Uses `maker`, such as a seeded one, for every variation.
+    pub fn with_maker(
+        variation_controls: VariationControls,
+        ai_table: Arc<Mutex<AITable>>,
+        maker: MelodyMaker,
+    ) -> Self {
         Performer {
-            maker: MelodyMaker::new(),
+            maker,
             variation_controls,
             ai_table,
         }
@@ -190,7 +665,51 @@ impl Performer {
         ai_table.current_name().to_owned()
     }
 
-    fn create_variation(&self, melody: &Melody) -> Melody {
+    /// Whether to answer the phrase just played, decided with this performer's maker.
+    pub fn will_respond(&self) -> bool {
+        self.variation_controls.will_respond(&self.maker)
+    }
+
+    /// `melody` humanized as the settings direct, with this performer's maker.
+    pub fn humanized(&self, melody: &Melody) -> Melody {
+        self.maker
+            .humanized(melody, &self.variation_controls.humanize())
+    }
+
+    /// Cleans up `melody` as the input settings direct, then varies it and sets the
+    /// variation's playback tempo.
+    pub fn respond_to(&self, melody: &Melody) -> (Melody, VariationReport) {
+        let (variation, report) = self.respond_in_beats(melody);
+        (self.variation_controls.performed(&variation), report)
+    }
+
+    /// Like `respond_to`, but counts the variation in beats of the player's tempo, so that
+    /// its playback tempo can be chosen as it starts.
+    pub fn respond_in_beats(&self, melody: &Melody) -> (BeatMelody, VariationReport) {
+        let controls = &self.variation_controls;
+        let melody = self.cleaned(melody);
+        let (variation, report) = self.create_variation(&melody);
+        (controls.in_beats(&melody, &variation), report)
+    }
+
+    /// `melody` as it is varied: without notes too brief to count and, if the input is
+    /// thinned, without its densest notes.
+    pub fn cleaned(&self, melody: &Melody) -> Melody {
+        let controls = &self.variation_controls;
+        let melody = melody.without_brief_notes(controls.shortest_note_slider.load().current());
+        if controls.thin_input.load() {
+            melody.thinned(controls.max_density_slider.load().current())
+        } else {
+            melody
+        }
+    }
+
+    /// Varies and ornaments `melody` with the current algorithm, without the input clean-up
+    /// and tempo changes of `respond_to`.
+    pub fn create_variation(&self, melody: &Melody) -> (Melody, VariationReport) {
+        let _span =
+            debug_span!("variation", algorithm = %self.current_name(), notes = melody.len())
+                .entered();
         let p_random = Self::from_slider(&self.variation_controls.p_random_slider);
         let p_ornament = Self::from_slider(&self.variation_controls.p_ornament_slider);
         let whimsify = self.variation_controls.whimsify.load();
@@ -198,27 +717,55 @@ impl Performer {
             let ai_table = self.ai_table.lock().unwrap();
             ai_table.current_choice()
         };
-        let mut variation = var_func(&self.maker, &melody, p_random);
+        let (source, gestures) = if self.variation_controls.preserve_gestures.load() {
+            melody.collapsed_gestures()
+        } else {
+            (melody.clone(), vec![])
+        };
+        let mut variation = var_func(&self.maker, &source, p_random);
         if variation.len() > 0 && whimsify {
             variation = self.maker.whimsified_ending(&variation);
         }
-        self.maker
-            .ornamented(&melody.best_scale_for(), &variation, p_ornament)
+        let variation = self
+            .variation_controls
+            .rhythm_preservation
+            .load()
+            .applied(&variation, &source)
+            .with_gestures(&gestures);
+        let variation = self
+            .variation_controls
+            .dynamics
+            .load()
+            .applied(&variation, melody);
+        let variation = match self.variation_controls.chord_track(melody) {
+            Some(chords) => variation.targeting_chord_tones(&chords),
+            None => variation,
+        };
+        let ornamented = self.maker.ornamented_with(
+            &melody.best_scale_for(),
+            &variation,
+            p_ornament,
+            self.variation_controls.ornament_settings(),
+        );
+        let report = self.maker.variation_report(melody, &variation, &ornamented);
+        (ornamented, report)
     }
 }
 
+/// A note, or a rest if its velocity is 0, that began `started` seconds into recording
+/// and has not yet ended.
 #[derive(Copy, Clone)]
 pub struct PendingNote {
     pitch: u8,
-    timestamp: Instant,
+    started: f64,
     velocity: u8,
 }
 
 impl PendingNote {
-    pub fn new(pitch: u8, velocity: u8) -> Self {
+    pub fn new(pitch: u8, velocity: u8, started: f64) -> Self {
         PendingNote {
             pitch,
-            timestamp: Instant::now(),
+            started,
             velocity,
         }
     }
@@ -227,8 +774,9 @@ impl PendingNote {
         self.pitch
     }
 
-    pub fn elapsed(&self) -> f64 {
-        self.timestamp.elapsed().as_secs_f64()
+    /// How long the note has lasted at `seconds`.
+    pub fn held_at(&self, seconds: f64) -> f64 {
+        (seconds - self.started).max(0.0)
     }
 
     pub fn is_rest(&self) -> bool {
@@ -238,14 +786,13 @@ impl PendingNote {
     pub fn instant_rest_from(&self) -> Note {
         Note::new(self.pitch as MidiByte, 0.0, 0)
     }
-}
 
-impl From<PendingNote> for Note {
-    fn from(pending_note: PendingNote) -> Self {
+    /// The note as recorded, if it ends at `seconds`.
+    pub fn ended_at(&self, seconds: f64) -> Note {
         Note::new(
-            pending_note.pitch as MidiByte,
-            pending_note.elapsed(),
-            pending_note.velocity as MidiByte,
+            self.pitch as MidiByte,
+            self.held_at(seconds),
+            self.velocity as MidiByte,
         )
     }
 }
@@ -257,6 +804,13 @@ enum IncomingMelody {
 }
 
 impl IncomingMelody {
+    fn is_new(&self) -> bool {
+        match self {
+            IncomingMelody::New(_) => true,
+            IncomingMelody::Preexisting(_) => false,
+        }
+    }
+
     fn melody(&self) -> &Melody {
         match self {
             IncomingMelody::New(melody) => melody,
@@ -264,18 +818,147 @@ impl IncomingMelody {
         }
     }
 
-    fn database_msg(&self, variation: &Melody, stats: VariationStats) -> FromAiMsg {
+    /// Tells the database where the backing track was when a new melody began, if it was
+    /// playing, so that the melody is tagged with the bar.
+    fn push_bar_position(&self, backing: &BackingTransport, ai2dbase: &BlockingQueue<FromAiMsg>) {
+        if let IncomingMelody::New(melody) = self {
+            if let Some(position) = backing.position_before(melody.duration()) {
+                ai2dbase.push(FromAiMsg::BarPosition(position));
+            }
+        }
+    }
+
+    /// Stores `variation`, followed by the variations of any other voices in `others`.
+    fn database_msgs(
+        &self,
+        variation: &Melody,
+        stats: VariationStats,
+        others: &[(Melody, VariationStats)],
+    ) -> Vec<FromAiMsg> {
         match self {
-            IncomingMelody::New(melody) => FromAiMsg::MelodyVariation {
-                melody: melody.clone(),
-                variation: variation.clone(),
-                stats,
-            },
-            IncomingMelody::Preexisting(info) => FromAiMsg::AlternateVariation {
-                melody_id: info.row_id(),
-                variation: variation.clone(),
-                stats,
+            IncomingMelody::New(melody) if others.is_empty() => {
+                vec![FromAiMsg::MelodyVariation {
+                    melody: melody.clone(),
+                    variation: variation.clone(),
+                    stats,
+                }]
+            }
+            IncomingMelody::New(melody) => {
+                let mut variations = vec![(variation.clone(), stats)];
+                variations.extend(others.iter().cloned());
+                vec![FromAiMsg::MelodyVariations {
+                    melody: melody.clone(),
+                    variations,
+                }]
+            }
+            IncomingMelody::Preexisting(info) => Some((variation.clone(), stats))
+                .into_iter()
+                .chain(others.iter().cloned())
+                .map(|(variation, stats)| FromAiMsg::AlternateVariation {
+                    melody_id: info.row_id(),
+                    variation,
+                    stats,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(seconds: f64, channel: Channel, note: u8, velocity: u8) -> TimedMidi {
+        TimedMidi {
+            seconds,
+            msg: MidiMsg::ChannelVoice {
+                channel,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity },
             },
         }
     }
+
+    fn pitches(melody: &Melody) -> Vec<MidiByte> {
+        melody
+            .iter()
+            .filter(|n| !n.is_rest())
+            .map(|n| n.pitch())
+            .collect()
+    }
+
+    fn two_phrases() -> Vec<TimedMidi> {
+        vec![
+            note(0.0, Channel::Ch1, 60, 100),
+            note(0.5, Channel::Ch1, 60, 0),
+            note(0.5, Channel::Ch1, 62, 100),
+            note(1.0, Channel::Ch1, 62, 0),
+            note(1.0, PERCUSSION_CHANNEL, 36, 100),
+            note(1.0, Channel::Ch1, 64, 100),
+            note(2.0, Channel::Ch1, 64, 0),
+            note(5.0, Channel::Ch1, 67, 100),
+            note(5.5, Channel::Ch1, 67, 0),
+        ]
+    }
+
+    #[test]
+    fn test_record_from_events() {
+        let settings = RecordingSettings::default();
+        let phrases = record_from_events(&two_phrases(), &settings);
+        assert_eq!(phrases.len(), 2);
+        assert_eq!(pitches(&phrases[0]), vec![60, 62, 64]);
+        assert_eq!(pitches(&phrases[1]), vec![67]);
+        let last = phrases[0][phrases[0].len() - 1];
+        assert!(last.is_rest());
+        assert_eq!(last.duration(), settings.replay_delay);
+
+        let longer = RecordingSettings {
+            replay_delay: 4.0,
+            ..settings
+        };
+        let phrases = record_from_events(&two_phrases(), &longer);
+        assert_eq!(phrases.len(), 1);
+        assert_eq!(pitches(&phrases[0]), vec![60, 62, 64, 67]);
+        assert_eq!(record_from_events(&[], &settings).len(), 0);
+    }
+
+    #[test]
+    fn test_performer() {
+        let controls = VariationControls::new();
+        let p_ornament = controls.p_ornament_slider.load();
+        controls.p_ornament_slider.store(p_ornament.slid_to(0.0));
+        let mut table = make_ai_table();
+        table.choose("Retrograde");
+        let performer = Performer::new(controls.clone(), Arc::new(Mutex::new(table)));
+        let phrases = record_from_events(&two_phrases(), &RecordingSettings::default());
+        let phrase = &phrases[0];
+        let (variation, _) = performer.create_variation(phrase);
+        assert_eq!(variation, phrase.retrograde());
+
+        let performers: Vec<Performer> = (0..2)
+            .map(|_| {
+                Performer::with_maker(
+                    controls.clone(),
+                    Arc::new(Mutex::new(make_ai_table())),
+                    MelodyMaker::seeded(592),
+                )
+            })
+            .collect();
+        assert_eq!(
+            performers[0].create_variation(phrase).0,
+            performers[1].create_variation(phrase).0
+        );
+    }
+
+    #[test]
+    fn test_inversion_axis() {
+        let maker = MelodyMaker::new();
+        // The leading rest's pitch is not the axis.
+        let melody = Melody::from("0,0.5,0.0,60,0.5,0.8,64,0.5,0.8");
+        let pitches = |m: &Melody| m.iter().map(|n| n.pitch()).collect::<Vec<_>>();
+        assert_eq!(pitches(&inversion(&maker, &melody, 0.0)), vec![120, 60, 56]);
+        for rests in [Melody::new(), Melody::from("0,0.5,0.0")] {
+            assert_eq!(inversion(&maker, &rests, 0.0), rests);
+            assert_eq!(retrograde_inversion(&maker, &rests, 0.0), rests);
+        }
+    }
 }
@@ -1,4 +1,6 @@
+use crate::pitch;
 use crate::subsequence_finder::{find_maximal_repeated_subs, Subsequences};
+use anyhow::anyhow;
 use bare_metal_modulo::{MNum, ModNumC, OffsetNumC};
 use distribution_select::Distribution;
 use enum_iterator::{all, Sequence};
@@ -7,16 +9,26 @@ use histogram_macros::*;
 use midi_msg::MidiMsg::ChannelVoice;
 use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
 use ordered_float::OrderedFloat;
+use rand::distributions::Standard;
 use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::f64::consts::PI;
+use std::fmt::{Display, Formatter};
 use std::iter::Sum;
 use std::ops::RangeInclusive;
 use std::ops::{AddAssign, Neg};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
 
 pub type MidiByte = i16;
 
-const MAX_MIDI_VALUE: MidiByte = i8::MAX as MidiByte;
+pub const MAX_MIDI_VALUE: MidiByte = i8::MAX as MidiByte;
+/// No note or rest is held longer than this; far longer ones cannot be scheduled at all.
+const MAX_NOTE_SECONDS: f64 = 3600.0;
 const NOTES_PER_OCTAVE: MidiByte = 12;
 const USIZE_NOTES_PER_OCTAVE: usize = NOTES_PER_OCTAVE as usize;
 const DIATONIC_SCALE_SIZE: usize = 7;
@@ -93,7 +105,7 @@ pub fn velocity2volume(midi_velocity: MidiByte) -> f64 {
     max(0, min(MAX_MIDI_VALUE, midi_velocity)) as f64 / MAX_MIDI_VALUE as f64
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Note {
     pitch: MidiByte,
     duration: OrderedFloat<f64>,
@@ -139,6 +151,14 @@ impl Note {
         (midi, self.duration.into_inner())
     }
 
+    /// Whether the pitch and velocity can be sent as MIDI, and the duration is a length of
+    /// time that can be waited for.
+    pub fn is_valid(&self) -> bool {
+        (0..=MAX_MIDI_VALUE).contains(&self.pitch)
+            && (0..=MAX_MIDI_VALUE).contains(&self.velocity)
+            && (0.0..=MAX_NOTE_SECONDS).contains(&self.duration.into_inner())
+    }
+
     pub fn pitch(&self) -> MidiByte {
         self.pitch
     }
@@ -163,6 +183,14 @@ impl Note {
         }
     }
 
+    pub fn retimed(&self, new_duration: f64) -> Note {
+        Note {
+            pitch: self.pitch,
+            duration: OrderedFloat(new_duration),
+            velocity: self.velocity,
+        }
+    }
+
     pub fn reweigh(&mut self, extra_duration: OrderedFloat<f64>, extra_intensity: MidiByte) {
         let durations = self.duration + extra_duration;
         self.velocity = ((self.duration.into_inner() * self.velocity as f64
@@ -193,7 +221,7 @@ fn dotify_float(f: OrderedFloat<f64>) -> String {
     result
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Melody {
     notes: Vec<Note>,
 }
@@ -324,6 +352,56 @@ impl Melody {
         format!("[{}]", list_str)
     }
 
+    /// A compact form for pasting into chat or notes: one `pitch:seconds:velocity` token per
+    /// note, such as `C4:0.5:100`, with velocity 0 for rests.
+    pub fn to_text(&self) -> String {
+        self.notes
+            .iter()
+            .map(|n| {
+                format!(
+                    "{}:{}:{}",
+                    pitch::name(n.pitch as u8),
+                    (n.duration() * 1000.0).round() / 1000.0,
+                    n.velocity
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn from_text(text: &str) -> anyhow::Result<Self> {
+        let mut melody = Melody::new();
+        for token in text.split_whitespace() {
+            let parts = token.split(':').collect::<Vec<_>>();
+            if parts.len() != 3 {
+                return Err(anyhow!("Expected pitch:seconds:velocity, found {token}"));
+            }
+            let pitch = pitch::from_name(parts[0]).ok_or(anyhow!("Unknown pitch {}", parts[0]))?;
+            let duration = parts[1].parse::<f64>()?;
+            let velocity = parts[2].parse::<u8>()?;
+            let note = Note::new(pitch, duration, velocity as MidiByte);
+            if !note.is_valid() {
+                return Err(anyhow!("Out of range: {token}"));
+            }
+            melody.add(note);
+        }
+        Ok(melody)
+    }
+
+    /// For interchange with analysis scripts: `{"notes": [{"pitch": 60, "duration": 0.5,
+    /// "velocity": 100}, ...]}`, with velocity 0 for rests.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let melody: Melody = serde_json::from_str(json)?;
+        if melody.notes.iter().any(|n| !n.is_valid()) {
+            return Err(anyhow!("Out of range: {json}"));
+        }
+        Ok(melody)
+    }
+
     pub fn last_note(&self) -> Note {
         *self.notes.last().unwrap()
     }
@@ -343,7 +421,7 @@ impl Melody {
             result.push_str(
                 format!(
                     "{} [({:2}) ({:2})] ",
-                    scale.note_str(n.pitch),
+                    pitch::spelled_name(&scale, n.pitch),
                     n.duration,
                     n.velocity
                 )
@@ -513,6 +591,459 @@ impl Melody {
         result
     }
 
+    /// Returns a copy of this melody with onsets dropped wherever they arrive faster than
+    /// `max_onsets_per_second`, as in a glissando. The time of each dropped note and its rests
+    /// is added to the preceding event. The first and last notes are always kept.
+    pub fn thinned(&self, max_onsets_per_second: f64) -> Melody {
+        let onsets = self.onset_indices();
+        if onsets.is_empty() || max_onsets_per_second <= 0.0 {
+            return self.clone();
+        }
+        let min_gap = 1.0 / max_onsets_per_second;
+        let mut result = self.fragment(0, onsets[0]);
+        let mut time = self.notes[0..onsets[0]]
+            .iter()
+            .map(|n| n.duration())
+            .sum::<f64>();
+        let mut last_kept: Option<f64> = None;
+        for (k, start) in onsets.iter().enumerate() {
+            let end = onsets.get(k + 1).copied().unwrap_or(self.len());
+            let length = self.duration_with_rest(*start).into_inner();
+            let keep = k + 1 == onsets.len() || last_kept.map_or(true, |t| time - t >= min_gap);
+            if keep {
+                for i in *start..end {
+                    result.add(self[i]);
+                }
+                last_kept = Some(time);
+            } else {
+                let last = result.len() - 1;
+                result[last] = result[last].retimed(result[last].duration() + length);
+            }
+            time += length;
+        }
+        result
+    }
+
+    /// Finds the trills and glissandi in this melody. Each is returned with the ordinal of
+    /// its first onset and the number of onsets it spans.
+    pub fn gestures(&self) -> Vec<(usize, usize, Gesture)> {
+        let onsets = self.onset_indices();
+        let lengths = self.onset_lengths();
+        let mut result = vec![];
+        let mut k = 0;
+        while k < onsets.len() {
+            match Gesture::find(self, &onsets, &lengths, k) {
+                Some((span, gesture)) => {
+                    result.push((k, span, gesture));
+                    k += span;
+                }
+                None => k += 1,
+            }
+        }
+        result
+    }
+
+    /// Replaces every trill and glissando with a single note on its first pitch, lasting as
+    /// long as the whole gesture. Returns the simplified melody along with each gesture and
+    /// the ordinal of the onset that now stands for it.
+    pub fn collapsed_gestures(&self) -> (Melody, Vec<(usize, Gesture)>) {
+        let onsets = self.onset_indices();
+        let mut result = self.fragment(0, onsets.first().copied().unwrap_or(self.len()));
+        let mut placeholders = vec![];
+        let mut next = 0;
+        for (k, span, gesture) in self.gestures() {
+            for ordinal in next..k {
+                result.append(&self.onset_group(&onsets, ordinal));
+            }
+            let last_group = self.onset_group(&onsets, k + span - 1);
+            let final_rest = last_group.duration() - last_group[0].duration();
+            let total = (k..k + span)
+                .map(|o| self.duration_with_rest(onsets[o]).into_inner())
+                .sum::<f64>();
+            let first = self[onsets[k]];
+            placeholders.push((result.onset_indices().len(), gesture));
+            result.add(Note::new(first.pitch, total - final_rest, first.velocity));
+            result.add(Note::new(first.pitch, final_rest, 0));
+            next = k + span;
+        }
+        for ordinal in next..onsets.len() {
+            result.append(&self.onset_group(&onsets, ordinal));
+        }
+        (result, placeholders)
+    }
+
+    /// Undoes `collapsed_gestures()`: each onset named in `gestures` is replaced by its
+    /// gesture, starting from that onset's pitch and spread across its duration.
+    pub fn with_gestures(&self, gestures: &[(usize, Gesture)]) -> Melody {
+        let onsets = self.onset_indices();
+        let mut result = self.fragment(0, onsets.first().copied().unwrap_or(self.len()));
+        for ordinal in 0..onsets.len() {
+            let group = self.onset_group(&onsets, ordinal);
+            match gestures.iter().find(|(o, _)| *o == ordinal) {
+                Some((_, gesture)) => result.append(&gesture.realized(&group)),
+                None => result.append(&group),
+            }
+        }
+        result
+    }
+
+    fn onset_group(&self, onsets: &[usize], ordinal: usize) -> Melody {
+        let start = onsets[ordinal];
+        let end = onsets.get(ordinal + 1).copied().unwrap_or(self.len());
+        self.fragment(start, end - start)
+    }
+
+    pub fn mean_note_on_velocity(&self) -> f64 {
+        let onsets = self.onset_indices();
+        if onsets.is_empty() {
+            0.0
+        } else {
+            onsets.iter().map(|i| self[*i].velocity as f64).sum::<f64>() / onsets.len() as f64
+        }
+    }
+
+    /// Returns the indices of every note that is not a rest.
+    pub fn onset_indices(&self) -> Vec<usize> {
+        (0..self.len()).filter(|i| !self[*i].is_rest()).collect()
+    }
+
+    /// Returns the time from each onset to the next, i.e., each note's duration with its rests.
+    pub fn onset_lengths(&self) -> Vec<f64> {
+        self.onset_indices()
+            .iter()
+            .map(|i| self.duration_with_rest(*i).into_inner())
+            .collect()
+    }
+
+    /// Returns the number of semitones from each onset to the next, ignoring rests.
+    pub fn onset_intervals(&self) -> Vec<MidiByte> {
+        let onsets = self.onset_indices();
+        onsets
+            .windows(2)
+            .map(|w| self[w[1]].pitch - self[w[0]].pitch)
+            .collect()
+    }
+
+    /// How different the shapes of two melodies are, from 0.0 for the same sequence of
+    /// intervals to 1.0 for nothing in common. It is the edit distance between their
+    /// interval sequences, relative to the longer one, so transposition and rhythm are
+    /// ignored.
+    pub fn interval_distance(&self, other: &Melody) -> f64 {
+        let (a, b) = (self.onset_intervals(), other.onset_intervals());
+        let longer = max(a.len(), b.len());
+        if longer == 0 {
+            return 0.0;
+        }
+        let mut previous = (0..=b.len()).collect::<Vec<_>>();
+        for (i, x) in a.iter().enumerate() {
+            let mut current = vec![i + 1];
+            for (j, y) in b.iter().enumerate() {
+                let substitution = previous[j] + usize::from(x != y);
+                current.push(min(substitution, min(previous[j + 1], current[j]) + 1));
+            }
+            previous = current;
+        }
+        previous[b.len()] as f64 / longer as f64
+    }
+
+    /// Returns a copy of this melody retimed so that the onset lengths match `lengths`.
+    /// Each note and its rests are scaled together.
+    pub fn with_onset_lengths(&self, lengths: &[f64]) -> Melody {
+        let onsets = self.onset_indices();
+        let mut result = self.clone();
+        for (k, start) in onsets.iter().enumerate() {
+            let old_length = self.duration_with_rest(*start).into_inner();
+            if old_length > 0.0 {
+                let factor = lengths[k] / old_length;
+                let end = onsets.get(k + 1).copied().unwrap_or(self.len());
+                for i in *start..end {
+                    result[i] = result[i].retimed(result[i].duration() * factor);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns a copy of this melody with onset times and velocities nudged at random by
+    /// `rng` and, optionally, evenly divided pairs of notes swung, as specified by
+    /// `humanize`.
+    pub fn humanized<R: Rng>(&self, humanize: &Humanize, rng: &mut R) -> Melody {
+        let mut lengths = self.onset_lengths();
+        if humanize.swing {
+            let mut k = 0;
+            while k + 1 < lengths.len() {
+                if (lengths[k] - lengths[k + 1]).abs() <= lengths[k] * SWING_TOLERANCE {
+                    k += RhythmicShift::Swing.apply(&mut lengths, k);
+                }
+                k += 1;
+            }
+        }
+        if humanize.timing_jitter > 0.0 {
+            for k in 1..lengths.len() {
+                let limit = humanize
+                    .timing_jitter
+                    .min(lengths[k - 1] / 2.0)
+                    .min(lengths[k] / 2.0);
+                if limit > 0.0 {
+                    let shift = rng.gen_range(-limit..=limit);
+                    lengths[k - 1] += shift;
+                    lengths[k] -= shift;
+                }
+            }
+        }
+        let mut result = self.with_onset_lengths(&lengths);
+        if humanize.velocity_jitter > 0 {
+            for i in result.onset_indices() {
+                let jitter = rng.gen_range(-humanize.velocity_jitter..=humanize.velocity_jitter);
+                result[i].velocity = (result[i].velocity + jitter).clamp(1, MAX_MIDI_VALUE);
+            }
+        }
+        result
+    }
+
+    /// Returns the last `num_onsets` notes that are not rests, along with their rests.
+    pub fn final_events(&self, num_onsets: usize) -> Melody {
+        let onsets = self.onset_indices();
+        let start = if onsets.len() > num_onsets {
+            onsets[onsets.len() - num_onsets]
+        } else {
+            0
+        };
+        self.fragment(start, self.notes_left_from(start))
+    }
+
+    /// Returns the first `num_onsets` notes that are not rests, along with their rests.
+    pub fn first_events(&self, num_onsets: usize) -> Melody {
+        let onsets = self.onset_indices();
+        let end = onsets.get(num_onsets).copied().unwrap_or(self.len());
+        self.fragment(0, end)
+    }
+
+    pub fn diatonic_transposed(&self, scale: &MusicMode, steps: MidiByte) -> Melody {
+        Melody {
+            notes: self
+                .notes
+                .iter()
+                .map(|n| n.repitched(scale.next_pitch(n.pitch, DiatonicInterval::pure(steps))))
+                .collect(),
+        }
+    }
+
+    /// Returns this melody's pitches and velocities played to the rhythm of `other`. Each
+    /// note takes the length and the following rest of the corresponding note of `other`,
+    /// starting again from the first if this melody has more notes. This melody's own rests
+    /// are replaced by those of `other`.
+    pub fn with_rhythm_of(&self, other: &Melody) -> Melody {
+        let onsets = self.onset_indices();
+        let rhythm = other.onset_indices();
+        if onsets.is_empty() || rhythm.is_empty() {
+            return self.clone();
+        }
+        let mut result = self.fragment(0, onsets[0]);
+        for (k, start) in onsets.iter().enumerate() {
+            let model = rhythm[k % rhythm.len()];
+            let sounding = other[model].duration();
+            let rest = other.duration_with_rest(model).into_inner() - sounding;
+            let note = self[*start];
+            result.add(note.retimed(sounding));
+            if rest > 0.0 {
+                result.add(Note::new(note.pitch, rest, 0));
+            }
+        }
+        result
+    }
+
+    /// Returns a copy of this melody whose velocities follow `profile` over the course of
+    /// the phrase, around this melody's mean velocity. Rests stay silent.
+    pub fn shaped_dynamics(&self, profile: &DynamicsProfile) -> Melody {
+        let total = self.duration();
+        let mean = self.mean_note_on_velocity();
+        if total <= 0.0 || mean <= 0.0 {
+            return self.clone();
+        }
+        let mut result = self.clone();
+        let mut start = 0.0;
+        for i in 0..self.len() {
+            if !self[i].is_rest() {
+                let level = profile.level_at(start / total);
+                result[i].velocity = ((mean * level).round() as MidiByte).clamp(1, MAX_MIDI_VALUE);
+            }
+            start += self[i].duration();
+        }
+        result
+    }
+
+    /// Returns a copy of this melody in which each note on a strong beat is a tone of the
+    /// chord that `chords` gives for that point in the melody, moved by as few semitones as
+    /// possible. A beat is the median onset length, and every other beat from the first
+    /// onset is strong.
+    pub fn targeting_chord_tones(&self, chords: &ChordTrack) -> Melody {
+        let total = self.duration();
+        if self.onset_indices().is_empty() || total <= 0.0 {
+            return self.clone();
+        }
+        let beat = self.median_duration_note_on().into_inner();
+        let mut result = self.clone();
+        let mut start = 0.0;
+        let mut first_onset = None;
+        for i in 0..self.len() {
+            if !self[i].is_rest() && beat > 0.0 {
+                let beats = (start - *first_onset.get_or_insert(start)) / beat;
+                let nearest = beats.round();
+                if (beats - nearest).abs() <= STRONG_BEAT_TOLERANCE
+                    && nearest as i64 % BEATS_PER_STRONG_BEAT == 0
+                {
+                    if let Some(chord) = chords.chord_at(start / total) {
+                        let pitch = chord.nearest_tone(self[i].pitch);
+                        let mut j = i;
+                        while j == i || (j < self.len() && self[j].is_rest()) {
+                            result[j] = result[j].repitched(pitch);
+                            j += 1;
+                        }
+                    }
+                }
+            }
+            start += self[i].duration();
+        }
+        result
+    }
+
+    /// The velocity of the last note to begin at or before `seconds` into this melody, or
+    /// of the first note if none has begun yet.
+    fn onset_velocity_at(&self, seconds: f64) -> MidiByte {
+        let mut velocity = self
+            .onset_indices()
+            .first()
+            .map_or(0, |i| self[*i].velocity);
+        let mut start = 0.0;
+        for note in self.iter() {
+            if start > seconds {
+                break;
+            }
+            if !note.is_rest() {
+                velocity = note.velocity;
+            }
+            start += note.duration();
+        }
+        velocity
+    }
+
+    pub fn stretched(&self, factor: f64) -> Melody {
+        Melody {
+            notes: self
+                .notes
+                .iter()
+                .map(|n| n.retimed(n.duration() * factor))
+                .collect(),
+        }
+    }
+
+    /// Returns a copy of this melody in which the rests at its very end last
+    /// no longer than `max_rest` seconds in total.
+    pub fn with_final_rest_limit(&self, max_rest: f64) -> Melody {
+        let mut result = self.clone();
+        let mut start = result.len();
+        while start > 0 && result[start - 1].is_rest() {
+            start -= 1;
+        }
+        let total: f64 = (start..result.len()).map(|i| result[i].duration()).sum();
+        if total > max_rest {
+            let factor = max_rest / total;
+            for i in start..result.len() {
+                result[i] = result[i].retimed(result[i].duration() * factor);
+            }
+        }
+        result
+    }
+
+    pub fn append(&mut self, other: &Melody) {
+        self.notes.extend(other.notes.iter().copied());
+    }
+
+    /// Returns a copy of this melody that lasts exactly `seconds`: it is cut off at
+    /// that point if longer, and ends with a rest filling out the time if shorter.
+    pub fn fitted_to(&self, seconds: f64) -> Melody {
+        let mut result = Melody::new();
+        let mut elapsed = 0.0;
+        for note in self.notes.iter() {
+            if elapsed >= seconds {
+                break;
+            }
+            let duration = note.duration().min(seconds - elapsed);
+            result.add(note.retimed(duration));
+            elapsed += duration;
+        }
+        if elapsed < seconds {
+            let pitch = self.notes.last().map_or(0, |n| n.pitch());
+            result.add(Note::new(pitch, seconds - elapsed, 0));
+        }
+        result
+    }
+
+    /// Estimates the triads implied by this melody. The melody is split into windows
+    /// of at least `CHORD_WINDOW_SECONDS`, and each window is matched to the diatonic
+    /// triad of `self.best_scale_for()` that covers the most sounding time.
+    /// Returns the start time (in seconds) of each chord change, paired with its chord.
+    pub fn implied_chords(&self) -> Vec<(f64, Chord)> {
+        let mut result = vec![];
+        if self.onset_indices().is_empty() {
+            return result;
+        }
+        let triads = Chord::diatonic_triads(&self.best_scale_for());
+        let mut weights = [0.0; USIZE_NOTES_PER_OCTAVE];
+        let mut window_start = 0.0;
+        let mut elapsed = 0.0;
+        for note in self.notes.iter() {
+            if !note.is_rest() {
+                if elapsed - window_start >= CHORD_WINDOW_SECONDS {
+                    Chord::add_best_match(&mut result, window_start, &triads, &weights);
+                    window_start = elapsed;
+                    weights = [0.0; USIZE_NOTES_PER_OCTAVE];
+                }
+                weights[note.pitch.rem_euclid(NOTES_PER_OCTAVE) as usize] += note.duration();
+            }
+            elapsed += note.duration();
+        }
+        Chord::add_best_match(&mut result, window_start, &triads, &weights);
+        result
+    }
+
+    /// Returns this melody played backwards. Each note is kept together with the rests
+    /// that follow it, so that the rests remain synchronized with their notes.
+    pub fn retrograde(&self) -> Melody {
+        let mut notes = vec![];
+        let mut events: Vec<Vec<Note>> = vec![];
+        for note in self.notes.iter() {
+            if note.is_rest() {
+                match events.last_mut() {
+                    None => notes.push(*note),
+                    Some(event) => event.push(*note),
+                }
+            } else {
+                events.push(vec![*note]);
+            }
+        }
+        for event in events.iter().rev() {
+            notes.extend(event.iter().copied());
+        }
+        Melody { notes }
+    }
+
+    /// Returns this melody with every pitch mirrored around `axis_pitch`.
+    pub fn inverted(&self, axis_pitch: MidiByte) -> Melody {
+        Melody {
+            notes: self
+                .notes
+                .iter()
+                .map(|n| n.repitched(max(0, min(MAX_MIDI_VALUE, 2 * axis_pitch - n.pitch))))
+                .collect(),
+        }
+    }
+
+    pub fn retrograde_inverted(&self, axis_pitch: MidiByte) -> Melody {
+        self.inverted(axis_pitch).retrograde()
+    }
+
     pub fn notes_ranked_by_duration(&self) -> Vec<(usize, Note)> {
         let mut result = self
             .notes
@@ -540,12 +1071,63 @@ impl std::ops::Index<usize> for Melody {
     }
 }
 
+/// Figures weighted by how likely each is to be picked.
+#[derive(Clone, Default)]
+struct FigureWeights {
+    weights: BTreeMap<MelodicFigure, f64>,
+}
+
+impl FigureWeights {
+    fn add(&mut self, figure: MelodicFigure, weight: f64) {
+        *self.weights.entry(figure).or_insert(0.0) += weight;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+
+    fn filtered<F: Fn(&MelodicFigure) -> bool>(&self, keep: F) -> Self {
+        FigureWeights {
+            weights: self
+                .weights
+                .iter()
+                .filter(|(f, _)| keep(f))
+                .map(|(f, w)| (*f, *w))
+                .collect(),
+        }
+    }
+
+    fn pick(&self, rng: &mut StdRng) -> MelodicFigure {
+        let total: f64 = self.weights.values().sum();
+        let mut remaining = rng.gen::<f64>() * total;
+        for (figure, weight) in self.weights.iter() {
+            if remaining < *weight {
+                return *figure;
+            }
+            remaining -= weight;
+        }
+        *self.weights.keys().last().unwrap()
+    }
+}
+
+/// Creates variations and ornaments. Every random choice comes from the maker's own
+/// generator, so a maker built with `seeded` gives the same results for the same melodies
+/// and settings every time it is run.
 pub struct MelodyMaker {
     figure_tables: BTreeMap<usize, BTreeMap<MidiByte, Vec<MelodicFigure>>>,
+    rng: Mutex<StdRng>,
 }
 
 impl MelodyMaker {
     pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
         let figure_tables: BTreeMap<usize, BTreeMap<MidiByte, Vec<MelodicFigure>>> = FIGURE_LENGTHS
             .iter()
             .map(|len| (*len, MelodicFigure::interval2figures(*len)))
@@ -560,7 +1142,47 @@ impl MelodyMaker {
             }
         }
 
-        MelodyMaker { figure_tables }
+        MelodyMaker {
+            figure_tables,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    fn rng(&self) -> MutexGuard<StdRng> {
+        self.rng.lock().unwrap()
+    }
+
+    fn random<T>(&self) -> T
+    where
+        Standard: rand::distributions::Distribution<T>,
+    {
+        self.rng().gen()
+    }
+
+    /// `melody` humanized with this maker's generator, so that a seeded maker gives the
+    /// same performance every time.
+    pub fn humanized(&self, melody: &Melody, humanize: &Humanize) -> Melody {
+        melody.humanized(humanize, &mut *self.rng())
+    }
+
+    /// Returns `true` with probability `p`.
+    pub fn chance(&self, p: f64) -> bool {
+        self.random::<f64>() < p
+    }
+
+    fn figure_weights(&self, melody: &Melody) -> FigureWeights {
+        let mut result = FigureWeights::default();
+        for table in self.figure_tables.values() {
+            for figures in table.values() {
+                for figure in figures.iter() {
+                    result.add(*figure, 1.0);
+                }
+            }
+        }
+        for (_, figure, _) in self.all_figure_matches(melody) {
+            result.add(figure, 1.0);
+        }
+        result
     }
 
     pub fn make_figure_distribution(&self, melody: &Melody) -> Distribution<MelodicFigure> {
@@ -618,48 +1240,87 @@ impl MelodyMaker {
     }
 
     pub fn ornamented(&self, scale: &MusicMode, melody: &Melody, p_ornament: f64) -> Melody {
+        let settings = OrnamentSettings {
+            timing: OrnamentTiming::Absolute,
+            velocity_sensitive: false,
+            enabled: EnabledOrnaments::only(Ornament::Figure),
+        };
+        self.ornamented_with(scale, melody, p_ornament, settings)
+    }
+
+    pub fn ornamented_with(
+        &self,
+        scale: &MusicMode,
+        melody: &Melody,
+        p_ornament: f64,
+        settings: OrnamentSettings,
+    ) -> Melody {
         if melody.len() == 0 {
             return melody.clone();
         }
-        let mut rng = rand::thread_rng();
-        let consolidated = melody.get_consolidated_notes();
-        let ornament_duration = consolidated.iter().map(|(_, n)| n.duration).min().unwrap();
+        let base_duration = settings.timing.ornament_duration(melody);
+        let mean_velocity = melody.mean_note_on_velocity();
 
         let mut result = Melody::new();
         let mut i = 0;
         while i < melody.len() {
             let mut ornamented = false;
-            if let Some(gap) =
-                Self::next_diatonic_gap(&scale, melody, i).and_then(|g| g.pure_degree())
-            {
-                if let Some((figure_length, lead_duration)) =
-                    Self::ornament_figure_length(ornament_duration, melody, i)
-                {
-                    if let Some(figure_list) =
-                        self.figure_tables.get(&figure_length).unwrap().get(&gap)
-                    {
-                        if Self::any_notes_after(melody, i) && rand::random::<f64>() < p_ornament {
-                            let figure = figure_list.choose(&mut rng).unwrap();
-                            let mut pitches = figure.make_pitches(melody[i].pitch, &scale);
-                            pitches.pop_back();
-                            let mut duration = vec![lead_duration.into_inner()];
-                            while !pitches.is_empty() {
-                                let p = pitches.pop_front().unwrap();
-                                result.add(Note::new(
-                                    p,
-                                    duration.pop().unwrap_or(ornament_duration.into_inner()),
-                                    melody[i].velocity,
-                                ));
-                                result.add(Note::new(p, 0.0, 0));
-                            }
-                            ornamented = true;
+            let emphasis = if settings.velocity_sensitive {
+                Self::emphasis(melody, i, mean_velocity)
+            } else {
+                1.0
+            };
+            let ornament_duration = base_duration / emphasis;
+            let p_here = (p_ornament * emphasis).min(1.0);
+            let figures = if settings.enabled.contains(Ornament::Figure) {
+                self.figure_ornament(scale, melody, i, ornament_duration)
+            } else {
+                None
+            };
+            let mut choices: Vec<Ornament> = all::<Ornament>()
+                .filter(|o| *o != Ornament::Figure && settings.enabled.contains(*o))
+                .filter(|o| Self::fits(*o, melody, i, ornament_duration.into_inner()))
+                .collect();
+            if figures.is_some() {
+                choices.push(Ornament::Figure);
+            }
+            if !choices.is_empty() && self.random::<f64>() < p_here {
+                let choice = *choices.choose(&mut *self.rng()).unwrap();
+                let velocity = melody[i].velocity;
+                match (choice, figures) {
+                    (Ornament::Figure, Some((figure_list, lead_duration))) => {
+                        let figure = figure_list.choose(&mut *self.rng()).unwrap();
+                        let mut pitches = figure.make_pitches(melody[i].pitch, &scale);
+                        pitches.pop_back();
+                        let mut duration = vec![lead_duration.into_inner()];
+                        while !pitches.is_empty() {
+                            let p = pitches.pop_front().unwrap();
+                            result.add(Note::new(
+                                p,
+                                duration.pop().unwrap_or(ornament_duration.into_inner()),
+                                velocity,
+                            ));
+                            result.add(Note::new(p, 0.0, 0));
+                        }
+                        i += 1;
+                        while i < melody.len() && melody[i].is_rest() {
                             i += 1;
-                            while i < melody.len() && melody[i].is_rest() {
-                                i += 1;
-                            }
                         }
                     }
+                    _ => {
+                        let d = ornament_duration.into_inner();
+                        let pitch = melody[i].pitch;
+                        let pitches = self.neighbor_pitches(choice, scale, melody[i], d);
+                        for p in pitches.iter() {
+                            result.add(Note::new(*p, d, velocity));
+                            result.add(Note::new(*p, 0.0, 0));
+                        }
+                        let leftover = melody[i].duration() - d * pitches.len() as f64;
+                        result.add(Note::new(pitch, leftover, velocity));
+                        i += 1;
+                    }
                 }
+                ornamented = true;
             }
             if !ornamented {
                 result.add(melody[i]);
@@ -669,6 +1330,74 @@ impl MelodyMaker {
         result
     }
 
+    /// How emphatic note `i` is compared to the melody as a whole: its velocity relative to
+    /// the mean, limited to the range `MIN_EMPHASIS..=MAX_EMPHASIS`.
+    fn emphasis(melody: &Melody, i: usize, mean_velocity: f64) -> f64 {
+        if melody[i].is_rest() || mean_velocity <= 0.0 {
+            1.0
+        } else {
+            (melody[i].velocity as f64 / mean_velocity).clamp(MIN_EMPHASIS, MAX_EMPHASIS)
+        }
+    }
+
+    /// The melodic figures that could fill the gap from note `i` to the next, with the
+    /// length of the figure's first note.
+    fn figure_ornament(
+        &self,
+        scale: &MusicMode,
+        melody: &Melody,
+        i: usize,
+        ornament_duration: OrderedFloat<f64>,
+    ) -> Option<(&Vec<MelodicFigure>, OrderedFloat<f64>)> {
+        let gap = Self::next_diatonic_gap(scale, melody, i)?.pure_degree()?;
+        let (figure_length, lead_duration) =
+            Self::ornament_figure_length(ornament_duration, melody, i)?;
+        let figure_list = self.figure_tables.get(&figure_length).unwrap().get(&gap)?;
+        if Self::any_notes_after(melody, i) {
+            Some((figure_list, lead_duration))
+        } else {
+            None
+        }
+    }
+
+    /// Whether note `i` is long enough for `ornament`, leaving the note itself at least as
+    /// long as each ornament note.
+    fn fits(ornament: Ornament, melody: &Melody, i: usize, ornament_duration: f64) -> bool {
+        !melody[i].is_rest()
+            && ornament_duration > 0.0
+            && melody[i].duration() >= ornament_duration * (ornament.min_notes() + 1) as f64
+    }
+
+    /// The pitches played before `note` itself when it is given `ornament`, using the
+    /// neighboring tones of `scale`.
+    fn neighbor_pitches(
+        &self,
+        ornament: Ornament,
+        scale: &MusicMode,
+        note: Note,
+        ornament_duration: f64,
+    ) -> Vec<MidiByte> {
+        let pitch = note.pitch;
+        let step = |degree| scale.next_pitch(pitch, DiatonicInterval::pure(degree));
+        match ornament {
+            Ornament::Figure => vec![],
+            Ornament::Mordent => vec![pitch, step(1)],
+            Ornament::Turn => vec![step(1), pitch, step(-1)],
+            Ornament::GraceNote => {
+                if self.random::<bool>() {
+                    vec![step(1)]
+                } else {
+                    vec![step(-1)]
+                }
+            }
+            Ornament::Trill => {
+                let alternations = ((note.duration() / ornament_duration) as usize - 1) / 2;
+                (0..alternations).flat_map(|_| [pitch, step(1)]).collect()
+            }
+            Ornament::Slide => vec![step(-2), step(-1)],
+        }
+    }
+
     fn ornament_figure_length(
         ornament_duration: OrderedFloat<f64>,
         melody: &Melody,
@@ -719,7 +1448,7 @@ impl MelodyMaker {
             .copied()
             .collect::<Vec<_>>();
         if options.len() > 0 {
-            options[rand::random::<usize>() % options.len()]
+            options[self.random::<usize>() % options.len()]
         } else {
             figure
         }
@@ -767,7 +1496,7 @@ impl MelodyMaker {
         // 5. p_go_back increases with each interval we pick. It resets when we get back to an original note.
         //   * Idea: Maybe p_go_back can be that interval, and it always just starts at zero.
         let scale = original.best_scale_for();
-        let distro = self.make_figure_distribution(original);
+        let distro = self.figure_weights(original);
         let mut p_back = 0.0;
         let mut variation = Melody::new();
         let mut pitch_queue: VecDeque<MidiByte> = VecDeque::new();
@@ -777,14 +1506,14 @@ impl MelodyMaker {
                 variation.add(original[i].repitched(variation.last_note().pitch()));
             } else {
                 let popped = pitch_queue.pop_front().unwrap_or_else(|| {
-                    let target_direction = if rand::random::<f64>() < 0.5 {
+                    let target_direction = if self.random::<f64>() < 0.5 {
                         MelodyDirection::Toward
                     } else {
                         p_back += p_go_back;
                         MelodyDirection::Away
                     };
 
-                    let reduced_distro = distro.distro_with(|f| {
+                    let reduced_distro = distro.filtered(|f| {
                         MelodyDirection::find(*f, original[i].pitch(), &original, i)
                             == target_direction
                     });
@@ -793,7 +1522,7 @@ impl MelodyMaker {
                     } else {
                         &reduced_distro
                     })
-                    .random_pick();
+                    .pick(&mut self.rng());
                     let start_pitch = variation.last_note().pitch();
                     for pitch in figure.make_pitches(start_pitch, &scale).iter().skip(1) {
                         pitch_queue.push_back(*pitch);
@@ -811,7 +1540,7 @@ impl MelodyMaker {
 
     pub fn randomize_subsection(&self, melody: &mut Melody, subrange: RangeInclusive<usize>) {
         let scale = melody.best_scale_for();
-        let distro = self.make_figure_distribution(melody);
+        let distro = self.figure_weights(melody);
         let mut start = *subrange.start();
         let end = *subrange.end();
         loop {
@@ -821,14 +1550,14 @@ impl MelodyMaker {
                 3 | 5 => 3,
                 4 => 4,
                 _ => {
-                    if rand::random::<f64>() < 0.33 {
+                    if self.random::<f64>() < 0.33 {
                         3
                     } else {
                         4
                     }
                 }
             };
-            let reduced_distro = distro.distro_with(|f| {
+            let reduced_distro = distro.filtered(|f| {
                 let mut matches = figure_len == f.len();
                 if figure_len <= 4 {
                     let pitches = f.make_pitches(melody[start].pitch(), &scale);
@@ -843,7 +1572,7 @@ impl MelodyMaker {
             } else {
                 &reduced_distro
             })
-            .random_pick();
+            .pick(&mut self.rng());
             let mut pitches = figure.make_pitches(melody[start].pitch(), &scale);
             while let Some(new_pitch) = pitches.pop_front() {
                 let original = melody[start].pitch();
@@ -874,16 +1603,119 @@ impl MelodyMaker {
         variation
     }
 
-    pub fn whimsified_ending(&self, original: &Melody) -> Melody {
-        let distro = self.make_figure_distribution(original);
-        let whimsifier = distro.random_pick();
-        let mut result = original.clone();
-        let mut countdown = whimsifier.len();
-        let mut i = original.len();
-        while countdown > 0 {
-            if i == 0 {
-                return result;
-            }
+    const DEVELOPMENT_MOTIVE_ONSETS: usize = 4;
+    const DEVELOPMENT_SEGMENTS: usize = 4;
+    const DEVELOPMENT_STRETCH: f64 = 2.0;
+
+    /// Develops the closing motive of `original` instead of restating the whole melody.
+    /// The final notes of `original` are sequenced a diatonic step up or down several times.
+    /// With probability `p_develop`, each repetition is also fragmented or stretched.
+    pub fn create_motive_development(&self, original: &Melody, p_develop: f64) -> Melody {
+        let scale = original.best_scale_for();
+        let mut motive = original
+            .final_events(Self::DEVELOPMENT_MOTIVE_ONSETS)
+            .with_final_rest_limit(original.median_duration_note_on().into_inner());
+        let step = if self.random::<bool>() { 1 } else { -1 };
+        let mut development = Melody::new();
+        for _ in 0..Self::DEVELOPMENT_SEGMENTS {
+            motive = motive.diatonic_transposed(&scale, step);
+            let segment = if self.random::<f64>() < p_develop {
+                if self.random::<bool>() {
+                    motive.first_events(max(1, Self::DEVELOPMENT_MOTIVE_ONSETS / 2))
+                } else {
+                    motive.stretched(Self::DEVELOPMENT_STRETCH)
+                }
+            } else {
+                motive.clone()
+            };
+            development.append(&segment);
+        }
+        development
+    }
+
+    const CONTOUR_MAX_STEP: MidiByte = 3;
+    const CONTOUR_RANGE_MARGIN: MidiByte = NOTES_PER_OCTAVE;
+
+    /// Keeps the shape of `original` but not its notes. Starting from the same first pitch,
+    /// each note goes up, goes down or repeats as it does in `original`, by one to
+    /// `CONTOUR_MAX_STEP` steps of the original's scale. With probability `p_random`, a note
+    /// picks its direction at random instead. Notes stay within an octave of the original's
+    /// range, taking smaller steps or turning back if they must. Rhythm and dynamics are
+    /// unchanged.
+    pub fn create_contour_variation(&self, original: &Melody, p_random: f64) -> Melody {
+        let onsets = original.onset_indices();
+        if onsets.is_empty() {
+            return original.clone();
+        }
+        let scale = original.best_scale_for();
+        let (lo, hi) = original.min_max_pitches();
+        let range = max(0, lo - Self::CONTOUR_RANGE_MARGIN)
+            ..=min(MAX_MIDI_VALUE, hi + Self::CONTOUR_RANGE_MARGIN);
+        let mut variation = original.clone();
+        let mut pitch = original[onsets[0]].pitch;
+        for (k, start) in onsets.iter().enumerate() {
+            if k > 0 {
+                let mut direction =
+                    (original[*start].pitch - original[onsets[k - 1]].pitch).signum();
+                if self.random::<f64>() < p_random {
+                    direction = self.rng().gen_range(-1..=1);
+                }
+                if direction != 0 {
+                    let mut steps = self.rng().gen_range(1..=Self::CONTOUR_MAX_STEP);
+                    let from = pitch;
+                    let step_to = |steps| scale.next_pitch(from, DiatonicInterval::pure(steps));
+                    while steps > 1 && !range.contains(&step_to(direction * steps)) {
+                        steps -= 1;
+                    }
+                    if !range.contains(&step_to(direction * steps)) {
+                        direction = -direction;
+                    }
+                    pitch = step_to(direction * steps);
+                }
+            }
+            let end = onsets.get(k + 1).copied().unwrap_or(original.len());
+            for i in *start..end {
+                variation[i] = original[i].repitched(pitch);
+            }
+        }
+        variation
+    }
+
+    /// Keeps the pitch sequence of `original` but re-times its notes. Each note (with its
+    /// trailing rest) is subjected to a randomly chosen `RhythmicShift` with probability
+    /// `p_retime`. The variation is rescaled to have the same total duration as `original`.
+    pub fn create_rhythmic_variation(&self, original: &Melody, p_retime: f64) -> Melody {
+        let mut lengths = original.onset_lengths();
+        let shifts = all::<RhythmicShift>().collect::<Vec<_>>();
+        let mut k = 0;
+        while k < lengths.len() {
+            if self.random::<f64>() < p_retime {
+                let shift = shifts.choose(&mut *self.rng()).unwrap();
+                k += shift.apply(&mut lengths, k);
+            }
+            k += 1;
+        }
+
+        let mut variation = original.with_onset_lengths(&lengths);
+        let new_duration = variation.duration();
+        if new_duration > 0.0 && new_duration != original.duration() {
+            let factor = original.duration() / new_duration;
+            for i in 0..variation.len() {
+                variation[i] = variation[i].retimed(variation[i].duration() * factor);
+            }
+        }
+        variation
+    }
+
+    pub fn whimsified_ending(&self, original: &Melody) -> Melody {
+        let whimsifier = self.figure_weights(original).pick(&mut self.rng());
+        let mut result = original.clone();
+        let mut countdown = whimsifier.len();
+        let mut i = original.len();
+        while countdown > 0 {
+            if i == 0 {
+                return result;
+            }
             i -= 1;
             if !result[i].is_rest() {
                 countdown -= 1;
@@ -902,6 +1734,284 @@ impl MelodyMaker {
         }
         result
     }
+
+    /// Summarizes how `variation` differs from `original`, and how many notes
+    /// were added when `variation` was ornamented to produce `ornamented`.
+    pub fn variation_report(
+        &self,
+        original: &Melody,
+        variation: &Melody,
+        ornamented: &Melody,
+    ) -> VariationReport {
+        let shared = min(original.len(), variation.len());
+        let pitches_changed = (0..shared)
+            .filter(|i| original[*i].pitch != variation[*i].pitch)
+            .collect();
+        let durations_changed = (0..shared)
+            .filter(|i| original[*i].duration != variation[*i].duration)
+            .collect();
+        let figures_replaced = self
+            .all_figure_matches(variation)
+            .iter()
+            .filter(|(start, figure, _)| {
+                *start >= original.len()
+                    || self
+                        .matching_figure(original, *start)
+                        .map_or(true, |(f, _)| f != *figure)
+            })
+            .map(|(start, figure, _)| (*start, figure.name()))
+            .collect();
+        let ornament_notes = ornamented
+            .onset_indices()
+            .len()
+            .saturating_sub(variation.onset_indices().len());
+        VariationReport {
+            pitches_changed,
+            durations_changed,
+            figures_replaced,
+            ornament_notes,
+        }
+    }
+}
+
+const MIN_EMPHASIS: f64 = 0.5;
+const MAX_EMPHASIS: f64 = 2.0;
+
+/// Options for `MelodyMaker::ornamented_with`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrnamentSettings {
+    pub timing: OrnamentTiming,
+    /// When set, louder notes are ornamented more often, and with faster ornaments,
+    /// than softer ones.
+    pub velocity_sensitive: bool,
+    pub enabled: EnabledOrnaments,
+}
+
+/// The kinds of ornament that `MelodyMaker::ornamented_with` can insert. All but `Figure`
+/// decorate a single note with its neighbors in the scale, within the note's own length.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Sequence)]
+pub enum Ornament {
+    /// Passing tones leading to the next note, taken from the melodic figures.
+    Figure,
+    /// The note, the scale step above, then the note again.
+    Mordent,
+    /// The scale step above, the note, the scale step below, then the note.
+    Turn,
+    /// A quick scale step above or below, just before the note.
+    GraceNote,
+    /// The note alternating with the scale step above it.
+    Trill,
+    /// Two scale steps rising into the note.
+    Slide,
+}
+
+impl Ornament {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ornament::Figure => "Passing Figures",
+            Ornament::Mordent => "Mordents",
+            Ornament::Turn => "Turns",
+            Ornament::GraceNote => "Grace Notes",
+            Ornament::Trill => "Trills",
+            Ornament::Slide => "Slides",
+        }
+    }
+
+    /// How many ornament notes come before the note itself, at the least.
+    fn min_notes(&self) -> usize {
+        match self {
+            Ornament::Figure | Ornament::GraceNote => 1,
+            Ornament::Mordent | Ornament::Slide => 2,
+            Ornament::Turn => 3,
+            Ornament::Trill => 4,
+        }
+    }
+}
+
+/// Which kinds of ornament may be inserted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EnabledOrnaments(u8);
+
+impl EnabledOrnaments {
+    pub fn all() -> Self {
+        all::<Ornament>().fold(Self::none(), |e, o| e.with(o, true))
+    }
+
+    pub fn none() -> Self {
+        EnabledOrnaments(0)
+    }
+
+    pub fn only(ornament: Ornament) -> Self {
+        Self::none().with(ornament, true)
+    }
+
+    pub fn with(&self, ornament: Ornament, enabled: bool) -> Self {
+        if enabled {
+            EnabledOrnaments(self.0 | Self::bit(ornament))
+        } else {
+            EnabledOrnaments(self.0 & !Self::bit(ornament))
+        }
+    }
+
+    pub fn contains(&self, ornament: Ornament) -> bool {
+        self.0 & Self::bit(ornament) != 0
+    }
+
+    fn bit(ornament: Ornament) -> u8 {
+        1 << ornament as u8
+    }
+}
+
+impl Default for EnabledOrnaments {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// How long each note of an inserted ornament lasts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OrnamentTiming {
+    /// As long as the shortest note in the melody, regardless of tempo.
+    Absolute,
+    /// The given fraction of a beat, where a beat is the melody's median note length.
+    BeatFraction(f64),
+}
+
+impl OrnamentTiming {
+    pub fn ornament_duration(&self, melody: &Melody) -> OrderedFloat<f64> {
+        let shortest = melody
+            .get_consolidated_notes()
+            .iter()
+            .map(|(_, n)| n.duration)
+            .min()
+            .unwrap();
+        match self {
+            OrnamentTiming::Absolute => shortest,
+            OrnamentTiming::BeatFraction(fraction) => {
+                let duration = melody.median_duration_note_on() * *fraction;
+                if duration > OrderedFloat(0.0) {
+                    duration
+                } else {
+                    shortest
+                }
+            }
+        }
+    }
+}
+
+/// Describes what a variation algorithm did to the melody it varied. Indices refer
+/// to notes of the variation before ornamentation.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct VariationReport {
+    pub pitches_changed: Vec<usize>,
+    pub durations_changed: Vec<usize>,
+    pub figures_replaced: Vec<(usize, String)>,
+    pub ornament_notes: usize,
+}
+
+impl Display for VariationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} pitches changed, {} durations changed, {} figures replaced, {} ornament notes added",
+            self.pitches_changed.len(),
+            self.durations_changed.len(),
+            self.figures_replaced.len(),
+            self.ornament_notes
+        )
+    }
+}
+
+/// Onset lengths are grouped into bins this many to a beat (the median onset length)
+/// when measuring rhythmic entropy.
+const RHYTHM_BINS_PER_BEAT: f64 = 4.0;
+
+/// Measures that describe a melody at a glance. Rests are ignored except by
+/// `rest_ratio`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MelodyStats {
+    /// The lowest and highest pitches played.
+    pub pitch_range: (MidiByte, MidiByte),
+    /// The mean number of semitones, up or down, from each note to the next.
+    pub mean_interval: f64,
+    /// Notes per second.
+    pub density: f64,
+    /// The Shannon entropy, in bits, of the notes' lengths relative to the beat: 0.0 when
+    /// every note is equally long, and higher the more varied the rhythm.
+    pub rhythmic_entropy: f64,
+    /// The share of the melody's duration spent in rests, from 0.0 to 1.0.
+    pub rest_ratio: f64,
+}
+
+impl Display for MelodyStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (lo, hi) = self.pitch_range;
+        write!(
+            f,
+            "{} to {} ({} semitones), mean interval {:.1}, {:.1} notes/s, rhythmic entropy {:.2} bits, {:.0}% rests",
+            pitch::name(lo as u8),
+            pitch::name(hi as u8),
+            hi - lo,
+            self.mean_interval,
+            self.density,
+            self.rhythmic_entropy,
+            self.rest_ratio * 100.0
+        )
+    }
+}
+
+/// Measures `melody`. A melody without any notes measures 0.0 throughout.
+pub fn stats(melody: &Melody) -> MelodyStats {
+    let onsets = melody.onset_indices();
+    if onsets.is_empty() {
+        return MelodyStats::default();
+    }
+    let pitches = onsets.iter().map(|i| melody[*i].pitch);
+    let pitch_range = (pitches.clone().min().unwrap(), pitches.max().unwrap());
+    let intervals = melody.onset_intervals();
+    let mean_interval = if intervals.is_empty() {
+        0.0
+    } else {
+        intervals.iter().map(|i| i.abs() as f64).sum::<f64>() / intervals.len() as f64
+    };
+    let duration = melody.duration();
+    let rests = melody
+        .iter()
+        .filter(|n| n.is_rest())
+        .map(|n| n.duration())
+        .sum::<f64>();
+    let (density, rest_ratio) = if duration > 0.0 {
+        (onsets.len() as f64 / duration, rests / duration)
+    } else {
+        (0.0, 0.0)
+    };
+    MelodyStats {
+        pitch_range,
+        mean_interval,
+        density,
+        rhythmic_entropy: rhythmic_entropy(melody),
+        rest_ratio,
+    }
+}
+
+fn rhythmic_entropy(melody: &Melody) -> f64 {
+    let beat = melody.median_duration_note_on().into_inner();
+    if beat <= 0.0 {
+        return 0.0;
+    }
+    let lengths = melody.onset_lengths();
+    let mut bins = BTreeMap::new();
+    for length in lengths.iter() {
+        let bin = (length / beat * RHYTHM_BINS_PER_BEAT).round() as i64;
+        *bins.entry(bin).or_insert(0) += 1;
+    }
+    let total = lengths.len() as f64;
+    bins.values()
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -1005,15 +2115,14 @@ impl MelodySection {
     }
 
     pub fn vary(&mut self, scale: &MusicMode, replace_prob: f64, maker: &MelodyMaker) {
-        let mut rng = rand::thread_rng();
         let mut i = 0;
         loop {
-            let figure_length = FIGURE_LENGTHS.choose(&mut rng).unwrap();
+            let figure_length = FIGURE_LENGTHS.choose(&mut *maker.rng()).unwrap();
             let figure_end = i + figure_length - 2;
             if figure_end >= self.intervals.len() {
                 break;
             }
-            if rand::random::<f64>() < replace_prob {
+            if maker.random::<f64>() < replace_prob {
                 self.figure_replace(maker, &mut i, scale, *figure_length, figure_end);
             }
             i += 1;
@@ -1028,7 +2137,6 @@ impl MelodySection {
         figure_length: usize,
         figure_end: usize,
     ) {
-        let mut rng = rand::thread_rng();
         let current_intervals = &self.intervals[*i..=figure_end];
         if let Some(step_gap) = current_intervals
             .iter()
@@ -1046,7 +2154,8 @@ impl MelodySection {
                     .iter()
                     .filter(|f| f.pattern() != current_diatonic)
                     .collect::<Vec<_>>();
-                if let Some(replacement) = figure_candidates.choose(&mut rng).copied() {
+                let replacement = figure_candidates.choose(&mut *maker.rng()).copied();
+                if let Some(replacement) = replacement {
                     for (j, interval) in replacement.pattern().iter().enumerate() {
                         self.intervals[*i + j] = DiatonicInterval::pure(*interval);
                     }
@@ -1159,25 +2268,611 @@ pub enum NoteLetter {
     G,
 }
 
-impl NoteLetter {
-    pub fn next(&self) -> Self {
-        enum_iterator::next_cycle(self).unwrap()
+impl NoteLetter {
+    pub fn next(&self) -> Self {
+        enum_iterator::next_cycle(self).unwrap()
+    }
+
+    pub fn prev(&self) -> Self {
+        enum_iterator::previous_cycle(self).unwrap()
+    }
+
+    pub fn natural_pitch(&self) -> MidiByte {
+        match self {
+            NoteLetter::A => 9,
+            NoteLetter::B => 11,
+            NoteLetter::C => 0,
+            NoteLetter::D => 2,
+            NoteLetter::E => 4,
+            NoteLetter::F => 5,
+            NoteLetter::G => 7,
+        }
+    }
+}
+
+const CHORD_WINDOW_SECONDS: f64 = 1.0;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Sequence)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+}
+
+impl ChordQuality {
+    fn from_intervals(third: MidiByte, fifth: MidiByte) -> Self {
+        match (third, fifth) {
+            (4, 8) => ChordQuality::Augmented,
+            (4, _) => ChordQuality::Major,
+            (3, 6) => ChordQuality::Diminished,
+            _ => ChordQuality::Minor,
+        }
+    }
+
+    pub fn intervals(&self) -> [MidiByte; 3] {
+        match self {
+            ChordQuality::Major => [0, 4, 7],
+            ChordQuality::Minor => [0, 3, 7],
+            ChordQuality::Diminished => [0, 3, 6],
+            ChordQuality::Augmented => [0, 4, 8],
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "\u{b0}",
+            ChordQuality::Augmented => "+",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Chord {
+    root: MidiByte,
+    quality: ChordQuality,
+}
+
+impl Chord {
+    pub fn new(root: MidiByte, quality: ChordQuality) -> Self {
+        Chord {
+            root: root.rem_euclid(NOTES_PER_OCTAVE),
+            quality,
+        }
+    }
+
+    /// Returns the triad built on each degree of `scale`.
+    pub fn diatonic_triads(scale: &MusicMode) -> Vec<Self> {
+        (0..DIATONIC_SCALE_SIZE as MidiByte)
+            .map(|degree| {
+                let root = scale.next_pitch(scale.root(), DiatonicInterval::pure(degree));
+                let third = scale.next_pitch(root, DiatonicInterval::pure(2)) - root;
+                let fifth = scale.next_pitch(root, DiatonicInterval::pure(4)) - root;
+                Chord::new(root, ChordQuality::from_intervals(third, fifth))
+            })
+            .collect()
+    }
+
+    /// Returns the pitch classes (0-11) of the chord tones, root first.
+    pub fn pitch_classes(&self) -> [MidiByte; 3] {
+        self.quality
+            .intervals()
+            .map(|i| (self.root + i) % NOTES_PER_OCTAVE)
+    }
+
+    pub fn contains(&self, pitch: MidiByte) -> bool {
+        self.pitch_classes()
+            .contains(&pitch.rem_euclid(NOTES_PER_OCTAVE))
+    }
+
+    /// The chord tone closest to `pitch`, the lower one if two are equally close.
+    pub fn nearest_tone(&self, pitch: MidiByte) -> MidiByte {
+        (0..NOTES_PER_OCTAVE)
+            .flat_map(|d| [pitch - d, pitch + d])
+            .find(|p| self.contains(*p))
+            .unwrap()
+    }
+
+    pub fn root(&self) -> MidiByte {
+        self.root
+    }
+
+    pub fn quality(&self) -> ChordQuality {
+        self.quality
+    }
+
+    /// Returns a chord symbol such as "Dm", with the root spelled according to `scale`.
+    pub fn name(&self, scale: &MusicMode) -> String {
+        format!(
+            "{}{}",
+            scale.note_str(self.root + NOTES_PER_OCTAVE),
+            self.quality.suffix()
+        )
+    }
+
+    fn add_best_match(
+        chords: &mut Vec<(f64, Chord)>,
+        start: f64,
+        triads: &Vec<Chord>,
+        weights: &[f64; USIZE_NOTES_PER_OCTAVE],
+    ) {
+        if weights.iter().all(|w| *w == 0.0) {
+            return;
+        }
+        let best = triads
+            .iter()
+            .max_by_key(|triad| {
+                let tones = triad.pitch_classes();
+                let coverage: f64 = tones.iter().map(|p| weights[*p as usize]).sum();
+                (
+                    OrderedFloat(coverage),
+                    OrderedFloat(weights[tones[0] as usize]),
+                )
+            })
+            .copied()
+            .unwrap();
+        if chords.last().map_or(true, |(_, prev)| *prev != best) {
+            chords.push((start, best));
+        }
+    }
+}
+
+impl FromStr for Chord {
+    type Err = anyhow::Error;
+
+    /// Parses a chord symbol such as "C", "F#m", "Bbdim" or "Eb+", as well as the symbols
+    /// `ChordQuality::suffix` gives.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let letter = chars
+            .next()
+            .ok_or(anyhow!("Empty chord symbol"))?
+            .to_ascii_uppercase()
+            .to_string();
+        let natural = NOTE_IDS
+            .iter()
+            .position(|(l, a)| *a == Accidental::Natural && format!("{l:?}") == letter)
+            .ok_or(anyhow!("No chord root in {s}"))? as MidiByte;
+        let mut suffix = chars.as_str();
+        let mut root = natural;
+        for accidental in [Accidental::Sharp, Accidental::Flat] {
+            let ascii = if accidental == Accidental::Sharp {
+                '#'
+            } else {
+                'b'
+            };
+            if let Some(rest) = suffix
+                .strip_prefix(ascii)
+                .or(suffix.strip_prefix(accidental.symbol()))
+            {
+                root += accidental.pitch_shift();
+                suffix = rest;
+            }
+        }
+        let quality = all::<ChordQuality>()
+            .find(|q| q.suffix() == suffix)
+            .or(match suffix {
+                "dim" => Some(ChordQuality::Diminished),
+                "aug" => Some(ChordQuality::Augmented),
+                _ => None,
+            })
+            .ok_or(anyhow!("Unknown chord quality in {s}"))?;
+        Ok(Chord::new(root, quality))
+    }
+}
+
+/// Parses chord symbols separated by spaces, commas or bar lines, such as "C Am | F G".
+pub fn parse_progression(text: &str) -> anyhow::Result<Vec<Chord>> {
+    text.split(|c: char| c.is_whitespace() || c == ',' || c == '|')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<Chord>())
+        .collect()
+}
+
+/// How far from a beat, as a fraction of the beat, an onset may be and still fall on it.
+const STRONG_BEAT_TOLERANCE: f64 = 0.25;
+const BEATS_PER_STRONG_BEAT: i64 = 2;
+
+/// Chords for a variation to fit, each starting the given fraction of the way through the
+/// phrase.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChordTrack {
+    changes: Vec<(f64, Chord)>,
+}
+
+impl ChordTrack {
+    /// `chords` in order, each lasting an equal share of the phrase.
+    pub fn evenly(chords: &[Chord]) -> Self {
+        ChordTrack {
+            changes: chords
+                .iter()
+                .enumerate()
+                .map(|(i, chord)| (i as f64 / chords.len() as f64, *chord))
+                .collect(),
+        }
+    }
+
+    /// The chords that `melody` implies, by `Melody::implied_chords`.
+    pub fn implied_by(melody: &Melody) -> Self {
+        let total = melody.duration();
+        ChordTrack {
+            changes: melody
+                .implied_chords()
+                .iter()
+                .filter(|_| total > 0.0)
+                .map(|(start, chord)| (start / total, *chord))
+                .collect(),
+        }
+    }
+
+    /// The chord sounding `fraction` of the way through the phrase.
+    pub fn chord_at(&self, fraction: f64) -> Option<Chord> {
+        self.changes
+            .iter()
+            .take_while(|(start, _)| *start <= fraction)
+            .last()
+            .or(self.changes.first())
+            .map(|(_, chord)| *chord)
+    }
+}
+
+/// Where the chords that variations fit come from.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence, Serialize, Deserialize)]
+pub enum ChordSource {
+    #[default]
+    Off,
+    /// A progression typed in by the player, spread over each phrase.
+    Progression,
+    /// The chords the player's phrase implies.
+    Implied,
+}
+
+impl ChordSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChordSource::Off => "Off",
+            ChordSource::Progression => "Entered Progression",
+            ChordSource::Implied => "Implied by Melody",
+        }
+    }
+}
+
+const SWING_RATIO: f64 = 2.0 / 3.0;
+const SWING_TOLERANCE: f64 = 0.1;
+
+/// Settings for `Melody::humanized`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Humanize {
+    /// The farthest, in seconds, that an onset may move.
+    pub timing_jitter: f64,
+    /// The most that a note's velocity may change.
+    pub velocity_jitter: MidiByte,
+    pub swing: bool,
+}
+
+impl Humanize {
+    pub fn none() -> Self {
+        Humanize {
+            timing_jitter: 0.0,
+            velocity_jitter: 0,
+            swing: false,
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::none()
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Sequence)]
+pub enum RhythmicShift {
+    Swing,
+    Diminution,
+    Augmentation,
+    Syncopation,
+}
+
+impl RhythmicShift {
+    /// Re-times the note event at `k` within `lengths`, possibly along with the event
+    /// that follows it. Returns the number of following events that were consumed.
+    fn apply(&self, lengths: &mut Vec<f64>, k: usize) -> usize {
+        match self {
+            RhythmicShift::Diminution => {
+                lengths[k] /= 2.0;
+                0
+            }
+            RhythmicShift::Augmentation => {
+                lengths[k] *= 2.0;
+                0
+            }
+            RhythmicShift::Swing => {
+                if k + 1 < lengths.len() {
+                    let pair = lengths[k] + lengths[k + 1];
+                    lengths[k] = pair * SWING_RATIO;
+                    lengths[k + 1] = pair - lengths[k];
+                    1
+                } else {
+                    0
+                }
+            }
+            RhythmicShift::Syncopation => {
+                if k + 1 < lengths.len() {
+                    let anticipation = lengths[k] / 2.0;
+                    lengths[k] -= anticipation;
+                    lengths[k + 1] += anticipation;
+                    1
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+const PHRASE_END_BEATS: f64 = 4.0;
+const MIN_PHRASE_ESTIMATE_ONSETS: usize = 4;
+const CADENCE_HOLD_RATIO: f64 = 1.5;
+const CADENCE_LEAP: MidiByte = 7;
+
+/// How far above and below its mean velocity an arch or crescendo reaches, as a fraction
+/// of the mean.
+const DYNAMIC_SWELL: f64 = 0.3;
+
+/// How loud a phrase shaped by `Melody::shaped_dynamics` is over its course.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DynamicsProfile {
+    /// Soft at both ends and loudest in the middle.
+    Arch,
+    /// Growing steadily louder.
+    Crescendo,
+    /// Rising and falling as the given melody did, at the same fraction of its length.
+    Echo(Melody),
+}
+
+impl DynamicsProfile {
+    /// The loudness `t` of the way through a phrase, from 0.0 to 1.0, relative to its mean.
+    fn level_at(&self, t: f64) -> f64 {
+        match self {
+            DynamicsProfile::Arch => 1.0 + DYNAMIC_SWELL * (2.0 * (PI * t).sin() - 1.0),
+            DynamicsProfile::Crescendo => 1.0 + DYNAMIC_SWELL * (2.0 * t - 1.0),
+            DynamicsProfile::Echo(model) => {
+                let mean = model.mean_note_on_velocity();
+                if mean > 0.0 {
+                    model.onset_velocity_at(t * model.duration()) as f64 / mean
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+/// Which `DynamicsProfile`, if any, shapes the velocities of a variation.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence, Serialize, Deserialize)]
+pub enum DynamicShaping {
+    /// The variation keeps the velocities its algorithm gave it.
+    #[default]
+    AsPlayed,
+    Arch,
+    Crescendo,
+    /// The variation grows louder and softer where the player's phrase did.
+    Echo,
+}
+
+impl DynamicShaping {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DynamicShaping::AsPlayed => "As Played",
+            DynamicShaping::Arch => "Arch",
+            DynamicShaping::Crescendo => "Crescendo",
+            DynamicShaping::Echo => "Echo Player",
+        }
+    }
+
+    /// Shapes the velocities of `variation`, a variation of `original`.
+    pub fn applied(&self, variation: &Melody, original: &Melody) -> Melody {
+        match self {
+            DynamicShaping::AsPlayed => variation.clone(),
+            DynamicShaping::Arch => variation.shaped_dynamics(&DynamicsProfile::Arch),
+            DynamicShaping::Crescendo => variation.shaped_dynamics(&DynamicsProfile::Crescendo),
+            DynamicShaping::Echo => {
+                variation.shaped_dynamics(&DynamicsProfile::Echo(original.clone()))
+            }
+        }
+    }
+}
+
+/// How much of the player's rhythm a variation keeps, whatever the algorithm did to it.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence, Serialize, Deserialize)]
+pub enum RhythmPreservation {
+    /// The variation keeps the rhythm its algorithm gave it.
+    #[default]
+    Free,
+    /// The player's note lengths and rests, scaled to last as long as the variation.
+    Proportional,
+    /// The player's note lengths and rests, exactly.
+    Exact,
+}
+
+impl RhythmPreservation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RhythmPreservation::Free => "Free",
+            RhythmPreservation::Proportional => "Proportional",
+            RhythmPreservation::Exact => "Exact",
+        }
+    }
+
+    /// Gives `variation` as much of the rhythm of `original` as this calls for.
+    pub fn applied(&self, variation: &Melody, original: &Melody) -> Melody {
+        match self {
+            RhythmPreservation::Free => variation.clone(),
+            RhythmPreservation::Exact => variation.with_rhythm_of(original),
+            RhythmPreservation::Proportional => {
+                let rhythmic = variation.with_rhythm_of(original);
+                if rhythmic.duration() > 0.0 {
+                    rhythmic.stretched(variation.duration() / rhythmic.duration())
+                } else {
+                    rhythmic
+                }
+            }
+        }
+    }
+}
+
+/// Strategies for deciding when the player has finished a phrase.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Sequence)]
+pub enum PhraseSegmentation {
+    /// The phrase ends after a fixed silence.
+    SilenceDelay,
+    /// The silence that ends a phrase scales with the player's tempo.
+    TempoRelative,
+    /// The phrase ends sooner after a held note that lands on the tonic or follows a large leap.
+    Cadence,
+}
+
+impl PhraseSegmentation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PhraseSegmentation::SilenceDelay => "Silence Delay",
+            PhraseSegmentation::TempoRelative => "Tempo Relative",
+            PhraseSegmentation::Cadence => "Cadence",
+        }
+    }
+
+    /// Returns how many seconds of silence end a phrase whose notes so far are `melody`.
+    /// Every strategy falls back to `replay_delay` until `melody` has enough notes to analyze.
+    pub fn phrase_end_silence(&self, melody: &Melody, replay_delay: f64) -> f64 {
+        let onsets = melody.onset_indices();
+        if *self == PhraseSegmentation::SilenceDelay || onsets.len() < MIN_PHRASE_ESTIMATE_ONSETS {
+            return replay_delay;
+        }
+        let beat = melody.median_duration_note_on().into_inner();
+        match self {
+            PhraseSegmentation::SilenceDelay => replay_delay,
+            PhraseSegmentation::TempoRelative => {
+                (beat * PHRASE_END_BEATS).clamp(replay_delay / 2.0, replay_delay * 2.0)
+            }
+            PhraseSegmentation::Cadence => {
+                let last = onsets[onsets.len() - 1];
+                let previous = onsets[onsets.len() - 2];
+                let held =
+                    melody.duration_with_rest(last).into_inner() >= beat * CADENCE_HOLD_RATIO;
+                let tonic = melody[last].pitch.rem_euclid(NOTES_PER_OCTAVE)
+                    == melody.find_root_pitch().rem_euclid(NOTES_PER_OCTAVE);
+                let leap = (melody[last].pitch - melody[previous].pitch).abs() >= CADENCE_LEAP;
+                if held && (tonic || leap) {
+                    replay_delay / 2.0
+                } else {
+                    replay_delay
+                }
+            }
+        }
+    }
+}
+
+const MAX_GESTURE_NOTE_SECONDS: f64 = 0.15;
+const MIN_TRILL_ONSETS: usize = 4;
+const MIN_GLISSANDO_ONSETS: usize = 5;
+const MAX_TRILL_INTERVAL: MidiByte = 2;
+const MAX_GLISSANDO_STEP: MidiByte = 2;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum GestureKind {
+    Trill,
+    Glissando,
+}
+
+/// A rapid run of notes that is heard as one gesture. Pitches are stored relative to
+/// the first note, and each note's share of the gesture's total time is kept so that
+/// it can be replayed at any pitch and duration.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Gesture {
+    kind: GestureKind,
+    offsets: Vec<MidiByte>,
+    proportions: Vec<f64>,
+}
+
+impl Gesture {
+    pub fn kind(&self) -> GestureKind {
+        self.kind
     }
 
-    pub fn prev(&self) -> Self {
-        enum_iterator::previous_cycle(self).unwrap()
+    /// Looks for a gesture starting at onset ordinal `k`, returning how many onsets it spans.
+    fn find(
+        melody: &Melody,
+        onsets: &[usize],
+        lengths: &[f64],
+        k: usize,
+    ) -> Option<(usize, Gesture)> {
+        let fast = |o: usize| lengths[o] <= MAX_GESTURE_NOTE_SECONDS;
+        let pitch = |o: usize| melody[onsets[o]].pitch;
+        let mut end = k;
+        while end < onsets.len() && fast(end) {
+            end += 1;
+        }
+        if end - k < min(MIN_TRILL_ONSETS, MIN_GLISSANDO_ONSETS) {
+            return None;
+        }
+
+        let interval = pitch(k + 1) - pitch(k);
+        if interval == 0 {
+            return None;
+        }
+        let alternates = |o: usize| {
+            let expected = if (o - k) % 2 == 1 {
+                interval
+            } else {
+                -interval
+            };
+            pitch(o) - pitch(o - 1) == expected
+        };
+        let mut trill_end = k + 1;
+        if interval.abs() <= MAX_TRILL_INTERVAL {
+            while trill_end < end && alternates(trill_end) {
+                trill_end += 1;
+            }
+        }
+        let continues_run = |o: usize| {
+            let step = pitch(o) - pitch(o - 1);
+            step.signum() == interval.signum() && step.abs() <= MAX_GLISSANDO_STEP
+        };
+        let mut glissando_end = k + 1;
+        while glissando_end < end && continues_run(glissando_end) {
+            glissando_end += 1;
+        }
+
+        let (kind, span) = if trill_end - k >= MIN_TRILL_ONSETS {
+            (GestureKind::Trill, trill_end - k)
+        } else if glissando_end - k >= MIN_GLISSANDO_ONSETS {
+            (GestureKind::Glissando, glissando_end - k)
+        } else {
+            return None;
+        };
+        let total = lengths[k..k + span].iter().sum::<f64>();
+        Some((
+            span,
+            Gesture {
+                kind,
+                offsets: (k..k + span).map(|o| pitch(o) - pitch(k)).collect(),
+                proportions: lengths[k..k + span].iter().map(|l| l / total).collect(),
+            },
+        ))
     }
 
-    pub fn natural_pitch(&self) -> MidiByte {
-        match self {
-            NoteLetter::A => 9,
-            NoteLetter::B => 11,
-            NoteLetter::C => 0,
-            NoteLetter::D => 2,
-            NoteLetter::E => 4,
-            NoteLetter::F => 5,
-            NoteLetter::G => 7,
+    /// Plays this gesture in place of `group`, a single note and its rests.
+    fn realized(&self, group: &Melody) -> Melody {
+        let start = group[0];
+        let total = group.duration();
+        let mut result = Melody::new();
+        for (offset, proportion) in self.offsets.iter().zip(self.proportions.iter()) {
+            let pitch = (start.pitch + offset).clamp(0, MAX_MIDI_VALUE);
+            result.add(Note::new(pitch, total * proportion, start.velocity));
+            result.add(Note::new(pitch, 0.0, 0));
         }
+        result
     }
 }
 
@@ -1645,6 +3340,14 @@ impl Accidental {
             Accidental::Sharp => 1,
         }
     }
+
+    pub fn ascii(&self) -> &'static str {
+        match self {
+            Accidental::Flat => "b",
+            Accidental::Natural => "",
+            Accidental::Sharp => "#",
+        }
+    }
 }
 
 // Inspired by: https://figuringoutmelody.com/the-24-universal-melodic-figures/
@@ -1673,6 +3376,13 @@ impl MelodicFigure {
         self.pattern().len() + 1
     }
 
+    pub fn name(&self) -> String {
+        format!(
+            "{:?} ({:?}, {:?})",
+            self.shape, self.polarity, self.direction
+        )
+    }
+
     /// Returns the net change of diatonic steps from the start to the end of this `MelodicFigure`.
     pub fn total_diatonic_change(&self) -> MidiByte {
         self.pattern().iter().sum()
@@ -1843,12 +3553,15 @@ impl MelodicFigureShape {
 #[cfg(test)]
 mod tests {
     use crate::analyzer::{
-        flats_for, major_flats_for, major_sharps_for, sharps_for, Accidental, DiatonicInterval,
-        FigureDirection, FigurePolarity, MelodicFigure, MelodicFigureShape, Melody,
-        MelodyDirection, MelodyMaker, MelodySection, MidiByte, MusicMode, Note, NoteLetter,
-        DIATONIC_SCALE_SIZE,
+        flats_for, major_flats_for, major_sharps_for, sharps_for, stats, Accidental, Chord,
+        ChordQuality, DiatonicInterval, DynamicShaping, DynamicsProfile, EnabledOrnaments,
+        FigureDirection, FigurePolarity, GestureKind, Humanize, MelodicFigure, MelodicFigureShape,
+        Melody, MelodyDirection, MelodyMaker, MelodySection, MidiByte, MusicMode, Note, NoteLetter,
+        Ornament, OrnamentSettings, OrnamentTiming, PhraseSegmentation, RhythmPreservation,
+        VariationReport, DIATONIC_SCALE_SIZE,
     };
     use bare_metal_modulo::ModNumC;
+    use enum_iterator::all;
     use float_cmp::assert_approx_eq;
     use ordered_float::OrderedFloat;
     use std::cmp::{max, min};
@@ -1859,6 +3572,132 @@ mod tests {
     const COUNTDOWN_ECHO: &str = "[[66, 0.42, 1.0], [66, 0.55, 0.0], [73, 0.17, 1.0], [73, 0.01, 0.0], [71, 0.13, 0.76], [71, 0.0, 0.0], [73, 0.45, 0.40], [73, 0.13, 0.0], [66, 0.85, 0.79], [66, 0.32, 0.0], [74, 0.16, 1.0], [74, 0.0, 0.0], [74, 0.37, 0.86], [74, 0.03, 0.0], [73, 0.2, 1.0], [73, 0.03, 0.0], [71, 0.03, 0.05], [71, 0.04, 0.0], [71, 0.93, 1.0], [71, 0.27, 0.0], [74, 0.16, 1.0], [74, 0.03, 0.0], [73, 0.13, 1.0], [73, 0.03, 0.0], [74, 0.45, 1.0], [74, 0.12, 0.0], [66, 0.58, 0.79], [66, 0.5, 0.0], [71, 0.15, 0.74], [71, 0.02, 0.0], [71, 0.13, 0.80], [71, 0.03, 0.0], [71, 0.21, 1.0], [71, 0.08, 0.0], [69, 0.24, 0.93], [69, 0.08, 0.0], [68, 0.22, 0.64], [68, 0.07, 0.0], [71, 0.24, 1.0], [71, 0.06, 0.0], [69, 0.68, 1.0], [69, 0.15, 0.0], [73, 0.16, 1.0], [73, 0.03, 0.0], [71, 0.14, 0.90], [71, 0.03, 0.0], [73, 0.29, 1.0], [73, 0.22, 0.0], [66, 0.61, 0.63], [66, 0.45, 0.0], [74, 0.15, 0.86], [74, 0.04, 0.0], [74, 0.14, 0.82], [74, 0.02, 0.0], [74, 0.2, 1.0], [74, 0.13, 0.0], [73, 0.29, 0.95], [73, 0.0, 0.0], [72, 0.04, 0.48], [72, 0.03, 0.0], [71, 1.01, 1.0], [71, 0.41, 0.0], [74, 0.14, 0.93], [74, 0.04, 0.0], [73, 0.13, 0.79], [73, 0.03, 0.0], [74, 0.49, 1.0], [74, 0.12, 0.0], [66, 0.93, 0.53], [66, 0.19, 0.0], [71, 0.16, 0.80], [71, 0.02, 0.0], [71, 0.13, 0.78], [71, 0.03, 0.0], [71, 0.21, 0.86], [71, 0.11, 0.0], [69, 0.24, 0.85], [69, 0.08, 0.0], [68, 0.24, 0.66], [68, 0.07, 0.0], [71, 0.24, 1.0], [71, 0.11, 0.0], [69, 0.75, 0.85], [69, 0.05, 0.0], [68, 0.18, 0.70], [68, 0.02, 0.0], [69, 0.16, 0.88], [69, 0.04, 0.0], [71, 0.02, 0.98], [71, 0.0, 0.0], [83, 0.01, 1.0], [83, 0.0, 0.0], [71, 0.56, 0.97], [71, 0.16, 0.0], [69, 0.19, 1.0], [69, 0.04, 0.0], [71, 0.2, 1.0], [71, 0.05, 0.0], [73, 0.24, 1.0], [73, 0.0, 0.0], [72, 0.03, 0.61], [72, 0.07, 0.0], [71, 0.2, 0.90], [71, 0.03, 0.0], [69, 0.01, 0.05], [69, 0.06, 0.0], [69, 0.18, 0.72], [69, 0.11, 0.0], [68, 0.19, 0.45], [68, 0.18, 0.0], [66, 0.51, 0.75], [66, 0.17, 0.0], [74, 0.56, 1.0], [74, 0.01, 0.0], [73, 1.09, 0.78], [73, 0.07, 0.0], [75, 0.16, 0.89], [75, 0.03, 0.0], [73, 0.16, 0.83], [73, 0.03, 0.0], [71, 0.18, 0.56], [71, 0.03, 0.0], [73, 0.78, 0.63], [73, 0.06, 0.0], [73, 0.14, 0.90], [73, 0.04, 0.0], [73, 0.14, 0.86], [73, 0.04, 0.0], [73, 0.26, 0.80], [73, 0.1, 0.0], [71, 0.23, 0.90], [71, 0.07, 0.0], [69, 0.19, 0.97], [69, 0.1, 0.0], [68, 0.23, 0.58], [68, 0.15, 0.0], [66, 1.22, 0.67], [66, 2.0, 0.0]]";
     const NUM_RANDOM_TESTS: usize = 20;
 
+    #[test]
+    fn test_melody_text() {
+        let melody = Melody::from(EXAMPLE_MELODY);
+        let text = melody.to_text();
+        assert!(text.starts_with("G3:0.39:115 G3:0.04:0 B3:0.33:92"));
+        assert_eq!(Melody::from_text(text.as_str()).unwrap(), melody);
+        assert!(Melody::from_text("C4:0.5").is_err());
+        assert!(Melody::from_text("X4:0.5:100").is_err());
+        assert!(Melody::from_text("C4:0.5:200").is_err());
+        assert!(Melody::from_text("C4:inf:100").is_err());
+        assert!(Melody::from_text("C4:NaN:100").is_err());
+        assert!(Melody::from_text("C4:1e300:100").is_err());
+        assert_eq!(Melody::from_text("").unwrap(), Melody::new());
+    }
+
+    #[test]
+    fn test_interval_distance() {
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.8,0,0.2,0.0,64,0.5,0.8,65,0.5,0.8");
+        assert_eq!(melody.onset_intervals(), vec![2, 2, 1]);
+        let transposed = Melody::from("67,0.25,0.8,69,1.0,0.8,71,0.5,0.8,72,0.5,0.8");
+        assert_approx_eq!(f64, melody.interval_distance(&transposed), 0.0);
+        let leap = Melody::from("60,0.5,0.8,62,0.5,0.8,64,0.5,0.8,67,0.5,0.8");
+        assert_approx_eq!(f64, melody.interval_distance(&leap), 1.0 / 3.0);
+        assert_approx_eq!(f64, leap.interval_distance(&melody), 1.0 / 3.0);
+        let step_down = Melody::from("60,0.5,0.8,59,0.5,0.8");
+        assert_approx_eq!(f64, melody.interval_distance(&step_down), 1.0);
+        assert_approx_eq!(f64, Melody::new().interval_distance(&Melody::new()), 0.0);
+    }
+
+    #[test]
+    fn test_shaped_dynamics() {
+        let variation = Melody::from("60,0.5,0.5,62,0.5,0.5,0,0.5,0.0,64,0.5,0.5,65,0.5,0.5");
+        let velocities =
+            |melody: &Melody| -> Vec<MidiByte> { melody.iter().map(|n| n.velocity()).collect() };
+        let crescendo = velocities(&variation.shaped_dynamics(&DynamicsProfile::Crescendo));
+        assert_eq!(crescendo[2], 0);
+        assert!(crescendo[0] < crescendo[1] && crescendo[3] < crescendo[4]);
+        let arch = velocities(&variation.shaped_dynamics(&DynamicsProfile::Arch));
+        assert!(arch[0] < arch[1] && arch[1] < arch[3] && arch[4] < arch[3]);
+
+        let player = Melody::from("60,1.0,0.3,62,1.0,0.9");
+        let echo = velocities(&DynamicShaping::Echo.applied(&variation, &player));
+        assert!(echo[0] == echo[1] && echo[1] < echo[3] && echo[3] == echo[4]);
+        assert_eq!(
+            DynamicShaping::AsPlayed.applied(&variation, &player),
+            variation
+        );
+        assert_eq!(
+            Melody::new().shaped_dynamics(&DynamicsProfile::Arch),
+            Melody::new()
+        );
+    }
+
+    #[test]
+    fn test_with_rhythm_of() {
+        let player = Melody::from("60,0.5,0.8,60,0.25,0.0,62,1.0,0.8,64,0.5,0.8,64,0.5,0.0");
+        let variation = Melody::from("67,0.25,0.8,65,0.25,0.8,64,0.25,0.8,62,0.25,0.8");
+        let exact = variation.with_rhythm_of(&player);
+        assert_eq!(
+            exact,
+            Melody::from(
+                "67,0.5,0.8,67,0.25,0.0,65,1.0,0.8,64,0.5,0.8,64,0.5,0.0,62,0.5,0.8,62,0.25,0.0"
+            )
+        );
+        assert_eq!(
+            RhythmPreservation::Exact.applied(&variation, &player),
+            exact
+        );
+        assert_eq!(
+            RhythmPreservation::Free.applied(&variation, &player),
+            variation
+        );
+        let proportional = RhythmPreservation::Proportional.applied(&variation, &player);
+        assert_approx_eq!(f64, proportional.duration(), variation.duration());
+        assert_eq!(proportional.len(), exact.len());
+        assert_eq!(variation.with_rhythm_of(&Melody::new()), variation);
+    }
+
+    #[test]
+    fn test_stats() {
+        let melody = Melody::from("60,0.5,0.8,60,0.5,0.0,64,0.5,0.8,67,1.0,0.8");
+        let s = stats(&melody);
+        assert_eq!(s.pitch_range, (60, 67));
+        assert_approx_eq!(f64, s.mean_interval, 3.5);
+        assert_approx_eq!(f64, s.density, 1.2);
+        assert_approx_eq!(f64, s.rest_ratio, 0.2);
+        // Two notes a beat long and one half a beat.
+        let expected = -(2.0 / 3.0 * (2.0f64 / 3.0).log2() + 1.0 / 3.0 * (1.0f64 / 3.0).log2());
+        assert_approx_eq!(f64, s.rhythmic_entropy, expected);
+        assert_approx_eq!(
+            f64,
+            stats(&Melody::from("60,0.5,0.8,62,0.5,0.8")).rhythmic_entropy,
+            0.0
+        );
+        assert_eq!(stats(&Melody::new()).density, 0.0);
+    }
+
+    #[test]
+    fn test_melody_json() {
+        let melody = Melody::from(EXAMPLE_MELODY);
+        let json = melody.to_json();
+        assert!(json.starts_with(r#"{"notes":[{"pitch":55,"duration":0.39,"velocity":115}"#));
+        assert_eq!(Melody::from_json(json.as_str()).unwrap(), melody);
+        assert!(Melody::from_json(r#"{"notes":[{"pitch":60,"duration":0.5}]}"#).is_err());
+        assert!(
+            Melody::from_json(r#"{"notes":[{"pitch":60,"duration":-1,"velocity":9}]}"#).is_err()
+        );
+        assert!(
+            Melody::from_json(r#"{"notes":[{"pitch":200,"duration":1,"velocity":9}]}"#).is_err()
+        );
+        assert!(
+            Melody::from_json(r#"{"notes":[{"pitch":60,"duration":1e300,"velocity":9}]}"#).is_err()
+        );
+
+        let report = VariationReport {
+            pitches_changed: vec![1, 3],
+            figures_replaced: vec![(2, "Run".to_owned())],
+            ..VariationReport::default()
+        };
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            serde_json::from_str::<VariationReport>(&json).unwrap(),
+            report
+        );
+    }
+
     #[test]
     fn test_parse_melody() {
         let m = "69,0.24,1.0,69,0.09,0.0,72,0.31,1.0,72,0.08,0.0,71,0.29,0.69";
@@ -2158,6 +3997,67 @@ mod tests {
         test_variation_changed(MelodyMaker::create_motive_variation, 0.10, 0.60);
     }
 
+    #[test]
+    fn test_rhythmic_variation() {
+        let maker = MelodyMaker::new();
+        let melody = Melody::from(COUNTDOWN_MELODY);
+        assert_eq!(maker.create_rhythmic_variation(&melody, 0.0), melody);
+        for _ in 0..NUM_RANDOM_TESTS {
+            let var = maker.create_rhythmic_variation(&melody, 1.0);
+            assert_eq!(var.len(), melody.len());
+            assert_approx_eq!(f64, var.duration(), melody.duration(), epsilon = 0.0001);
+            let mut different_count = 0;
+            for i in 0..var.len() {
+                assert_eq!(var[i].pitch, melody[i].pitch);
+                assert_eq!(var[i].velocity, melody[i].velocity);
+                if var[i].duration != melody[i].duration {
+                    different_count += 1;
+                }
+            }
+            assert!(different_count > 0);
+        }
+    }
+
+    #[test]
+    fn test_humanize() {
+        let mut rng = rand::thread_rng();
+        let melody = Melody::from(COUNTDOWN_MELODY);
+        assert_eq!(melody.humanized(&Humanize::none(), &mut rng), melody);
+        let humanize = Humanize {
+            timing_jitter: 0.02,
+            velocity_jitter: 10,
+            swing: false,
+        };
+        for _ in 0..NUM_RANDOM_TESTS {
+            let humanized = melody.humanized(&humanize, &mut rng);
+            assert_eq!(humanized.len(), melody.len());
+            assert_approx_eq!(
+                f64,
+                humanized.duration(),
+                melody.duration(),
+                epsilon = 0.0001
+            );
+            for i in 0..melody.len() {
+                assert_eq!(humanized[i].pitch, melody[i].pitch);
+                assert!((humanized[i].velocity - melody[i].velocity).abs() <= 10);
+            }
+        }
+
+        let mut even = Melody::new();
+        for pitch in [60, 62, 64, 65] {
+            even.add(Note::new(pitch, 0.3, 100));
+        }
+        let swing = Humanize {
+            swing: true,
+            ..Humanize::none()
+        };
+        let swung = even.humanized(&swing, &mut rng);
+        assert_approx_eq!(f64, swung[0].duration(), 0.4);
+        assert_approx_eq!(f64, swung[1].duration(), 0.2);
+        assert_approx_eq!(f64, swung[2].duration(), 0.4);
+        assert_approx_eq!(f64, swung[3].duration(), 0.2);
+    }
+
     #[test]
     fn test_motive_remelodize_unchanged() {
         let melody = Melody::from(COUNTDOWN_MELODY);
@@ -3114,6 +5014,127 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_gestures() {
+        let mut melody = Melody::new();
+        melody.add(Note::new(60, 0.4, 100));
+        melody.add(Note::new(60, 0.1, 0));
+        for pitch in [64, 65, 64, 65, 64, 65] {
+            melody.add(Note::new(pitch, 0.08, 90));
+            melody.add(Note::new(pitch, 0.02, 0));
+        }
+        for pitch in [67, 69, 71, 72, 74] {
+            melody.add(Note::new(pitch, 0.1, 80));
+            melody.add(Note::new(pitch, 0.0, 0));
+        }
+        melody.add(Note::new(76, 0.5, 100));
+        melody.add(Note::new(76, 0.0, 0));
+
+        let gestures = melody.gestures();
+        let summary = gestures
+            .iter()
+            .map(|(k, span, g)| (*k, *span, g.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![(1, 6, GestureKind::Trill), (7, 5, GestureKind::Glissando)]
+        );
+
+        let (collapsed, placeholders) = melody.collapsed_gestures();
+        assert_approx_eq!(f64, collapsed.duration(), melody.duration());
+        let onsets = collapsed.onset_indices();
+        assert_eq!(onsets.len(), 4);
+        assert_eq!(
+            placeholders.iter().map(|(o, _)| *o).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_approx_eq!(
+            f64,
+            collapsed.duration_with_rest(onsets[1]).into_inner(),
+            0.6
+        );
+
+        let restored = collapsed.with_gestures(&placeholders);
+        assert_eq!(restored.len(), melody.len());
+        assert_approx_eq!(f64, restored.duration(), melody.duration());
+        for i in 0..melody.len() {
+            assert_eq!(restored[i].pitch, melody[i].pitch);
+        }
+
+        let mut moved = collapsed.clone();
+        moved[onsets[1]] = Note::new(62, moved[onsets[1]].duration(), 90);
+        let restored = moved.with_gestures(&placeholders);
+        let trill = restored.onset_indices()[1..7]
+            .iter()
+            .map(|i| restored[*i].pitch)
+            .collect::<Vec<_>>();
+        assert_eq!(trill, vec![62, 63, 62, 63, 62, 63]);
+    }
+
+    #[test]
+    fn test_fitted_to() {
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.8,64,1.0,0.8");
+        let cut = melody.fitted_to(1.25);
+        assert_eq!(cut.len(), 3);
+        assert_approx_eq!(f64, cut[2].duration(), 0.25);
+        assert_approx_eq!(f64, cut.duration(), 1.25);
+        let padded = melody.fitted_to(3.0);
+        assert_eq!(padded.len(), 4);
+        assert!(padded[3].is_rest());
+        assert_approx_eq!(f64, padded.duration(), 3.0);
+    }
+
+    #[test]
+    fn test_seeded_maker() {
+        let melody = Melody::from(COUNTDOWN_MELODY);
+        let scale = melody.best_scale_for();
+        let humanize = Humanize {
+            timing_jitter: 0.02,
+            velocity_jitter: 10,
+            swing: true,
+        };
+        let run = |seed| {
+            let maker = MelodyMaker::seeded(seed);
+            let responses = (0..20).map(|_| maker.chance(0.5)).collect::<Vec<_>>();
+            let melodies = [
+                maker.create_motive_variation(&melody, 0.7),
+                maker.create_whimsical_variation(&melody, 0.7),
+                maker.create_wandering_variation(&melody, 0.7),
+                maker.create_motive_development(&melody, 0.7),
+                maker.create_rhythmic_variation(&melody, 0.7),
+                maker.ornamented(&scale, &melody, 0.7),
+                maker.whimsified_ending(&melody),
+                maker.humanized(&melody, &humanize),
+            ];
+            (melodies, responses)
+        };
+        assert_eq!(run(2718), run(2718));
+        assert_ne!(run(2718), run(3141));
+    }
+
+    #[test]
+    fn test_thinned() {
+        let mut glissando = Melody::new();
+        glissando.add(Note::new(60, 0.5, 100));
+        glissando.add(Note::new(60, 0.0, 0));
+        for pitch in 61..72 {
+            glissando.add(Note::new(pitch, 0.02, 100));
+            glissando.add(Note::new(pitch, 0.01, 0));
+        }
+        glissando.add(Note::new(72, 0.5, 100));
+        glissando.add(Note::new(72, 0.0, 0));
+
+        assert_eq!(glissando.thinned(0.0), glissando);
+        let thinned = glissando.thinned(10.0);
+        assert_approx_eq!(f64, thinned.duration(), glissando.duration());
+        let pitches = thinned
+            .onset_indices()
+            .iter()
+            .map(|i| thinned[*i].pitch())
+            .collect::<Vec<_>>();
+        assert_eq!(pitches, vec![60, 61, 65, 69, 72]);
+    }
+
     #[test]
     fn test_without_brief_notes() {
         let melody = Melody {
@@ -3214,6 +5235,142 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tempo_relative_ornaments() {
+        let maker = MelodyMaker::new();
+        let melody = lean_on_me_melody();
+        let scale = melody.best_scale_for();
+        let beat = melody.median_duration_note_on();
+        assert_eq!(
+            OrnamentTiming::BeatFraction(0.5).ornament_duration(&melody),
+            beat * 0.5
+        );
+        let slow = melody.stretched(2.0);
+        assert_eq!(
+            OrnamentTiming::BeatFraction(0.5).ornament_duration(&slow),
+            beat
+        );
+        let shortest = melody
+            .get_consolidated_notes()
+            .iter()
+            .map(|(_, n)| n.duration)
+            .min()
+            .unwrap();
+        assert_eq!(
+            OrnamentTiming::Absolute.ornament_duration(&melody),
+            shortest
+        );
+        assert_eq!(
+            OrnamentTiming::Absolute.ornament_duration(&slow),
+            shortest * 2.0
+        );
+        for _ in 0..NUM_RANDOM_TESTS {
+            let settings = OrnamentSettings {
+                timing: OrnamentTiming::BeatFraction(0.25),
+                velocity_sensitive: false,
+                enabled: EnabledOrnaments::all(),
+            };
+            let ornamented = maker.ornamented_with(&scale, &melody, 1.0, settings);
+            assert_approx_eq!(f64, melody.duration(), ornamented.duration());
+        }
+    }
+
+    #[test]
+    fn test_ornament_vocabulary() {
+        let none = EnabledOrnaments::none();
+        assert!(all::<Ornament>().all(|o| EnabledOrnaments::all().contains(o)));
+        assert!(all::<Ornament>().all(|o| !none.contains(o)));
+        let trills = none.with(Ornament::Trill, true);
+        assert!(trills.contains(Ornament::Trill) && !trills.contains(Ornament::Turn));
+        assert_eq!(trills.with(Ornament::Trill, false), none);
+
+        let maker = MelodyMaker::seeded(598);
+        let scale = MusicMode::new(ModNumC::new(0), 60);
+        let melody = Melody::from("60,1.0,0.8,64,1.0,0.8,0,0.5,0.0,67,0.125,0.8,65,1.0,0.8");
+        let settings = |enabled| OrnamentSettings {
+            timing: OrnamentTiming::Absolute,
+            velocity_sensitive: false,
+            enabled,
+        };
+        assert_eq!(
+            maker.ornamented_with(&scale, &melody, 1.0, settings(none)),
+            melody
+        );
+        for ornament in all::<Ornament>().filter(|o| *o != Ornament::Figure) {
+            let ornamented = maker.ornamented_with(
+                &scale,
+                &melody,
+                1.0,
+                settings(EnabledOrnaments::only(ornament)),
+            );
+            assert!(ornamented.len() > melody.len());
+            assert_approx_eq!(f64, melody.duration(), ornamented.duration());
+            assert!(ornamented
+                .iter()
+                .all(|n| n.is_rest() || scale.contains(n.pitch())));
+        }
+    }
+
+    #[test]
+    fn test_velocity_sensitive_ornaments() {
+        let mut melody = Melody::new();
+        for (pitch, velocity) in [(60, 40), (62, 80), (64, 120), (65, 0)] {
+            melody.add(Note::new(pitch, 0.5, velocity));
+        }
+        let mean = melody.mean_note_on_velocity();
+        assert_eq!(mean, 80.0);
+        assert_eq!(MelodyMaker::emphasis(&melody, 0, mean), 0.5);
+        assert_eq!(MelodyMaker::emphasis(&melody, 1, mean), 1.0);
+        assert_eq!(MelodyMaker::emphasis(&melody, 2, mean), 1.5);
+        assert_eq!(MelodyMaker::emphasis(&melody, 3, mean), 1.0);
+
+        let maker = MelodyMaker::new();
+        let melody = lean_on_me_melody();
+        let scale = melody.best_scale_for();
+        let settings = OrnamentSettings {
+            timing: OrnamentTiming::Absolute,
+            velocity_sensitive: true,
+            enabled: EnabledOrnaments::all(),
+        };
+        for _ in 0..NUM_RANDOM_TESTS {
+            let ornamented = maker.ornamented_with(&scale, &melody, 0.5, settings);
+            assert_approx_eq!(f64, melody.duration(), ornamented.duration());
+        }
+    }
+
+    #[test]
+    fn test_phrase_segmentation() {
+        let delay = 2.0;
+        let mut melody = Melody::new();
+        for (pitch, duration) in [(60, 0.25), (62, 0.25), (64, 0.25)] {
+            melody.add(Note::new(pitch, duration, 100));
+        }
+        for strategy in all::<PhraseSegmentation>() {
+            assert_eq!(strategy.phrase_end_silence(&melody, delay), delay);
+        }
+
+        melody.add(Note::new(65, 0.25, 100));
+        melody.add(Note::new(60, 1.0, 100));
+        assert_eq!(
+            PhraseSegmentation::SilenceDelay.phrase_end_silence(&melody, delay),
+            delay
+        );
+        assert_eq!(
+            PhraseSegmentation::TempoRelative.phrase_end_silence(&melody, delay),
+            1.0
+        );
+        assert_eq!(
+            PhraseSegmentation::Cadence.phrase_end_silence(&melody, delay),
+            delay / 2.0
+        );
+
+        melody.add(Note::new(62, 0.25, 100));
+        assert_eq!(
+            PhraseSegmentation::Cadence.phrase_end_silence(&melody, delay),
+            delay
+        );
+    }
+
     #[test]
     fn test_whimsified_ending() {
         let maker = MelodyMaker::new();
@@ -3299,6 +5456,158 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_motive_development() {
+        let maker = MelodyMaker::new();
+        let melody = lean_on_me_melody();
+        let scale = melody.best_scale_for();
+        let motive = melody.final_events(MelodyMaker::DEVELOPMENT_MOTIVE_ONSETS);
+        assert_eq!(
+            motive.onset_indices().len(),
+            MelodyMaker::DEVELOPMENT_MOTIVE_ONSETS
+        );
+        for p in [0.0, 1.0] {
+            let development = maker.create_motive_development(&melody, p);
+            assert!(development.len() > motive.len());
+            assert!(development.iter().all(|n| scale.contains(n.pitch)));
+        }
+        let unchanged = maker.create_motive_development(&melody, 0.0);
+        assert_eq!(
+            unchanged.len(),
+            motive.len() * MelodyMaker::DEVELOPMENT_SEGMENTS
+        );
+    }
+
+    #[test]
+    fn test_contour_variation() {
+        let maker = MelodyMaker::new();
+        let melody =
+            Melody::from("60,0.5,0.8,62,0.5,0.8,62,0.5,0.8,62,0.5,0.0,67,0.5,0.8,64,1.0,0.8");
+        let contour = |m: &Melody| {
+            m.onset_intervals()
+                .iter()
+                .map(|i| i.signum())
+                .collect::<Vec<_>>()
+        };
+        let scale = melody.best_scale_for();
+        for _ in 0..NUM_RANDOM_TESTS {
+            let variation = maker.create_contour_variation(&melody, 0.0);
+            assert_eq!(contour(&variation), contour(&melody));
+            assert_eq!(variation[0].pitch(), 60);
+            assert!(variation.iter().all(|n| scale.contains(n.pitch())));
+            assert_eq!(variation.onset_lengths(), melody.onset_lengths());
+            assert!(variation
+                .iter()
+                .zip(melody.iter())
+                .all(|(v, m)| v.is_rest() == m.is_rest()));
+        }
+    }
+
+    #[test]
+    fn test_variation_report() {
+        let maker = MelodyMaker::new();
+        let melody = Melody::from(COUNTDOWN_MELODY);
+        let unchanged = maker.variation_report(&melody, &melody, &melody);
+        assert_eq!(unchanged, VariationReport::default());
+
+        let variation = maker.create_motive_variation(&melody, 1.0);
+        let ornamented = maker.ornamented(&melody.best_scale_for(), &variation, 1.0);
+        let report = maker.variation_report(&melody, &variation, &ornamented);
+        assert!(report.pitches_changed.len() > 0);
+        assert!(report.durations_changed.is_empty());
+        assert!(report.figures_replaced.len() > 0);
+        assert!(report.ornament_notes > 0);
+    }
+
+    #[test]
+    fn test_implied_chords() {
+        let mut melody = Melody::new();
+        for (pitch, duration) in [
+            (60, 0.5),
+            (64, 0.3),
+            (67, 0.4),
+            (65, 0.4),
+            (69, 0.4),
+            (72, 0.4),
+            (67, 0.4),
+            (71, 0.4),
+            (62, 0.4),
+        ] {
+            melody.add(Note::new(pitch, duration, 100));
+        }
+        let scale = melody.best_scale_for();
+        let names = melody
+            .implied_chords()
+            .iter()
+            .map(|(_, c)| c.name(&scale))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["C", "F", "G"]);
+
+        let qualities = Chord::diatonic_triads(&scale)
+            .iter()
+            .map(|c| c.quality())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            qualities,
+            vec![
+                ChordQuality::Major,
+                ChordQuality::Minor,
+                ChordQuality::Minor,
+                ChordQuality::Major,
+                ChordQuality::Major,
+                ChordQuality::Minor,
+                ChordQuality::Diminished
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chord_tones() {
+        let chords = parse_progression("C, G | F#m Bb\u{b0} Eb+").unwrap();
+        assert_eq!(chords[0], Chord::new(0, ChordQuality::Major));
+        assert_eq!(chords[2], Chord::new(6, ChordQuality::Minor));
+        assert_eq!(chords[3], Chord::new(10, ChordQuality::Diminished));
+        assert_eq!(chords[4], "Ebaug".parse().unwrap());
+        assert!(parse_progression("C H").is_err());
+        assert!(parse_progression("Csus4").is_err());
+
+        let track = ChordTrack::evenly(&chords[..2]);
+        assert_eq!(track.chord_at(0.25), Some(chords[0]));
+        assert_eq!(track.chord_at(0.5), Some(chords[1]));
+        assert_eq!(ChordTrack::default().chord_at(0.5), None);
+        assert_eq!(chords[0].nearest_tone(62), 60);
+        assert_eq!(chords[0].nearest_tone(66), 67);
+
+        let mut melody = Melody::new();
+        for _ in 0..4 {
+            melody.add(Note::new(62, 0.5, 100));
+        }
+        let targeted = melody.targeting_chord_tones(&track);
+        let pitches = targeted.iter().map(|n| n.pitch).collect::<Vec<_>>();
+        assert_eq!(pitches, vec![60, 62, 62, 62]);
+        let implied = ChordTrack::implied_by(&melody);
+        assert_eq!(melody.targeting_chord_tones(&implied), melody);
+    }
+
+    #[test]
+    fn test_retrograde_inversion() {
+        let melody = lean_on_me_melody();
+        let retrograde = melody.retrograde();
+        assert_eq!(retrograde.len(), melody.len());
+        assert_approx_eq!(f64, retrograde.duration(), melody.duration());
+        assert_eq!(retrograde.last_note().pitch, melody[0].pitch);
+        assert_eq!(retrograde.retrograde(), melody);
+
+        let axis = melody[0].pitch;
+        let inverted = melody.inverted(axis);
+        for i in 0..melody.len() {
+            assert_eq!(inverted[i].pitch - axis, axis - melody[i].pitch);
+            assert_eq!(inverted[i].duration, melody[i].duration);
+        }
+        assert_eq!(inverted.inverted(axis), melody);
+        assert_eq!(melody.retrograde_inverted(axis), inverted.retrograde());
+    }
+
     #[test]
     fn test_melody_note_ranking() {
         let melody = lean_on_me_melody();
@@ -0,0 +1,169 @@
+use crate::runtime::SliderValue;
+use crate::threads::{spawn_named, AUTOMATION_THREAD, SINGLETON};
+use crossbeam_utils::atomic::AtomicCell;
+use enum_iterator::Sequence;
+use std::f64::consts::TAU;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const AUTOMATION_TICK_MILLIS: u64 = 50;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Sequence)]
+pub enum AutomationShape {
+    Sine,
+    Triangle,
+    /// Holds each value of the step sequence for an equal share of the period.
+    Steps,
+}
+
+impl AutomationShape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AutomationShape::Sine => "Sine",
+            AutomationShape::Triangle => "Triangle",
+            AutomationShape::Steps => "Steps",
+        }
+    }
+}
+
+/// Moves one slider back and forth around the value it had when the automation began,
+/// once every `period_seconds`. At full `depth` (1.0) it swings all the way to the ends
+/// of the slider's range; values beyond the range are clamped. Moving the slider by hand
+/// while it is automated makes the new position the center of the swing.
+#[derive(Clone)]
+pub struct Automation {
+    pub slider_name: String,
+    slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub shape: AutomationShape,
+    pub period_seconds: f64,
+    pub depth: f64,
+    /// Levels from -1.0 to 1.0 for `AutomationShape::Steps`.
+    pub steps: Vec<f64>,
+    center: f64,
+    last_written: Option<f64>,
+    started: Instant,
+}
+
+impl Automation {
+    pub fn new(
+        slider_name: &str,
+        slider: Arc<AtomicCell<SliderValue<f64>>>,
+        shape: AutomationShape,
+        period_seconds: f64,
+        depth: f64,
+        steps: Vec<f64>,
+    ) -> Self {
+        let center = slider.load().current();
+        Automation {
+            slider_name: slider_name.to_owned(),
+            slider,
+            shape,
+            period_seconds,
+            depth,
+            steps,
+            center,
+            last_written: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Where the swing stands `seconds` into the automation, from -1.0 to 1.0.
+    pub fn level(&self, seconds: f64) -> f64 {
+        let phase = (seconds / self.period_seconds.max(f64::EPSILON)).fract();
+        match self.shape {
+            AutomationShape::Sine => (phase * TAU).sin(),
+            AutomationShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            AutomationShape::Steps => {
+                if self.steps.is_empty() {
+                    0.0
+                } else {
+                    let i = (phase * self.steps.len() as f64) as usize;
+                    self.steps[i.min(self.steps.len() - 1)].clamp(-1.0, 1.0)
+                }
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let sv = self.slider.load();
+        if self.last_written.is_some_and(|last| last != sv.current()) {
+            self.center = sv.current();
+        }
+        let range = sv.make_range();
+        let half_range = (range.end() - range.start()) / 2.0;
+        let offset = self.depth * half_range * self.level(self.started.elapsed().as_secs_f64());
+        let value = (self.center + offset).clamp(*range.start(), *range.end());
+        self.slider.store(sv.slid_to(value));
+        self.last_written = Some(value);
+    }
+
+    /// Puts the slider back where the swing is centered.
+    pub fn restore(&self) {
+        let sv = self.slider.load();
+        self.slider.store(sv.slid_to(self.center));
+    }
+}
+
+/// Parses a step sequence such as "-1, 0, 0.5, 1".
+pub fn parse_steps(text: &str) -> anyhow::Result<Vec<f64>> {
+    let mut steps = vec![];
+    for part in text.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        steps.push(part.parse::<f64>()?);
+    }
+    Ok(steps)
+}
+
+/// Moves every slider in `automations` along its swing until `quit` is set.
+pub fn start_automation_thread(
+    automations: Arc<Mutex<Vec<Automation>>>,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(AUTOMATION_THREAD, SINGLETON, move || {
+        while !quit.load() {
+            for automation in automations.lock().unwrap().iter_mut() {
+                automation.update();
+            }
+            thread::sleep(Duration::from_millis(AUTOMATION_TICK_MILLIS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_automation() {
+        let slider = Arc::new(AtomicCell::new(SliderValue::new(0.5, 0.0, 1.0)));
+        let mut automation = Automation::new(
+            "Ornamentation",
+            slider.clone(),
+            AutomationShape::Sine,
+            4.0,
+            0.5,
+            vec![],
+        );
+        assert_approx_eq!(f64, automation.level(1.0), 1.0, epsilon = 1e-9);
+        assert_approx_eq!(f64, automation.level(3.0), -1.0, epsilon = 1e-9);
+        automation.shape = AutomationShape::Triangle;
+        assert_approx_eq!(f64, automation.level(0.0), -1.0);
+        assert_approx_eq!(f64, automation.level(2.0), 1.0);
+        assert_approx_eq!(f64, automation.level(3.0), 0.0);
+        automation.shape = AutomationShape::Steps;
+        automation.steps = parse_steps("-1, 0.5, 2").unwrap();
+        assert_approx_eq!(f64, automation.level(0.5), -1.0);
+        assert_approx_eq!(f64, automation.level(2.0), 0.5);
+        assert_approx_eq!(f64, automation.level(3.9), 1.0);
+        assert!(parse_steps("1, x").is_err());
+
+        automation.update();
+        let value = slider.load().current();
+        assert!((0.25..=0.75).contains(&value));
+        slider.store(slider.load().slid_to(0.9));
+        automation.update();
+        automation.restore();
+        assert_approx_eq!(f64, slider.load().current(), 0.9);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::midi_input::RawMidiEvent;
+use crate::threads::{spawn_named, DRAIN_THREAD, MOCK_MIDI_THREAD, SINGLETON};
+use anyhow::anyhow;
+use crossbeam_queue::SegQueue;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::{start_output_thread, SynthMsg};
+use midi_fundsp::SynthFunc;
+use midir::{MidiInput, MidiInputConnection, MidiInputPort};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const OUTPUT_POLL_MILLIS: u64 = 5;
+
+pub type SynthList = Arc<Mutex<Vec<(String, SynthFunc)>>>;
+
+/// Where the player's MIDI comes from: a MIDI port, or a stand-in for tests and
+/// machines without MIDI hardware.
+pub trait MidiSource: Send {
+    fn name(&self) -> String;
+
+    /// Starts calling `receive` with the timestamp in microseconds and the bytes of each
+    /// message, until the returned connection is closed.
+    fn connect(
+        self: Box<Self>,
+        receive: Box<dyn FnMut(u64, &[u8]) + Send>,
+    ) -> anyhow::Result<Box<dyn MidiConnection>>;
+}
+
+pub trait MidiConnection {
+    fn close(self: Box<Self>);
+}
+
+/// Where the synthesizer messages are heard: the sound card, or a stand-in for tests and
+/// machines without audio hardware.
+pub trait AudioSink {
+    /// Plays each message pushed to `synth_msgs` on `synths` until `quit` is set.
+    fn start(
+        &self,
+        synths: SynthList,
+        synth_msgs: Arc<SegQueue<SynthMsg>>,
+        quit: Arc<AtomicCell<bool>>,
+    );
+}
+
+/// A port on a MIDI device, read through `midir`.
+pub struct MidirSource {
+    midi_in: MidiInput,
+    in_port: MidiInputPort,
+}
+
+impl MidirSource {
+    pub fn new(midi_in: MidiInput, in_port: MidiInputPort) -> Self {
+        MidirSource { midi_in, in_port }
+    }
+}
+
+impl MidiSource for MidirSource {
+    fn name(&self) -> String {
+        self.midi_in.port_name(&self.in_port).unwrap_or_default()
+    }
+
+    fn connect(
+        self: Box<Self>,
+        mut receive: Box<dyn FnMut(u64, &[u8]) + Send>,
+    ) -> anyhow::Result<Box<dyn MidiConnection>> {
+        let MidirSource { midi_in, in_port } = *self;
+        let connection = midi_in
+            .connect(
+                &in_port,
+                "midir-read-input",
+                move |stamp, message, _| receive(stamp, message),
+                (),
+            )
+            .map_err(|e| anyhow!("{e}"))?;
+        Ok(Box::new(MidirConnection(connection)))
+    }
+}
+
+struct MidirConnection(MidiInputConnection<()>);
+
+impl MidiConnection for MidirConnection {
+    fn close(self: Box<Self>) {
+        self.0.close();
+    }
+}
+
+/// Sends nothing, for running without a MIDI device.
+pub struct NullMidiSource;
+
+impl MidiSource for NullMidiSource {
+    fn name(&self) -> String {
+        "No MIDI input".to_owned()
+    }
+
+    fn connect(
+        self: Box<Self>,
+        _receive: Box<dyn FnMut(u64, &[u8]) + Send>,
+    ) -> anyhow::Result<Box<dyn MidiConnection>> {
+        Ok(Box::new(StopOnClose(Arc::new(AtomicCell::new(true)))))
+    }
+}
+
+/// Plays a list of events, such as a loaded capture, as if from a device, keeping the
+/// spacing between their timestamps.
+pub struct MockMidiSource {
+    events: Vec<RawMidiEvent>,
+}
+
+impl MockMidiSource {
+    pub fn new(events: Vec<RawMidiEvent>) -> Self {
+        MockMidiSource { events }
+    }
+}
+
+impl MidiSource for MockMidiSource {
+    fn name(&self) -> String {
+        "Mock MIDI input".to_owned()
+    }
+
+    fn connect(
+        self: Box<Self>,
+        mut receive: Box<dyn FnMut(u64, &[u8]) + Send>,
+    ) -> anyhow::Result<Box<dyn MidiConnection>> {
+        let stop = Arc::new(AtomicCell::new(false));
+        let stopped = stop.clone();
+        let events = self.events;
+        let started = spawn_named(MOCK_MIDI_THREAD, SINGLETON, move || {
+            let mut prev_micros = events.first().map_or(0, |e| e.micros());
+            for event in events.iter() {
+                thread::sleep(Duration::from_micros(
+                    event.micros().saturating_sub(prev_micros),
+                ));
+                if stopped.load() {
+                    break;
+                }
+                prev_micros = event.micros();
+                receive(event.micros(), event.bytes());
+            }
+        });
+        if started.is_err() {
+            return Err(anyhow!("Mock MIDI input is already playing"));
+        }
+        Ok(Box::new(StopOnClose(stop)))
+    }
+}
+
+struct StopOnClose(Arc<AtomicCell<bool>>);
+
+impl MidiConnection for StopOnClose {
+    fn close(self: Box<Self>) {
+        self.0.store(true);
+    }
+}
+
+/// The sound card, through `midi_fundsp`, mixed down to `CHANNELS` output channels.
+/// `midi_fundsp` always opens the system's default output device, so a `device` asked for
+/// by name can only be reported as unavailable until it offers a way to choose one.
+pub struct SynthSink<const CHANNELS: usize> {
+    device: Option<String>,
+}
+
+impl<const CHANNELS: usize> SynthSink<CHANNELS> {
+    pub fn new(device: Option<String>) -> Self {
+        SynthSink { device }
+    }
+}
+
+impl<const CHANNELS: usize> AudioSink for SynthSink<CHANNELS> {
+    fn start(
+        &self,
+        synths: SynthList,
+        synth_msgs: Arc<SegQueue<SynthMsg>>,
+        quit: Arc<AtomicCell<bool>>,
+    ) {
+        if let Some(device) = &self.device {
+            warn!("Cannot choose audio device {device}; playing on the default output device");
+        }
+        start_output_thread::<CHANNELS>(synth_msgs, synths, quit);
+    }
+}
+
+/// Discards every message, for running without audio hardware.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn start(
+        &self,
+        _synths: SynthList,
+        synth_msgs: Arc<SegQueue<SynthMsg>>,
+        quit: Arc<AtomicCell<bool>>,
+    ) {
+        drain(synth_msgs, quit, |_| {});
+    }
+}
+
+/// Keeps every message it is sent, so that tests can check what would have been heard.
+#[derive(Clone, Default)]
+pub struct MockSink {
+    received: Arc<Mutex<Vec<SynthMsg>>>,
+}
+
+impl MockSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn received(&self) -> Vec<SynthMsg> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+impl AudioSink for MockSink {
+    fn start(
+        &self,
+        _synths: SynthList,
+        synth_msgs: Arc<SegQueue<SynthMsg>>,
+        quit: Arc<AtomicCell<bool>>,
+    ) {
+        let received = self.received.clone();
+        drain(synth_msgs, quit, move |msg| {
+            received.lock().unwrap().push(msg)
+        });
+    }
+}
+
+fn drain<F: FnMut(SynthMsg) + Send + 'static>(
+    synth_msgs: Arc<SegQueue<SynthMsg>>,
+    quit: Arc<AtomicCell<bool>>,
+    mut handle: F,
+) {
+    let started = spawn_named(DRAIN_THREAD, SINGLETON, move || {
+        while !quit.load() {
+            while let Some(msg) = synth_msgs.pop() {
+                handle(msg);
+            }
+            thread::sleep(Duration::from_millis(OUTPUT_POLL_MILLIS));
+        }
+    });
+    if let Err(e) = started {
+        error!("Nothing will be heard: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_variation::{make_ai_table, start_ai_thread, KeyboardSplit};
+    use crate::analyzer::PhraseSegmentation;
+    use crate::backing::BackingTransport;
+    use crate::dynamics::OutputLevel;
+    use crate::harmonizer::HarmonyInterval;
+    use crate::midi_clock::ClockSync;
+    use crate::midi_input::{start_input_thread, MidiCapture};
+    use crate::midi_learn::MidiLearn;
+    use crate::queue::BlockingQueue;
+    use crate::runtime::{
+        concert_pitch_slider, pedal_replay_slider, replay_slider, stuck_note_slider,
+        transpose_slider, MelodyRunStatus, MixControls, VariationControls, HUMAN_SPEAKER,
+    };
+    use crate::scheduler::Scheduler;
+    use crate::watchdog::{same_speaker, start_watchdog_thread, HeldKeys, PanicButton};
+    use midi_msg::{ChannelVoiceMsg, MidiMsg};
+    use std::time::Instant;
+
+    fn cell<T>(value: T) -> Arc<AtomicCell<T>> {
+        Arc::new(AtomicCell::new(value))
+    }
+
+    #[test]
+    fn test_mock_pipeline() {
+        let quit = cell(false);
+        let input2ai = Arc::new(BlockingQueue::new());
+        let ai2output = Arc::new(BlockingQueue::new());
+        let watchdog2output = Arc::new(SegQueue::new());
+        let panic_button = PanicButton::new();
+        let melody_run_status = MelodyRunStatus::new();
+        let variation_controls = VariationControls::new();
+        let clock = ClockSync::new(
+            variation_controls.tempo_slider.clone(),
+            variation_controls.fixed_tempo.clone(),
+        );
+        let sink = MockSink::new();
+        sink.start(
+            Arc::new(Mutex::new(vec![])),
+            watchdog2output.clone(),
+            quit.clone(),
+        );
+        start_watchdog_thread(
+            ai2output.clone(),
+            watchdog2output,
+            cell(stuck_note_slider()),
+            cell(transpose_slider()),
+            cell(concert_pitch_slider()),
+            MixControls::new(),
+            cell(HeldKeys::NONE),
+            cell(OutputLevel::default()),
+            panic_button.clone(),
+            melody_run_status.clone(),
+            quit.clone(),
+        )
+        .unwrap();
+        start_ai_thread(
+            Arc::new(Mutex::new(make_ai_table())),
+            vec![],
+            input2ai.clone(),
+            Arc::new(SegQueue::new()),
+            Scheduler::new(ai2output),
+            Arc::new(BlockingQueue::new()),
+            variation_controls,
+            cell(replay_slider()),
+            cell(pedal_replay_slider()),
+            cell(PhraseSegmentation::SilenceDelay),
+            cell(false),
+            cell(KeyboardSplit::default()),
+            cell(HarmonyInterval::Off),
+            Arc::new(Mutex::new(None)),
+            BackingTransport::new(),
+            clock.clone(),
+            cell(None),
+            melody_run_status,
+            quit.clone(),
+        )
+        .unwrap();
+        let events = vec![
+            RawMidiEvent::new(0, &[0x90, 60, 100]),
+            RawMidiEvent::new(100_000, &[0x80, 60, 0]),
+        ];
+        start_input_thread(
+            input2ai,
+            Box::new(MockMidiSource::new(events)),
+            MidiCapture::new(),
+            Arc::new(Mutex::new(MidiLearn::new())),
+            cell(None),
+            panic_button,
+            clock,
+            Arc::new(Mutex::new(None)),
+            cell(false),
+            quit.clone(),
+        )
+        .unwrap();
+
+        let started = Instant::now();
+        let heard = |sink: &MockSink| {
+            sink.received().iter().any(|m| {
+                same_speaker(m.speaker, HUMAN_SPEAKER)
+                    && matches!(
+                        m.msg,
+                        MidiMsg::ChannelVoice {
+                            msg: ChannelVoiceMsg::NoteOn { note: 60, .. },
+                            ..
+                        }
+                    )
+            })
+        };
+        while !heard(&sink) && started.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        quit.store(true);
+        assert!(heard(&sink));
+    }
+}
@@ -0,0 +1,445 @@
+use crate::ai_variation::PERCUSSION_CHANNEL;
+use crate::midi_file::{timed_events, MICROS_PER_BEAT};
+use crate::scheduler::Scheduler;
+use crate::threads::{spawn_named, BACKING_THREAD, SINGLETON};
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_msg::MidiMsg;
+use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The backing track is heard from both speakers, between the player and the AI.
+pub const BACKING_SPEAKER: Speaker = Speaker::Both;
+/// With the transport left alone, the backing thread still wakes this often to check
+/// `quit` and whether the track has ended.
+const BACKING_IDLE_MILLIS: u64 = 100;
+const NOTE_ON: u8 = 0x90;
+const NOTE_OFF: u8 = 0x80;
+/// Files without a time signature are in 4/4.
+const DEFAULT_BEATS_PER_BAR: u8 = 4;
+const QUARTER_NOTE_POWER: u8 = 2;
+
+/// A Standard MIDI File to play along with. Its notes keep their channels, but percussion
+/// is skipped, as it is when recording. Bars are counted using the file's first tempo
+/// and time signature.
+#[derive(Clone, Debug)]
+pub struct BackingTrack {
+    name: String,
+    events: Vec<(f64, [u8; 3])>,
+    duration: f64,
+    bar_seconds: f64,
+    beats_per_bar: u8,
+}
+
+impl BackingTrack {
+    pub fn from_smf(name: &str, bytes: &[u8]) -> anyhow::Result<Self> {
+        let smf = Smf::parse(bytes)?;
+        let (timed, duration) = timed_events(&smf);
+        let mut micros_per_quarter = None;
+        let mut signature = None;
+        let mut events = vec![];
+        for (seconds, kind) in timed {
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(micros)) => {
+                    micros_per_quarter.get_or_insert(micros.as_int());
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(beats, power, _, _)) => {
+                    signature.get_or_insert((beats, power));
+                }
+                TrackEventKind::Midi { channel, message }
+                    if channel.as_int() != PERCUSSION_CHANNEL as u8 =>
+                {
+                    let note = match message {
+                        MidiMessage::NoteOn { key, vel } => Some((NOTE_ON, key, vel)),
+                        MidiMessage::NoteOff { key, vel } => Some((NOTE_OFF, key, vel)),
+                        _ => None,
+                    };
+                    if let Some((status, key, vel)) = note {
+                        let status = status | channel.as_int();
+                        events.push((seconds, [status, key.as_int(), vel.as_int()]));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if events.is_empty() {
+            return Err(anyhow!("No notes found"));
+        }
+        let quarter_seconds = micros_per_quarter.unwrap_or(MICROS_PER_BEAT) as f64 / 1_000_000.0;
+        let (beats_per_bar, power) =
+            signature.unwrap_or((DEFAULT_BEATS_PER_BAR, QUARTER_NOTE_POWER));
+        let beat_seconds = quarter_seconds * 2.0_f64.powi(QUARTER_NOTE_POWER as i32 - power as i32);
+        Ok(BackingTrack {
+            name: name.to_owned(),
+            events,
+            duration,
+            bar_seconds: beats_per_bar.max(1) as f64 * beat_seconds,
+            beats_per_bar: beats_per_bar.max(1),
+        })
+    }
+
+    pub fn load(filename: &str) -> anyhow::Result<Self> {
+        Self::from_smf(filename, fs::read(filename)?.as_slice())
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+
+    pub fn bar_seconds(&self) -> f64 {
+        self.bar_seconds
+    }
+
+    /// The bar and beat `seconds` into the track.
+    pub fn position_at(&self, seconds: f64) -> BarPosition {
+        let bars = seconds.max(0.0) / self.bar_seconds;
+        BarPosition {
+            bar: bars.floor() as usize + 1,
+            beat: bars.fract() * self.beats_per_bar as f64 + 1.0,
+        }
+    }
+
+    /// How long after `seconds` into the track the next bar begins; 0.0 on a bar line.
+    pub fn until_next_bar(&self, seconds: f64) -> f64 {
+        let into_bar = seconds.rem_euclid(self.bar_seconds);
+        if into_bar == 0.0 {
+            0.0
+        } else {
+            self.bar_seconds - into_bar
+        }
+    }
+
+    fn first_event_from(&self, seconds: f64) -> usize {
+        self.events.partition_point(|(at, _)| *at < seconds)
+    }
+
+    /// The note offs for keys left held by playing the track from `from` until `to`.
+    fn held_between(&self, from: f64, to: f64) -> Vec<[u8; 2]> {
+        let mut held = vec![];
+        for (_, bytes) in self.events[self.first_event_from(from)..]
+            .iter()
+            .take_while(|(at, _)| *at <= to)
+        {
+            let key = [NOTE_OFF | (bytes[0] & 0x0f), bytes[1]];
+            held.retain(|k| *k != key);
+            if bytes[0] & 0xf0 == NOTE_ON && bytes[2] > 0 {
+                held.push(key);
+            }
+        }
+        held
+    }
+}
+
+/// A place in a backing track, counting bars and beats from 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BarPosition {
+    pub bar: usize,
+    pub beat: f64,
+}
+
+impl BarPosition {
+    /// Melodies are tagged with the bar of the backing track they began in.
+    pub fn tag(&self) -> String {
+        format!("Bar {}", self.bar)
+    }
+}
+
+impl Display for BarPosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bar {}, beat {:.1}", self.bar, self.beat)
+    }
+}
+
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub enum TransportState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Clock {
+    state: TransportState,
+    /// Seconds into the track when playback last started or paused.
+    offset: f64,
+    started: Instant,
+    /// Changes whenever playback starts or stops, so the backing thread knows to start
+    /// again from the clock's position.
+    generation: u64,
+}
+
+impl Clock {
+    fn seconds(&self) -> f64 {
+        match self.state {
+            TransportState::Playing => self.offset + self.started.elapsed().as_secs_f64(),
+            _ => self.offset,
+        }
+    }
+
+    fn moved_to(&self, state: TransportState, offset: f64) -> Self {
+        Clock {
+            state,
+            offset,
+            started: Instant::now(),
+            generation: self.generation + 1,
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            state: TransportState::Stopped,
+            offset: 0.0,
+            started: Instant::now(),
+            generation: 0,
+        }
+    }
+}
+
+/// Play, pause and stop for a backing track, which is heard by way of the backing thread.
+/// The condition variable is signalled whenever the clock changes.
+#[derive(Clone, Default)]
+pub struct BackingTransport {
+    track: Arc<Mutex<Option<BackingTrack>>>,
+    clock: Arc<(Mutex<Clock>, Condvar)>,
+}
+
+impl BackingTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops whatever was playing and replaces it with `track`.
+    pub fn load(&self, track: BackingTrack) {
+        self.stop();
+        *self.track.lock().unwrap() = Some(track);
+    }
+
+    pub fn track_name(&self) -> Option<String> {
+        let track = self.track.lock().unwrap();
+        track.as_ref().map(|t| t.name().to_owned())
+    }
+
+    pub fn state(&self) -> TransportState {
+        self.clock().state
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.clock().seconds()
+    }
+
+    fn clock(&self) -> Clock {
+        *self.clock.0.lock().unwrap()
+    }
+
+    /// Replaces the clock with whatever `change` makes of it, if anything.
+    fn change_clock(&self, change: impl FnOnce(&Clock) -> Option<Clock>) {
+        let (clock, changed) = &*self.clock;
+        let mut clock = clock.lock().unwrap();
+        if let Some(moved) = change(&clock) {
+            *clock = moved;
+            changed.notify_all();
+        }
+    }
+
+    pub fn play(&self) {
+        if self.track.lock().unwrap().is_some() {
+            self.change_clock(|clock| {
+                (clock.state != TransportState::Playing)
+                    .then(|| clock.moved_to(TransportState::Playing, clock.offset))
+            });
+        }
+    }
+
+    pub fn pause(&self) {
+        self.change_clock(|clock| {
+            (clock.state == TransportState::Playing)
+                .then(|| clock.moved_to(TransportState::Paused, clock.seconds()))
+        });
+    }
+
+    /// Stops, ready to play again from the beginning.
+    pub fn stop(&self) {
+        self.change_clock(|clock| Some(clock.moved_to(TransportState::Stopped, 0.0)));
+    }
+
+    /// Waits until the clock is no longer at `generation`, or for `timeout`, and returns it.
+    fn wait_for_change(&self, generation: Option<u64>, timeout: Duration) -> Clock {
+        let (clock, changed) = &*self.clock;
+        let clock = clock.lock().unwrap();
+        *changed
+            .wait_timeout_while(clock, timeout, |c| Some(c.generation) == generation)
+            .unwrap()
+            .0
+    }
+
+    /// Where the track is now, or was `seconds_ago`, unless it is stopped or had not
+    /// started yet.
+    pub fn position_before(&self, seconds_ago: f64) -> Option<BarPosition> {
+        let clock = self.clock();
+        let seconds = clock.seconds() - seconds_ago;
+        let track = self.track.lock().unwrap();
+        match track.as_ref() {
+            Some(track) if clock.state != TransportState::Stopped && seconds >= 0.0 => {
+                Some(track.position_at(seconds))
+            }
+            _ => None,
+        }
+    }
+
+    /// While the track plays, how long until its next bar begins.
+    pub fn until_next_bar(&self) -> Option<f64> {
+        let clock = self.clock();
+        let track = self.track.lock().unwrap();
+        match track.as_ref() {
+            Some(track) if clock.state == TransportState::Playing => {
+                Some(track.until_next_bar(clock.seconds()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Plays the backing track of `transport` through `scheduler` whenever its transport is
+/// playing. Each time it starts, the rest of the track is scheduled at once; when it
+/// pauses or stops, whatever has yet to play is cancelled and any notes still sounding
+/// are released.
+pub fn start_backing_thread(
+    transport: BackingTransport,
+    scheduler: Scheduler,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(BACKING_THREAD, SINGLETON, move || {
+        let source = scheduler.source();
+        let idle = Duration::from_millis(BACKING_IDLE_MILLIS);
+        let mut generation = None;
+        let mut playing: Option<(BackingTrack, Clock)> = None;
+        while !quit.load() {
+            let clock = transport.wait_for_change(generation, idle);
+            if generation != Some(clock.generation) {
+                if let Some((track, started)) = playing.take() {
+                    release(&track, &started, &scheduler, source);
+                }
+                generation = Some(clock.generation);
+                if clock.state == TransportState::Playing {
+                    let track = transport.track.lock().unwrap().clone();
+                    if let Some(track) = track {
+                        schedule_from(&track, &clock, &scheduler, source);
+                        playing = Some((track, clock));
+                    }
+                }
+            }
+            if let Some((track, started)) = &playing {
+                if started.seconds() >= track.duration {
+                    transport.stop();
+                }
+            }
+        }
+        if let Some((track, started)) = playing {
+            release(&track, &started, &scheduler, source);
+        }
+    })
+}
+
+/// Schedules every event of `track` from where `clock` started it playing.
+fn schedule_from(track: &BackingTrack, clock: &Clock, scheduler: &Scheduler, source: u64) {
+    for (seconds, bytes) in track.events[track.first_event_from(clock.offset)..].iter() {
+        if let Some(msg) = synth_msg(bytes) {
+            let at = clock.started + Duration::from_secs_f64(seconds - clock.offset);
+            scheduler.schedule(source, at, msg);
+        }
+    }
+}
+
+/// Cancels what is left of `track` as `clock` started it, and releases its held notes.
+fn release(track: &BackingTrack, clock: &Clock, scheduler: &Scheduler, source: u64) {
+    scheduler.cancel(source);
+    for [status, key] in track.held_between(clock.offset, clock.seconds()) {
+        if let Some(msg) = synth_msg(&[status, key, 0]) {
+            scheduler.output().push(msg);
+        }
+    }
+}
+
+fn synth_msg(bytes: &[u8]) -> Option<SynthMsg> {
+    MidiMsg::from_midi(bytes).ok().map(|(msg, _)| SynthMsg {
+        msg,
+        speaker: BACKING_SPEAKER,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Melody;
+    use crate::midi_file::melody_to_smf;
+    use crate::queue::BlockingQueue;
+    use crate::scheduler::start_test_scheduler_thread;
+    use float_cmp::assert_approx_eq;
+    use midi_msg::ChannelVoiceMsg;
+
+    #[test]
+    fn test_backing_track() {
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.8,64,1.0,0.8,65,2.0,0.8");
+        let bytes = melody_to_smf(&melody).unwrap();
+        let track = BackingTrack::from_smf("test", bytes.as_slice()).unwrap();
+        assert_eq!(track.events.len(), 8);
+        assert_approx_eq!(f64, track.duration(), 4.0, epsilon = 0.01);
+        assert_approx_eq!(f64, track.bar_seconds(), 2.0);
+        assert_eq!(track.position_at(0.0), BarPosition { bar: 1, beat: 1.0 });
+        assert_eq!(track.position_at(3.0), BarPosition { bar: 2, beat: 3.0 });
+        assert_eq!(track.position_at(3.0).tag(), "Bar 2");
+        assert_approx_eq!(f64, track.until_next_bar(2.5), 1.5);
+        assert_eq!(track.until_next_bar(4.0), 0.0);
+        assert!(BackingTrack::from_smf("bad", &[0, 1, 2]).is_err());
+
+        let transport = BackingTransport::new();
+        transport.play();
+        assert_eq!(transport.state(), TransportState::Stopped);
+        transport.load(track);
+        assert_eq!(transport.track_name(), Some("test".to_owned()));
+        assert_eq!(transport.position_before(0.0), None);
+        let quit = Arc::new(AtomicCell::new(false));
+        let ai2output = Arc::new(BlockingQueue::new());
+        let scheduler = Scheduler::new(ai2output.clone());
+        let scheduling = start_test_scheduler_thread(scheduler.clone(), quit.clone());
+        start_backing_thread(transport.clone(), scheduler, quit.clone()).unwrap();
+        transport.play();
+        assert_eq!(transport.state(), TransportState::Playing);
+        assert!(transport.until_next_bar().unwrap() <= 2.0);
+        let first = ai2output.pop_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            first.msg,
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOn { note: 60, .. },
+                ..
+            }
+        ));
+        transport.pause();
+        assert_eq!(transport.state(), TransportState::Paused);
+        assert!(transport.position_before(0.0).is_some());
+        assert_eq!(transport.until_next_bar(), None);
+        let released = ai2output.pop_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            released.msg,
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { note: 60, .. },
+                ..
+            }
+        ));
+        transport.stop();
+        assert_eq!(transport.seconds(), 0.0);
+        quit.store(true);
+        scheduling.join().unwrap();
+    }
+}
@@ -1,7 +1,23 @@
+use clap::Parser;
 use musicserver1::database::{Database, MelodyInfo, Preference};
+use musicserver1::tokens::remi_tokens;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 
-fn main() {
+#[derive(Parser, Debug)]
+struct Args {
+    /// Write every melody and its variations as REMI-style tokens to this file, one
+    /// tab-separated line per pair: row ids, then human tokens, then variation tokens
+    #[arg(long)]
+    remi: Option<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     let mut database = Database::new();
+    if let Some(filename) = args.remi {
+        return export_remi(&mut database, filename.as_str());
+    }
     for (info1, info2, stats) in database
         .get_melody_pairs(Preference::Neutral, Preference::Favorite)
         .unwrap()
@@ -11,6 +27,7 @@ fn main() {
         println!("{stats:?}");
         println!();
     }
+    Ok(())
 }
 
 fn print_info(info: &MelodyInfo) {
@@ -22,3 +39,20 @@ fn print_info(info: &MelodyInfo) {
         info.time()
     );
 }
+
+fn export_remi(database: &mut Database, filename: &str) -> anyhow::Result<()> {
+    let pairs = database.get_melody_pairs(Preference::Ignore, Preference::Ignore)?;
+    let mut out = BufWriter::new(File::create(filename)?);
+    for (melody_info, variation_info, _) in pairs.iter() {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}",
+            melody_info.row_id(),
+            variation_info.row_id(),
+            remi_tokens(melody_info.melody()).join(" "),
+            remi_tokens(variation_info.melody()).join(" ")
+        )?;
+    }
+    println!("Wrote {} pairs to {filename}", pairs.len());
+    Ok(())
+}
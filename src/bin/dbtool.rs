@@ -0,0 +1,50 @@
+use clap::{Parser, Subcommand};
+use musicserver1::database::{Database, Preference};
+use std::path::PathBuf;
+
+/// Maintenance for the melody database.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Database file to work on, if not the one the replayer uses
+    #[arg(long)]
+    database: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Delete every melody rated below the given rating (Favorite, Neutral or Ignore)
+    Prune {
+        #[arg(long)]
+        min_rating: Preference,
+    },
+    /// Merge melodies with identical notes, keeping the oldest copy
+    Dedup,
+    /// Write every melody to a directory as MIDI files with a JSON index
+    Export { dir: PathBuf },
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut database = match args.database {
+        Some(filename) => Database::open(filename.as_str()),
+        None => Database::new(),
+    };
+    match args.command {
+        Command::Prune { min_rating } => {
+            let deleted = database.prune(min_rating)?;
+            println!("Deleted {deleted} melodies rated below {min_rating}");
+        }
+        Command::Dedup => {
+            let removed = database.deduplicate()?;
+            println!("Removed {removed} duplicate melodies");
+        }
+        Command::Export { dir } => {
+            let written = database.export_bundle(dir.as_path())?;
+            println!("Exported {written} melodies to {}", dir.display());
+        }
+    }
+    Ok(())
+}
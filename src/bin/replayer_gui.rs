@@ -1,28 +1,67 @@
+use anyhow::{anyhow, bail};
 use bare_metal_modulo::*;
+use clap::Parser;
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::atomic::AtomicCell;
 use eframe::egui::{self, Key, TextEdit};
 use eframe::egui::{
-    Align2, Color32, FontData, FontDefinitions, FontFamily, FontId, Painter, Pos2, Sense, Stroke,
-    Ui, Vec2, Visuals,
+    Align, Align2, Button, Color32, FontData, FontDefinitions, FontFamily, FontId, Painter, Pos2,
+    Rect, Sense, Stroke, Ui, Vec2, Visuals,
 };
 use eframe::emath::Numeric;
 use enum_iterator::all;
-use midi_fundsp::io::{start_input_thread, start_output_thread, Speaker, SynthMsg};
+use midi_fundsp::io::{Speaker, SynthMsg};
 use midi_fundsp::SynthFunc;
-use midir::{Ignore, InitError, MidiInput, MidiInputPort, MidiInputPorts};
+use midir::{Ignore, InitError, MidiInput, MidiInputPort, MidiInputPorts, MidiOutput};
 use musicserver1::ai_variation::{
-    make_ai_table, start_ai_thread, AIFuncType, DEFAULT_AI_NAME, NO_AI_NAME,
+    make_ai_table, start_ai_thread, AIFuncType, KeyboardSplit, Personality, DEFAULT_AI_NAME,
+    NO_AI_NAME,
 };
-use musicserver1::analyzer::{Accidental, KeySignature, Melody, MidiByte, MusicMode};
+use musicserver1::analyzer::{
+    parse_progression, stats, Accidental, ChordSource, DynamicShaping, Humanize, KeySignature,
+    Melody, MidiByte, MusicMode, PhraseSegmentation, RhythmPreservation,
+};
+use musicserver1::automation::{parse_steps, start_automation_thread, Automation, AutomationShape};
+use musicserver1::backends::{AudioSink, MidirSource, NullSink, SynthList, SynthSink};
+use musicserver1::backing::{start_backing_thread, BackingTrack, BackingTransport, TransportState};
+use musicserver1::config::{Config, CONFIG_FILE};
 use musicserver1::database::{
     start_database_thread, Database, DatabaseGuiUpdate, FromAiMsg, GuiDatabaseUpdate, MelodyInfo,
-    Preference, VariationStats,
+    PairPager, PracticeAttempt, Preference, Session, Snapshot, VariationStats, PAIR_PAGE_SIZE,
+};
+use musicserver1::dynamics::{OutputLevel, PressureRoute, LIMITER_THRESHOLD};
+use musicserver1::folder_watch::start_folder_watch_thread;
+use musicserver1::harmonizer::HarmonyInterval;
+use musicserver1::heatmap::{rolling_heatmaps, NoteHeatmap, NUM_PITCH_CLASSES};
+use musicserver1::instance::InstanceLock;
+use musicserver1::logging::{init_logging, LogControl, LogLevel};
+use musicserver1::melody_store::{open_store, MelodyStore};
+use musicserver1::midi_clock::{start_clock_output_thread, ClockSync};
+use musicserver1::midi_input::{
+    load_capture, start_input_thread, start_replay_thread, MidiCapture,
 };
+use musicserver1::midi_learn::{MidiLearn, TAP_TEMPO};
+use musicserver1::pitch;
+use musicserver1::plugins::{load_plugins, with_plugins, PLUGIN_DIR};
+use musicserver1::queue::{BlockingQueue, RingBuffer};
+use musicserver1::report::session_html;
 use musicserver1::runtime::{
-    make_synth_table, replay_slider, send_recorded_melody, ChooserTable, MelodyRunStatus,
-    SliderValue, SynthChoice, VariationControls, HUMAN_SPEAKER, VARIATION_SPEAKER, send_two_melodies,
+    concert_pitch_slider, make_synth_table, pedal_replay_slider, replay_slider,
+    send_recorded_melody, send_two_melodies, stuck_note_slider, transpose_slider,
+    user_pick_element, ChooserTable, MelodyRunStatus, MixControls, SliderValue, SynthChoice,
+    VariationControls, COMMON_CONCERT_PITCHES, HUMAN_SPEAKER, VARIATION_SPEAKER,
+};
+use musicserver1::scheduler::{start_scheduler_thread, Scheduler};
+use musicserver1::scripting::{load_scripts, SCRIPT_DIR};
+use musicserver1::share::{
+    best_exchange, qr_modules, save_exchange, share_url, start_share_server_thread,
+};
+use musicserver1::threads::{
+    running_threads, spawn_named, AlreadyRunning, Shutdown, GUI_LISTENER_THREAD,
+    MAX_PLAYBACK_THREADS, PLAYBACK_THREAD, SINGLETON,
 };
+use musicserver1::training::{progress, PracticeTarget};
+use musicserver1::watchdog::{start_watchdog_thread, HeldKeys, PanicButton};
 use std::cmp::{max, min};
 use std::fmt::Display;
 use std::ops::RangeInclusive;
@@ -30,16 +69,166 @@ use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 const NUM_OUTPUT_CHANNELS: usize = 2; // More than this, and it has occasional noise-clipping problems.
+const DEFAULT_CAPTURE_FILE: &str = "midi_capture.txt";
+const SESSION_REPORT_FILE: &str = "session_report.html";
+const RECENT_RESPONSES: usize = 10;
+const DEFAULT_AUTOMATION_PERIOD: f64 = 60.0;
+const AUTOMATION_PERIOD_RANGE: RangeInclusive<f64> = 5.0..=600.0;
+/// How often the headless replayer looks for program changes from the keyboard.
+const HEADLESS_POLL_MILLIS: u64 = 25;
+/// Begins the names under which the second AI voice's settings are saved.
+const SECOND_VOICE_PREFIX: &str = "Second Voice ";
+
+/// Plays back variations of the melodies performed on a MIDI keyboard. Options given here
+/// override the startup defaults saved in the config file.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Name of the MIDI input port to use, instead of choosing one
+    #[arg(long)]
+    in_port: Option<String>,
+    /// Name of the audio output device to play on. Only the default device can be opened
+    /// for now, so naming another one logs a warning
+    #[arg(long)]
+    out_device: Option<String>,
+    /// Name of the synthesizer for the human performer
+    #[arg(long)]
+    synth: Option<String>,
+    /// List the MIDI input ports and synthesizers, then exit
+    #[arg(long)]
+    list_devices: bool,
+    /// Run without the GUI, choosing a MIDI port at the console if needed
+    #[arg(long)]
+    headless: bool,
+    /// Use the named profile from the config file, such as a particular venue's setup
+    #[arg(long)]
+    profile: Option<String>,
+    /// Vary every MIDI file dropped into this folder, writing each variation alongside it
+    #[arg(long)]
+    watch_folder: Option<String>,
+    /// Write every raw MIDI packet received to this file as it arrives
+    #[arg(long)]
+    midi_log: Option<String>,
+    /// Play a MIDI capture or log through the AI at its original speed after starting up
+    #[arg(long)]
+    replay_midi: Option<String>,
+    /// Run without sound, such as on a machine with no audio device
+    #[arg(long)]
+    no_audio: bool,
+    /// How much to log: Error, Warn, Info, Debug or Trace
+    #[arg(long, default_value = "Info")]
+    log_level: LogLevel,
+}
 
-fn main() {
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if args.list_devices {
+        return list_devices();
+    }
+    let log_control = init_logging(args.log_level)?;
+    // Held until the process exits.
+    let _instance = InstanceLock::acquire()?;
+    let config = Config::load_or_default(CONFIG_FILE);
+    let settings = settings_from(&config, &args)?;
+    let mut app = ReplayerApp::from_config(config, args.profile, settings)?;
+    app.log_control = Some(log_control);
+    if args.no_audio {
+        app.audio = Box::new(NullSink);
+    }
+    if let Some(filename) = args.midi_log {
+        app.midi_capture.log_to(filename.as_str())?;
+    }
+    if let Some(filename) = args.replay_midi {
+        let replayed = app.replay_capture(filename.as_str())?;
+        info!("Replaying {replayed} events from {filename}");
+    }
+    let shutdown = app.shutdown.clone();
+    ctrlc::set_handler(move || {
+        shutdown.run();
+        std::process::exit(0);
+    })?;
+    if args.headless {
+        return app.run_headless();
+    }
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
         "Replayer",
         native_options,
-        Box::new(|cc| Box::new(ReplayerApp::new(cc).unwrap())),
-    ).unwrap();
+        Box::new(move |cc| {
+            app.load_fonts(cc);
+            Box::new(app)
+        }),
+    )
+    .unwrap();
+    Ok(())
+}
+
+/// The settings in effect for the chosen profile, overridden by anything given on the
+/// command line.
+fn settings_from(config: &Config, args: &Args) -> anyhow::Result<Config> {
+    let mut settings = config.active(args.profile.as_deref())?;
+    if args.in_port.is_some() {
+        settings.midi_in_port = args.in_port.clone();
+    }
+    if args.out_device.is_some() {
+        settings.audio_device = args.out_device.clone();
+    }
+    if args.synth.is_some() {
+        settings.human_synth = args.synth.clone();
+    }
+    if args.watch_folder.is_some() {
+        settings.watch_folder = args.watch_folder.clone();
+    }
+    Ok(settings)
+}
+
+fn list_devices() -> anyhow::Result<()> {
+    let midi_in = MidiInput::new("midir listing ports")?;
+    println!("MIDI input ports:");
+    for in_port in midi_in.ports().iter() {
+        println!("  {}", midi_in.port_name(in_port)?);
+    }
+    println!("MIDI output ports (for sending MIDI clock):");
+    for name in output_port_names() {
+        println!("  {name}");
+    }
+    println!("Synthesizers:");
+    for name in make_synth_table().name_vec() {
+        println!("  {name}");
+    }
+    Ok(())
+}
+
+fn output_port_names() -> Vec<String> {
+    MidiOutput::new("midir listing output ports")
+        .map(|midi_out| {
+            midi_out
+                .ports()
+                .iter()
+                .filter_map(|port| midi_out.port_name(port).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Opens the MIDI output port called `name`, for sending MIDI clock.
+fn clock_output(name: &str) -> anyhow::Result<Box<dyn FnMut(&[u8]) + Send>> {
+    let midi_out = MidiOutput::new("replayer clock")?;
+    let port = midi_out
+        .ports()
+        .into_iter()
+        .find(|port| midi_out.port_name(port).ok().as_deref() == Some(name))
+        .ok_or(anyhow!("No MIDI output port named {name}"))?;
+    let mut connection = midi_out
+        .connect(&port, "replayer-clock")
+        .map_err(|e| anyhow!("{e}"))?;
+    Ok(Box::new(move |bytes: &[u8]| {
+        if let Err(e) = connection.send(bytes) {
+            warn!("Could not send MIDI clock: {e}");
+        }
+    }))
 }
 
 #[derive(Clone)]
@@ -51,11 +240,20 @@ enum MidiScenario {
 }
 
 impl MidiScenario {
-    fn new(midi_in: &mut Result<MidiInput, InitError>) -> Self {
+    fn new(midi_in: &mut Result<MidiInput, InitError>, preferred_port: Option<&str>) -> Self {
         match midi_in {
             Ok(ref mut midi_in) => {
                 midi_in.ignore(Ignore::None);
                 let in_ports = midi_in.ports();
+                let port_names = in_ports
+                    .iter()
+                    .map(|port| midi_in.port_name(port).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                if let Some(i) = find_port(&port_names, preferred_port) {
+                    return MidiScenario::InputPortSelected {
+                        in_port: in_ports[i].clone(),
+                    };
+                }
                 match in_ports.len() {
                     0 => MidiScenario::NoInputPorts(
                         "No MIDI devices found\nRestart program after MIDI device plugged in"
@@ -74,6 +272,11 @@ impl MidiScenario {
     }
 }
 
+/// Where the port called `name` is among `port_names`, if it is there at all.
+fn find_port(port_names: &[String], name: Option<&str>) -> Option<usize> {
+    name.and_then(|name| port_names.iter().position(|port| port == name))
+}
+
 #[derive(Clone, Debug)]
 pub struct VecTracker<T: Clone> {
     items: Vec<T>,
@@ -138,6 +341,20 @@ impl<T: Clone> VecTracker<T> {
         }
     }
 
+    pub fn index(&self) -> Option<usize> {
+        self.tracker.map(|t| t.a())
+    }
+
+    pub fn go_to(&mut self, index: usize) {
+        if index < self.items.len() {
+            self.tracker = Some(ModNum::new(index, self.items.len()));
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
     pub fn go_left(&mut self) {
         if !self.is_empty() && !self.at_start() {
             self.tracker = self.tracker.map(|t| t - 1);
@@ -154,6 +371,20 @@ impl<T: Clone> VecTracker<T> {
         self.items.push(item);
         self.go_to_end();
     }
+
+    /// Puts `older` before the current items, staying on the current item.
+    pub fn prepend(&mut self, mut older: Vec<T>) {
+        let shift = older.len();
+        older.append(&mut self.items);
+        self.items = older;
+        self.tracker = match self.tracker {
+            Some(t) => Some(ModNum::new(t.a() + shift, self.items.len())),
+            None if !self.items.is_empty() => {
+                Some(ModNum::new(self.items.len() - 1, self.items.len()))
+            }
+            None => None,
+        };
+    }
 }
 
 struct TableInfo<T: Clone> {
@@ -180,6 +411,28 @@ impl<T: Clone> TableInfo<T> {
         let table = self.table.lock().unwrap();
         table.current_index()
     }
+
+    /// Swaps in `table`, keeping the current choice if it is still there, or else taking
+    /// the new table's first.
+    fn replace_table(&mut self, table: ChooserTable<T>) {
+        if !table.name_vec().contains(&self.name) {
+            warn!("{} is no longer available", self.name);
+            self.name = table.current_name().to_owned();
+        }
+        *self.table.lock().unwrap() = table;
+        self.update_choice();
+    }
+
+    fn choose_configured(&mut self, name: &Option<String>) {
+        if let Some(name) = name {
+            if self.table.lock().unwrap().name_vec().contains(name) {
+                self.name = name.clone();
+                self.update_choice();
+            } else {
+                warn!("Configured choice {name} is not available");
+            }
+        }
+    }
 }
 
 struct ReplayerApp {
@@ -188,30 +441,104 @@ struct ReplayerApp {
     ai_algorithm: TableInfo<Arc<AIFuncType>>,
     human_synth: TableInfo<SynthFunc>,
     ai_synth: TableInfo<SynthFunc>,
+    /// The synthesizers the output thread plays, shared so that they can be reloaded.
+    synth_list: SynthList,
     variation_controls: VariationControls,
+    second_voice_algorithm: TableInfo<Arc<AIFuncType>>,
+    second_voice_controls: VariationControls,
     replay_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pedal_delay_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    stuck_note_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    transpose_slider: Arc<AtomicCell<SliderValue<MidiByte>>>,
+    concert_pitch_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    mix_controls: MixControls,
+    phrase_segmentation: Arc<AtomicCell<PhraseSegmentation>>,
+    mpe: Arc<AtomicCell<bool>>,
+    keyboard_split: Arc<AtomicCell<KeyboardSplit>>,
+    harmony: Arc<AtomicCell<HarmonyInterval>>,
     in_port: Option<MidiInputPort>,
     in_port_name: Option<String>,
+    /// The port named by the command line, the profile or the defaults, in that order.
+    requested_in_port: Option<String>,
     melody_pref: Arc<AtomicCell<Preference>>,
     variation_pref: Arc<AtomicCell<Preference>>,
     today_search_pref: Arc<AtomicCell<Preference>>,
     older_search_pref: Arc<AtomicCell<Preference>>,
     melody_var_info: Arc<Mutex<VecTracker<(MelodyInfo, MelodyInfo, VariationStats)>>>,
     melody_var_update_needed: Arc<AtomicCell<bool>>,
-    database: Option<Database>,
-    input2ai: Arc<SegQueue<SynthMsg>>,
-    ai2dbase: Arc<SegQueue<FromAiMsg>>,
+    database: Option<Box<dyn MelodyStore>>,
+    input2ai: Arc<BlockingQueue<SynthMsg>>,
+    ai2dbase: Arc<BlockingQueue<FromAiMsg>>,
     dbase2gui: Arc<SegQueue<DatabaseGuiUpdate>>,
     gui2dbase: Arc<SegQueue<GuiDatabaseUpdate>>,
     gui2ai: Arc<SegQueue<MelodyInfo>>,
-    ai2output: Arc<SegQueue<SynthMsg>>,
+    ai2output: Arc<BlockingQueue<SynthMsg>>,
+    scheduler: Scheduler,
+    watchdog2output: Arc<SegQueue<SynthMsg>>,
+    audio: Box<dyn AudioSink>,
     melody_progress: Arc<AtomicCell<Option<f32>>>,
     melody_run_status: MelodyRunStatus,
     adjust_search_preferences: bool,
     variations_of_current_melody: bool,
     show_variation: bool,
+    show_chords: bool,
+    show_timeline: bool,
+    timeline_zoom: f32,
+    show_heatmap: bool,
+    heatmap_window: usize,
+    show_piano_roll: bool,
+    piano_roll_zoom: f32,
+    held_keys: Arc<AtomicCell<HeldKeys>>,
+    output_level: Arc<AtomicCell<OutputLevel>>,
+    show_keyboard: bool,
+    panic_button: PanicButton,
+    report_status: String,
+    musicxml_status: String,
+    config: Config,
+    profile: Option<String>,
+    settings_status: String,
+    preset_name: String,
+    presets_by_program: bool,
+    preset_status: String,
+    automations: Arc<Mutex<Vec<Automation>>>,
+    automation_slider: String,
+    automation_shape: AutomationShape,
+    automation_period: f64,
+    automation_depth: f64,
+    automation_steps: String,
+    automation_status: String,
+    snapshots: Arc<Mutex<Vec<Snapshot>>>,
+    snapshot_name: String,
+    log_control: Option<LogControl>,
+    sessions: Arc<Mutex<Vec<Session>>>,
+    session_notes: String,
+    practice_target: Arc<Mutex<Option<PracticeTarget>>>,
+    practice_attempts: Arc<Mutex<Vec<PracticeAttempt>>>,
+    chord_text: String,
+    chord_status: String,
+    backing: BackingTransport,
+    backing_file: String,
+    backing_status: String,
+    clock_sync: ClockSync,
+    clock_ports: Vec<String>,
+    clock_port: Option<String>,
+    clock_status: String,
+    recent_responses: Arc<Mutex<RingBuffer<(MelodyInfo, MelodyInfo)>>>,
+    response_back: usize,
+    paste_text: String,
+    paste_status: String,
+    share_status: String,
+    share_qr: Option<(String, usize, Vec<bool>)>,
+    share_server_started: bool,
     new_tags: [String; 2],
-    quit_threads: Arc<AtomicCell<bool>>,
+    midi_capture: MidiCapture,
+    midi_learn: Arc<Mutex<MidiLearn>>,
+    program_change: Arc<AtomicCell<Option<u8>>>,
+    input_connected: Arc<AtomicCell<bool>>,
+    capture_file: String,
+    capture_status: String,
+    watch_folder: Option<String>,
+    shutdown: Shutdown,
 }
 
 const MAIN_MELODY_SCALING: f32 = 0.8;
@@ -224,7 +551,34 @@ const Y_OFFSET: f32 = BORDER_SIZE * 2.0;
 const X_OFFSET: f32 = BORDER_SIZE * 5.0;
 const ACCIDENTAL_SIZE_MULTIPLIER: f32 = 5.0;
 const KEY_SIGNATURE_OFFSET: f32 = 28.0;
+const CHORD_SIZE_MULTIPLIER: f32 = 2.5;
 const NUM_STAFF_LINES: MidiByte = 5;
+const TIMELINE_HEIGHT: f32 = 24.0;
+const TIMELINE_GAP: f32 = 4.0;
+const DEFAULT_TIMELINE_ZOOM: f32 = 20.0;
+const TIMELINE_ZOOM_RANGE: RangeInclusive<f32> = 2.0..=100.0;
+const HEATMAP_CELL_SIZE: f32 = 14.0;
+const DEFAULT_HEATMAP_WINDOW: usize = 10;
+const HEATMAP_WINDOW_RANGE: RangeInclusive<usize> = 1..=50;
+const PIANO_ROLL_ROW_HEIGHT: f32 = 6.0;
+const DEFAULT_PIANO_ROLL_ZOOM: f32 = 100.0;
+const PIANO_ROLL_ZOOM_RANGE: RangeInclusive<f32> = 20.0..=400.0;
+/// Empty rows above and below the outermost notes.
+const PIANO_ROLL_MARGIN: MidiByte = 2;
+const MAX_VELOCITY: f32 = 127.0;
+/// The 88 keys of a piano, A0 through C8.
+const LOWEST_PIANO_KEY: usize = 21;
+const HIGHEST_PIANO_KEY: usize = 108;
+const WHITE_KEY_WIDTH: f32 = 12.0;
+const WHITE_KEY_HEIGHT: f32 = 60.0;
+const BLACK_KEY_WIDTH: f32 = 8.0;
+const BLACK_KEY_HEIGHT: f32 = 36.0;
+const BLACK_PITCH_CLASSES: [usize; 5] = [1, 3, 6, 8, 10];
+const HUMAN_KEY_COLOR: Color32 = Color32::BLUE;
+const VARIATION_KEY_COLOR: Color32 = Color32::RED;
+const QR_MODULE_SIZE: f32 = 4.0;
+/// The blank border that QR readers need around the code.
+const QR_QUIET_MODULES: usize = 4;
 const LINE_STROKE: Stroke = Stroke {
     width: 1.0,
     color: Color32::BLACK,
@@ -240,7 +594,7 @@ macro_rules! load_font {
             .next()
             .unwrap()
             .to_owned();
-        println!("Loading font {name} from {}.", $filename);
+        debug!("Loading font {name} from {}.", $filename);
         $fonts.font_data.insert(
             name.clone(),
             FontData::from_static(include_bytes!($filename)),
@@ -254,34 +608,76 @@ macro_rules! load_font {
 }
 
 impl ReplayerApp {
-    fn new(cc: &eframe::CreationContext<'_>) -> anyhow::Result<Self> {
+    fn load_fonts(&self, cc: &eframe::CreationContext<'_>) {
         let mut fonts = FontDefinitions::default();
         load_font!(fonts, "../../bravura/BravuraText.otf");
         cc.egui_ctx.set_fonts(fonts);
+    }
 
-        let ai_algorithm = TableInfo::new(make_ai_table());
+    /// `config` is the whole config file, and `settings` the part of it in effect for
+    /// `profile`, after any command-line overrides.
+    fn from_config(
+        config: Config,
+        profile: Option<String>,
+        settings: Config,
+    ) -> anyhow::Result<Self> {
+        let mut plugins = load_plugins(PLUGIN_DIR);
+        plugins.extend(load_scripts(SCRIPT_DIR));
+        let ai_algorithm = TableInfo::new(with_plugins(&make_ai_table(), &plugins));
         let human_synth = TableInfo::new(make_synth_table());
         let ai_synth = TableInfo::new(make_synth_table());
+        let synth_list = Arc::new(Mutex::new(make_synth_table().choice_vec()));
         let variation_controls = VariationControls::new();
         let replay_delay_slider = Arc::new(AtomicCell::new(replay_slider()));
-        let mut database = Database::new();
+        let pedal_delay_slider = Arc::new(AtomicCell::new(pedal_replay_slider()));
+        let stuck_note_slider = Arc::new(AtomicCell::new(stuck_note_slider()));
+        let mix_controls = MixControls::new();
+        let named_sliders = Self::named_sliders(
+            &variation_controls,
+            &replay_delay_slider,
+            &pedal_delay_slider,
+            &stuck_note_slider,
+            &mix_controls,
+        );
+        let midi_learn = Self::make_midi_learn(&variation_controls, named_sliders);
+        let clock_sync = ClockSync::new(
+            variation_controls.tempo_slider.clone(),
+            variation_controls.fixed_tempo.clone(),
+        );
+        let mut database = open_store(settings.database_path.as_deref());
         let database_timer = Instant::now();
         let (melody_var_info, melody_pref, variation_pref) =
-            Self::wrapped_melody_info(&mut database, Preference::Neutral, Preference::Favorite);
+            Self::wrapped_melody_info(database.as_mut(), Preference::Neutral, Preference::Favorite);
         let database_load_time = database_timer.elapsed().as_secs_f64();
-        println!("Database load time: {database_load_time}s");
+        info!("Database load time: {database_load_time}s");
         let melody_run_status = MelodyRunStatus::new();
+        let watchdog2output = Arc::new(SegQueue::new());
+        let ai2output = Arc::new(BlockingQueue::new());
+        let shutdown = Shutdown::new(melody_run_status.clone(), watchdog2output.clone());
 
         let mut app = ReplayerApp {
             midi_scenario: Arc::new(Mutex::new(MidiScenario::StartingUp)),
             midi_in: Arc::new(Mutex::new(None)),
             variation_controls,
             replay_delay_slider,
+            pedal_delay_slider,
+            stuck_note_slider,
+            transpose_slider: Arc::new(AtomicCell::new(transpose_slider())),
+            concert_pitch_slider: Arc::new(AtomicCell::new(concert_pitch_slider())),
+            mix_controls,
+            phrase_segmentation: Arc::new(AtomicCell::new(PhraseSegmentation::SilenceDelay)),
+            mpe: Arc::new(AtomicCell::new(false)),
+            keyboard_split: Arc::new(AtomicCell::new(KeyboardSplit::default())),
+            harmony: Arc::new(AtomicCell::new(HarmonyInterval::Off)),
             ai_algorithm,
+            second_voice_algorithm: TableInfo::new(with_plugins(&make_ai_table(), &plugins)),
+            second_voice_controls: VariationControls::new(),
             human_synth,
             ai_synth,
+            synth_list,
             in_port: None,
             in_port_name: None,
+            requested_in_port: None,
             melody_pref,
             variation_pref,
             today_search_pref: Arc::new(AtomicCell::new(Preference::Neutral)),
@@ -289,26 +685,92 @@ impl ReplayerApp {
             melody_var_info,
             melody_var_update_needed: Arc::new(AtomicCell::new(true)),
             database: Some(database),
-            input2ai: Arc::new(SegQueue::new()),
-            ai2dbase: Arc::new(SegQueue::new()),
+            input2ai: Arc::new(BlockingQueue::new()),
+            ai2dbase: Arc::new(BlockingQueue::new()),
             dbase2gui: Arc::new(SegQueue::new()),
             gui2dbase: Arc::new(SegQueue::new()),
             gui2ai: Arc::new(SegQueue::new()),
-            ai2output: Arc::new(SegQueue::new()),
+            scheduler: Scheduler::new(ai2output.clone()),
+            ai2output,
+            watchdog2output,
+            audio: Box::new(SynthSink::<NUM_OUTPUT_CHANNELS>::new(
+                settings.audio_device.clone(),
+            )),
             melody_progress: Arc::new(AtomicCell::new(None)),
             melody_run_status,
             adjust_search_preferences: false,
             variations_of_current_melody: false,
             show_variation: true,
+            show_chords: false,
+            show_timeline: false,
+            timeline_zoom: DEFAULT_TIMELINE_ZOOM,
+            show_heatmap: false,
+            heatmap_window: DEFAULT_HEATMAP_WINDOW,
+            show_piano_roll: false,
+            piano_roll_zoom: DEFAULT_PIANO_ROLL_ZOOM,
+            held_keys: Arc::new(AtomicCell::new(HeldKeys::NONE)),
+            output_level: Arc::new(AtomicCell::new(OutputLevel::default())),
+            show_keyboard: false,
+            panic_button: PanicButton::new(),
+            report_status: String::new(),
+            musicxml_status: String::new(),
+            config,
+            profile,
+            settings_status: String::new(),
+            preset_name: String::new(),
+            presets_by_program: false,
+            preset_status: String::new(),
+            automations: Arc::new(Mutex::new(vec![])),
+            automation_slider: String::from("Ornamentation"),
+            automation_shape: AutomationShape::Sine,
+            automation_period: DEFAULT_AUTOMATION_PERIOD,
+            automation_depth: 0.5,
+            automation_steps: String::from("-1, 0, 1, 0"),
+            automation_status: String::new(),
+            snapshots: Arc::new(Mutex::new(vec![])),
+            snapshot_name: String::new(),
+            log_control: None,
+            sessions: Arc::new(Mutex::new(vec![])),
+            session_notes: String::new(),
+            practice_target: Arc::new(Mutex::new(None)),
+            practice_attempts: Arc::new(Mutex::new(vec![])),
+            chord_text: String::new(),
+            chord_status: String::new(),
+            backing: BackingTransport::new(),
+            backing_file: String::new(),
+            backing_status: String::new(),
+            clock_sync,
+            clock_ports: vec![],
+            clock_port: None,
+            clock_status: String::new(),
+            recent_responses: Arc::new(Mutex::new(RingBuffer::new(RECENT_RESPONSES))),
+            response_back: 0,
+            paste_text: String::new(),
+            paste_status: String::new(),
+            share_status: String::new(),
+            share_qr: None,
+            share_server_started: false,
             new_tags: [String::new(), String::new()],
-            quit_threads: Arc::new(AtomicCell::new(false)),
+            midi_capture: MidiCapture::new(),
+            midi_learn: Arc::new(Mutex::new(midi_learn)),
+            program_change: Arc::new(AtomicCell::new(None)),
+            input_connected: Arc::new(AtomicCell::new(true)),
+            capture_file: DEFAULT_CAPTURE_FILE.to_owned(),
+            capture_status: String::new(),
+            watch_folder: settings.watch_folder.clone(),
+            shutdown,
         };
-        app.startup();
+        app.apply_config(&settings);
+        // Only the newest page was loaded; the database thread sends the rest once started.
+        if app.melody_var_info.lock().unwrap().len() == PAIR_PAGE_SIZE {
+            app.request_refresh();
+        }
+        app.startup()?;
         Ok(app)
     }
 
     fn wrapped_melody_info(
-        database: &mut Database,
+        database: &mut dyn MelodyStore,
         min_today_pref: Preference,
         min_older_pref: Preference,
     ) -> (
@@ -326,7 +788,7 @@ impl ReplayerApp {
     }
 
     fn retrieve_melody_info(
-        database: &mut Database,
+        database: &mut dyn MelodyStore,
         min_today_pref: Preference,
         min_older_pref: Preference,
     ) -> (
@@ -334,11 +796,11 @@ impl ReplayerApp {
         Preference,
         Preference,
     ) {
-        let mut melody_var_info = VecTracker::new(
-            database
-                .get_melody_pairs(min_today_pref, min_older_pref)
-                .unwrap(),
-        );
+        let ids = database
+            .melody_pair_ids(min_today_pref, min_older_pref)
+            .unwrap();
+        let newest = PairPager::new(ids).next_page(PAIR_PAGE_SIZE);
+        let mut melody_var_info = VecTracker::new(database.pairs_for(&newest).unwrap());
         melody_var_info.go_to_end();
         let (melody_pref, variation_pref) = melody_var_info
             .get()
@@ -350,13 +812,40 @@ impl ReplayerApp {
 
     fn main_screen(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
-            let heading = format!("Replayer ({})", self.in_port_name.as_ref().unwrap());
+            let mut heading = format!("Replayer ({})", self.in_port_name.as_ref().unwrap());
+            if !self.input_connected.load() {
+                heading.push_str(" - device not responding");
+            }
             self.control_screen(ui, heading);
         });
     }
 
+    /// Variations keep the last progression that could be read while a new one is typed.
+    fn set_chord_progression(&mut self) {
+        self.chord_status = match parse_progression(self.chord_text.as_str()) {
+            Ok(chords) => {
+                *self.variation_controls.chord_progression.lock().unwrap() = chords;
+                String::new()
+            }
+            Err(e) => format!("Cannot read chords: {e}"),
+        };
+    }
+
     fn control_screen(&mut self, ui: &mut Ui, heading: String) {
-        ui.heading(heading);
+        self.handle_program_change();
+        ui.horizontal(|ui| {
+            ui.heading(heading);
+            let panic = ui.button("Panic").on_hover_text("Silence every note now");
+            if panic.clicked() {
+                self.panic_button.press();
+            }
+            let reload = ui
+                .button("Reload Patches")
+                .on_hover_text("Rebuild the synthesizers, letting sounding notes ring on");
+            if reload.clicked() {
+                self.reload_patches();
+            }
+        });
         ui.horizontal(|ui| {
             ui.horizontal(|ui| {
                 let human_name = self.human_synth.name.clone();
@@ -388,17 +877,1155 @@ impl ReplayerApp {
                 Self::insert_slider(ui, p_ornament_slider, "Probability of Inserting Ornament");
                 let replay_delay_slider = self.replay_delay_slider.clone();
                 Self::insert_slider(ui, replay_delay_slider, "Replay Delay (seconds)");
+                let pedal_delay_slider = self.pedal_delay_slider.clone();
+                Self::insert_slider(
+                    ui,
+                    pedal_delay_slider,
+                    "Replay Delay with Pedal Held (seconds)",
+                );
+                let stuck_note_slider = self.stuck_note_slider.clone();
+                Self::insert_slider(ui, stuck_note_slider, "Release Stuck Notes After (seconds)");
+                let transpose_slider = self.transpose_slider.clone();
+                Self::insert_slider(ui, transpose_slider, "Transpose (semitones)");
+                self.concert_pitch_controls(ui);
+                self.mix_sliders(ui);
+                self.phrase_segmentation_buttons(ui);
+                self.harmony_buttons(ui);
                 let shortest_note_slider = self.variation_controls.shortest_note_slider.clone();
                 Self::insert_slider(ui, shortest_note_slider, "Shortest Playable Note (seconds)");
+                let mut thin_input = self.variation_controls.thin_input.load();
+                ui.checkbox(&mut thin_input, "Thin Dense Input");
+                self.variation_controls.thin_input.store(thin_input);
+                if thin_input {
+                    let max_density_slider = self.variation_controls.max_density_slider.clone();
+                    Self::insert_slider(ui, max_density_slider, "Maximum Notes per Second");
+                }
+                let mut preserve_gestures = self.variation_controls.preserve_gestures.load();
+                ui.checkbox(&mut preserve_gestures, "Keep Trills and Glissandi Intact");
+                self.variation_controls
+                    .preserve_gestures
+                    .store(preserve_gestures);
+                let mut rhythm = self.variation_controls.rhythm_preservation.load();
+                ui.horizontal(|ui| {
+                    ui.label("Keep Player's Rhythm:");
+                    for choice in all::<RhythmPreservation>() {
+                        ui.radio_value(&mut rhythm, choice, choice.name());
+                    }
+                });
+                self.variation_controls.rhythm_preservation.store(rhythm);
+                let mut dynamics = self.variation_controls.dynamics.load();
+                ui.horizontal(|ui| {
+                    ui.label("Dynamics:");
+                    for choice in all::<DynamicShaping>() {
+                        ui.radio_value(&mut dynamics, choice, choice.name());
+                    }
+                });
+                self.variation_controls.dynamics.store(dynamics);
+                let mut chord_source = self.variation_controls.chord_source.load();
+                ui.horizontal(|ui| {
+                    ui.label("Chords:");
+                    for choice in all::<ChordSource>() {
+                        ui.radio_value(&mut chord_source, choice, choice.name());
+                    }
+                });
+                self.variation_controls.chord_source.store(chord_source);
+                if chord_source == ChordSource::Progression {
+                    ui.horizontal(|ui| {
+                        ui.label("Progression:");
+                        let entry =
+                            TextEdit::singleline(&mut self.chord_text).hint_text("C Am | F G");
+                        if ui.add(entry).changed() {
+                            self.set_chord_progression();
+                        }
+                    });
+                    ui.label(self.chord_status.as_str());
+                }
                 let mut whimsify = self.variation_controls.whimsify.load();
                 ui.checkbox(&mut whimsify, "Whimsify Suffix");
                 self.variation_controls.whimsify.store(whimsify);
+                let mut tempo_relative = self.variation_controls.tempo_relative_ornaments.load();
+                ui.checkbox(&mut tempo_relative, "Tempo-Relative Ornaments");
+                self.variation_controls
+                    .tempo_relative_ornaments
+                    .store(tempo_relative);
+                let mut velocity_sensitive =
+                    self.variation_controls.velocity_sensitive_ornaments.load();
+                ui.checkbox(&mut velocity_sensitive, "Velocity-Sensitive Ornaments");
+                self.variation_controls
+                    .velocity_sensitive_ornaments
+                    .store(velocity_sensitive);
+                ui.horizontal(|ui| {
+                    ui.label("Ornaments:");
+                    for (ornament, switch) in self.variation_controls.ornament_switches.iter() {
+                        let mut enabled = switch.load();
+                        ui.checkbox(&mut enabled, ornament.name());
+                        switch.store(enabled);
+                    }
+                });
+                if tempo_relative {
+                    let ornament_beat_slider = self.variation_controls.ornament_beat_slider.clone();
+                    Self::insert_slider(ui, ornament_beat_slider, "Ornament Note Length (beats)");
+                }
+                let p_respond_slider = self.variation_controls.p_respond_slider.clone();
+                Self::insert_slider(ui, p_respond_slider, "Probability of Responding");
+                let mut listen_only = self.variation_controls.listen_only.load();
+                ui.checkbox(&mut listen_only, "Listen Only (record without responding)");
+                self.variation_controls.listen_only.store(listen_only);
+                let mut fixed_tempo = self.variation_controls.fixed_tempo.load();
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut fixed_tempo, "Fixed Playback Tempo");
+                    if ui.button(TAP_TEMPO).clicked() {
+                        self.midi_learn.lock().unwrap().tap();
+                        fixed_tempo = self.variation_controls.fixed_tempo.load();
+                    }
+                });
+                self.variation_controls.fixed_tempo.store(fixed_tempo);
+                let mut follow_clock = self.clock_sync.follow.load();
+                let mut send_clock = self.clock_sync.send.load();
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut follow_clock, "Follow MIDI Clock");
+                    ui.checkbox(&mut send_clock, "Send MIDI Clock");
+                });
+                self.clock_sync.follow.store(follow_clock);
+                self.clock_sync.send.store(send_clock);
+                if send_clock {
+                    self.clock_output_controls(ui);
+                }
+                let mut trade_bars = self.variation_controls.trade_bars.load();
+                ui.checkbox(&mut trade_bars, "Trade Bars with the AI");
+                self.variation_controls.trade_bars.store(trade_bars);
+                if fixed_tempo || trade_bars || send_clock {
+                    let tempo_slider = self.variation_controls.tempo_slider.clone();
+                    Self::insert_slider(ui, tempo_slider, "Playback Tempo (BPM)");
+                }
+                if trade_bars {
+                    let bars_slider = self.variation_controls.bars_per_turn_slider.clone();
+                    Self::insert_slider(ui, bars_slider, "Bars per Turn");
+                }
+                let timing_jitter_slider = self.variation_controls.timing_jitter_slider.clone();
+                Self::insert_slider(ui, timing_jitter_slider, "Humanize Timing (seconds)");
+                let velocity_jitter_slider = self.variation_controls.velocity_jitter_slider.clone();
+                Self::insert_slider(ui, velocity_jitter_slider, "Humanize Velocity");
+                let mut swing = self.variation_controls.swing.load();
+                ui.checkbox(&mut swing, "Swing");
+                self.variation_controls.swing.store(swing);
+                let mut overlap = self.variation_controls.overlap.load();
+                ui.checkbox(&mut overlap, "Overlap (keep recording during variation)");
+                self.variation_controls.overlap.store(overlap);
+            });
+
+            ui.vertical(|ui| {
+                self.midi_capture_controls(ui);
+                self.midi_learn_controls(ui);
+                self.automation_controls(ui);
+                self.settings_controls(ui);
+                self.preset_controls(ui);
+                self.snapshot_controls(ui);
+                self.session_controls(ui);
+                self.practice_controls(ui);
+                self.backing_controls(ui);
+                self.recent_response_controls(ui);
+                self.second_voice_controls(ui);
+                self.paste_controls(ui);
+                self.share_controls(ui);
+                self.diagnostics(ui);
             });
         });
 
         self.display_melody_section(ui, MAIN_MELODY_SCALING);
     }
 
+    fn diagnostics(&self, ui: &mut Ui) {
+        ui.collapsing("Diagnostics", |ui| {
+            if let Some(log_control) = &self.log_control {
+                let mut level = log_control.level();
+                ui.horizontal(|ui| {
+                    ui.label("Log Level:");
+                    for choice in all::<LogLevel>() {
+                        ui.radio_value(&mut level, choice, choice.name());
+                    }
+                });
+                if level != log_control.level() {
+                    log_control.set_level(level);
+                }
+                ui.separator();
+            }
+            let threads = running_threads();
+            ui.label(format!("{} threads running", threads.len()));
+            for (name, seconds) in threads {
+                ui.label(format!("{name}: {seconds:.1}s"));
+            }
+            ui.separator();
+            ui.label("Queue waits in ms (median / 90% / 99% / max)");
+            let stages = [
+                ("MIDI input to AI", self.input2ai.latency()),
+                ("AI to output", self.ai2output.latency()),
+                ("AI to database", self.ai2dbase.latency()),
+            ];
+            for (stage, latency) in stages.iter() {
+                match latency.summary() {
+                    Some(s) => ui.label(format!(
+                        "{stage}: {} / {} / {} / {} ({} messages)",
+                        millis(s.median),
+                        millis(s.p90),
+                        millis(s.p99),
+                        millis(s.max),
+                        s.samples
+                    )),
+                    None => ui.label(format!("{stage}: no messages yet")),
+                };
+            }
+            if ui.button("Reset Timings").clicked() {
+                for (_, latency) in stages.iter() {
+                    latency.clear();
+                }
+            }
+        });
+    }
+
+    /// The sliders that can be saved as startup defaults or bound to a controller knob.
+    fn named_sliders(
+        variation_controls: &VariationControls,
+        replay_delay_slider: &Arc<AtomicCell<SliderValue<f64>>>,
+        pedal_delay_slider: &Arc<AtomicCell<SliderValue<f64>>>,
+        stuck_note_slider: &Arc<AtomicCell<SliderValue<f64>>>,
+        mix_controls: &MixControls,
+    ) -> Vec<(&'static str, Arc<AtomicCell<SliderValue<f64>>>)> {
+        let mut sliders = Self::voice_sliders(variation_controls);
+        sliders.extend([
+            ("Replay Delay", replay_delay_slider.clone()),
+            ("Pedal Replay Delay", pedal_delay_slider.clone()),
+            ("Stuck Note Release", stuck_note_slider.clone()),
+            ("Master Volume", mix_controls.master_volume_slider.clone()),
+            ("Human Pan", mix_controls.human_pan_slider.clone()),
+            ("Variation Pan", mix_controls.variation_pan_slider.clone()),
+            ("Pressure Depth", mix_controls.pressure_depth_slider.clone()),
+        ]);
+        sliders
+    }
+
+    /// The sliders that shape how one AI voice varies and performs a melody.
+    fn voice_sliders(
+        vc: &VariationControls,
+    ) -> Vec<(&'static str, Arc<AtomicCell<SliderValue<f64>>>)> {
+        vec![
+            ("Randomization", vc.p_random_slider.clone()),
+            ("Ornamentation", vc.p_ornament_slider.clone()),
+            ("Shortest Note", vc.shortest_note_slider.clone()),
+            ("Response Probability", vc.p_respond_slider.clone()),
+            ("Ornament Beat", vc.ornament_beat_slider.clone()),
+            ("Playback Tempo", vc.tempo_slider.clone()),
+            ("Timing Jitter", vc.timing_jitter_slider.clone()),
+            ("Note Density", vc.max_density_slider.clone()),
+        ]
+    }
+
+    /// Each AI voice's settings, and the prefix of the names they are saved under.
+    fn voices(&self) -> [(&'static str, &VariationControls); 2] {
+        [
+            ("", &self.variation_controls),
+            (SECOND_VOICE_PREFIX, &self.second_voice_controls),
+        ]
+    }
+
+    fn make_midi_learn(
+        variation_controls: &VariationControls,
+        named_sliders: Vec<(&'static str, Arc<AtomicCell<SliderValue<f64>>>)>,
+    ) -> MidiLearn {
+        let mut learn = MidiLearn::new();
+        for (name, slider) in named_sliders {
+            learn.register(name, slider);
+        }
+        learn.register_tap_tempo(
+            variation_controls.tempo_slider.clone(),
+            variation_controls.fixed_tempo.clone(),
+        );
+        learn
+    }
+
+    fn midi_learn_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("MIDI Learn", |ui| {
+            let mut learn = self.midi_learn.lock().unwrap();
+            for name in learn.binding_names() {
+                ui.horizontal(|ui| {
+                    ui.label(name.as_str());
+                    ui.label(learn.binding_label(name.as_str()));
+                    if ui.button("Forget").clicked() {
+                        learn.forget(name.as_str());
+                    }
+                    if learn.is_armed(name.as_str()) {
+                        let prompt = if name == TAP_TEMPO {
+                            "Press a key... (cancel)"
+                        } else {
+                            "Move a knob... (cancel)"
+                        };
+                        if ui.button(prompt).clicked() {
+                            learn.disarm();
+                        }
+                    } else if ui.button("Learn").clicked() {
+                        learn.arm(name.as_str());
+                    }
+                });
+            }
+        });
+    }
+
+    /// Lets any named slider drift on its own, following a slow LFO or a step sequence.
+    fn automation_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Automation", |ui| {
+            let named_sliders = Self::named_sliders(
+                &self.variation_controls,
+                &self.replay_delay_slider,
+                &self.pedal_delay_slider,
+                &self.stuck_note_slider,
+                &self.mix_controls,
+            );
+            egui::ComboBox::from_label("Slider")
+                .selected_text(self.automation_slider.as_str())
+                .show_ui(ui, |ui| {
+                    for (name, _) in named_sliders.iter() {
+                        ui.selectable_value(&mut self.automation_slider, name.to_string(), *name);
+                    }
+                });
+            ui.horizontal(|ui| {
+                for shape in all::<AutomationShape>() {
+                    ui.radio_value(&mut self.automation_shape, shape, shape.name());
+                }
+            });
+            if self.automation_shape == AutomationShape::Steps {
+                ui.horizontal(|ui| {
+                    ui.label("Steps (-1 to 1):");
+                    ui.text_edit_singleline(&mut self.automation_steps);
+                });
+            }
+            ui.add(
+                egui::Slider::new(&mut self.automation_period, AUTOMATION_PERIOD_RANGE)
+                    .text("Period (seconds)"),
+            );
+            ui.add(egui::Slider::new(&mut self.automation_depth, 0.0..=1.0).text("Depth"));
+            if ui.button("Automate").clicked() {
+                self.automation_status = match parse_steps(self.automation_steps.as_str()) {
+                    Ok(steps) => self.add_automation(&named_sliders, steps),
+                    Err(e) => format!("Cannot read steps: {e}"),
+                };
+            }
+            ui.label(self.automation_status.as_str());
+            let mut automations = self.automations.lock().unwrap();
+            let mut stopped = None;
+            for (i, automation) in automations.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{}: {}, every {:.0}s",
+                        automation.slider_name,
+                        automation.shape.name(),
+                        automation.period_seconds
+                    ));
+                    if ui.button("Stop").clicked() {
+                        stopped = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = stopped {
+                automations.remove(i).restore();
+            }
+        });
+    }
+
+    fn add_automation(
+        &self,
+        named_sliders: &[(&'static str, Arc<AtomicCell<SliderValue<f64>>>)],
+        steps: Vec<f64>,
+    ) -> String {
+        let name = self.automation_slider.as_str();
+        let mut automations = self.automations.lock().unwrap();
+        if let Some(i) = automations.iter().position(|a| a.slider_name == name) {
+            automations.remove(i).restore();
+        }
+        match named_sliders.iter().find(|(n, _)| *n == name) {
+            Some((_, slider)) => {
+                automations.push(Automation::new(
+                    name,
+                    slider.clone(),
+                    self.automation_shape,
+                    self.automation_period,
+                    self.automation_depth,
+                    steps,
+                ));
+                format!("Automating {name}")
+            }
+            None => format!("No slider named {name}"),
+        }
+    }
+
+    fn current_config(&self) -> Config {
+        let mut config = self
+            .config
+            .active(self.profile.as_deref())
+            .unwrap_or_default();
+        config.midi_in_port = self.in_port_name.clone();
+        config.human_synth = Some(self.human_synth.name.clone());
+        config.variation_synth = Some(self.ai_synth.name.clone());
+        config.variation_algorithm = Some(self.ai_algorithm.name.clone());
+        config.second_voice_algorithm = Some(self.second_voice_algorithm.name.clone());
+        let named_sliders = Self::named_sliders(
+            &self.variation_controls,
+            &self.replay_delay_slider,
+            &self.pedal_delay_slider,
+            &self.stuck_note_slider,
+            &self.mix_controls,
+        );
+        for (name, slider) in named_sliders {
+            config
+                .sliders
+                .insert(name.to_owned(), slider.load().current());
+        }
+        for (name, switch) in self.named_switches() {
+            config.switches.insert(name.to_owned(), switch.load());
+        }
+        let second_voice = &self.second_voice_controls;
+        for (name, slider) in Self::voice_sliders(second_voice) {
+            config.sliders.insert(
+                format!("{SECOND_VOICE_PREFIX}{name}"),
+                slider.load().current(),
+            );
+        }
+        for (name, switch) in Self::voice_switches(second_voice) {
+            config
+                .switches
+                .insert(format!("{SECOND_VOICE_PREFIX}{name}"), switch.load());
+        }
+        for (prefix, vc) in self.voices() {
+            config.integer_sliders.insert(
+                format!("{prefix}Humanize Velocity"),
+                vc.velocity_jitter_slider.load().current() as i64,
+            );
+            config.integer_sliders.insert(
+                format!("{prefix}Bars per Turn"),
+                vc.bars_per_turn_slider.load().current() as i64,
+            );
+        }
+        config.keyboard_split = Some(self.keyboard_split.load());
+        config.harmony = Some(self.harmony.load());
+        config.pressure_route = Some(self.mix_controls.pressure_route.load());
+        config.rhythm_preservation = Some(self.variation_controls.rhythm_preservation.load());
+        config.dynamics = Some(self.variation_controls.dynamics.load());
+        config.chord_source = Some(self.variation_controls.chord_source.load());
+        config.chord_progression = Some(self.chord_text.clone());
+        config.transpose = Some(self.transpose_slider.load().current());
+        config.concert_pitch = Some(self.concert_pitch_slider.load().current());
+        config.midi_mappings = self.midi_learn.lock().unwrap().mappings();
+        config
+    }
+
+    fn apply_config(&mut self, config: &Config) {
+        if config.midi_in_port.is_some() {
+            self.requested_in_port = config.midi_in_port.clone();
+        }
+        self.ai_algorithm
+            .choose_configured(&config.variation_algorithm);
+        self.second_voice_algorithm
+            .choose_configured(&config.second_voice_algorithm);
+        self.human_synth.choose_configured(&config.human_synth);
+        self.ai_synth.choose_configured(&config.variation_synth);
+        let named_sliders = Self::named_sliders(
+            &self.variation_controls,
+            &self.replay_delay_slider,
+            &self.pedal_delay_slider,
+            &self.stuck_note_slider,
+            &self.mix_controls,
+        );
+        for (name, slider) in named_sliders {
+            if let Some(value) = config.sliders.get(name) {
+                let sv = slider.load();
+                let range = sv.make_range();
+                slider.store(sv.slid_to(value.clamp(*range.start(), *range.end())));
+            }
+        }
+        for (name, switch) in self.named_switches() {
+            if let Some(value) = config.switches.get(name) {
+                switch.store(*value);
+            }
+        }
+        let second_voice = &self.second_voice_controls;
+        for (name, slider) in Self::voice_sliders(second_voice) {
+            if let Some(value) = config.sliders.get(&format!("{SECOND_VOICE_PREFIX}{name}")) {
+                let sv = slider.load();
+                let range = sv.make_range();
+                slider.store(sv.slid_to(value.clamp(*range.start(), *range.end())));
+            }
+        }
+        for (name, switch) in Self::voice_switches(second_voice) {
+            if let Some(value) = config.switches.get(&format!("{SECOND_VOICE_PREFIX}{name}")) {
+                switch.store(*value);
+            }
+        }
+        for (prefix, vc) in self.voices() {
+            if let Some(value) = config
+                .integer_sliders
+                .get(&format!("{prefix}Humanize Velocity"))
+            {
+                let sv = vc.velocity_jitter_slider.load();
+                let range = sv.make_range();
+                let value = (*value).clamp(*range.start() as i64, *range.end() as i64);
+                vc.velocity_jitter_slider
+                    .store(sv.slid_to(value as MidiByte));
+            }
+            if let Some(value) = config
+                .integer_sliders
+                .get(&format!("{prefix}Bars per Turn"))
+            {
+                let sv = vc.bars_per_turn_slider.load();
+                let range = sv.make_range();
+                let value = (*value).clamp(*range.start() as i64, *range.end() as i64);
+                vc.bars_per_turn_slider.store(sv.slid_to(value as usize));
+            }
+        }
+        if let Some(split) = config.keyboard_split {
+            self.keyboard_split.store(split);
+        }
+        if let Some(harmony) = config.harmony {
+            self.harmony.store(harmony);
+        }
+        if let Some(route) = config.pressure_route {
+            self.mix_controls.pressure_route.store(route);
+        }
+        if let Some(rhythm) = config.rhythm_preservation {
+            self.variation_controls.rhythm_preservation.store(rhythm);
+        }
+        if let Some(dynamics) = config.dynamics {
+            self.variation_controls.dynamics.store(dynamics);
+        }
+        if let Some(source) = config.chord_source {
+            self.variation_controls.chord_source.store(source);
+        }
+        if let Some(text) = &config.chord_progression {
+            self.chord_text = text.clone();
+            self.set_chord_progression();
+        }
+        if let Some(semitones) = config.transpose {
+            let sv = self.transpose_slider.load();
+            let range = sv.make_range();
+            self.transpose_slider
+                .store(sv.slid_to(semitones.clamp(*range.start(), *range.end())));
+        }
+        if let Some(hz) = config.concert_pitch {
+            let sv = self.concert_pitch_slider.load();
+            let range = sv.make_range();
+            self.concert_pitch_slider
+                .store(sv.slid_to(hz.clamp(*range.start(), *range.end())));
+        }
+        if !config.midi_mappings.is_empty() {
+            self.midi_learn
+                .lock()
+                .unwrap()
+                .set_mappings(&config.midi_mappings);
+        }
+    }
+
+    /// The checkboxes that are saved along with the sliders.
+    fn named_switches(&self) -> Vec<(&'static str, Arc<AtomicCell<bool>>)> {
+        let mut switches = Self::voice_switches(&self.variation_controls);
+        switches.extend([
+            ("Follow MIDI Clock", self.clock_sync.follow.clone()),
+            ("MPE", self.mpe.clone()),
+        ]);
+        switches
+    }
+
+    /// The checkboxes of one AI voice.
+    fn voice_switches(vc: &VariationControls) -> Vec<(&'static str, Arc<AtomicCell<bool>>)> {
+        let ornaments = vc
+            .ornament_switches
+            .iter()
+            .map(|(ornament, switch)| (ornament.name(), switch.clone()));
+        let mut switches = vec![
+            ("Thin Dense Input", vc.thin_input.clone()),
+            ("Keep Gestures Intact", vc.preserve_gestures.clone()),
+            ("Whimsify Suffix", vc.whimsify.clone()),
+            (
+                "Tempo-Relative Ornaments",
+                vc.tempo_relative_ornaments.clone(),
+            ),
+            (
+                "Velocity-Sensitive Ornaments",
+                vc.velocity_sensitive_ornaments.clone(),
+            ),
+            ("Listen Only", vc.listen_only.clone()),
+            ("Fixed Playback Tempo", vc.fixed_tempo.clone()),
+            ("Trade Bars", vc.trade_bars.clone()),
+            ("Swing", vc.swing.clone()),
+            ("Overlap", vc.overlap.clone()),
+        ];
+        switches.extend(ornaments);
+        switches
+    }
+
+    /// Loads the preset, or chooses the human synthesizer, selected by a program change
+    /// from the keyboard, if one has arrived since the last call.
+    fn handle_program_change(&mut self) {
+        if let Some(program) = self.program_change.take() {
+            let preset = self
+                .config
+                .preset_for_program(program)
+                .filter(|_| self.presets_by_program)
+                .map(|(name, preset)| (name.clone(), preset.clone()));
+            match preset {
+                Some((name, preset)) => {
+                    self.apply_config(&preset);
+                    self.preset_status = format!("Loaded preset {name}");
+                    info!("Program change {program}: loaded preset {name}");
+                }
+                None => {
+                    let names = self.human_synth.table.lock().unwrap().name_vec();
+                    match names.get(program as usize) {
+                        Some(name) => {
+                            self.human_synth.name = name.clone();
+                            info!("Program change {program}: {name}");
+                        }
+                        None => {
+                            warn!("Program change {program} ignored: no such synthesizer");
+                            return;
+                        }
+                    }
+                }
+            }
+            self.send_program_changes();
+        }
+    }
+
+    /// Rebuilds the synth table and swaps it in for the output thread, whose voices already
+    /// sounding carry on. Each speaker keeps its synthesizer if it is still in the table,
+    /// and is sent its program number again, in case that has moved.
+    fn reload_patches(&mut self) {
+        let table = make_synth_table();
+        *self.synth_list.lock().unwrap() = table.choice_vec();
+        info!("Reloaded {} synthesizers", table.name_vec().len());
+        self.human_synth.replace_table(make_synth_table());
+        self.ai_synth.replace_table(table);
+        self.send_program_changes();
+    }
+
+    fn send_program_changes(&self) {
+        for (synth, speaker) in [
+            (&self.human_synth, HUMAN_SPEAKER),
+            (&self.ai_synth, VARIATION_SPEAKER),
+        ] {
+            let msg = SynthMsg::program_change(synth.current_index() as u8, speaker);
+            self.ai2output.push(msg);
+        }
+    }
+
+    fn settings_controls(&mut self, ui: &mut Ui) {
+        if ui.button("Save Settings as Startup Defaults").clicked() {
+            let current = self.current_config();
+            self.config.store(self.profile.as_deref(), current);
+            self.settings_status = match self.config.save(CONFIG_FILE) {
+                Ok(()) => match &self.profile {
+                    Some(profile) => format!("Saved profile {profile} to {CONFIG_FILE}"),
+                    None => format!("Saved to {CONFIG_FILE}"),
+                },
+                Err(e) => format!("Save failed: {e}"),
+            };
+        }
+        ui.label(self.settings_status.as_str());
+    }
+
+    /// Named setups of synthesizers, algorithm, sliders, checkboxes, keyboard split and
+    /// harmony, kept in the settings file. With program-change selection on, a MIDI program
+    /// change loads the preset with that number instead of choosing the human synthesizer.
+    /// Each preset keeps the number it was first saved with.
+    fn preset_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Presets", |ui| {
+            ui.horizontal(|ui| {
+                ui.add(TextEdit::singleline(&mut self.preset_name));
+                if ui.button("Save Preset").clicked() && !self.preset_name.is_empty() {
+                    let current = self.current_config();
+                    self.config.store_preset(self.preset_name.as_str(), current);
+                    self.preset_status = self.save_presets(self.preset_name.as_str());
+                    self.preset_name.clear();
+                }
+            });
+            let label = "Program Change Selects Preset";
+            ui.checkbox(&mut self.presets_by_program, label);
+            let presets = self.config.presets.clone();
+            for (name, preset) in presets.iter() {
+                ui.horizontal(|ui| {
+                    match preset.program {
+                        Some(program) => ui.label(format!("Program {program}: {name}")),
+                        None => ui.label(format!("No program: {name}")),
+                    };
+                    if ui.button("Load").clicked() {
+                        self.apply_config(preset);
+                        self.send_program_changes();
+                        self.preset_status = format!("Loaded preset {name}");
+                    }
+                    if ui.button("Delete").clicked() {
+                        self.config.presets.remove(name);
+                        self.preset_status = self.save_presets(name);
+                    }
+                });
+            }
+            ui.label(self.preset_status.as_str());
+        });
+    }
+
+    fn save_presets(&self, name: &str) -> String {
+        match self.config.save(CONFIG_FILE) {
+            Ok(()) => format!("Saved presets to {CONFIG_FILE}"),
+            Err(e) => format!("Could not save preset {name}: {e}"),
+        }
+    }
+
+    /// Saves the current melody, its variation and every setting under a name, so that a
+    /// good moment in a session can be brought back later.
+    fn snapshot_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Snapshots", |ui| {
+            ui.horizontal(|ui| {
+                ui.add(TextEdit::singleline(&mut self.snapshot_name));
+                let current = self.melody_var_info.lock().unwrap().get().cloned();
+                if let Some((melody_info, variation_info, _)) = current {
+                    if ui.button("Capture Snapshot").clicked() {
+                        match self.current_config().to_toml() {
+                            Ok(settings) => {
+                                self.gui2dbase.push(GuiDatabaseUpdate::SaveSnapshot(
+                                    Snapshot::new(
+                                        self.snapshot_name.clone(),
+                                        melody_info.row_id(),
+                                        variation_info.row_id(),
+                                        settings,
+                                    ),
+                                ));
+                                self.snapshot_name.clear();
+                            }
+                            Err(e) => error!("Could not capture snapshot: {e}"),
+                        }
+                    }
+                }
+            });
+            let snapshots = self.snapshots.lock().unwrap().clone();
+            for snapshot in snapshots {
+                ui.horizontal(|ui| {
+                    ui.label(snapshot.name.as_str());
+                    if ui.button("Restore").clicked() {
+                        match Config::from_toml(snapshot.settings.as_str()) {
+                            Ok(config) => {
+                                self.apply_config(&config);
+                                self.send_program_changes();
+                            }
+                            Err(e) => error!("Could not restore {}: {e}", snapshot.name),
+                        }
+                        self.gui2dbase.push(GuiDatabaseUpdate::RestorePair {
+                            melody_row: snapshot.melody_row,
+                            variation_row: snapshot.variation_row,
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    /// Plays a figure from the figure library for the player to play back. While practicing,
+    /// each phrase is scored against the figure instead of answered, and the scores are kept
+    /// in the database.
+    fn practice_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Practice", |ui| {
+            let target = self.practice_target.lock().unwrap().clone();
+            let mut practicing = target.is_some();
+            ui.checkbox(
+                &mut practicing,
+                "Practice Mode (score attempts instead of responding)",
+            );
+            match (practicing, target) {
+                (true, None) => self.new_practice_target(),
+                (false, Some(_)) => *self.practice_target.lock().unwrap() = None,
+                (true, Some(target)) => {
+                    ui.label(format!("Target: {}", target.name()));
+                    ui.horizontal(|ui| {
+                        if ui.button("Play Target").clicked() {
+                            self.play_melody_thread(
+                                target.melody().clone(),
+                                VARIATION_SPEAKER,
+                                Humanize::none(),
+                            );
+                        }
+                        if ui.button("New Target").clicked() {
+                            self.new_practice_target();
+                        }
+                    });
+                }
+                (false, None) => {}
+            }
+            let attempts = self.practice_attempts.lock().unwrap().clone();
+            if let Some(last) = attempts.first() {
+                ui.label(format!(
+                    "Last attempt: {:.0}% on {}",
+                    last.score * 100.0,
+                    last.figure
+                ));
+            }
+            for figure in progress(&attempts) {
+                ui.label(format!(
+                    "{}: {} attempts, best {:.0}%, recently {:.0}%",
+                    figure.figure,
+                    figure.attempts,
+                    figure.best * 100.0,
+                    figure.recent * 100.0
+                ));
+            }
+        });
+    }
+
+    fn new_practice_target(&self) {
+        let target = PracticeTarget::random(&mut rand::thread_rng());
+        self.play_melody_thread(target.melody().clone(), VARIATION_SPEAKER, Humanize::none());
+        *self.practice_target.lock().unwrap() = Some(target);
+    }
+
+    /// Sent MIDI clock follows the Playback Tempo slider.
+    fn clock_output_controls(&mut self, ui: &mut Ui) {
+        if self.clock_ports.is_empty() {
+            self.clock_ports = output_port_names();
+        }
+        let mut chosen = self.clock_port.clone();
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("Clock Output")
+                .selected_text(chosen.as_deref().unwrap_or("None"))
+                .show_ui(ui, |ui| {
+                    for name in self.clock_ports.iter() {
+                        ui.selectable_value(&mut chosen, Some(name.clone()), name.as_str());
+                    }
+                });
+            if ui.button("Rescan Ports").clicked() {
+                self.clock_ports = output_port_names();
+            }
+        });
+        if chosen != self.clock_port {
+            if let Some(name) = chosen.as_deref() {
+                self.clock_status = match clock_output(name) {
+                    Ok(output) => {
+                        self.clock_sync.set_output(Some(output));
+                        self.clock_port = chosen;
+                        String::new()
+                    }
+                    Err(e) => format!("Cannot send clock: {e}"),
+                };
+            }
+        }
+        ui.label(self.clock_status.as_str());
+    }
+
+    /// A MIDI file to play along with. While it plays, responses wait for its next bar, and
+    /// new melodies are tagged with the bar they began in.
+    fn backing_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Backing Track", |ui| {
+            ui.horizontal(|ui| {
+                ui.add(TextEdit::singleline(&mut self.backing_file).hint_text("backing.mid"));
+                if ui.button("Load").clicked() {
+                    self.backing_status = match BackingTrack::load(self.backing_file.as_str()) {
+                        Ok(track) => {
+                            self.backing.load(track);
+                            String::new()
+                        }
+                        Err(e) => format!("Cannot load backing track: {e}"),
+                    };
+                }
+            });
+            if let Some(name) = self.backing.track_name() {
+                ui.horizontal(|ui| {
+                    if self.backing.state() == TransportState::Playing {
+                        if ui.button("Pause").clicked() {
+                            self.backing.pause();
+                        }
+                    } else if ui.button("Play").clicked() {
+                        self.backing.play();
+                    }
+                    if ui.button("Stop").clicked() {
+                        self.backing.stop();
+                    }
+                });
+                match self.backing.position_before(0.0) {
+                    Some(position) => ui.label(format!("{name}: {position}")),
+                    None => ui.label(name),
+                };
+            }
+            ui.label(self.backing_status.as_str());
+        });
+    }
+
+    /// Every run of the program is a session, and a new one can be started at any time. The
+    /// notes belong to the current session; earlier sessions can be browsed pair by pair.
+    fn session_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Sessions", |ui| {
+            if ui.button("New Session").clicked() {
+                self.start_session();
+            }
+            let sessions = self.sessions.lock().unwrap().clone();
+            if let Some(current) = sessions.first() {
+                ui.label(format!(
+                    "Notes for the session begun {}",
+                    current.date_time_stamp()
+                ));
+                ui.add(TextEdit::multiline(&mut self.session_notes).desired_rows(3));
+                if ui.button("Save Notes").clicked() {
+                    self.gui2dbase.push(GuiDatabaseUpdate::SessionNotes {
+                        rowid: current.rowid,
+                        notes: self.session_notes.clone(),
+                    });
+                }
+            }
+            for session in sessions.iter() {
+                ui.horizontal(|ui| {
+                    let label = ui.label(session.date_time_stamp());
+                    if !session.notes.is_empty() {
+                        label.on_hover_text(session.notes.as_str());
+                    }
+                    if ui.button("Show Pairs").clicked() {
+                        self.gui2dbase
+                            .push(GuiDatabaseUpdate::SessionPairs(session.rowid));
+                    }
+                });
+            }
+        });
+    }
+
+    fn start_session(&mut self) {
+        match self.current_config().to_toml() {
+            Ok(settings) => {
+                self.gui2dbase
+                    .push(GuiDatabaseUpdate::NewSession { settings });
+                self.session_notes.clear();
+            }
+            Err(e) => error!("Could not start session: {e}"),
+        }
+    }
+
+    /// Another AI voice, with its own algorithm and settings, that answers each phrase
+    /// along with the main one.
+    fn second_voice_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Second AI Voice", |ui| {
+            ui.label(format!("{NO_AI_NAME} keeps this voice silent."));
+            Self::radio_choice(ui, "Algorithm", &mut self.second_voice_algorithm);
+            let p_random_slider = self.second_voice_controls.p_random_slider.clone();
+            Self::insert_slider(ui, p_random_slider, "Probability of Randomization");
+            let p_ornament_slider = self.second_voice_controls.p_ornament_slider.clone();
+            Self::insert_slider(ui, p_ornament_slider, "Probability of Inserting Ornament");
+            let mut whimsify = self.second_voice_controls.whimsify.load();
+            ui.checkbox(&mut whimsify, "Whimsify Suffix");
+            self.second_voice_controls.whimsify.store(whimsify);
+        });
+    }
+
+    /// The last few melodies that arrived with their variations, newest first, so that a
+    /// response can be heard again, replaced, or silenced after the moment has passed.
+    fn recent_response_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Recent Responses", |ui| {
+            let recent = self.recent_responses.lock().unwrap().clone();
+            if recent.is_empty() {
+                ui.label("No responses yet");
+                return;
+            }
+            self.response_back = min(self.response_back, recent.len() - 1);
+            let (melody_info, variation_info) = recent.recent(self.response_back).unwrap();
+            ui.horizontal(|ui| {
+                let older = self.response_back + 1 < recent.len();
+                if ui.add_enabled(older, Button::new("<")).clicked() {
+                    self.response_back += 1;
+                }
+                ui.label(format!(
+                    "{} ({} back)",
+                    variation_info.date_time_stamp(),
+                    self.response_back
+                ));
+                let newer = self.response_back > 0;
+                if ui.add_enabled(newer, Button::new(">")).clicked() {
+                    self.response_back -= 1;
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Replay Variation").clicked() {
+                    self.melody_run_status.send_stop();
+                    self.play_melody_thread(
+                        variation_info.melody().clone(),
+                        SynthChoice::Variation.speaker(),
+                        self.variation_controls.humanize(),
+                    );
+                }
+                if ui.button("Regenerate").clicked() {
+                    self.melody_run_status.send_stop();
+                    self.create_new_variation(melody_info);
+                    self.response_back = 0;
+                }
+                if self.melody_progress.load().is_some() && ui.button("Mute").clicked() {
+                    self.melody_run_status.send_stop();
+                }
+            });
+        });
+    }
+
+    /// Melodies copied as text with the Copy buttons can be pasted back here. Replaying one
+    /// sends it through the input queue, so it is heard and varied as if it were played live.
+    fn paste_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Paste Melody", |ui| {
+            ui.horizontal(|ui| {
+                ui.add(TextEdit::singleline(&mut self.paste_text).hint_text("C4:0.5:100 ..."));
+                if ui.button("Replay Pasted").clicked() {
+                    self.paste_status = match Melody::from_text(self.paste_text.as_str()) {
+                        Ok(melody) if melody.len() > 0 => {
+                            self.melody_run_status.send_stop();
+                            let scheduler = self.scheduler.with_output(self.input2ai.clone());
+                            let melody_progress = self.melody_progress.clone();
+                            let melody_run_status = self.melody_run_status.clone();
+                            let started =
+                                spawn_named(PLAYBACK_THREAD, MAX_PLAYBACK_THREADS, move || {
+                                    melody_run_status.wait_until_stopped();
+                                    send_recorded_melody(
+                                        &melody,
+                                        HUMAN_SPEAKER,
+                                        Instant::now(),
+                                        scheduler,
+                                        melody_progress,
+                                        melody_run_status,
+                                    );
+                                });
+                            match started {
+                                Ok(()) => String::new(),
+                                Err(e) => format!("Could not replay melody: {e}"),
+                            }
+                        }
+                        Ok(_) => "Nothing to replay".to_owned(),
+                        Err(e) => format!("Could not read melody: {e}"),
+                    };
+                }
+            });
+            ui.label(self.paste_status.as_str());
+        });
+    }
+
+    /// Shows a QR code from which a visitor can download their best exchange of the
+    /// session, served from this machine over the local network.
+    fn share_controls(&mut self, ui: &mut Ui) {
+        ui.collapsing("Share", |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Share Best Exchange").clicked() {
+                    self.share_best_exchange();
+                }
+                if self.share_qr.is_some() && ui.button("Hide QR Code").clicked() {
+                    self.share_qr = None;
+                }
+                ui.label(self.share_status.as_str());
+            });
+            if let Some((url, width, dark)) = &self.share_qr {
+                let size = (width + 2 * QR_QUIET_MODULES) as f32 * QR_MODULE_SIZE;
+                let (response, painter) =
+                    ui.allocate_painter(Vec2::new(size, size), Sense::hover());
+                painter.rect_filled(response.rect, 0.0, Color32::WHITE);
+                for (i, _) in dark.iter().enumerate().filter(|(_, d)| **d) {
+                    let (row, col) = (i / width + QR_QUIET_MODULES, i % width + QR_QUIET_MODULES);
+                    let corner =
+                        response.rect.min + Vec2::new(col as f32, row as f32) * QR_MODULE_SIZE;
+                    painter.rect_filled(
+                        Rect::from_min_size(corner, Vec2::splat(QR_MODULE_SIZE)),
+                        0.0,
+                        Color32::BLACK,
+                    );
+                }
+                ui.label(url.as_str());
+            }
+        });
+    }
+
+    fn share_best_exchange(&mut self) {
+        let pairs = self.todays_pairs();
+        let shared = match best_exchange(&pairs) {
+            Some((melody_info, variation_info, _)) => save_exchange(
+                melody_info,
+                variation_info,
+                self.concert_pitch_slider.load().current(),
+            ),
+            None => {
+                self.share_status = "No phrases recorded today".to_owned();
+                return;
+            }
+        };
+        let qr = shared.and_then(|file_name| {
+            let url = share_url(file_name.as_str());
+            let (width, dark) = qr_modules(url.as_str())?;
+            Ok((url, width, dark))
+        });
+        match qr {
+            Ok(qr) => {
+                self.share_status.clear();
+                if !self.share_server_started {
+                    match start_share_server_thread(self.shutdown.quit_threads.clone()) {
+                        Ok(()) => self.share_server_started = true,
+                        Err(e) => self.share_status = format!("Sharing unavailable: {e}"),
+                    }
+                }
+                self.share_qr = Some(qr);
+            }
+            Err(e) => self.share_status = format!("Share failed: {e}"),
+        }
+    }
+
+    fn midi_capture_controls(&mut self, ui: &mut Ui) {
+        ui.label("MIDI Debug Capture");
+        let mut recording = self.midi_capture.recording.load();
+        ui.checkbox(&mut recording, "Capture Raw MIDI");
+        self.midi_capture.recording.store(recording);
+        ui.add(TextEdit::singleline(&mut self.capture_file));
+        ui.horizontal(|ui| {
+            if ui.button("Save Capture").clicked() {
+                self.capture_status = match self.midi_capture.save(self.capture_file.as_str()) {
+                    Ok(()) => format!(
+                        "Saved {} events to {}",
+                        self.midi_capture.len(),
+                        self.capture_file
+                    ),
+                    Err(e) => format!("Save failed: {e}"),
+                };
+            }
+            if ui.button("Clear Capture").clicked() {
+                self.midi_capture.clear();
+                self.capture_status.clear();
+            }
+            if ui.button("Replay Capture").clicked() {
+                self.capture_status = match self.replay_capture(self.capture_file.as_str()) {
+                    Ok(replayed) => format!("Replaying {replayed} events"),
+                    Err(e) => format!("Replay failed: {e}"),
+                };
+            }
+        });
+        ui.label(format!(
+            "{} events captured. {}",
+            self.midi_capture.len(),
+            self.capture_status
+        ));
+    }
+
+    /// Feeds the capture saved in `filename` to the AI, returning how many events it holds.
+    fn replay_capture(&self, filename: &str) -> anyhow::Result<usize> {
+        let events = load_capture(filename)?;
+        let replayed = events.len();
+        start_replay_thread(
+            self.input2ai.clone(),
+            events,
+            self.midi_learn.clone(),
+            self.program_change.clone(),
+            self.panic_button.clone(),
+            self.clock_sync.clone(),
+            Arc::new(Mutex::new(None)),
+            self.shutdown.quit_threads.clone(),
+        )
+        .map_err(|e| {
+            if e.is::<AlreadyRunning>() {
+                anyhow!("replay already running")
+            } else {
+                e
+            }
+        })?;
+        Ok(replayed)
+    }
+
     fn display_melody_section(&mut self, ui: &mut Ui, staff_scaling: f32) {
         ui.checkbox(
             &mut self.adjust_search_preferences,
@@ -426,12 +2053,342 @@ impl ReplayerApp {
                     self.request_refresh();
                 }
             }
+            ui.checkbox(&mut self.show_chords, "Show Chords");
+            self.session_timeline(ui);
+            self.session_heatmap(ui);
+            self.piano_roll(ui);
+            self.held_keyboard(ui);
             if self.displaying_melody_var_info() {
                 self.display_melody_info(ui, staff_scaling);
             }
         }
     }
 
+    /// Shows today's phrases as alternating human and variation blocks, each as wide as
+    /// its melody is long. Clicking a block selects its pair and plays the block.
+    fn session_timeline(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_timeline, "Session Timeline");
+            if self.show_timeline {
+                ui.add(
+                    egui::Slider::new(&mut self.timeline_zoom, TIMELINE_ZOOM_RANGE)
+                        .text("Zoom (pixels per second)"),
+                );
+            }
+            if ui.button("Export HTML Report").clicked() {
+                self.export_session_report();
+            }
+            ui.label(self.report_status.as_str());
+        });
+        if !self.show_timeline {
+            return;
+        }
+
+        let melody_var_info = self.melody_var_info.clone();
+        let mut melody_var_info = melody_var_info.lock().unwrap();
+        let mut blocks = vec![];
+        let mut x = 0.0;
+        for (i, (melody_info, variation_info, _)) in melody_var_info.iter().enumerate() {
+            if Database::is_today(melody_info.timestamp()) {
+                for (info, choice) in [
+                    (melody_info, SynthChoice::Original),
+                    (variation_info, SynthChoice::Variation),
+                ] {
+                    let width = info.melody().duration() as f32 * self.timeline_zoom;
+                    blocks.push((i, choice, x, width));
+                    x += width + TIMELINE_GAP;
+                }
+            }
+        }
+        if blocks.is_empty() {
+            ui.label("No phrases recorded today");
+            return;
+        }
+
+        let mut clicked = None;
+        egui::ScrollArea::horizontal().show(ui, |ui| {
+            let (response, painter) =
+                ui.allocate_painter(Vec2::new(x, TIMELINE_HEIGHT), Sense::click());
+            let click_pos = response
+                .clicked()
+                .then(|| response.interact_pointer_pos())
+                .flatten();
+            for (i, choice, x, width) in blocks.iter().copied() {
+                let rect = Rect::from_min_size(
+                    response.rect.min + Vec2::new(x, 0.0),
+                    Vec2::new(width, TIMELINE_HEIGHT),
+                );
+                let color = match choice {
+                    SynthChoice::Original => Color32::BLACK,
+                    SynthChoice::Variation => Color32::RED,
+                };
+                painter.rect_filled(rect, 2.0, color);
+                if melody_var_info.index() == Some(i) {
+                    painter.rect_stroke(rect, 2.0, Stroke::new(2.0, Color32::GREEN));
+                }
+                if click_pos.map_or(false, |p| rect.contains(p)) {
+                    clicked = Some((i, choice));
+                }
+            }
+        });
+
+        if let Some((i, choice)) = clicked {
+            self.melody_run_status.send_stop();
+            melody_var_info.go_to(i);
+            let (melody_info, variation_info, _) = melody_var_info.get().unwrap();
+            self.melody_pref.store(melody_info.rating());
+            self.variation_pref.store(variation_info.rating());
+            self.melody_var_update_needed.store(true);
+            let info = match choice {
+                SynthChoice::Original => melody_info,
+                SynthChoice::Variation => variation_info,
+            };
+            let humanize = match choice {
+                SynthChoice::Original => Humanize::none(),
+                SynthChoice::Variation => self.variation_controls.humanize(),
+            };
+            self.play_melody_thread(info.melody().clone(), choice.speaker(), humanize);
+        }
+    }
+
+    /// Shows how long the human and the variations have spent on each pitch class in each
+    /// octave over today's most recent phrases, with the highest octave at the top. A high
+    /// overlap suggests that the variations mimic rather than diversify.
+    fn session_heatmap(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_heatmap, "Pitch Heatmap");
+            if self.show_heatmap {
+                ui.add(
+                    egui::Slider::new(&mut self.heatmap_window, HEATMAP_WINDOW_RANGE)
+                        .text("Recent Phrases"),
+                );
+            }
+        });
+        if !self.show_heatmap {
+            return;
+        }
+
+        let (human, variation) = rolling_heatmaps(&self.todays_pairs(), self.heatmap_window);
+        let octaves = match (human.octave_range(), variation.octave_range()) {
+            (Some(h), Some(v)) => min(*h.start(), *v.start())..=max(*h.end(), *v.end()),
+            (Some(range), None) | (None, Some(range)) => range,
+            (None, None) => {
+                ui.label("No phrases recorded today");
+                return;
+            }
+        };
+        ui.label(format!(
+            "Overlap: {:.0}%",
+            human.overlap(&variation) * 100.0
+        ));
+        ui.horizontal(|ui| {
+            for (label, heatmap, color) in [
+                ("Human", &human, Color32::BLACK),
+                ("Variation", &variation, Color32::RED),
+            ] {
+                ui.vertical(|ui| {
+                    ui.label(label);
+                    Self::draw_heatmap(ui, heatmap, octaves.clone(), color);
+                });
+            }
+        });
+    }
+
+    fn draw_heatmap(
+        ui: &mut Ui,
+        heatmap: &NoteHeatmap,
+        octaves: RangeInclusive<usize>,
+        color: Color32,
+    ) {
+        let rows = octaves.end() - octaves.start() + 1;
+        let size = Vec2::new(NUM_PITCH_CLASSES as f32, rows as f32) * HEATMAP_CELL_SIZE;
+        let (response, painter) = ui.allocate_painter(size, Sense::hover());
+        let most = heatmap.max();
+        for (row, octave) in octaves.rev().enumerate() {
+            for pitch_class in 0..NUM_PITCH_CLASSES {
+                let rect = Rect::from_min_size(
+                    response.rect.min
+                        + Vec2::new(pitch_class as f32, row as f32) * HEATMAP_CELL_SIZE,
+                    Vec2::splat(HEATMAP_CELL_SIZE),
+                );
+                if most > 0.0 {
+                    let intensity = heatmap.seconds(octave, pitch_class) / most;
+                    painter.rect_filled(rect, 0.0, color.linear_multiply(intensity as f32));
+                }
+                painter.rect_stroke(rect, 0.0, Stroke::new(1.0, Color32::LIGHT_GRAY));
+            }
+        }
+    }
+
+    /// Draws the current melody and its variation on one timeline, a row per pitch with the
+    /// Cs shaded and darker bars for louder notes. During playback the view scrolls to keep
+    /// the playhead, placed by the longer of the two melodies, in sight.
+    fn piano_roll(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_piano_roll, "Piano Roll");
+            if self.show_piano_roll {
+                ui.add(
+                    egui::Slider::new(&mut self.piano_roll_zoom, PIANO_ROLL_ZOOM_RANGE)
+                        .text("Zoom (pixels per second)"),
+                );
+            }
+        });
+        if !self.show_piano_roll {
+            return;
+        }
+
+        let current = self.melody_var_info.lock().unwrap().get().cloned();
+        let (melody_info, variation_info, _) = match current {
+            Some(pair) => pair,
+            None => return,
+        };
+        let melodies = [
+            (melody_info.melody(), Color32::BLACK),
+            (variation_info.melody(), Color32::RED),
+        ];
+        let pitches = melodies
+            .iter()
+            .flat_map(|(melody, _)| melody.iter())
+            .filter(|note| !note.is_rest())
+            .map(|note| note.pitch())
+            .collect::<Vec<_>>();
+        let (lowest, highest) = match (pitches.iter().min(), pitches.iter().max()) {
+            (Some(lowest), Some(highest)) => {
+                (lowest - PIANO_ROLL_MARGIN, highest + PIANO_ROLL_MARGIN)
+            }
+            _ => return,
+        };
+        let duration = melodies
+            .iter()
+            .map(|(melody, _)| melody.duration() as f32)
+            .fold(0.0, f32::max);
+        let size = Vec2::new(
+            duration * self.piano_roll_zoom,
+            (highest - lowest + 1) as f32 * PIANO_ROLL_ROW_HEIGHT,
+        );
+
+        let zoom = self.piano_roll_zoom;
+        let progress = self.melody_progress.load();
+        egui::ScrollArea::horizontal()
+            .id_source("piano_roll")
+            .show(ui, |ui| {
+                let (response, painter) = ui.allocate_painter(size, Sense::hover());
+                let origin = response.rect.min;
+                let row = |pitch: MidiByte| {
+                    Pos2::new(
+                        origin.x,
+                        origin.y + (highest - pitch) as f32 * PIANO_ROLL_ROW_HEIGHT,
+                    )
+                };
+                for pitch in lowest..=highest {
+                    if pitch % NUM_PITCH_CLASSES as MidiByte == 0 {
+                        let rect = Rect::from_min_size(
+                            row(pitch),
+                            Vec2::new(size.x, PIANO_ROLL_ROW_HEIGHT),
+                        );
+                        painter.rect_filled(rect, 0.0, Color32::from_gray(235));
+                    }
+                }
+                for (melody, color) in melodies {
+                    let mut start = 0.0;
+                    for note in melody.iter() {
+                        let length = note.duration() as f32;
+                        if !note.is_rest() {
+                            let rect = Rect::from_min_size(
+                                row(note.pitch()) + Vec2::new(start * zoom, 0.0),
+                                Vec2::new(length * zoom, PIANO_ROLL_ROW_HEIGHT),
+                            );
+                            let loudness = 0.5 + 0.5 * note.velocity() as f32 / MAX_VELOCITY;
+                            painter.rect_filled(rect, 1.0, color.linear_multiply(loudness));
+                        }
+                        start += length;
+                    }
+                }
+                if let Some(progress) = progress {
+                    let x = origin.x + progress * size.x;
+                    painter.vline(x, response.rect.y_range(), Stroke::new(2.0, Color32::GREEN));
+                    let playhead = Rect::from_x_y_ranges(x..=x, response.rect.y_range());
+                    ui.scroll_to_rect(playhead, Some(Align::Center));
+                }
+            });
+    }
+
+    /// Lights each sounding key, blue for the human and red for the variation, more
+    /// strongly for louder notes. A key lit by both is split between the two colors. A key
+    /// that stays lit after its note should have ended is a stuck note.
+    fn held_keyboard(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.show_keyboard, "Held Keys");
+        if !self.show_keyboard {
+            return;
+        }
+
+        let held = self.held_keys.load();
+        let keys = LOWEST_PIANO_KEY..=HIGHEST_PIANO_KEY;
+        let num_white = keys.clone().filter(|key| !is_black_key(*key)).count();
+        let size = Vec2::new(num_white as f32 * WHITE_KEY_WIDTH, WHITE_KEY_HEIGHT);
+        let (response, painter) = ui.allocate_painter(size, Sense::hover());
+        let mut x = response.rect.min.x;
+        let mut black_keys = vec![];
+        for key in keys {
+            if is_black_key(key) {
+                let rect = Rect::from_min_size(
+                    Pos2::new(x - BLACK_KEY_WIDTH / 2.0, response.rect.min.y),
+                    Vec2::new(BLACK_KEY_WIDTH, BLACK_KEY_HEIGHT),
+                );
+                black_keys.push((rect, key));
+            } else {
+                let rect = Rect::from_min_size(
+                    Pos2::new(x, response.rect.min.y),
+                    Vec2::new(WHITE_KEY_WIDTH, WHITE_KEY_HEIGHT),
+                );
+                Self::draw_key(&painter, rect, Color32::WHITE, &held, key);
+                painter.rect_stroke(rect, 0.0, LINE_STROKE);
+                x += WHITE_KEY_WIDTH;
+            }
+        }
+        // Black keys go on top of the white keys they overlap.
+        for (rect, key) in black_keys {
+            Self::draw_key(&painter, rect, Color32::BLACK, &held, key);
+        }
+    }
+
+    fn draw_key(painter: &Painter, rect: Rect, idle: Color32, held: &HeldKeys, key: usize) {
+        painter.rect_filled(rect, 0.0, idle);
+        let lit = |color: Color32, velocity: u8| {
+            color.linear_multiply(0.3 + 0.7 * velocity as f32 / MAX_VELOCITY)
+        };
+        let (human, variation) = (held.human[key], held.variation[key]);
+        if human > 0 && variation > 0 {
+            let middle = rect.center().y;
+            let top = Rect::from_min_max(rect.min, Pos2::new(rect.max.x, middle));
+            let bottom = Rect::from_min_max(Pos2::new(rect.min.x, middle), rect.max);
+            painter.rect_filled(top, 0.0, lit(HUMAN_KEY_COLOR, human));
+            painter.rect_filled(bottom, 0.0, lit(VARIATION_KEY_COLOR, variation));
+        } else if human > 0 {
+            painter.rect_filled(rect, 0.0, lit(HUMAN_KEY_COLOR, human));
+        } else if variation > 0 {
+            painter.rect_filled(rect, 0.0, lit(VARIATION_KEY_COLOR, variation));
+        }
+    }
+
+    fn todays_pairs(&self) -> Vec<(MelodyInfo, MelodyInfo, VariationStats)> {
+        let melody_var_info = self.melody_var_info.lock().unwrap();
+        melody_var_info
+            .iter()
+            .filter(|(melody_info, _, _)| Database::is_today(melody_info.timestamp()))
+            .cloned()
+            .collect()
+    }
+
+    fn export_session_report(&mut self) {
+        let pairs = self.todays_pairs();
+        let html = session_html(&pairs, self.concert_pitch_slider.load().current());
+        self.report_status = match std::fs::write(SESSION_REPORT_FILE, html) {
+            Ok(()) => format!("Wrote {} phrases to {SESSION_REPORT_FILE}", pairs.len()),
+            Err(e) => format!("Export failed: {e}"),
+        };
+    }
+
     fn displaying_melody_var_info(&self) -> bool {
         let melody_var_info = self.melody_var_info.lock().unwrap();
         !melody_var_info.is_empty()
@@ -483,7 +2440,7 @@ impl ReplayerApp {
         };
         if self.melody_var_update_needed.load() {
             self.variation_controls.update_from(&stats);
-            self.ai_algorithm.name = stats.algorithm_name;
+            self.ai_algorithm.name = stats.algorithm_name.clone();
             self.ai_algorithm.update_choice();
             self.melody_var_update_needed.store(false);
         }
@@ -493,7 +2450,13 @@ impl ReplayerApp {
         if self.show_variation {
             self.show_pref_selector(ui, "Variation", self.variation_pref.clone());
             self.tags(ui, &variation_info, 1);
+            Self::variation_details(ui, &stats);
+        }
+        let mut measured = vec![("Melody", melody_info.melody())];
+        if self.show_variation {
+            measured.push(("Variation", variation_info.melody()));
         }
+        Self::phrase_statistics(ui, &measured);
 
         ui.horizontal(|ui| {
             self.melody_play_stop_buttons(ui, &melody_info, &variation_info);
@@ -502,13 +2465,59 @@ impl ReplayerApp {
         if ui.button("Create New Variation").clicked() {
             self.create_new_variation(&melody_info);
         }
+        ui.horizontal(|ui| {
+            if ui.button("Export MusicXML").clicked() {
+                self.export_musicxml(&melody_info, &variation_info);
+            }
+            ui.label(self.musicxml_status.as_str());
+        });
 
         let size = Vec2::new(ui.available_width(), ui.available_height() * staff_scaling);
         let mut melodies = vec![(melody_info.melody(), Color32::BLACK)];
         if self.show_variation {
             melodies.push((variation_info.melody(), Color32::RED));
         }
-        MelodyRenderer::render(ui, size, &melodies, self.melody_progress.clone());
+        MelodyRenderer::render(
+            ui,
+            size,
+            &melodies,
+            self.melody_progress.clone(),
+            self.show_chords,
+        );
+    }
+
+    fn export_musicxml(&mut self, melody_info: &MelodyInfo, variation_info: &MelodyInfo) {
+        let filename = format!("melody-{}.musicxml", melody_info.row_id());
+        let xml = melody_info.melody().to_musicxml(variation_info.melody());
+        self.musicxml_status = match std::fs::write(filename.as_str(), xml) {
+            Ok(()) => format!("Wrote {filename}"),
+            Err(e) => format!("Export failed: {e}"),
+        };
+    }
+
+    fn variation_details(ui: &mut Ui, stats: &VariationStats) {
+        ui.collapsing("Variation Details", |ui| {
+            ui.label(format!(
+                "{}: randomization {:.2}, ornamentation {:.2}, shortest note {:.2}s, whimsify {}",
+                stats.algorithm_name,
+                stats.random_prob,
+                stats.ornament_prob,
+                stats.min_note_duration,
+                stats.whimsify
+            ));
+            ui.label(stats.report.to_string());
+            for (start, figure) in stats.report.figures_replaced.iter() {
+                ui.label(format!("Note {start}: {figure}"));
+            }
+        });
+    }
+
+    fn phrase_statistics(ui: &mut Ui, melodies: &[(&str, &Melody)]) {
+        ui.collapsing("Phrase Statistics", |ui| {
+            for (label, melody) in melodies.iter() {
+                ui.label(format!("{label}: {}", stats(melody)));
+            }
+        });
     }
 
     fn show_pref_selector(&mut self, ui: &mut Ui, label: &str, pref: Arc<AtomicCell<Preference>>) {
@@ -655,13 +2664,22 @@ impl ReplayerApp {
         self.melody_run_status.send_stop();
         let human_melody = melody_info.melody().clone();
         let computer_melody = variation_info.melody().clone();
-        let ai2output = self.ai2output.clone();
+        let scheduler = self.scheduler.clone();
         let melody_progress = self.melody_progress.clone();
         let melody_run_status = self.melody_run_status.clone();
-        thread::spawn(move || {
-            while melody_run_status.is_stopping() {}
-            send_two_melodies(&human_melody, &computer_melody, ai2output, melody_progress, melody_run_status);
+        let started = spawn_named(PLAYBACK_THREAD, MAX_PLAYBACK_THREADS, move || {
+            melody_run_status.wait_until_stopped();
+            send_two_melodies(
+                &human_melody,
+                &computer_melody,
+                scheduler,
+                melody_progress,
+                melody_run_status,
+            );
         });
+        if let Err(e) = started {
+            warn!("Not played: {e}");
+        }
     }
 
     fn font_id(size: f32) -> FontId {
@@ -675,24 +2693,37 @@ impl ReplayerApp {
         let text = format!("Play {synth:?}");
         if ui.button(text).clicked() {
             self.melody_run_status.send_stop();
-            self.play_melody_thread(info.melody().clone(), synth.speaker());
+            let humanize = match synth {
+                SynthChoice::Original => Humanize::none(),
+                SynthChoice::Variation => self.variation_controls.humanize(),
+            };
+            self.play_melody_thread(info.melody().clone(), synth.speaker(), humanize);
+        }
+        if ui.button("Copy").clicked() {
+            let text = info.melody().to_text();
+            ui.ctx().output_mut(|o| o.copied_text = text);
         }
     }
 
-    fn play_melody_thread(&self, melody: Melody, speaker: Speaker) {
-        let ai2output = self.ai2output.clone();
+    fn play_melody_thread(&self, melody: Melody, speaker: Speaker, humanize: Humanize) {
+        let melody = melody.humanized(&humanize, &mut rand::thread_rng());
+        let scheduler = self.scheduler.clone();
         let melody_progress = self.melody_progress.clone();
         let melody_run_status = self.melody_run_status.clone();
-        thread::spawn(move || {
-            while melody_run_status.is_stopping() {}
+        let started = spawn_named(PLAYBACK_THREAD, MAX_PLAYBACK_THREADS, move || {
+            melody_run_status.wait_until_stopped();
             send_recorded_melody(
                 &melody,
                 speaker,
-                ai2output,
+                Instant::now(),
+                scheduler,
                 melody_progress,
                 melody_run_status,
             );
         });
+        if let Err(e) = started {
+            warn!("Not played: {e}");
+        }
     }
 
     fn preference_buttons(ui: &mut Ui, pref: Arc<AtomicCell<Preference>>) {
@@ -705,6 +2736,68 @@ impl ReplayerApp {
         pref.store(current_value);
     }
 
+    fn phrase_segmentation_buttons(&self, ui: &mut Ui) {
+        let mut current_value = self.phrase_segmentation.load();
+        ui.horizontal(|ui| {
+            ui.label("Phrase End:");
+            for strategy in all::<PhraseSegmentation>() {
+                ui.radio_value(&mut current_value, strategy, strategy.name());
+            }
+        });
+        self.phrase_segmentation.store(current_value);
+        let mut mpe = self.mpe.load();
+        ui.checkbox(&mut mpe, "MPE (record every member channel as one melody)");
+        self.mpe.store(mpe);
+        self.keyboard_split_controls(ui);
+    }
+
+    fn harmony_buttons(&self, ui: &mut Ui) {
+        let mut current_value = self.harmony.load();
+        ui.horizontal(|ui| {
+            ui.label("Harmonize:");
+            for interval in all::<HarmonyInterval>() {
+                ui.radio_value(&mut current_value, interval, interval.name());
+            }
+        });
+        self.harmony.store(current_value);
+    }
+
+    /// The A4 that every synthesizer is tuned to, for playing along with instruments
+    /// tuned away from 440 Hz.
+    fn concert_pitch_controls(&self, ui: &mut Ui) {
+        let concert_pitch_slider = self.concert_pitch_slider.clone();
+        Self::insert_slider(ui, concert_pitch_slider, "Concert Pitch (A4 in Hz)");
+        ui.horizontal(|ui| {
+            ui.label("Tune A4 to:");
+            for hz in COMMON_CONCERT_PITCHES {
+                if ui.button(format!("{hz} Hz")).clicked() {
+                    let sv = self.concert_pitch_slider.load();
+                    self.concert_pitch_slider.store(sv.slid_to(hz));
+                }
+            }
+        });
+    }
+
+    fn keyboard_split_controls(&self, ui: &mut Ui) {
+        let mut split = self.keyboard_split.load();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut split.enabled, "Split Keyboard at");
+            if split.enabled {
+                let range = LOWEST_PIANO_KEY as u8..=HIGHEST_PIANO_KEY as u8;
+                ui.add(egui::Slider::new(&mut split.point, range).show_value(false));
+                ui.label(pitch::name(split.point));
+            }
+        });
+        if split.enabled {
+            ui.horizontal(|ui| {
+                ui.label("Respond to:");
+                ui.checkbox(&mut split.bass_responds, "Bass");
+                ui.checkbox(&mut split.treble_responds, "Treble");
+            });
+        }
+        self.keyboard_split.store(split);
+    }
+
     fn radio_choice<T: Clone>(ui: &mut Ui, header: &str, info: &mut TableInfo<T>) {
         ui.vertical(|ui| {
             let table = info.table.lock().unwrap();
@@ -716,6 +2809,40 @@ impl ReplayerApp {
         info.update_choice();
     }
 
+    fn mix_sliders(&self, ui: &mut Ui) {
+        let master_volume_slider = self.mix_controls.master_volume_slider.clone();
+        Self::insert_slider(ui, master_volume_slider, "Master Volume");
+        self.output_meter(ui);
+        let human_pan_slider = self.mix_controls.human_pan_slider.clone();
+        Self::insert_slider(ui, human_pan_slider, "Human Pan (left to right)");
+        let variation_pan_slider = self.mix_controls.variation_pan_slider.clone();
+        Self::insert_slider(ui, variation_pan_slider, "Variation Pan (left to right)");
+        let mut route = self.mix_controls.pressure_route.load();
+        ui.horizontal(|ui| {
+            ui.label("Aftertouch:");
+            for choice in all::<PressureRoute>() {
+                ui.radio_value(&mut route, choice, choice.name());
+            }
+        });
+        self.mix_controls.pressure_route.store(route);
+        if route != PressureRoute::Off {
+            let pressure_depth_slider = self.mix_controls.pressure_depth_slider.clone();
+            Self::insert_slider(ui, pressure_depth_slider, "Aftertouch Depth");
+        }
+    }
+
+    /// How loud the notes now sounding are, against the level at which the limiter starts
+    /// holding new notes back.
+    fn output_meter(&self, ui: &mut Ui) {
+        let level = self.output_level.load();
+        let fill = (level.rms / LIMITER_THRESHOLD).min(1.0) as f32;
+        ui.add(egui::ProgressBar::new(fill).text(format!(
+            "Level {:.1} of {LIMITER_THRESHOLD:.1}, loudest note {:.0}%",
+            level.rms,
+            level.peak * 100.0
+        )));
+    }
+
     fn insert_slider<N: FromStr + Numeric + Display>(
         ui: &mut Ui,
         slider: Arc<AtomicCell<SliderValue<N>>>,
@@ -724,8 +2851,14 @@ impl ReplayerApp {
         let sv = slider.load();
         let mut value = sv.current();
         let range = sv.make_range();
-        ui.add(egui::Slider::new(&mut value, range).text(text));
-        slider.store(sv.slid_to(value));
+        // Stored only when moved, so that the GUI never overwrites what another thread,
+        // such as automation or MIDI learn, has just written.
+        if ui
+            .add(egui::Slider::new(&mut value, range).text(text))
+            .changed()
+        {
+            slider.store(sv.slid_to(value));
+        }
     }
 
     fn startup_screen(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
@@ -734,26 +2867,62 @@ impl ReplayerApp {
         });
     }
 
-    fn startup(&mut self) {
-        start_output_thread::<NUM_OUTPUT_CHANNELS>(
-            self.ai2output.clone(),
-            {
-                let table = self.human_synth.table.lock().unwrap();
-                Arc::new(Mutex::new(table.choice_vec()))
-            },
-            self.quit_threads.clone(),
+    fn startup(&mut self) -> anyhow::Result<()> {
+        self.audio.start(
+            self.synth_list.clone(),
+            self.watchdog2output.clone(),
+            self.shutdown.quit_output.clone(),
         );
+        self.send_program_changes();
+        start_automation_thread(self.automations.clone(), self.shutdown.quit_threads.clone())?;
+        start_scheduler_thread(self.scheduler.clone(), self.shutdown.quit_threads.clone())?;
+        start_backing_thread(
+            self.backing.clone(),
+            self.scheduler.clone(),
+            self.shutdown.quit_threads.clone(),
+        )?;
+        start_clock_output_thread(
+            self.clock_sync.clone(),
+            self.scheduler.clone(),
+            self.shutdown.quit_threads.clone(),
+        )?;
+        start_watchdog_thread(
+            self.ai2output.clone(),
+            self.watchdog2output.clone(),
+            self.stuck_note_slider.clone(),
+            self.transpose_slider.clone(),
+            self.concert_pitch_slider.clone(),
+            self.mix_controls.clone(),
+            self.held_keys.clone(),
+            self.output_level.clone(),
+            self.panic_button.clone(),
+            self.melody_run_status.clone(),
+            self.shutdown.quit_threads.clone(),
+        )?;
         start_ai_thread(
             self.ai_algorithm.table.clone(),
+            vec![Personality {
+                ai_table: self.second_voice_algorithm.table.clone(),
+                variation_controls: self.second_voice_controls.clone(),
+            }],
             self.input2ai.clone(),
             self.gui2ai.clone(),
-            self.ai2output.clone(),
+            self.scheduler.clone(),
             self.ai2dbase.clone(),
             self.variation_controls.clone(),
             self.replay_delay_slider.clone(),
+            self.pedal_delay_slider.clone(),
+            self.phrase_segmentation.clone(),
+            self.mpe.clone(),
+            self.keyboard_split.clone(),
+            self.harmony.clone(),
+            self.practice_target.clone(),
+            self.backing.clone(),
+            self.clock_sync.clone(),
             self.melody_progress.clone(),
             self.melody_run_status.clone(),
-        );
+            self.shutdown.quit_threads.clone(),
+        )?;
 
         let database = self.database.take();
 
@@ -762,14 +2931,26 @@ impl ReplayerApp {
             self.gui2dbase.clone(),
             self.ai2dbase.clone(),
             database.unwrap(),
-        );
+        )?;
+        self.gui2dbase.push(GuiDatabaseUpdate::RefreshSnapshots);
+        self.gui2dbase.push(GuiDatabaseUpdate::RefreshPractice);
+        self.start_session();
+        if let Some(folder) = &self.watch_folder {
+            start_folder_watch_thread(
+                folder.clone(),
+                self.ai_algorithm.table.clone(),
+                self.variation_controls.clone(),
+                self.shutdown.quit_threads.clone(),
+            )?;
+        }
 
         self.try_midi_input();
+        Ok(())
     }
 
     fn try_midi_input(&self) {
         let mut midi_in = MidiInput::new("midir reading input");
-        let scenario = MidiScenario::new(&mut midi_in);
+        let scenario = MidiScenario::new(&mut midi_in, self.requested_in_port.as_deref());
         {
             let mut midi_scenario = self.midi_scenario.lock().unwrap();
             *midi_scenario = scenario;
@@ -819,6 +3000,13 @@ impl ReplayerApp {
     }
 
     fn start_input(&mut self, ctx: &egui::Context) {
+        self.start_ui_listening_thread(ctx);
+        if let Err(e) = self.connect_input() {
+            error!("Could not start MIDI input: {e}");
+        }
+    }
+
+    fn connect_input(&mut self) -> anyhow::Result<()> {
         let in_port = self.in_port.as_ref().unwrap().clone();
         self.set_in_port_name(&in_port);
         let midi_in = {
@@ -826,14 +3014,46 @@ impl ReplayerApp {
             self_midi_in.take()
         };
 
-        self.start_ui_listening_thread(ctx);
-
         start_input_thread(
             self.input2ai.clone(),
-            midi_in.unwrap(),
-            self.in_port.as_ref().unwrap().clone(),
-            self.quit_threads.clone(),
+            Box::new(MidirSource::new(midi_in.unwrap(), in_port)),
+            self.midi_capture.clone(),
+            self.midi_learn.clone(),
+            self.program_change.clone(),
+            self.panic_button.clone(),
+            self.clock_sync.clone(),
+            Arc::new(Mutex::new(None)),
+            self.input_connected.clone(),
+            self.shutdown.quit_threads.clone(),
+        )
+    }
+
+    fn run_headless(&mut self) -> anyhow::Result<()> {
+        let scenario = self.midi_scenario.lock().unwrap().clone();
+        let in_port = match scenario {
+            MidiScenario::InputPortSelected { in_port } => in_port,
+            MidiScenario::MultipleInputPorts { in_ports } => {
+                let midi_in = self.midi_in.lock().unwrap();
+                let midi_in = midi_in.as_ref().unwrap();
+                user_pick_element(in_ports.iter().cloned(), |in_port| {
+                    midi_in.port_name(in_port).unwrap_or_default()
+                })
+            }
+            MidiScenario::NoInputPorts(msg) => bail!(msg),
+            MidiScenario::StartingUp => bail!("MIDI input was never initialized"),
+        };
+        self.in_port = Some(in_port);
+        self.connect_input()?;
+        println!(
+            "Replayer running headless on {}; press Ctrl-C to quit",
+            self.in_port_name.as_deref().unwrap_or("unknown port")
         );
+        // The Ctrl-C handler shuts everything down, then ends the process.
+        while !self.shutdown.quit_output.load() {
+            self.handle_program_change();
+            thread::sleep(Duration::from_millis(HEADLESS_POLL_MILLIS));
+        }
+        Ok(())
     }
 
     fn start_ui_listening_thread(&self, ctx: &egui::Context) {
@@ -844,21 +3064,49 @@ impl ReplayerApp {
         let melody_var_info = self.melody_var_info.clone();
         let melody_progress = self.melody_progress.clone();
         let update_needed = self.melody_var_update_needed.clone();
-        thread::spawn(move || loop {
-            if let Some(msg) = dbase2gui.pop() {
-                Self::handle_database_msg(
-                    msg,
-                    melody_pref.clone(),
-                    variation_pref.clone(),
-                    melody_var_info.clone(),
-                );
-                update_needed.store(true);
-                ctx.request_repaint();
-            }
-            if let Some(_) = melody_progress.load() {
-                ctx.request_repaint();
+        let snapshots = self.snapshots.clone();
+        let sessions = self.sessions.clone();
+        let practice_attempts = self.practice_attempts.clone();
+        let recent_responses = self.recent_responses.clone();
+        let held_keys = self.held_keys.clone();
+        let backing = self.backing.clone();
+        let program_change = self.program_change.clone();
+        let quit = self.shutdown.quit_threads.clone();
+        // Some screens call this on every frame; only the first call starts the thread.
+        let _ = spawn_named(GUI_LISTENER_THREAD, SINGLETON, move || {
+            let mut last_keys = HeldKeys::NONE;
+            while !quit.load() {
+                if let Some(msg) = dbase2gui.pop() {
+                    Self::handle_database_msg(
+                        msg,
+                        melody_pref.clone(),
+                        variation_pref.clone(),
+                        melody_var_info.clone(),
+                        snapshots.clone(),
+                        sessions.clone(),
+                        practice_attempts.clone(),
+                        recent_responses.clone(),
+                    );
+                    update_needed.store(true);
+                    ctx.request_repaint();
+                }
+                if let Some(_) = melody_progress.load() {
+                    ctx.request_repaint();
+                }
+                if backing.state() == TransportState::Playing {
+                    ctx.request_repaint();
+                }
+                // Program changes are handled as the screen is drawn.
+                if program_change.load().is_some() {
+                    ctx.request_repaint();
+                }
+                let keys = held_keys.load();
+                if keys != last_keys {
+                    last_keys = keys;
+                    ctx.request_repaint();
+                }
+                thread::sleep(Duration::from_millis(25));
             }
-            thread::sleep(Duration::from_millis(25));
         });
     }
 
@@ -867,6 +3115,10 @@ impl ReplayerApp {
         melody_pref: Arc<AtomicCell<Preference>>,
         variation_pref: Arc<AtomicCell<Preference>>,
         melody_var_info: Arc<Mutex<VecTracker<(MelodyInfo, MelodyInfo, VariationStats)>>>,
+        snapshots: Arc<Mutex<Vec<Snapshot>>>,
+        sessions: Arc<Mutex<Vec<Session>>>,
+        practice_attempts: Arc<Mutex<Vec<PracticeAttempt>>>,
+        recent_responses: Arc<Mutex<RingBuffer<(MelodyInfo, MelodyInfo)>>>,
     ) {
         match msg {
             DatabaseGuiUpdate::Info {
@@ -876,6 +3128,10 @@ impl ReplayerApp {
             } => {
                 melody_pref.store(player_info.rating());
                 variation_pref.store(variation_info.rating());
+                recent_responses
+                    .lock()
+                    .unwrap()
+                    .push((player_info.clone(), variation_info.clone()));
                 let mut melody_var_info = melody_var_info.lock().unwrap();
                 melody_var_info.add((player_info, variation_info, stats));
             }
@@ -886,6 +3142,9 @@ impl ReplayerApp {
                     variation_pref.store(v.rating());
                 }
             }
+            DatabaseGuiUpdate::OlderPairs(pairs) => {
+                melody_var_info.lock().unwrap().prepend(pairs);
+            }
             DatabaseGuiUpdate::Melodies(melodies) => {
                 let mut melody_var_info = melody_var_info.lock().unwrap();
                 let stats = melody_var_info.get().map_or(
@@ -901,6 +3160,15 @@ impl ReplayerApp {
                     melody_pref.store(m.rating());
                 }
             }
+            DatabaseGuiUpdate::Snapshots(saved) => {
+                *snapshots.lock().unwrap() = saved;
+            }
+            DatabaseGuiUpdate::Sessions(all) => {
+                *sessions.lock().unwrap() = all;
+            }
+            DatabaseGuiUpdate::PracticeAttempts(all) => {
+                *practice_attempts.lock().unwrap() = all;
+            }
         }
     }
 
@@ -934,6 +3202,14 @@ impl ReplayerApp {
     }
 }
 
+fn is_black_key(key: usize) -> bool {
+    BLACK_PITCH_CLASSES.contains(&(key % NUM_PITCH_CLASSES))
+}
+
+fn millis(wait: Duration) -> String {
+    format!("{:.1}", wait.as_secs_f64() * 1000.0)
+}
+
 /// Musical symbols are a very tricky issue. Here are resources I've used:
 /// * Font: [Bravura](https://github.com/steinbergmedia/bravura)
 /// * [Unicode for a few symbols](https://www.compart.com/en/unicode/block/U+2600)
@@ -977,6 +3253,7 @@ impl MelodyRenderer {
         size: Vec2,
         melodies: &Vec<(&Melody, Color32)>,
         melody_progress: Arc<AtomicCell<Option<f32>>>,
+        show_chords: bool,
     ) {
         if melodies.len() > 0 {
             let (response, painter) = ui.allocate_painter(size, Sense::hover());
@@ -1006,6 +3283,11 @@ impl MelodyRenderer {
             for (melody, color) in melodies.iter().rev() {
                 renderer.draw_melody(&painter, melody, *color);
             }
+            if show_chords {
+                for (row, (melody, color)) in melodies.iter().enumerate() {
+                    renderer.draw_chords(&painter, melody, *color, row);
+                }
+            }
         }
     }
 
@@ -1043,6 +3325,31 @@ impl MelodyRenderer {
         }
     }
 
+    fn draw_chords(&self, painter: &Painter, melody: &Melody, color: Color32, row: usize) {
+        let chords = melody.implied_chords();
+        let progress = self.melody_progress.load();
+        let size = CHORD_SIZE_MULTIPLIER * self.y_per_pitch;
+        let y = *self.y_range.start() + size * (row as f32 + 0.5);
+        let duration = melody.duration() as f32;
+        for (i, (start, chord)) in chords.iter().enumerate() {
+            let start = *start as f32 / duration;
+            let end = chords
+                .get(i + 1)
+                .map_or(1.0, |(next, _)| *next as f32 / duration);
+            let current = progress.map_or(false, |p| start <= p && p < end);
+            painter.text(
+                Pos2 {
+                    x: self.note_offset_x() + self.total_note_x() * start,
+                    y,
+                },
+                Align2::LEFT_CENTER,
+                chord.name(&self.scale),
+                ReplayerApp::font_id(size),
+                if current { Color32::GREEN } else { color },
+            );
+        }
+    }
+
     fn draw_staff(&self, painter: &Painter, clef: Clef, start_y: f32) {
         let mut y = start_y;
         clef.render(painter, self.min_x(), y, self.y_per_pitch);
@@ -1138,6 +3445,11 @@ impl eframe::App for ReplayerApp {
             }
         }
     }
+
+    fn on_close_event(&mut self) -> bool {
+        self.shutdown.run();
+        true
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -1192,3 +3504,57 @@ impl Clef {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_settings() {
+        let config = Config::from_toml(
+            "midi_in_port = \"Home Keyboard\"
+
+[profiles.venue]
+midi_in_port = \"Venue Keyboard\"
+",
+        )
+        .unwrap();
+        let ports = ["Home Keyboard", "Venue Keyboard", "Spare Keyboard"]
+            .map(String::from)
+            .to_vec();
+        let chosen = |command_line: &[&str]| {
+            let args =
+                Args::parse_from(std::iter::once("replayer_gui").chain(command_line.to_vec()));
+            let settings = settings_from(&config, &args).unwrap();
+            find_port(&ports, settings.midi_in_port.as_deref())
+        };
+        assert_eq!(chosen(&[]), Some(0));
+        assert_eq!(chosen(&["--profile", "venue"]), Some(1));
+        assert_eq!(
+            chosen(&["--profile", "venue", "--in-port", "Spare Keyboard"]),
+            Some(2)
+        );
+        assert_eq!(chosen(&["--in-port", "Unplugged Keyboard"]), None);
+
+        let args = Args::parse_from(["replayer_gui", "--out-device", "USB Audio CODEC"]);
+        assert_eq!(
+            settings_from(&config, &args).unwrap().audio_device,
+            Some("USB Audio CODEC".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_replace_table() {
+        let table = |names: &[&str]| {
+            ChooserTable::from(&names.iter().map(|n| (n.to_string(), 0)).collect())
+        };
+        let mut info = TableInfo::new(table(&["Organ", "Strings", "Piano"]));
+        info.name = "Piano".to_owned();
+        info.update_choice();
+        info.replace_table(table(&["Piano", "Organ"]));
+        assert_eq!((info.name.as_str(), info.current_index()), ("Piano", 0));
+        assert_eq!(info.index.load(), 0);
+        info.replace_table(table(&["Organ", "Strings"]));
+        assert_eq!((info.name.as_str(), info.current_index()), ("Organ", 0));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::ai_variation::KeyboardSplit;
+use crate::analyzer::{ChordSource, DynamicShaping, MidiByte, RhythmPreservation};
+use crate::dynamics::PressureRoute;
+use crate::harmonizer::HarmonyInterval;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::BTreeMap;
+use std::fs;
+use tracing::warn;
+
+pub const CONFIG_FILE: &str = "replayer.toml";
+const MAX_PROGRAM: u8 = 127;
+
+/// Startup defaults for the replayer, so that the same devices, synthesizers and slider
+/// settings need not be chosen again on every launch. Anything left out of the file keeps
+/// the program's built-in default. Named `profiles` override these defaults for a
+/// particular venue, such as a classroom with a different keyboard and database, while
+/// named `presets` capture whole performance setups to switch between while playing.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub midi_in_port: Option<String>,
+    /// The name of the sound card to play on.
+    pub audio_device: Option<String>,
+    pub human_synth: Option<String>,
+    pub variation_synth: Option<String>,
+    pub variation_algorithm: Option<String>,
+    pub second_voice_algorithm: Option<String>,
+    /// A SQLite file, or `melody_store::MEMORY_DATABASE` to keep nothing between runs.
+    pub database_path: Option<String>,
+    pub watch_folder: Option<String>,
+    pub sliders: BTreeMap<String, f64>,
+    /// Sliders that only take whole numbers, such as the bars in each traded turn.
+    pub integer_sliders: BTreeMap<String, i64>,
+    /// Checkbox settings, by the names the GUI gives them.
+    pub switches: BTreeMap<String, bool>,
+    pub keyboard_split: Option<KeyboardSplit>,
+    pub harmony: Option<HarmonyInterval>,
+    /// Where aftertouch is sent.
+    pub pressure_route: Option<PressureRoute>,
+    pub rhythm_preservation: Option<RhythmPreservation>,
+    pub dynamics: Option<DynamicShaping>,
+    pub chord_source: Option<ChordSource>,
+    /// Chord symbols, as `analyzer::parse_progression` reads them.
+    pub chord_progression: Option<String>,
+    /// Semitones by which everything sent to the synthesizers is shifted.
+    pub transpose: Option<MidiByte>,
+    /// The frequency of A4, in Hz.
+    pub concert_pitch: Option<f64>,
+    /// The controller learned for each slider, and the key learned for tap tempo, as
+    /// `midi_learn::MidiLearn::mappings` gives them.
+    pub midi_mappings: BTreeMap<String, u8>,
+    /// The MIDI program change that loads this preset. Only presets have one.
+    pub program: Option<u8>,
+    pub profiles: BTreeMap<String, Config>,
+    /// Shared by every profile, and kept only at the top level of the file.
+    pub presets: BTreeMap<String, Config>,
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Returns these settings, overridden by anything given in the profile called `name`.
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<Self> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or(anyhow!("No profile named {name}"))?;
+        let mut sliders = self.sliders.clone();
+        sliders.extend(profile.sliders.clone());
+        let mut integer_sliders = self.integer_sliders.clone();
+        integer_sliders.extend(profile.integer_sliders.clone());
+        let mut switches = self.switches.clone();
+        switches.extend(profile.switches.clone());
+        let mut midi_mappings = self.midi_mappings.clone();
+        midi_mappings.extend(profile.midi_mappings.clone());
+        Ok(Config {
+            midi_in_port: profile.midi_in_port.clone().or(self.midi_in_port.clone()),
+            audio_device: profile.audio_device.clone().or(self.audio_device.clone()),
+            human_synth: profile.human_synth.clone().or(self.human_synth.clone()),
+            variation_synth: profile
+                .variation_synth
+                .clone()
+                .or(self.variation_synth.clone()),
+            variation_algorithm: profile
+                .variation_algorithm
+                .clone()
+                .or(self.variation_algorithm.clone()),
+            second_voice_algorithm: profile
+                .second_voice_algorithm
+                .clone()
+                .or(self.second_voice_algorithm.clone()),
+            database_path: profile.database_path.clone().or(self.database_path.clone()),
+            watch_folder: profile.watch_folder.clone().or(self.watch_folder.clone()),
+            sliders,
+            integer_sliders,
+            switches,
+            keyboard_split: profile.keyboard_split.or(self.keyboard_split),
+            harmony: profile.harmony.or(self.harmony),
+            pressure_route: profile.pressure_route.or(self.pressure_route),
+            rhythm_preservation: profile.rhythm_preservation.or(self.rhythm_preservation),
+            dynamics: profile.dynamics.or(self.dynamics),
+            chord_source: profile.chord_source.or(self.chord_source),
+            chord_progression: profile
+                .chord_progression
+                .clone()
+                .or(self.chord_progression.clone()),
+            transpose: profile.transpose.or(self.transpose),
+            concert_pitch: profile.concert_pitch.or(self.concert_pitch),
+            midi_mappings,
+            program: None,
+            profiles: BTreeMap::new(),
+            presets: self.presets.clone(),
+        })
+    }
+
+    /// Returns the settings in effect: those of `profile` if one is given, or the defaults.
+    pub fn active(&self, profile: Option<&str>) -> anyhow::Result<Self> {
+        match profile {
+            Some(name) => self.with_profile(name),
+            None => Ok(Config {
+                profiles: BTreeMap::new(),
+                ..self.clone()
+            }),
+        }
+    }
+
+    /// Keeps `settings` as profile `profile`, or as the defaults if no profile is given.
+    pub fn store(&mut self, profile: Option<&str>, settings: Config) {
+        match profile {
+            Some(name) => {
+                self.profiles
+                    .insert(name.to_owned(), settings.without_collections());
+            }
+            None => {
+                let profiles = std::mem::take(&mut self.profiles);
+                let presets = std::mem::take(&mut self.presets);
+                *self = Config {
+                    profiles,
+                    presets,
+                    ..settings
+                };
+            }
+        }
+    }
+
+    /// Keeps `settings` as the preset called `name`, replacing any preset of that name.
+    /// A replaced preset keeps its program number, and a new one takes the lowest number
+    /// no other preset has, so that adding presets never renumbers the others.
+    pub fn store_preset(&mut self, name: &str, settings: Config) {
+        let program = self
+            .presets
+            .get(name)
+            .and_then(|preset| preset.program)
+            .or_else(|| (0..=MAX_PROGRAM).find(|p| self.preset_for_program(*p).is_none()));
+        self.presets.insert(
+            name.to_owned(),
+            Config {
+                program,
+                ..settings.without_collections()
+            },
+        );
+    }
+
+    /// The preset chosen by MIDI program change `program`.
+    pub fn preset_for_program(&self, program: u8) -> Option<(&String, &Config)> {
+        self.presets
+            .iter()
+            .find(|(_, preset)| preset.program == Some(program))
+    }
+
+    fn without_collections(self) -> Self {
+        Config {
+            profiles: BTreeMap::new(),
+            presets: BTreeMap::new(),
+            ..self
+        }
+    }
+
+    pub fn load(filename: &str) -> anyhow::Result<Self> {
+        Self::from_toml(fs::read_to_string(filename)?.as_str())
+    }
+
+    pub fn load_or_default(filename: &str) -> Self {
+        Self::load(filename).unwrap_or_else(|e| {
+            warn!("Using default settings; could not load {filename}: {e}");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, filename: &str) -> anyhow::Result<()> {
+        fs::write(filename, self.to_toml()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_toml() {
+        let config = Config {
+            midi_in_port: Some("Keystation 49".to_owned()),
+            audio_device: Some("USB Audio CODEC".to_owned()),
+            human_synth: Some("Saw Pulse".to_owned()),
+            sliders: BTreeMap::from([("Replay Delay".to_owned(), 2.5)]),
+            midi_mappings: BTreeMap::from([
+                ("Randomization".to_owned(), 7),
+                ("Tap Tempo".to_owned(), 36),
+            ]),
+            ..Config::default()
+        };
+        let text = config.to_toml().unwrap();
+        assert_eq!(Config::from_toml(text.as_str()).unwrap(), config);
+
+        let partial = Config::from_toml("database_path = \"other.db\"").unwrap();
+        assert_eq!(partial.database_path, Some("other.db".to_owned()));
+        assert!(partial.midi_in_port.is_none());
+        assert!(partial.sliders.is_empty());
+        assert!(partial.midi_mappings.is_empty());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let text = "human_synth = \"Saw Pulse\"
+database_path = \"home.db\"
+
+[sliders]
+\"Replay Delay\" = 2.5
+Randomization = 0.3
+
+[profiles.classroom]
+database_path = \"classroom.db\"
+
+[profiles.classroom.sliders]
+Randomization = 0.8
+";
+        let mut config = Config::from_toml(text).unwrap();
+        let classroom = config.with_profile("classroom").unwrap();
+        assert_eq!(classroom.database_path, Some("classroom.db".to_owned()));
+        assert_eq!(classroom.human_synth, Some("Saw Pulse".to_owned()));
+        assert_eq!(classroom.sliders.get("Replay Delay"), Some(&2.5));
+        assert_eq!(classroom.sliders.get("Randomization"), Some(&0.8));
+        assert!(config.with_profile("gallery").is_err());
+        assert_eq!(
+            config.active(None).unwrap().database_path,
+            Some("home.db".to_owned())
+        );
+
+        config.store(Some("gallery"), Config::default());
+        assert!(config.with_profile("gallery").is_ok());
+        config.store(None, classroom);
+        assert_eq!(config.database_path, Some("classroom.db".to_owned()));
+        assert_eq!(config.profiles.len(), 2);
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut config = Config::default();
+        let ballad = Config {
+            variation_algorithm: Some("Wanderer".to_owned()),
+            second_voice_algorithm: Some("Rhythm Only".to_owned()),
+            sliders: BTreeMap::from([("Second Voice Randomization".to_owned(), 0.4)]),
+            integer_sliders: BTreeMap::from([
+                ("Humanize Velocity".to_owned(), 12),
+                ("Second Voice Bars per Turn".to_owned(), 2),
+            ]),
+            switches: BTreeMap::from([("Swing".to_owned(), true)]),
+            harmony: Some(HarmonyInterval::Sixth),
+            pressure_route: Some(PressureRoute::Vibrato),
+            rhythm_preservation: Some(RhythmPreservation::Proportional),
+            dynamics: Some(DynamicShaping::Echo),
+            chord_source: Some(ChordSource::Progression),
+            chord_progression: Some("C Am | F G".to_owned()),
+            keyboard_split: Some(KeyboardSplit {
+                enabled: true,
+                ..KeyboardSplit::default()
+            }),
+            presets: BTreeMap::from([("nested".to_owned(), Config::default())]),
+            ..Config::default()
+        };
+        config.store_preset("ballad", ballad.clone());
+        config.store_preset("anthem", Config::default());
+        let text = config.to_toml().unwrap();
+        let mut config = Config::from_toml(text.as_str()).unwrap();
+        let (name, preset) = config.preset_for_program(0).unwrap();
+        assert_eq!(name, "ballad");
+        assert!(preset.presets.is_empty());
+        assert_eq!(preset.harmony, ballad.harmony);
+        assert_eq!(preset.pressure_route, ballad.pressure_route);
+        assert_eq!(preset.rhythm_preservation, ballad.rhythm_preservation);
+        assert_eq!(preset.dynamics, ballad.dynamics);
+        assert_eq!(preset.chord_source, ballad.chord_source);
+        assert_eq!(preset.chord_progression, ballad.chord_progression);
+        assert_eq!(preset.keyboard_split, ballad.keyboard_split);
+        assert_eq!(preset.switches.get("Swing"), Some(&true));
+        assert_eq!(preset.second_voice_algorithm, ballad.second_voice_algorithm);
+        assert_eq!(preset.sliders, ballad.sliders);
+        assert_eq!(preset.integer_sliders, ballad.integer_sliders);
+        assert!(config.preset_for_program(2).is_none());
+
+        // Numbers stay put as presets come and go, whatever their names.
+        config.store_preset("aria", Config::default());
+        config.store_preset("ballad", Config::default());
+        assert_eq!(config.preset_for_program(0).unwrap().0, "ballad");
+        assert_eq!(config.preset_for_program(2).unwrap().0, "aria");
+        config.presets.remove("aria");
+        config.store_preset("chorale", Config::default());
+        assert_eq!(config.preset_for_program(2).unwrap().0, "chorale");
+        config.presets.remove("chorale");
+
+        let mut with_profile = config.clone();
+        with_profile.store(Some("gallery"), config.active(None).unwrap());
+        assert!(with_profile.profiles["gallery"].presets.is_empty());
+        assert_eq!(
+            with_profile.with_profile("gallery").unwrap().presets.len(),
+            2
+        );
+    }
+}
@@ -1,23 +1,97 @@
-use crate::analyzer::{Melody, MidiByte, Note};
+use crate::analyzer::{Melody, MidiByte, Note, VariationReport};
+use crate::backing::BarPosition;
+use crate::melody_store::MelodyStore;
+use crate::midi_file::melody_to_smf;
+use crate::queue::BlockingQueue;
+use crate::threads::{spawn_named, DATABASE_THREAD, SINGLETON};
 use anyhow::bail;
 use chrono::{Local, NaiveDate, NaiveTime, TimeZone, Utc};
 use crossbeam_queue::SegQueue;
-use enum_iterator::Sequence;
+use enum_iterator::{all, Sequence};
+use ordered_float::OrderedFloat;
+use serde::Serialize;
 use sqlite::{Connection, State};
 use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
 use std::str::FromStr;
 use std::{
     fmt::{Display, Formatter},
     sync::Arc,
+    time::Duration,
 };
+use tracing::info;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct VariationStats {
     pub algorithm_name: String,
     pub random_prob: f64,
     pub ornament_prob: f64,
     pub min_note_duration: f64,
     pub whimsify: bool,
+    pub report: VariationReport,
+}
+
+/// A named moment in a session: the melody and variation being played, along with the
+/// settings that produced the variation, serialized as a `Config`.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub name: String,
+    pub timestamp: i64,
+    pub melody_row: i64,
+    pub variation_row: i64,
+    pub settings: String,
+}
+
+impl Snapshot {
+    pub fn new(name: String, melody_row: i64, variation_row: i64, settings: String) -> Self {
+        Snapshot {
+            name,
+            timestamp: Local::now().timestamp(),
+            melody_row,
+            variation_row,
+            settings,
+        }
+    }
+}
+
+/// Every pair recorded from program start until exit, or until the player starts a new
+/// session from the GUI, along with the settings in effect when it began, serialized as a
+/// `Config`, and the player's own notes about it.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub rowid: i64,
+    pub start: i64,
+    /// `None` while the session is still being recorded.
+    pub end: Option<i64>,
+    pub settings: String,
+    pub notes: String,
+}
+
+/// One try at playing back a practice figure, scored from 0.0 to 1.0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PracticeAttempt {
+    pub timestamp: i64,
+    pub figure: String,
+    pub score: f64,
+}
+
+impl PracticeAttempt {
+    pub fn new(figure: String, score: f64) -> Self {
+        PracticeAttempt {
+            timestamp: Local::now().timestamp(),
+            figure,
+            score,
+        }
+    }
+}
+
+impl Session {
+    pub fn date_time_stamp(&self) -> String {
+        let start = Local.timestamp_opt(self.start, 0).unwrap();
+        format!("{:?} {:?}", start.date_naive(), start.time())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -33,6 +107,17 @@ pub enum FromAiMsg {
         variation: Melody,
         stats: VariationStats,
     },
+    /// A new melody answered by more than one AI voice.
+    MelodyVariations {
+        melody: Melody,
+        variations: Vec<(Melody, VariationStats)>,
+    },
+    /// The player's attempt at a practice figure.
+    Practice(PracticeAttempt),
+    /// Where the backing track was when the new melody in the next message began.
+    BarPosition(BarPosition),
+    /// The last message the AI thread sends before quitting.
+    Shutdown,
 }
 
 #[derive(Clone, Debug)]
@@ -54,6 +139,21 @@ pub enum GuiDatabaseUpdate {
         min_today_pref: Preference,
         min_older_pref: Preference,
     },
+    SaveSnapshot(Snapshot),
+    RefreshSnapshots,
+    NewSession {
+        settings: String,
+    },
+    SessionNotes {
+        rowid: i64,
+        notes: String,
+    },
+    SessionPairs(i64),
+    RestorePair {
+        melody_row: i64,
+        variation_row: i64,
+    },
+    RefreshPractice,
 }
 
 #[derive(Clone, Debug)]
@@ -64,87 +164,243 @@ pub enum DatabaseGuiUpdate {
         stats: VariationStats,
     },
     AllPairs(Vec<(MelodyInfo, MelodyInfo, VariationStats)>),
+    /// Pairs older than all of those sent so far, loaded in the background after `AllPairs`.
+    OlderPairs(Vec<(MelodyInfo, MelodyInfo, VariationStats)>),
     Melodies(Vec<MelodyInfo>),
+    Snapshots(Vec<Snapshot>),
+    /// Every session, most recent (and current) first.
+    Sessions(Vec<Session>),
+    /// Every practice attempt, most recent first.
+    PracticeAttempts(Vec<PracticeAttempt>),
+}
+
+const GUI_POLL_MILLIS: u64 = 10;
+/// How many pairs are loaded at a time when refreshing the browser.
+pub const PAIR_PAGE_SIZE: usize = 100;
+
+/// The row ids of pairs still to be loaded, so that a large library can be sent to the
+/// browser a page at a time, newest first.
+pub struct PairPager {
+    remaining: Vec<(i64, i64)>,
+}
+
+impl PairPager {
+    pub fn new(ids: Vec<(i64, i64)>) -> Self {
+        PairPager { remaining: ids }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// The newest `page_size` of the remaining ids, oldest first.
+    pub fn next_page(&mut self, page_size: usize) -> Vec<(i64, i64)> {
+        let start = self.remaining.len().saturating_sub(page_size);
+        self.remaining.split_off(start)
+    }
 }
 
 pub fn start_database_thread(
     dbase2gui: Arc<SegQueue<DatabaseGuiUpdate>>,
     gui2dbase: Arc<SegQueue<GuiDatabaseUpdate>>,
-    ai2dbase: Arc<SegQueue<FromAiMsg>>,
-    mut database: Database,
-) {
-    std::thread::spawn(move || loop {
-        if let Some(info) = gui2dbase.pop() {
-            match info {
-                GuiDatabaseUpdate::VariationsOf(rowid) => {
-                    let pairs = database.get_single_melody_variations(rowid).unwrap();
-                    dbase2gui.push(DatabaseGuiUpdate::AllPairs(pairs));
-                }
-                GuiDatabaseUpdate::Info { rowid, rating } => {
-                    database.update_info(rowid, rating).unwrap();
-                }
-                GuiDatabaseUpdate::NewTag { rowid, tag } => {
-                    database.add_tag_for(rowid, tag).unwrap();
+    ai2dbase: Arc<BlockingQueue<FromAiMsg>>,
+    mut database: Box<dyn MelodyStore>,
+) -> anyhow::Result<()> {
+    spawn_named(DATABASE_THREAD, SINGLETON, move || {
+        // Everything queued before the AI thread's shutdown is still written.
+        let mut ai_finished = false;
+        // Older pairs still to be sent after a refresh, one page between other requests.
+        let mut pager: Option<PairPager> = None;
+        let mut session = None;
+        let mut bar_position: Option<BarPosition> = None;
+        while !(ai_finished && gui2dbase.is_empty()) {
+            if let Some(info) = gui2dbase.pop() {
+                match info {
+                    GuiDatabaseUpdate::VariationsOf(rowid) => {
+                        pager = None;
+                        let pairs = database.get_single_melody_variations(rowid).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::AllPairs(pairs));
+                    }
+                    GuiDatabaseUpdate::Info { rowid, rating } => {
+                        database.update_info(rowid, rating).unwrap();
+                    }
+                    GuiDatabaseUpdate::NewTag { rowid, tag } => {
+                        database.add_tag_for(rowid, tag).unwrap();
+                    }
+                    GuiDatabaseUpdate::RefreshAllPairs {
+                        min_today_pref,
+                        min_older_pref,
+                    } => {
+                        let ids = database
+                            .melody_pair_ids(min_today_pref, min_older_pref)
+                            .unwrap();
+                        let mut refresh = PairPager::new(ids);
+                        let newest = refresh.next_page(PAIR_PAGE_SIZE);
+                        let pairs = database.pairs_for(&newest).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::AllPairs(pairs));
+                        pager = Some(refresh);
+                    }
+                    GuiDatabaseUpdate::RefreshAllMelodies {
+                        min_today_pref,
+                        min_older_pref,
+                    } => {
+                        pager = None;
+                        let melodies = database
+                            .get_melodies_only(min_today_pref, min_older_pref)
+                            .unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::Melodies(melodies));
+                    }
+                    GuiDatabaseUpdate::SaveSnapshot(snapshot) => {
+                        database.add_snapshot(&snapshot).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::Snapshots(database.snapshots().unwrap()));
+                    }
+                    GuiDatabaseUpdate::RefreshSnapshots => {
+                        dbase2gui.push(DatabaseGuiUpdate::Snapshots(database.snapshots().unwrap()));
+                    }
+                    GuiDatabaseUpdate::NewSession { settings } => {
+                        session = Some(database.start_session(settings.as_str()).unwrap());
+                        dbase2gui.push(DatabaseGuiUpdate::Sessions(database.sessions().unwrap()));
+                    }
+                    GuiDatabaseUpdate::SessionNotes { rowid, notes } => {
+                        database.set_session_notes(rowid, notes.as_str()).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::Sessions(database.sessions().unwrap()));
+                    }
+                    GuiDatabaseUpdate::SessionPairs(rowid) => {
+                        pager = None;
+                        let pairs = database.session_pairs(rowid).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::AllPairs(pairs));
+                    }
+                    GuiDatabaseUpdate::RestorePair {
+                        melody_row,
+                        variation_row,
+                    } => {
+                        let (melody, variation, stats) =
+                            database.pair_for(melody_row, variation_row).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::Info {
+                            melody,
+                            variation,
+                            stats,
+                        });
+                    }
+                    GuiDatabaseUpdate::RefreshPractice => {
+                        dbase2gui.push(DatabaseGuiUpdate::PracticeAttempts(
+                            database.practice_attempts().unwrap(),
+                        ));
+                    }
                 }
-                GuiDatabaseUpdate::RefreshAllPairs {
-                    min_today_pref,
-                    min_older_pref,
-                } => {
-                    let pairs = database
-                        .get_melody_pairs(min_today_pref, min_older_pref)
-                        .unwrap();
-                    dbase2gui.push(DatabaseGuiUpdate::AllPairs(pairs));
-                }
-                GuiDatabaseUpdate::RefreshAllMelodies {
-                    min_today_pref,
-                    min_older_pref,
-                } => {
-                    let melodies = database
-                        .get_melodies_only(min_today_pref, min_older_pref)
-                        .unwrap();
-                    dbase2gui.push(DatabaseGuiUpdate::Melodies(melodies));
+            }
+
+            if gui2dbase.is_empty() {
+                if let Some(refresh) = pager.as_mut() {
+                    let page = refresh.next_page(PAIR_PAGE_SIZE);
+                    let pairs = database.pairs_for(&page).unwrap();
+                    dbase2gui.push(DatabaseGuiUpdate::OlderPairs(pairs));
+                    if refresh.is_finished() {
+                        pager = None;
+                    }
                 }
             }
-        }
 
-        if let Some(msg) = ai2dbase.pop() {
-            match msg {
-                FromAiMsg::MelodyOnly(_) => todo!("Not implemented yet"),
-                FromAiMsg::MelodyVariation {
-                    melody,
-                    variation,
-                    stats,
-                } => {
-                    let info = database
-                        .add_melody_and_variation(&melody, &variation, &stats)
-                        .unwrap();
-                    dbase2gui.push(DatabaseGuiUpdate::Info {
-                        melody: info.0,
-                        variation: info.1,
+            // Waits for the AI thread only while there are no GUI requests or pages to handle.
+            let ai_msg = if gui2dbase.is_empty() && pager.is_none() {
+                ai2dbase.pop_timeout(Duration::from_millis(GUI_POLL_MILLIS))
+            } else {
+                ai2dbase.pop()
+            };
+            if let Some(msg) = ai_msg {
+                match msg {
+                    FromAiMsg::MelodyOnly(melody) => {
+                        let info = database.store_melody(&melody).unwrap();
+                        tag_bar(database.as_mut(), info.row_id(), bar_position.take());
+                    }
+                    FromAiMsg::MelodyVariation {
+                        melody,
+                        variation,
                         stats,
-                    });
-                }
-                FromAiMsg::AlternateVariation {
-                    melody_id,
-                    variation,
-                    stats,
-                } => {
-                    let variation_info = database
-                        .add_variation(melody_id, &variation, &stats)
-                        .unwrap();
-                    let melody_info = database.melody_and_info_for(melody_id).unwrap();
-                    dbase2gui.push(DatabaseGuiUpdate::Info {
-                        melody: melody_info,
-                        variation: variation_info,
+                    } => {
+                        let info = database
+                            .add_melody_and_variation(&melody, &variation, &stats)
+                            .unwrap();
+                        tag_bar(database.as_mut(), info.0.row_id(), bar_position.take());
+                        dbase2gui.push(DatabaseGuiUpdate::Info {
+                            melody: info.0,
+                            variation: info.1,
+                            stats,
+                        });
+                    }
+                    FromAiMsg::AlternateVariation {
+                        melody_id,
+                        variation,
                         stats,
-                    });
+                    } => {
+                        let variation_info = database
+                            .add_variation(melody_id, &variation, &stats)
+                            .unwrap();
+                        let melody_info = database.melody_and_info_for(melody_id).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::Info {
+                            melody: melody_info,
+                            variation: variation_info,
+                            stats,
+                        });
+                    }
+                    FromAiMsg::MelodyVariations { melody, variations } => {
+                        let melody_info = database.store_melody(&melody).unwrap();
+                        tag_bar(database.as_mut(), melody_info.row_id(), bar_position.take());
+                        for (variation, stats) in variations {
+                            let variation_info = database
+                                .add_variation(melody_info.row_id(), &variation, &stats)
+                                .unwrap();
+                            dbase2gui.push(DatabaseGuiUpdate::Info {
+                                melody: melody_info.clone(),
+                                variation: variation_info,
+                                stats,
+                            });
+                        }
+                    }
+                    FromAiMsg::Practice(attempt) => {
+                        database.add_practice_attempt(&attempt).unwrap();
+                        dbase2gui.push(DatabaseGuiUpdate::PracticeAttempts(
+                            database.practice_attempts().unwrap(),
+                        ));
+                    }
+                    FromAiMsg::BarPosition(position) => bar_position = Some(position),
+                    FromAiMsg::Shutdown => ai_finished = true,
                 }
             }
         }
-    });
+        if let Some(rowid) = session {
+            database.end_session(rowid).unwrap();
+        }
+        info!("Database closed");
+    })
+}
+
+fn tag_bar(database: &mut dyn MelodyStore, rowid: i64, position: Option<BarPosition>) {
+    if let Some(position) = position {
+        database.add_tag_for(rowid, position.tag()).unwrap();
+    }
 }
 
 const DATABASE_FILENAME: &str = "taggable_variations.db";
+/// Within an exported bundle, the index of every melody and the directory of their MIDI files.
+pub const BUNDLE_INDEX: &str = "melodies.json";
+pub const BUNDLE_MIDI_DIR: &str = "midi";
+
+/// Identical melodies have identical notes, durations compared bit for bit.
+type NoteKey = Vec<(MidiByte, u64, MidiByte)>;
+
+/// One melody in an exported bundle. Variations name their original and how they were made.
+#[derive(Serialize)]
+struct BundleEntry {
+    rowid: i64,
+    timestamp: i64,
+    rating: String,
+    tags: BTreeSet<String>,
+    midi_file: String,
+    original_row: Option<i64>,
+    stats: Option<VariationStats>,
+    melody: Melody,
+}
 
 #[derive(Clone, Debug)]
 pub struct Database {
@@ -161,12 +417,21 @@ impl Database {
         connection.execute("CREATE TABLE IF NOT EXISTS tags (melody_row INTEGER, tag TEXT);")?;
         connection.execute("CREATE TABLE IF NOT EXISTS melodies (melody_row INTEGER, pitch INTEGER, duration FLOAT, velocity INTEGER);")?;
         connection.execute("CREATE TABLE IF NOT EXISTS variation_info (variation_row INTEGER, original_row INTEGER, algorithm_name TEXT, random_prob FLOAT, ornament_prob FLOAT, min_note_duration FLOAT, whimsify INTEGER);")?;
+        connection.execute("CREATE TABLE IF NOT EXISTS variation_reports (variation_row INTEGER, pitches_changed TEXT, durations_changed TEXT, figures_replaced TEXT, ornament_notes INTEGER);")?;
+        connection.execute("CREATE TABLE IF NOT EXISTS snapshots (name TEXT, timestamp INTEGER, melody_row INTEGER, variation_row INTEGER, settings TEXT);")?;
+        connection.execute("CREATE TABLE IF NOT EXISTS sessions (start INTEGER, finish INTEGER, settings TEXT, notes TEXT);")?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS practice (timestamp INTEGER, figure TEXT, score FLOAT);",
+        )?;
 
         connection
             .execute("CREATE INDEX IF NOT EXISTS original_rows ON variation_info (original_row)")?;
         connection.execute(
             "CREATE INDEX IF NOT EXISTS variation_rows ON variation_info (variation_row)",
         )?;
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS report_rows ON variation_reports (variation_row)",
+        )?;
         connection.execute("CREATE INDEX IF NOT EXISTS melodies_rows ON melodies (melody_row)")?;
         connection.execute("CREATE INDEX IF NOT EXISTS timestamps ON melody_index (timestamp)")?;
         connection.execute("CREATE INDEX IF NOT EXISTS ratings ON melody_index (rating)")?;
@@ -189,19 +454,13 @@ impl Database {
         Ok(result)
     }
 
-    pub fn add_tag_for(&self, melody_id: i64, tag: String) -> anyhow::Result<()> {
-        let connection = self.get_connection()?;
-        let mut statement =
-            connection.prepare("INSERT INTO tags (melody_row, tag) VALUES (?, ?)")?;
-        statement.bind((1, melody_id))?;
-        statement.bind((2, tag.as_str()))?;
-        statement.next()?;
-        Ok(())
+    pub fn new() -> Self {
+        Self::open(DATABASE_FILENAME)
     }
 
-    pub fn new() -> Self {
+    pub fn open(filename: &str) -> Self {
         Database {
-            filename: DATABASE_FILENAME.to_string(),
+            filename: filename.to_string(),
             melody_cache: BTreeMap::new(),
         }
     }
@@ -219,54 +478,30 @@ impl Database {
         min_today_pref: Preference,
         min_older_pref: Preference,
     ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
-        let mut result = vec![];
-        let connection = self.get_connection()?;
-        let melody_variation_ids =
-            Self::get_variation_info_ids(&connection, min_today_pref, min_older_pref)?;
-        for (original_id, variation_id) in melody_variation_ids {
-            let original = self.melody(&connection, original_id)?;
-            let variation = self.melody(&connection, variation_id)?;
-            let variation_info = Self::info_for(&connection, variation_id, variation)?;
-            let original_info = Self::info_for(&connection, original_id, original)?;
-            let stats = self.stats(&connection, variation_id)?;
-            result.push((original_info, variation_info, stats));
-        }
-        Ok(result)
+        let ids = self.melody_pair_ids(min_today_pref, min_older_pref)?;
+        self.pairs_for(&ids)
     }
 
-    pub fn get_single_melody_variations(
+    /// Returns the `k` recorded melodies with variations whose interval sequences are
+    /// closest to `melody`'s, closest first, each with its `Melody::interval_distance`.
+    pub fn find_similar(
         &mut self,
-        melody_id: i64,
-    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        melody: &Melody,
+        k: usize,
+    ) -> anyhow::Result<Vec<(f64, MelodyInfo)>> {
         let connection = self.get_connection()?;
-        let original = self.melody(&connection, melody_id)?;
-        let original_info = Self::info_for(&connection, melody_id, original)?;
-        let cmd = "SELECT variation_row FROM variation_info WHERE original_row = ?";
-        let mut statement = connection.prepare(cmd)?;
-        statement.bind((1, melody_id))?;
-        let mut result = vec![];
-        while let State::Row = statement.next()? {
-            let variation_id = statement.read::<i64, usize>(0)?;
-            let variation = self.melody(&connection, variation_id)?;
-            let variation_info = Self::info_for(&connection, variation_id, variation)?;
-            let stats = self.stats(&connection, variation_id)?;
-            result.push((original_info.clone(), variation_info, stats));
+        let melody_ids = Self::get_melody_ids(&connection, Preference::Ignore, Preference::Ignore)?;
+        let mut distances = vec![];
+        for melody_id in melody_ids {
+            let stored = self.melody(&connection, melody_id)?;
+            distances.push((OrderedFloat(melody.interval_distance(&stored)), melody_id));
         }
-        Ok(result)
-    }
-
-    pub fn get_melodies_only(
-        &mut self,
-        min_today_pref: Preference,
-        min_older_pref: Preference,
-    ) -> anyhow::Result<Vec<MelodyInfo>> {
+        distances.sort();
         let mut result = vec![];
-        let connection = self.get_connection()?;
-        let melody_ids = Self::get_melody_ids(&connection, min_today_pref, min_older_pref)?;
-        for melody_id in melody_ids {
-            let melody = self.melody(&connection, melody_id)?;
-            let melody_info = Self::info_for(&connection, melody_id, melody)?;
-            result.push(melody_info);
+        for (distance, melody_id) in distances.iter().take(k) {
+            let stored = self.melody(&connection, *melody_id)?;
+            let info = Self::info_for(&connection, *melody_id, stored)?;
+            result.push((distance.into_inner(), info));
         }
         Ok(result)
     }
@@ -338,12 +573,6 @@ impl Database {
         Ok(())
     }
 
-    pub fn melody_and_info_for(&mut self, rowid: i64) -> anyhow::Result<MelodyInfo> {
-        let connection = self.get_connection()?;
-        let melody = self.melody(&connection, rowid)?;
-        Self::info_for(&connection, rowid, melody)
-    }
-
     fn info_for(connection: &Connection, rowid: i64, melody: Melody) -> anyhow::Result<MelodyInfo> {
         let mut statement = connection
             .prepare("SELECT rowid, timestamp, rating FROM melody_index WHERE rowid = ?")?;
@@ -398,37 +627,477 @@ impl Database {
             let ornament_prob = statement.read::<f64, usize>(2)?;
             let min_note_duration = statement.read::<f64, usize>(3)?;
             let whimsify = statement.read::<i64, usize>(4)? != 0;
+            let report = Self::report_for(connection, variation_id)?;
             Ok(VariationStats {
                 algorithm_name,
                 random_prob,
                 ornament_prob,
                 min_note_duration,
                 whimsify,
+                report,
             })
         } else {
             bail!("{variation_id} not in database.")
         }
     }
 
-    pub fn update_info(&mut self, rowid: i64, rating: Preference) -> anyhow::Result<()> {
+    /// Variations stored before reports were recorded yield an empty report.
+    fn report_for(connection: &Connection, variation_id: i64) -> anyhow::Result<VariationReport> {
+        let mut statement = connection.prepare("SELECT pitches_changed, durations_changed, figures_replaced, ornament_notes FROM variation_reports WHERE variation_row = ?")?;
+        statement.bind((1, variation_id))?;
+        if let State::Row = statement.next()? {
+            Ok(VariationReport {
+                pitches_changed: parse_index_list(statement.read::<String, usize>(0)?.as_str()),
+                durations_changed: parse_index_list(statement.read::<String, usize>(1)?.as_str()),
+                figures_replaced: parse_figure_list(statement.read::<String, usize>(2)?.as_str()),
+                ornament_notes: statement.read::<i64, usize>(3)? as usize,
+            })
+        } else {
+            Ok(VariationReport::default())
+        }
+    }
+
+    fn store_report(
+        connection: &Connection,
+        variation_id: i64,
+        report: &VariationReport,
+    ) -> anyhow::Result<()> {
+        let mut statement = connection
+            .prepare("INSERT INTO variation_reports (variation_row, pitches_changed, durations_changed, figures_replaced, ornament_notes) VALUES (?,?,?,?,?)")?;
+        statement.bind((1, variation_id))?;
+        statement.bind((2, index_list(&report.pitches_changed).as_str()))?;
+        statement.bind((3, index_list(&report.durations_changed).as_str()))?;
+        statement.bind((4, figure_list(&report.figures_replaced).as_str()))?;
+        statement.bind((5, report.ornament_notes as i64))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn all_melody_rows(connection: &Connection) -> anyhow::Result<Vec<i64>> {
+        let mut statement = connection.prepare("SELECT rowid FROM melody_index ORDER BY rowid")?;
+        let mut result = vec![];
+        while let State::Row = statement.next()? {
+            result.push(statement.read::<i64, usize>(0)?);
+        }
+        Ok(result)
+    }
+
+    /// Maps each variation's row id to that of its original.
+    fn variation_originals(connection: &Connection) -> anyhow::Result<BTreeMap<i64, i64>> {
+        let mut statement =
+            connection.prepare("SELECT variation_row, original_row FROM variation_info")?;
+        let mut result = BTreeMap::new();
+        while let State::Row = statement.next()? {
+            result.insert(
+                statement.read::<i64, usize>(0)?,
+                statement.read::<i64, usize>(1)?,
+            );
+        }
+        Ok(result)
+    }
+
+    fn rating_for(connection: &Connection, rowid: i64) -> anyhow::Result<Preference> {
+        let mut statement =
+            connection.prepare("SELECT rating FROM melody_index WHERE rowid = ?")?;
+        statement.bind((1, rowid))?;
+        if let State::Row = statement.next()? {
+            statement.read::<String, usize>(0)?.parse::<Preference>()
+        } else {
+            bail!("{rowid} not in database.")
+        }
+    }
+
+    /// Removes a melody with its notes and tags, its record as a variation, and any
+    /// snapshot that shows it.
+    fn delete_melody(&mut self, connection: &Connection, rowid: i64) -> anyhow::Result<()> {
+        for cmd in [
+            "DELETE FROM melody_index WHERE rowid = ?",
+            "DELETE FROM melodies WHERE melody_row = ?",
+            "DELETE FROM tags WHERE melody_row = ?",
+            "DELETE FROM variation_info WHERE variation_row = ?1 OR original_row = ?1",
+            "DELETE FROM variation_reports WHERE variation_row = ?",
+            "DELETE FROM snapshots WHERE melody_row = ?1 OR variation_row = ?1",
+        ] {
+            let mut statement = connection.prepare(cmd)?;
+            statement.bind((1, rowid))?;
+            statement.next()?;
+        }
+        self.melody_cache.remove(&rowid);
+        Ok(())
+    }
+
+    /// Deletes every melody rated below `min_pref`, returning how many were deleted. A
+    /// human melody is kept as long as any of its variations is, so that the pair
+    /// survives; once it goes, so do its variations.
+    pub fn prune(&mut self, min_pref: Preference) -> anyhow::Result<usize> {
+        let connection = self.get_connection()?;
+        let originals = Self::variation_originals(&connection)?;
+        let cmd = format!(
+            "SELECT rowid FROM melody_index WHERE NOT ({})",
+            min_pref.sql_choice_str()
+        );
+        let mut statement = connection.prepare(cmd)?;
+        let mut failing = BTreeSet::new();
+        while let State::Row = statement.next()? {
+            failing.insert(statement.read::<i64, usize>(0)?);
+        }
+        let kept_originals = originals
+            .iter()
+            .filter(|(variation, _)| !failing.contains(*variation))
+            .map(|(_, original)| *original)
+            .collect::<BTreeSet<_>>();
+        let mut doomed = failing
+            .difference(&kept_originals)
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for (variation, original) in originals.iter() {
+            if doomed.contains(original) {
+                doomed.insert(*variation);
+            }
+        }
+        connection.execute("BEGIN")?;
+        for rowid in doomed.iter() {
+            self.delete_melody(&connection, *rowid)?;
+        }
+        connection.execute("COMMIT")?;
+        Ok(doomed.len())
+    }
+
+    /// Merges identical human melodies, and then identical variations of the same
+    /// original, returning how many copies were removed. The oldest copy is kept; it
+    /// takes the best rating of the group, every tag, and the others' variations.
+    pub fn deduplicate(&mut self) -> anyhow::Result<usize> {
         let connection = self.get_connection()?;
+        connection.execute("BEGIN")?;
+        let mut removed = 0;
+        // Originals go first, so that variations of merged originals become siblings.
+        for merging_originals in [true, false] {
+            let originals = Self::variation_originals(&connection)?;
+            let mut groups: BTreeMap<(Option<i64>, NoteKey), Vec<i64>> = BTreeMap::new();
+            for rowid in Self::all_melody_rows(&connection)? {
+                let original = originals.get(&rowid).copied();
+                if original.is_none() == merging_originals {
+                    let key = self
+                        .melody(&connection, rowid)?
+                        .iter()
+                        .map(|n| (n.pitch(), n.duration().to_bits(), n.velocity()))
+                        .collect();
+                    groups.entry((original, key)).or_default().push(rowid);
+                }
+            }
+            for rows in groups.values().filter(|rows| rows.len() > 1) {
+                self.merge_melodies(&connection, rows[0], &rows[1..])?;
+                removed += rows.len() - 1;
+            }
+        }
+        connection.execute("COMMIT")?;
+        Ok(removed)
+    }
+
+    fn merge_melodies(
+        &mut self,
+        connection: &Connection,
+        kept: i64,
+        copies: &[i64],
+    ) -> anyhow::Result<()> {
+        let mut ratings = vec![Self::rating_for(connection, kept)?];
+        for copy in copies.iter().copied() {
+            ratings.push(Self::rating_for(connection, copy)?);
+            for cmd in [
+                "UPDATE variation_info SET original_row = ? WHERE original_row = ?",
+                "UPDATE tags SET melody_row = ? WHERE melody_row = ?",
+                "UPDATE snapshots SET melody_row = ? WHERE melody_row = ?",
+                "UPDATE snapshots SET variation_row = ? WHERE variation_row = ?",
+            ] {
+                let mut statement = connection.prepare(cmd)?;
+                statement.bind((1, kept))?;
+                statement.bind((2, copy))?;
+                statement.next()?;
+            }
+            self.delete_melody(connection, copy)?;
+        }
+        let best = all::<Preference>().find(|p| ratings.contains(p)).unwrap();
         let mut statement =
             connection.prepare("UPDATE melody_index SET rating = ? WHERE rowid = ?")?;
-        statement.bind((1, rating.to_string().as_str()))?;
+        statement.bind((1, best.to_string().as_str()))?;
+        statement.bind((2, kept))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    /// Writes every melody to `dir` as a standard MIDI file, under `BUNDLE_MIDI_DIR`,
+    /// along with a JSON index of their notes, ratings, tags and variation settings, so
+    /// that the library can be read without this program. Returns how many were written.
+    pub fn export_bundle(&mut self, dir: &Path) -> anyhow::Result<usize> {
+        let connection = self.get_connection()?;
+        let originals = Self::variation_originals(&connection)?;
+        fs::create_dir_all(dir.join(BUNDLE_MIDI_DIR))?;
+        let mut entries = vec![];
+        for rowid in Self::all_melody_rows(&connection)? {
+            let melody = self.melody(&connection, rowid)?;
+            let info = Self::info_for(&connection, rowid, melody)?;
+            let midi_file = format!("{BUNDLE_MIDI_DIR}/{rowid}.mid");
+            fs::write(dir.join(midi_file.as_str()), melody_to_smf(info.melody())?)?;
+            let original_row = originals.get(&rowid).copied();
+            let stats = match original_row {
+                Some(_) => Some(self.stats(&connection, rowid)?),
+                None => None,
+            };
+            entries.push(BundleEntry {
+                rowid,
+                timestamp: info.timestamp,
+                rating: info.rating.to_string(),
+                tags: info.tags,
+                midi_file,
+                original_row,
+                stats,
+                melody: info.melody,
+            });
+        }
+        let index = BufWriter::new(File::create(dir.join(BUNDLE_INDEX))?);
+        serde_json::to_writer_pretty(index, &entries)?;
+        Ok(entries.len())
+    }
+}
+
+impl MelodyStore for Database {
+    fn add_tag_for(&mut self, melody_id: i64, tag: String) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement =
+            connection.prepare("INSERT INTO tags (melody_row, tag) VALUES (?, ?)")?;
+        statement.bind((1, melody_id))?;
+        statement.bind((2, tag.as_str()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn melody_pair_ids(
+        &self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<(i64, i64)>> {
+        let connection = self.get_connection()?;
+        Self::get_variation_info_ids(&connection, min_today_pref, min_older_pref)
+    }
+
+    fn pairs_for(
+        &mut self,
+        ids: &[(i64, i64)],
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let mut result = vec![];
+        let connection = self.get_connection()?;
+        for (original_id, variation_id) in ids.iter().copied() {
+            let original = self.melody(&connection, original_id)?;
+            let variation = self.melody(&connection, variation_id)?;
+            let variation_info = Self::info_for(&connection, variation_id, variation)?;
+            let original_info = Self::info_for(&connection, original_id, original)?;
+            let stats = self.stats(&connection, variation_id)?;
+            result.push((original_info, variation_info, stats));
+        }
+        Ok(result)
+    }
+
+    fn get_single_melody_variations(
+        &mut self,
+        melody_id: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let connection = self.get_connection()?;
+        let original = self.melody(&connection, melody_id)?;
+        let original_info = Self::info_for(&connection, melody_id, original)?;
+        let cmd = "SELECT variation_row FROM variation_info WHERE original_row = ?";
+        let mut statement = connection.prepare(cmd)?;
+        statement.bind((1, melody_id))?;
+        let mut result = vec![];
+        while let State::Row = statement.next()? {
+            let variation_id = statement.read::<i64, usize>(0)?;
+            let variation = self.melody(&connection, variation_id)?;
+            let variation_info = Self::info_for(&connection, variation_id, variation)?;
+            let stats = self.stats(&connection, variation_id)?;
+            result.push((original_info.clone(), variation_info, stats));
+        }
+        Ok(result)
+    }
+
+    fn get_melodies_only(
+        &mut self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<MelodyInfo>> {
+        let mut result = vec![];
+        let connection = self.get_connection()?;
+        let melody_ids = Self::get_melody_ids(&connection, min_today_pref, min_older_pref)?;
+        for melody_id in melody_ids {
+            let melody = self.melody(&connection, melody_id)?;
+            let melody_info = Self::info_for(&connection, melody_id, melody)?;
+            result.push(melody_info);
+        }
+        Ok(result)
+    }
+
+    fn melody_and_info_for(&mut self, rowid: i64) -> anyhow::Result<MelodyInfo> {
+        let connection = self.get_connection()?;
+        let melody = self.melody(&connection, rowid)?;
+        Self::info_for(&connection, rowid, melody)
+    }
+
+    fn add_snapshot(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement = connection.prepare("INSERT INTO snapshots (name, timestamp, melody_row, variation_row, settings) VALUES (?, ?, ?, ?, ?)")?;
+        statement.bind((1, snapshot.name.as_str()))?;
+        statement.bind((2, snapshot.timestamp))?;
+        statement.bind((3, snapshot.melody_row))?;
+        statement.bind((4, snapshot.variation_row))?;
+        statement.bind((5, snapshot.settings.as_str()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn snapshots(&self) -> anyhow::Result<Vec<Snapshot>> {
+        let connection = self.get_connection()?;
+        let mut statement = connection.prepare("SELECT name, timestamp, melody_row, variation_row, settings FROM snapshots ORDER BY timestamp DESC")?;
+        let mut result = vec![];
+        while let State::Row = statement.next()? {
+            result.push(Snapshot {
+                name: statement.read::<String, usize>(0)?,
+                timestamp: statement.read::<i64, usize>(1)?,
+                melody_row: statement.read::<i64, usize>(2)?,
+                variation_row: statement.read::<i64, usize>(3)?,
+                settings: statement.read::<String, usize>(4)?,
+            });
+        }
+        Ok(result)
+    }
+
+    fn add_practice_attempt(&mut self, attempt: &PracticeAttempt) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement = connection
+            .prepare("INSERT INTO practice (timestamp, figure, score) VALUES (?, ?, ?)")?;
+        statement.bind((1, attempt.timestamp))?;
+        statement.bind((2, attempt.figure.as_str()))?;
+        statement.bind((3, attempt.score))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn practice_attempts(&self) -> anyhow::Result<Vec<PracticeAttempt>> {
+        let connection = self.get_connection()?;
+        let mut statement = connection.prepare(
+            "SELECT timestamp, figure, score FROM practice ORDER BY timestamp DESC, rowid DESC",
+        )?;
+        let mut result = vec![];
+        while let State::Row = statement.next()? {
+            result.push(PracticeAttempt {
+                timestamp: statement.read::<i64, usize>(0)?,
+                figure: statement.read::<String, usize>(1)?,
+                score: statement.read::<f64, usize>(2)?,
+            });
+        }
+        Ok(result)
+    }
+
+    fn start_session(&mut self, settings: &str) -> anyhow::Result<i64> {
+        let start = Utc::now().timestamp();
+        let connection = self.get_connection()?;
+        let mut statement =
+            connection.prepare("UPDATE sessions SET finish = ? WHERE finish IS NULL")?;
+        statement.bind((1, start))?;
+        statement.next()?;
+        let mut statement = connection
+            .prepare("INSERT INTO sessions (start, settings, notes) VALUES (?, ?, '')")?;
+        statement.bind((1, start))?;
+        statement.bind((2, settings))?;
+        statement.next()?;
+        let mut statement = connection.prepare("SELECT last_insert_rowid()")?;
+        statement.next()?;
+        Ok(statement.read::<i64, usize>(0)?)
+    }
+
+    fn end_session(&mut self, rowid: i64) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement = connection
+            .prepare("UPDATE sessions SET finish = ? WHERE rowid = ? AND finish IS NULL")?;
+        statement.bind((1, Utc::now().timestamp()))?;
+        statement.bind((2, rowid))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn set_session_notes(&mut self, rowid: i64, notes: &str) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement = connection.prepare("UPDATE sessions SET notes = ? WHERE rowid = ?")?;
+        statement.bind((1, notes))?;
         statement.bind((2, rowid))?;
         statement.next()?;
         Ok(())
     }
 
-    pub fn add_melody_and_variation(
+    fn sessions(&self) -> anyhow::Result<Vec<Session>> {
+        let connection = self.get_connection()?;
+        let mut statement = connection.prepare(
+            "SELECT rowid, start, finish, settings, notes FROM sessions ORDER BY start DESC, rowid DESC",
+        )?;
+        let mut result = vec![];
+        while let State::Row = statement.next()? {
+            result.push(Session {
+                rowid: statement.read::<i64, usize>(0)?,
+                start: statement.read::<i64, usize>(1)?,
+                end: statement.read::<Option<i64>, usize>(2)?,
+                settings: statement.read::<String, usize>(3)?,
+                notes: statement.read::<String, usize>(4)?,
+            });
+        }
+        Ok(result)
+    }
+
+    fn session_pairs(
         &mut self,
-        melody: &Melody,
-        variation: &Melody,
-        stats: &VariationStats,
-    ) -> anyhow::Result<(MelodyInfo, MelodyInfo)> {
-        let player_info = self.store_melody(melody)?;
-        let variation_info = self.add_variation(player_info.row_id(), variation, stats)?;
-        Ok((player_info, variation_info))
+        rowid: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let connection = self.get_connection()?;
+        let mut statement =
+            connection.prepare("SELECT start, finish FROM sessions WHERE rowid = ?")?;
+        statement.bind((1, rowid))?;
+        if let State::Done = statement.next()? {
+            bail!("No session with row id {rowid}");
+        }
+        let start = statement.read::<i64, usize>(0)?;
+        let end = statement.read::<Option<i64>, usize>(1)?.unwrap_or(i64::MAX);
+        let mut statement = connection.prepare("SELECT original_row, variation_row FROM variation_info, melody_index WHERE original_row = melody_index.rowid AND timestamp >= ? AND timestamp <= ? ORDER BY timestamp")?;
+        statement.bind((1, start))?;
+        statement.bind((2, end))?;
+        let mut ids = vec![];
+        while let State::Row = statement.next()? {
+            ids.push((
+                statement.read::<i64, usize>(0)?,
+                statement.read::<i64, usize>(1)?,
+            ));
+        }
+        self.pairs_for(&ids)
+    }
+
+    fn pair_for(
+        &mut self,
+        melody_row: i64,
+        variation_row: i64,
+    ) -> anyhow::Result<(MelodyInfo, MelodyInfo, VariationStats)> {
+        let connection = self.get_connection()?;
+        let melody = self.melody(&connection, melody_row)?;
+        let variation = self.melody(&connection, variation_row)?;
+        Ok((
+            Self::info_for(&connection, melody_row, melody)?,
+            Self::info_for(&connection, variation_row, variation)?,
+            self.stats(&connection, variation_row)?,
+        ))
+    }
+
+    fn update_info(&mut self, rowid: i64, rating: Preference) -> anyhow::Result<()> {
+        let connection = self.get_connection()?;
+        let mut statement =
+            connection.prepare("UPDATE melody_index SET rating = ? WHERE rowid = ?")?;
+        statement.bind((1, rating.to_string().as_str()))?;
+        statement.bind((2, rowid))?;
+        statement.next()?;
+        Ok(())
     }
 
     fn add_variation(
@@ -449,6 +1118,7 @@ impl Database {
         statement.bind((6, stats.min_note_duration))?;
         statement.bind((7, if stats.whimsify { 1 } else { 0 }))?;
         statement.next()?;
+        Self::store_report(&connection, variation_info.rowid, &stats.report)?;
         Ok(variation_info)
     }
 
@@ -490,6 +1160,33 @@ impl Database {
     }
 }
 
+fn index_list(indices: &Vec<usize>) -> String {
+    indices
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_index_list(s: &str) -> Vec<usize> {
+    s.split(',').filter_map(|i| i.parse().ok()).collect()
+}
+
+fn figure_list(figures: &Vec<(usize, String)>) -> String {
+    figures
+        .iter()
+        .map(|(i, name)| format!("{i}:{name}"))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn parse_figure_list(s: &str) -> Vec<(usize, String)> {
+    s.split(';')
+        .filter_map(|f| f.split_once(':'))
+        .filter_map(|(i, name)| i.parse().ok().map(|i| (i, name.to_owned())))
+        .collect()
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct MelodyInfo {
     rowid: i64,
@@ -500,6 +1197,21 @@ pub struct MelodyInfo {
 }
 
 impl MelodyInfo {
+    /// A newly stored melody, rated `Neutral` and not yet tagged.
+    pub(crate) fn new(rowid: i64, timestamp: i64, melody: Melody) -> Self {
+        MelodyInfo {
+            rowid,
+            timestamp,
+            rating: Preference::Neutral,
+            tags: BTreeSet::new(),
+            melody,
+        }
+    }
+
+    pub(crate) fn add_tag(&mut self, tag: String) {
+        self.tags.insert(tag);
+    }
+
     pub fn row_id(&self) -> i64 {
         self.rowid
     }
@@ -563,6 +1275,15 @@ impl Preference {
             Preference::Ignore => "rating LIKE '%'",
         }
     }
+
+    /// Whether a melody rated `rating` is among those `sql_choice_str` selects.
+    pub fn admits(&self, rating: Preference) -> bool {
+        match self {
+            Preference::Favorite => rating == Preference::Favorite,
+            Preference::Neutral => rating != Preference::Ignore,
+            Preference::Ignore => true,
+        }
+    }
 }
 
 impl Display for Preference {
@@ -0,0 +1,456 @@
+use crate::runtime::{HUMAN_SPEAKER, VARIATION_SPEAKER};
+use crate::watchdog::same_speaker;
+use enum_iterator::Sequence;
+use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_msg::{Channel, ChannelVoiceMsg, ControlChange, MidiMsg};
+use serde::{Deserialize, Serialize};
+
+const HIGHEST_VELOCITY: u8 = 127;
+const BREATH_CONTROL: u8 = 2;
+const EXPRESSION_CONTROL: u8 = 11;
+const PAN_CONTROL: u8 = 10;
+const CENTERED_PAN: u8 = 64;
+const MOD_WHEEL_CONTROL: u8 = 1;
+const VOLUME_CONTROL: u8 = 7;
+const DEFAULT_VOLUME: u8 = 100;
+const CUTOFF_CONTROL: u8 = 74;
+const CENTERED_CUTOFF: u8 = 64;
+/// The combined level above which new notes are held back, so that stacked human and AI
+/// voices do not clip. One note at full velocity has a level of 1.0.
+pub const LIMITER_THRESHOLD: f64 = 4.0;
+
+/// How loud the notes now sounding are, estimated from their velocities, where one note at
+/// full velocity is 1.0. `rms` combines them the way unrelated voices add up, as the
+/// square root of the sum of their squares.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OutputLevel {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+impl OutputLevel {
+    pub fn of<I: Iterator<Item = u8>>(velocities: I) -> Self {
+        let (peak, sum_squares) = velocities
+            .map(fraction)
+            .fold((0.0, 0.0), |(p, s), v| (f64::max(p, v), s + v * v));
+        OutputLevel {
+            peak,
+            rms: f64::sqrt(sum_squares),
+        }
+    }
+}
+
+/// Where aftertouch goes: channel pressure, and the pressure on each held key, are sent on
+/// as this controller as well.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence, Serialize, Deserialize)]
+pub enum PressureRoute {
+    #[default]
+    Off,
+    Volume,
+    Vibrato,
+    Cutoff,
+}
+
+impl PressureRoute {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PressureRoute::Off => "Off",
+            PressureRoute::Volume => "Volume",
+            PressureRoute::Vibrato => "Vibrato",
+            PressureRoute::Cutoff => "Filter Cutoff",
+        }
+    }
+
+    /// The controller pressure is sent as, and its value with no pressure at all.
+    fn control(&self) -> Option<(u8, u8)> {
+        match self {
+            PressureRoute::Off => None,
+            PressureRoute::Volume => Some((VOLUME_CONTROL, DEFAULT_VOLUME)),
+            PressureRoute::Vibrato => Some((MOD_WHEEL_CONTROL, 0)),
+            PressureRoute::Cutoff => Some((CUTOFF_CONTROL, CENTERED_CUTOFF)),
+        }
+    }
+}
+
+/// What the player has set for the output as a whole.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mix {
+    pub master_volume: f64,
+    /// From -1.0, hard left, to 1.0, hard right.
+    pub human_pan: f64,
+    pub variation_pan: f64,
+    pub pressure_route: PressureRoute,
+    /// How far full pressure raises its controller, as a fraction of its full range.
+    pub pressure_depth: f64,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix {
+            master_volume: 1.0,
+            human_pan: -1.0,
+            variation_pan: 1.0,
+            pressure_route: PressureRoute::Off,
+            pressure_depth: 1.0,
+        }
+    }
+}
+
+impl Mix {
+    /// The pan controller value for notes on `speaker`. Notes meant for both speakers
+    /// stay centered.
+    fn pan_value(&self, speaker: Speaker) -> u8 {
+        let pan = if same_speaker(speaker, HUMAN_SPEAKER) {
+            self.human_pan
+        } else if same_speaker(speaker, VARIATION_SPEAKER) {
+            self.variation_pan
+        } else {
+            return CENTERED_PAN;
+        };
+        ((pan.clamp(-1.0, 1.0) + 1.0) / 2.0 * HIGHEST_VELOCITY as f64).round() as u8
+    }
+
+    /// The controller to send for `pressure`, if it is routed anywhere. From its resting
+    /// value, the controller rises by `pressure_depth` times the pressure.
+    fn pressure_control(&self, pressure: u8) -> Option<ControlChange> {
+        let (control, rest) = self.pressure_route.control()?;
+        let value = rest as f64 + self.pressure_depth.clamp(0.0, 1.0) * pressure as f64;
+        Some(ControlChange::CC {
+            control,
+            value: value.round().min(HIGHEST_VELOCITY as f64) as u8,
+        })
+    }
+}
+
+/// Shapes how loud each note is on its way to the synthesizers, and where it sits between
+/// the speakers.
+///
+/// A breath controller (CC2) or expression pedal (CC11) scales the velocity of each note
+/// started on its channel, as a fraction of their full value. The synthesizers build each
+/// voice from its pitch and velocity alone, so a change only reaches the notes that start
+/// after it. The controller messages themselves are passed on unchanged.
+///
+/// The master volume then scales every note, and a soft limiter holds back any note that
+/// would take the combined level of those sounding over `LIMITER_THRESHOLD`, by the square
+/// root of how far over it would go. Like expression, these act as each note starts,
+/// since the synthesizers' output never passes through this program.
+///
+/// Before a channel's first note, and before the next note after its pan has been moved,
+/// the pan is sent as a pan controller (CC10) message. The human's pan applies to every
+/// channel on `HUMAN_SPEAKER`, and the variation's to `VARIATION_SPEAKER`, replacing any
+/// pan the player sends.
+///
+/// Aftertouch, on the channel or on a held key, is passed on along with the controller
+/// it is routed to, so that pressing into a note can swell it, add vibrato or brighten it.
+#[derive(Default)]
+pub struct Dynamics {
+    channels: Vec<ChannelDynamics>,
+}
+
+struct ChannelDynamics {
+    speaker: Speaker,
+    channel: Channel,
+    breath: u8,
+    expression: u8,
+    /// The pan last sent, by this or by the player.
+    pan: Option<u8>,
+}
+
+impl Dynamics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns what to send in place of `synth_msg`, given the `mix` and the `level` of
+    /// the notes already sounding.
+    pub fn shape(&mut self, synth_msg: SynthMsg, mix: Mix, level: OutputLevel) -> Vec<SynthMsg> {
+        let speaker = synth_msg.speaker;
+        let voice = |channel, msg| SynthMsg {
+            msg: MidiMsg::ChannelVoice { channel, msg },
+            speaker,
+        };
+        match synth_msg.msg {
+            MidiMsg::ChannelVoice {
+                channel,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity },
+            } if velocity > 0 => {
+                let mut sent = vec![];
+                let dynamics = self.channel_dynamics(speaker, channel);
+                let pan = mix.pan_value(speaker);
+                if dynamics.pan != Some(pan) {
+                    dynamics.pan = Some(pan);
+                    sent.push(voice(
+                        channel,
+                        ChannelVoiceMsg::ControlChange {
+                            control: ControlChange::CC {
+                                control: PAN_CONTROL,
+                                value: pan,
+                            },
+                        },
+                    ));
+                }
+                let velocity = dynamics.expressed(velocity) * mix.master_volume;
+                let velocity = limited(velocity, level);
+                sent.push(voice(channel, ChannelVoiceMsg::NoteOn { note, velocity }));
+                sent
+            }
+            MidiMsg::ChannelVoice {
+                channel,
+                msg: ChannelVoiceMsg::ControlChange { control },
+            } => {
+                let dynamics = self.channel_dynamics(speaker, channel);
+                match control {
+                    ControlChange::Breath(value) => dynamics.breath = coarse(value),
+                    ControlChange::CC {
+                        control: BREATH_CONTROL,
+                        value,
+                    } => dynamics.breath = value,
+                    ControlChange::Expression(value) => dynamics.expression = coarse(value),
+                    ControlChange::CC {
+                        control: EXPRESSION_CONTROL,
+                        value,
+                    } => dynamics.expression = value,
+                    ControlChange::Pan(value) => dynamics.pan = Some(coarse(value)),
+                    ControlChange::CC {
+                        control: PAN_CONTROL,
+                        value,
+                    } => dynamics.pan = Some(value),
+                    _ => {}
+                }
+                vec![synth_msg]
+            }
+            MidiMsg::ChannelVoice {
+                channel,
+                msg:
+                    ChannelVoiceMsg::ChannelPressure { pressure }
+                    | ChannelVoiceMsg::PolyPressure { pressure, .. },
+            } => {
+                let mut sent = vec![synth_msg];
+                if let Some(control) = mix.pressure_control(pressure) {
+                    sent.push(voice(channel, ChannelVoiceMsg::ControlChange { control }));
+                }
+                sent
+            }
+            _ => vec![synth_msg],
+        }
+    }
+
+    fn channel_dynamics(&mut self, speaker: Speaker, channel: Channel) -> &mut ChannelDynamics {
+        match self
+            .channels
+            .iter()
+            .position(|c| same_speaker(speaker, c.speaker) && c.channel == channel)
+        {
+            Some(i) => &mut self.channels[i],
+            None => {
+                self.channels.push(ChannelDynamics {
+                    speaker,
+                    channel,
+                    breath: HIGHEST_VELOCITY,
+                    expression: HIGHEST_VELOCITY,
+                    pan: None,
+                });
+                self.channels.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+impl ChannelDynamics {
+    /// `velocity` scaled by the breath and expression on this channel.
+    fn expressed(&self, velocity: u8) -> f64 {
+        velocity as f64 * fraction(self.breath) * fraction(self.expression)
+    }
+}
+
+/// `velocity` held back if it would take the combined `level` over `LIMITER_THRESHOLD`. A
+/// note is never scaled all the way to 0, which would end it instead.
+fn limited(velocity: f64, level: OutputLevel) -> u8 {
+    let note = velocity / HIGHEST_VELOCITY as f64;
+    let combined = f64::sqrt(level.rms * level.rms + note * note);
+    let scale = f64::sqrt(f64::min(1.0, LIMITER_THRESHOLD / combined));
+    (velocity * scale)
+        .round()
+        .clamp(1.0, HIGHEST_VELOCITY as f64) as u8
+}
+
+/// The most significant 7 bits of a high-resolution controller value.
+fn coarse(value: u16) -> u8 {
+    (value >> 7) as u8
+}
+
+fn fraction(value: u8) -> f64 {
+    value as f64 / HIGHEST_VELOCITY as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice(speaker: Speaker, msg: ChannelVoiceMsg) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg,
+            },
+            speaker,
+        }
+    }
+
+    fn on(speaker: Speaker, note: u8, velocity: u8) -> SynthMsg {
+        voice(speaker, ChannelVoiceMsg::NoteOn { note, velocity })
+    }
+
+    fn cc(speaker: Speaker, control: u8, value: u8) -> SynthMsg {
+        voice(
+            speaker,
+            ChannelVoiceMsg::ControlChange {
+                control: ControlChange::CC { control, value },
+            },
+        )
+    }
+
+    fn velocities_of(sent: Vec<SynthMsg>) -> Vec<u8> {
+        sent.iter()
+            .filter_map(|synth_msg| match synth_msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOn { velocity, .. },
+                    ..
+                } => Some(velocity),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_expression() {
+        let mut dynamics = Dynamics::new();
+        let mut dynamics = |msg| dynamics.shape(msg, Mix::default(), OutputLevel::default());
+        let left = Speaker::Left;
+        assert_eq!(velocities_of(dynamics(on(left, 60, 100))), vec![100]);
+        assert_eq!(dynamics(cc(left, EXPRESSION_CONTROL, 0)).len(), 1);
+        assert_eq!(velocities_of(dynamics(on(left, 62, 100))), vec![1]);
+        dynamics(cc(left, EXPRESSION_CONTROL, 127));
+        dynamics(cc(left, BREATH_CONTROL, 64));
+        assert_eq!(velocities_of(dynamics(on(left, 64, 100))), vec![50]);
+        // Each speaker has its own controllers, and a release is never scaled.
+        assert_eq!(
+            velocities_of(dynamics(on(Speaker::Right, 64, 100))),
+            vec![100]
+        );
+        assert_eq!(velocities_of(dynamics(on(left, 64, 0))), vec![0]);
+    }
+
+    #[test]
+    fn test_volume_and_limiter() {
+        let mut dynamics = Dynamics::new();
+        let left = Speaker::Left;
+        let quiet = OutputLevel::default();
+        let volume = |master_volume| Mix {
+            master_volume,
+            ..Mix::default()
+        };
+        let sent = dynamics.shape(on(left, 60, 100), volume(0.5), quiet);
+        assert_eq!(velocities_of(sent), vec![50]);
+        let sent = dynamics.shape(on(left, 60, 100), volume(1.5), quiet);
+        assert_eq!(velocities_of(sent), vec![127]);
+        let level = OutputLevel::of([127, 127, 127, 127, 0].into_iter());
+        assert_eq!((level.peak, level.rms), (1.0, 2.0));
+        // Twice the threshold already.
+        let loud = OutputLevel {
+            peak: 1.0,
+            rms: 8.0,
+        };
+        let sent = dynamics.shape(on(left, 60, 127), volume(1.0), loud);
+        assert_eq!(velocities_of(sent), vec![89]);
+        let sent = dynamics.shape(on(left, 60, 0), volume(1.0), loud);
+        assert_eq!(velocities_of(sent), vec![0]);
+        assert_eq!(OutputLevel::of([].into_iter()), quiet);
+    }
+
+    fn pans_of(sent: Vec<SynthMsg>) -> Vec<u8> {
+        sent.iter()
+            .filter_map(|synth_msg| match synth_msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg:
+                        ChannelVoiceMsg::ControlChange {
+                            control:
+                                ControlChange::CC {
+                                    control: PAN_CONTROL,
+                                    value,
+                                },
+                        },
+                    ..
+                } => Some(value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pan() {
+        let mut dynamics = Dynamics::new();
+        let quiet = OutputLevel::default();
+        let mut mix = Mix::default();
+        // Each speaker's pan is sent before its first note, and only then.
+        let sent = dynamics.shape(on(HUMAN_SPEAKER, 60, 100), mix, quiet);
+        assert_eq!(pans_of(sent.clone()), vec![0]);
+        assert_eq!(velocities_of(sent), vec![100]);
+        let sent = dynamics.shape(on(VARIATION_SPEAKER, 60, 100), mix, quiet);
+        assert_eq!(pans_of(sent), vec![127]);
+        assert!(pans_of(dynamics.shape(on(HUMAN_SPEAKER, 62, 100), mix, quiet)).is_empty());
+        // Moved, or changed by the player, it is sent again before the next note.
+        mix.human_pan = -0.5;
+        let sent = dynamics.shape(on(HUMAN_SPEAKER, 64, 100), mix, quiet);
+        assert_eq!(pans_of(sent), vec![32]);
+        dynamics.shape(cc(HUMAN_SPEAKER, PAN_CONTROL, 100), mix, quiet);
+        let sent = dynamics.shape(on(HUMAN_SPEAKER, 65, 100), mix, quiet);
+        assert_eq!(pans_of(sent), vec![32]);
+        let sent = dynamics.shape(on(Speaker::Both, 60, 100), mix, quiet);
+        assert_eq!(pans_of(sent), vec![CENTERED_PAN]);
+    }
+
+    #[test]
+    fn test_pressure_route() {
+        let mut dynamics = Dynamics::new();
+        let quiet = OutputLevel::default();
+        let pressure = voice(
+            HUMAN_SPEAKER,
+            ChannelVoiceMsg::ChannelPressure { pressure: 100 },
+        );
+        let mut mix = Mix::default();
+        assert_eq!(dynamics.shape(pressure.clone(), mix, quiet).len(), 1);
+        let controls = |sent: Vec<SynthMsg>| {
+            sent.iter()
+                .filter_map(|synth_msg| match synth_msg.msg {
+                    MidiMsg::ChannelVoice {
+                        msg:
+                            ChannelVoiceMsg::ControlChange {
+                                control: ControlChange::CC { control, value },
+                            },
+                        ..
+                    } => Some((control, value)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        mix.pressure_route = PressureRoute::Vibrato;
+        mix.pressure_depth = 0.5;
+        let sent = dynamics.shape(pressure.clone(), mix, quiet);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(controls(sent), vec![(MOD_WHEEL_CONTROL, 50)]);
+        mix.pressure_route = PressureRoute::Volume;
+        let sent = dynamics.shape(pressure, mix, quiet);
+        assert_eq!(controls(sent), vec![(VOLUME_CONTROL, 127)]);
+        mix.pressure_route = PressureRoute::Cutoff;
+        let key_pressure = voice(
+            HUMAN_SPEAKER,
+            ChannelVoiceMsg::PolyPressure {
+                note: 60,
+                pressure: 20,
+            },
+        );
+        let sent = dynamics.shape(key_pressure, mix, quiet);
+        assert_eq!(controls(sent), vec![(CUTOFF_CONTROL, 74)]);
+    }
+}
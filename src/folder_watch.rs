@@ -0,0 +1,133 @@
+use crate::ai_variation::{long_enough, AITable, Performer};
+use crate::analyzer::FIGURE_LENGTHS;
+use crate::midi_file::{load_midi_file, save_midi_file};
+use crate::runtime::VariationControls;
+use crate::threads::{spawn_named, FOLDER_WATCH_THREAD, SINGLETON};
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use std::collections::btree_map::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+const MIDI_FILE_EXTENSION: &str = "mid";
+const VARIATION_SUFFIX: &str = "_variation";
+const WATCH_POLL_MILLIS: u64 = 500;
+
+/// Where the variation of `source` is written: alongside it, as `<name>_variation.mid`.
+pub fn variation_path(source: &Path) -> PathBuf {
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    source.with_file_name(format!("{stem}{VARIATION_SUFFIX}.{MIDI_FILE_EXTENSION}"))
+}
+
+/// Whether `path` names a MIDI file to be varied, rather than a variation already written.
+pub fn is_source_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    name.ends_with(format!(".{MIDI_FILE_EXTENSION}").as_str())
+        && !name.ends_with(format!("{VARIATION_SUFFIX}.{MIDI_FILE_EXTENSION}").as_str())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn vary_file(performer: &Performer, source: &Path) -> anyhow::Result<PathBuf> {
+    let melody = load_midi_file(source.to_string_lossy().as_ref())?;
+    // The same minimum the AI thread asks of the player's phrases, with no silence to wait
+    // out. An empty melody would otherwise bring the watcher down.
+    let min_melody_pitches = *FIGURE_LENGTHS.iter().max().unwrap();
+    if !long_enough(&performer.cleaned(&melody), min_melody_pitches, 0.0) {
+        return Err(anyhow!("Too few notes to vary"));
+    }
+    let (variation, _) = performer.respond_to(&melody);
+    let destination = variation_path(source);
+    save_midi_file(&variation, destination.to_string_lossy().as_ref())?;
+    Ok(destination)
+}
+
+/// Watches `folder` for `.mid` files without a variation alongside them. Each one is varied
+/// with the current AI settings, so that other programs can use the replayer by dropping
+/// files into the folder and picking up the results.
+pub fn start_folder_watch_thread(
+    folder: String,
+    ai_table: Arc<Mutex<AITable>>,
+    variation_controls: VariationControls,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(FOLDER_WATCH_THREAD, SINGLETON, move || {
+        if let Err(e) = fs::create_dir_all(folder.as_str()) {
+            error!("Cannot watch folder {folder}: {e}");
+            return;
+        }
+        info!("Watching {folder} for MIDI files");
+        let performer = Performer::new(variation_controls, ai_table);
+        // Files that could not be read, such as those still being copied in, are retried
+        // once they have been modified again.
+        let mut failed = BTreeMap::new();
+        while !quit.load() {
+            match fs::read_dir(folder.as_str()) {
+                Ok(entries) => {
+                    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                        if is_source_file(&path)
+                            && !variation_path(&path).exists()
+                            && failed.get(&path) != Some(&modified(&path))
+                        {
+                            match vary_file(&performer, &path) {
+                                Ok(destination) => info!("Wrote {destination:?}"),
+                                Err(e) => {
+                                    warn!("Could not vary {path:?}: {e}");
+                                    failed.insert(path.clone(), modified(&path));
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Cannot read folder {folder}: {e}"),
+            }
+            thread::sleep(Duration::from_millis(WATCH_POLL_MILLIS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_variation::make_ai_table;
+    use crate::analyzer::Melody;
+
+    #[test]
+    fn test_watch_file_names() {
+        let source = Path::new("inbox/phrase.mid");
+        assert!(is_source_file(source));
+        assert!(is_source_file(Path::new("inbox/PHRASE.MID")));
+        assert_eq!(
+            variation_path(source),
+            PathBuf::from("inbox/phrase_variation.mid")
+        );
+        assert!(!is_source_file(variation_path(source).as_path()));
+        assert!(!is_source_file(Path::new("inbox/notes.txt")));
+    }
+
+    #[test]
+    fn test_vary_short_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let performer = Performer::new(
+            VariationControls::new(),
+            Arc::new(Mutex::new(make_ai_table())),
+        );
+        let short = Melody::from("60,0.5,0.8,62,0.5,0.8");
+        for (name, melody) in [("empty.mid", Melody::new()), ("short.mid", short)] {
+            let path = dir.path().join(name);
+            save_midi_file(&melody, path.to_string_lossy().as_ref()).unwrap();
+            assert!(vary_file(&performer, &path).is_err());
+            assert!(!variation_path(&path).exists());
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::analyzer::{DiatonicInterval, Melody, MidiByte, MusicMode, Note};
+use enum_iterator::Sequence;
+use midi_msg::{Channel, ChannelVoiceMsg};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// How many of the player's most recent notes decide the key to harmonize in.
+const KEY_WINDOW: usize = 24;
+const HIGHEST_MIDI_NOTE: MidiByte = 127;
+
+/// The diatonic interval the harmonizer adds above each of the player's notes.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence, Serialize, Deserialize)]
+pub enum HarmonyInterval {
+    #[default]
+    Off,
+    Third,
+    Sixth,
+}
+
+impl HarmonyInterval {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HarmonyInterval::Off => "Off",
+            HarmonyInterval::Third => "Third Above",
+            HarmonyInterval::Sixth => "Sixth Above",
+        }
+    }
+
+    fn scale_steps(&self) -> Option<MidiByte> {
+        match self {
+            HarmonyInterval::Off => None,
+            HarmonyInterval::Third => Some(2),
+            HarmonyInterval::Sixth => Some(5),
+        }
+    }
+}
+
+/// Echoes each of the player's notes as it arrives, transposed by a `HarmonyInterval`
+/// within the key of the notes heard recently, rather than waiting for a whole phrase.
+/// Each harmony note is released along with the note that started it, even if the key
+/// or the interval has changed in the meantime.
+#[derive(Default)]
+pub struct Harmonizer {
+    heard: VecDeque<MidiByte>,
+    sounding: Vec<(Channel, u8, u8)>,
+}
+
+impl Harmonizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the harmony for `msg`, which arrived on `channel`, if it needs one.
+    pub fn harmonize(
+        &mut self,
+        interval: HarmonyInterval,
+        channel: Channel,
+        msg: ChannelVoiceMsg,
+    ) -> Option<ChannelVoiceMsg> {
+        match msg {
+            ChannelVoiceMsg::NoteOn { note, velocity } if velocity > 0 => {
+                let steps = interval.scale_steps()?;
+                self.hear(note);
+                let harmony = self
+                    .key()
+                    .next_pitch(note as MidiByte, DiatonicInterval::pure(steps));
+                (harmony <= HIGHEST_MIDI_NOTE).then(|| {
+                    let harmony = harmony as u8;
+                    self.sounding.push((channel, note, harmony));
+                    ChannelVoiceMsg::NoteOn {
+                        note: harmony,
+                        velocity,
+                    }
+                })
+            }
+            ChannelVoiceMsg::NoteOn { note, .. } | ChannelVoiceMsg::NoteOff { note, .. } => {
+                let i = self
+                    .sounding
+                    .iter()
+                    .position(|(c, n, _)| *c == channel && *n == note)?;
+                let (_, _, harmony) = self.sounding.remove(i);
+                Some(ChannelVoiceMsg::NoteOff {
+                    note: harmony,
+                    velocity: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn hear(&mut self, note: u8) {
+        if self.heard.len() == KEY_WINDOW {
+            self.heard.pop_front();
+        }
+        self.heard.push_back(note as MidiByte);
+    }
+
+    fn key(&self) -> MusicMode {
+        let mut melody = Melody::new();
+        for pitch in self.heard.iter() {
+            melody.add(Note::new(*pitch, 1.0, 1));
+        }
+        melody.best_scale_for()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> ChannelVoiceMsg {
+        ChannelVoiceMsg::NoteOn {
+            note,
+            velocity: 100,
+        }
+    }
+
+    fn note_off(note: u8) -> ChannelVoiceMsg {
+        ChannelVoiceMsg::NoteOff { note, velocity: 0 }
+    }
+
+    #[test]
+    fn test_harmonizer() {
+        let mut harmonizer = Harmonizer::new();
+        let third = HarmonyInterval::Third;
+        // A C major scale, with the extra C settling the key.
+        for note in [60, 62, 64, 65, 67, 69, 71, 60] {
+            assert!(harmonizer
+                .harmonize(third, Channel::Ch1, note_on(note))
+                .is_some());
+            assert!(harmonizer
+                .harmonize(third, Channel::Ch1, note_off(note))
+                .is_some());
+        }
+        assert!(harmonizer.sounding.is_empty());
+        assert_eq!(
+            harmonizer.harmonize(third, Channel::Ch1, note_on(67)),
+            Some(note_on(71))
+        );
+
+        // The harmony is released even after the harmonizer is switched off.
+        let off = HarmonyInterval::Off;
+        assert_eq!(
+            harmonizer.harmonize(off, Channel::Ch1, note_off(67)),
+            Some(note_off(71))
+        );
+        assert_eq!(harmonizer.harmonize(off, Channel::Ch1, note_off(67)), None);
+        assert_eq!(harmonizer.harmonize(off, Channel::Ch1, note_on(60)), None);
+        assert_eq!(
+            harmonizer.harmonize(HarmonyInterval::Sixth, Channel::Ch1, note_on(64)),
+            Some(note_on(72))
+        );
+        assert_eq!(
+            harmonizer.harmonize(third, Channel::Ch1, note_on(127)),
+            None
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::analyzer::{Melody, MidiByte};
+use crate::database::{MelodyInfo, VariationStats};
+use std::ops::RangeInclusive;
+
+pub const NUM_PITCH_CLASSES: usize = 12;
+/// MIDI pitches 0 through 127 span the octaves numbered -1 through 9.
+pub const NUM_OCTAVES: usize = 11;
+
+/// How long each pitch class has sounded in each octave, in seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NoteHeatmap {
+    seconds: [[f64; NUM_PITCH_CLASSES]; NUM_OCTAVES],
+}
+
+impl NoteHeatmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, melody: &Melody) {
+        for note in melody.iter().filter(|n| !n.is_rest()) {
+            let pitch = note.pitch().clamp(0, MidiByte::MAX) as usize;
+            let octave = (pitch / NUM_PITCH_CLASSES).min(NUM_OCTAVES - 1);
+            self.seconds[octave][pitch % NUM_PITCH_CLASSES] += note.duration();
+        }
+    }
+
+    /// Seconds spent in `pitch_class` (0 for C) within `octave` (0 for octave -1).
+    pub fn seconds(&self, octave: usize, pitch_class: usize) -> f64 {
+        self.seconds[octave][pitch_class]
+    }
+
+    pub fn max(&self) -> f64 {
+        self.seconds.iter().flatten().copied().fold(0.0, f64::max)
+    }
+
+    /// The lowest through highest octaves that have sounded, if any have.
+    pub fn octave_range(&self) -> Option<RangeInclusive<usize>> {
+        let used = |octave: &usize| self.seconds[*octave].iter().any(|s| *s > 0.0);
+        let lowest = (0..NUM_OCTAVES).find(used)?;
+        let highest = (0..NUM_OCTAVES).rev().find(used)?;
+        Some(lowest..=highest)
+    }
+
+    /// How much the two heatmaps agree, from 0.0 when they share no cells to 1.0 when
+    /// their time is distributed identically. A variation that merely mimics its
+    /// performer scores close to 1.0.
+    pub fn overlap(&self, other: &NoteHeatmap) -> f64 {
+        let total = self.seconds.iter().flatten().sum::<f64>();
+        let other_total = other.seconds.iter().flatten().sum::<f64>();
+        if total <= 0.0 || other_total <= 0.0 {
+            return 0.0;
+        }
+        self.seconds
+            .iter()
+            .flatten()
+            .zip(other.seconds.iter().flatten())
+            .map(|(a, b)| (a / total).min(b / other_total))
+            .sum()
+    }
+}
+
+/// Heatmaps of the human melodies and of their variations over the last `window` pairs.
+pub fn rolling_heatmaps(
+    pairs: &[(MelodyInfo, MelodyInfo, VariationStats)],
+    window: usize,
+) -> (NoteHeatmap, NoteHeatmap) {
+    let mut human = NoteHeatmap::new();
+    let mut variation = NoteHeatmap::new();
+    for (melody_info, variation_info, _) in pairs.iter().rev().take(window) {
+        human.add(melody_info.melody());
+        variation.add(variation_info.melody());
+    }
+    (human, variation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_heatmap() {
+        let mut human = NoteHeatmap::new();
+        human.add(&Melody::from(
+            "60,0.5,0.8,72,0.25,0.8,0,1.0,0.0,61,0.25,0.8",
+        ));
+        assert_approx_eq!(f64, human.seconds(5, 0), 0.5);
+        assert_approx_eq!(f64, human.seconds(6, 0), 0.25);
+        assert_approx_eq!(f64, human.seconds(5, 1), 0.25);
+        assert_approx_eq!(f64, human.max(), 0.5);
+        assert_eq!(human.octave_range(), Some(5..=6));
+        assert_approx_eq!(f64, human.overlap(&human), 1.0);
+
+        let mut variation = NoteHeatmap::new();
+        variation.add(&Melody::from("60,1.0,0.8,67,1.0,0.8"));
+        assert_approx_eq!(f64, human.overlap(&variation), 0.5);
+        assert_approx_eq!(f64, human.overlap(&NoteHeatmap::new()), 0.0);
+        assert_eq!(NoteHeatmap::new().octave_range(), None);
+    }
+}
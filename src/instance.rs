@@ -0,0 +1,47 @@
+use anyhow::bail;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener};
+
+/// A local port that only the running replayer holds.
+pub const INSTANCE_PORT: u16 = 8641;
+
+/// Keeps a second replayer from starting while one is already running, since the two would
+/// fight over the MIDI device and play every note twice. The lock is a listening socket
+/// rather than a lock file, so the operating system releases it however the program ends,
+/// and a crash never leaves a stale lock behind.
+pub struct InstanceLock {
+    _listener: TcpListener,
+}
+
+impl InstanceLock {
+    pub fn acquire() -> anyhow::Result<Self> {
+        Self::acquire_port(INSTANCE_PORT)
+    }
+
+    pub fn acquire_port(port: u16) -> anyhow::Result<Self> {
+        match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(listener) => Ok(InstanceLock {
+                _listener: listener,
+            }),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => bail!(
+                "Another replayer is already running (it holds port {port}). \
+                 Close it before starting a new one."
+            ),
+            Err(e) => bail!("Cannot check for another running replayer on port {port}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_lock() {
+        let port = INSTANCE_PORT + 10_000;
+        let lock = InstanceLock::acquire_port(port).unwrap();
+        assert!(InstanceLock::acquire_port(port).is_err());
+        drop(lock);
+        assert!(InstanceLock::acquire_port(port).is_ok());
+    }
+}
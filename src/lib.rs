@@ -1,5 +1,34 @@
 pub mod ai_variation;
 pub mod analyzer;
+pub mod automation;
+pub mod backends;
+pub mod backing;
+pub mod config;
 pub mod database;
+pub mod dynamics;
+pub mod folder_watch;
+pub mod harmonizer;
+pub mod heatmap;
+pub mod instance;
+pub mod logging;
+pub mod melody_store;
+pub mod midi_clock;
+pub mod midi_file;
+pub mod midi_input;
+pub mod midi_learn;
+pub mod musicxml;
+pub mod pitch;
+pub mod plugins;
+pub mod queue;
+pub mod report;
 pub mod runtime;
+pub mod scheduler;
+pub mod scripting;
+pub mod share;
 pub mod subsequence_finder;
+pub mod tempo;
+pub mod threads;
+pub mod tokens;
+pub mod training;
+pub mod transpose;
+pub mod watchdog;
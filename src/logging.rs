@@ -0,0 +1,115 @@
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use enum_iterator::{all, Sequence};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// How much is logged, from errors alone up to every MIDI message received and every
+/// note played.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug, Sequence)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    /// Also reports how long each recording, variation and playback took.
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "Error",
+            LogLevel::Warn => "Warn",
+            LogLevel::Info => "Info",
+            LogLevel::Debug => "Debug",
+            LogLevel::Trace => "Trace",
+        }
+    }
+
+    fn filter(&self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all::<LogLevel>()
+            .find(|level| level.name().eq_ignore_ascii_case(s))
+            .ok_or(anyhow!("Unknown log level {s}"))
+    }
+}
+
+/// Changes the log level while the program runs.
+#[derive(Clone)]
+pub struct LogControl {
+    handle: reload::Handle<LevelFilter, Registry>,
+    level: Arc<AtomicCell<LogLevel>>,
+    // Log messages still waiting to be written are flushed when the last copy is dropped.
+    _writer: Arc<WorkerGuard>,
+}
+
+impl LogControl {
+    pub fn level(&self) -> LogLevel {
+        self.level.load()
+    }
+
+    pub fn set_level(&self, level: LogLevel) {
+        if self.handle.reload(level.filter()).is_ok() {
+            self.level.store(level);
+        }
+    }
+}
+
+/// Logs to standard error at `level` and above, naming the thread and the spans that each
+/// message came from. Messages are written by a background thread, so that logging never
+/// holds up the MIDI input or the playback threads.
+pub fn init_logging(level: LogLevel) -> anyhow::Result<LogControl> {
+    let (writer, guard) = tracing_appender::non_blocking(std::io::stderr());
+    let (filter, handle) = reload::Layer::new(level.filter());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            fmt::layer()
+                .with_writer(writer)
+                .with_thread_names(true)
+                .with_span_events(FmtSpan::CLOSE),
+        )
+        .try_init()?;
+    Ok(LogControl {
+        handle,
+        level: Arc::new(AtomicCell::new(level)),
+        _writer: Arc::new(guard),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level() {
+        for level in all::<LogLevel>() {
+            assert_eq!(
+                level.name().to_lowercase().parse::<LogLevel>().unwrap(),
+                level
+            );
+        }
+        assert!("loud".parse::<LogLevel>().is_err());
+    }
+}
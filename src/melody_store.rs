@@ -0,0 +1,430 @@
+use crate::analyzer::Melody;
+use crate::database::{
+    Database, MelodyInfo, PracticeAttempt, Preference, Session, Snapshot, VariationStats,
+};
+use anyhow::bail;
+use chrono::Utc;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// A `database_path` that keeps the library in memory, so that nothing is saved.
+pub const MEMORY_DATABASE: &str = ":memory:";
+
+/// Everything the database thread asks of the melody library, so that it can be kept in
+/// SQLite by `Database` or only for the run of the program by `MemoryStore`.
+pub trait MelodyStore: Send {
+    fn store_melody(&mut self, melody: &Melody) -> anyhow::Result<MelodyInfo>;
+
+    /// Stores `variation` as a variation of the melody with row id `melody_id`.
+    fn add_variation(
+        &mut self,
+        melody_id: i64,
+        variation: &Melody,
+        stats: &VariationStats,
+    ) -> anyhow::Result<MelodyInfo>;
+
+    fn melody_and_info_for(&mut self, rowid: i64) -> anyhow::Result<MelodyInfo>;
+
+    fn update_info(&mut self, rowid: i64, rating: Preference) -> anyhow::Result<()>;
+
+    fn add_tag_for(&mut self, melody_id: i64, tag: String) -> anyhow::Result<()>;
+
+    /// The (original, variation) row ids of the pairs whose variations meet the minimum
+    /// ratings, without loading any notes: older pairs first, then today's.
+    fn melody_pair_ids(
+        &self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<(i64, i64)>>;
+
+    fn pairs_for(
+        &mut self,
+        ids: &[(i64, i64)],
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>>;
+
+    fn get_single_melody_variations(
+        &mut self,
+        melody_id: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>>;
+
+    /// The human melodies with variations that meet the minimum ratings.
+    fn get_melodies_only(
+        &mut self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<MelodyInfo>>;
+
+    fn pair_for(
+        &mut self,
+        melody_row: i64,
+        variation_row: i64,
+    ) -> anyhow::Result<(MelodyInfo, MelodyInfo, VariationStats)>;
+
+    fn add_snapshot(&mut self, snapshot: &Snapshot) -> anyhow::Result<()>;
+
+    /// Returns every saved snapshot, most recent first.
+    fn snapshots(&self) -> anyhow::Result<Vec<Snapshot>>;
+
+    fn add_practice_attempt(&mut self, attempt: &PracticeAttempt) -> anyhow::Result<()>;
+
+    /// Returns every practice attempt, most recent first.
+    fn practice_attempts(&self) -> anyhow::Result<Vec<PracticeAttempt>>;
+
+    /// Starts a session now and returns its row id. Any session left open, because the
+    /// program did not exit cleanly, is ended at the same moment.
+    fn start_session(&mut self, settings: &str) -> anyhow::Result<i64>;
+
+    fn end_session(&mut self, rowid: i64) -> anyhow::Result<()>;
+
+    fn set_session_notes(&mut self, rowid: i64, notes: &str) -> anyhow::Result<()>;
+
+    /// Returns every session, most recent first.
+    fn sessions(&self) -> anyhow::Result<Vec<Session>>;
+
+    /// Returns the pairs whose human melody was recorded during the given session.
+    fn session_pairs(
+        &mut self,
+        rowid: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>>;
+
+    fn add_melody_and_variation(
+        &mut self,
+        melody: &Melody,
+        variation: &Melody,
+        stats: &VariationStats,
+    ) -> anyhow::Result<(MelodyInfo, MelodyInfo)> {
+        let player_info = self.store_melody(melody)?;
+        let variation_info = self.add_variation(player_info.row_id(), variation, stats)?;
+        Ok((player_info, variation_info))
+    }
+}
+
+/// Opens the SQLite database at `path`, or the default one, unless `path` is
+/// `MEMORY_DATABASE`.
+pub fn open_store(path: Option<&str>) -> Box<dyn MelodyStore> {
+    match path {
+        Some(MEMORY_DATABASE) => Box::new(MemoryStore::new()),
+        Some(path) => Box::new(Database::open(path)),
+        None => Box::new(Database::new()),
+    }
+}
+
+/// A melody library that lasts only as long as the program, for tests and for
+/// performances that need not be kept. Row ids are assigned as SQLite would.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    melodies: BTreeMap<i64, MelodyInfo>,
+    /// Keyed by the variation's row id.
+    variations: BTreeMap<i64, (i64, VariationStats)>,
+    snapshots: Vec<Snapshot>,
+    sessions: Vec<Session>,
+    practice: Vec<PracticeAttempt>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn info(&self, rowid: i64) -> anyhow::Result<MelodyInfo> {
+        match self.melodies.get(&rowid) {
+            Some(info) => Ok(info.clone()),
+            None => bail!("{rowid} not in database."),
+        }
+    }
+
+    fn info_mut(&mut self, rowid: i64) -> anyhow::Result<&mut MelodyInfo> {
+        match self.melodies.get_mut(&rowid) {
+            Some(info) => Ok(info),
+            None => bail!("{rowid} not in database."),
+        }
+    }
+
+    /// Whether the melody meets the minimum rating for its age.
+    fn admits(&self, rowid: i64, min_today_pref: Preference, min_older_pref: Preference) -> bool {
+        self.melodies.get(&rowid).is_some_and(|info| {
+            let min_pref = if Database::is_today(info.timestamp()) {
+                min_today_pref
+            } else {
+                min_older_pref
+            };
+            min_pref.admits(info.rating())
+        })
+    }
+
+    fn is_older(&self, rowid: i64) -> bool {
+        self.melodies
+            .get(&rowid)
+            .is_some_and(|info| !Database::is_today(info.timestamp()))
+    }
+}
+
+impl MelodyStore for MemoryStore {
+    fn store_melody(&mut self, melody: &Melody) -> anyhow::Result<MelodyInfo> {
+        let rowid = self.melodies.keys().last().map_or(1, |last| last + 1);
+        let info = MelodyInfo::new(rowid, Utc::now().timestamp(), melody.clone());
+        self.melodies.insert(rowid, info.clone());
+        Ok(info)
+    }
+
+    fn add_variation(
+        &mut self,
+        melody_id: i64,
+        variation: &Melody,
+        stats: &VariationStats,
+    ) -> anyhow::Result<MelodyInfo> {
+        let variation_info = self.store_melody(variation)?;
+        self.variations
+            .insert(variation_info.row_id(), (melody_id, stats.clone()));
+        Ok(variation_info)
+    }
+
+    fn melody_and_info_for(&mut self, rowid: i64) -> anyhow::Result<MelodyInfo> {
+        self.info(rowid)
+    }
+
+    fn update_info(&mut self, rowid: i64, rating: Preference) -> anyhow::Result<()> {
+        self.info_mut(rowid)?.set_rating(rating);
+        Ok(())
+    }
+
+    fn add_tag_for(&mut self, melody_id: i64, tag: String) -> anyhow::Result<()> {
+        self.info_mut(melody_id)?.add_tag(tag);
+        Ok(())
+    }
+
+    fn melody_pair_ids(
+        &self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<(i64, i64)>> {
+        let mut result = vec![];
+        for older in [true, false] {
+            for (variation_row, (original_row, _)) in self.variations.iter() {
+                if self.is_older(*variation_row) == older
+                    && self.admits(*variation_row, min_today_pref, min_older_pref)
+                {
+                    result.push((*original_row, *variation_row));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn pairs_for(
+        &mut self,
+        ids: &[(i64, i64)],
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let mut result = vec![];
+        for (original_id, variation_id) in ids.iter().copied() {
+            result.push(self.pair_for(original_id, variation_id)?);
+        }
+        Ok(result)
+    }
+
+    fn get_single_melody_variations(
+        &mut self,
+        melody_id: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let ids = self
+            .variations
+            .iter()
+            .filter(|(_, (original_row, _))| *original_row == melody_id)
+            .map(|(variation_row, _)| (melody_id, *variation_row))
+            .collect::<Vec<_>>();
+        self.pairs_for(&ids)
+    }
+
+    fn get_melodies_only(
+        &mut self,
+        min_today_pref: Preference,
+        min_older_pref: Preference,
+    ) -> anyhow::Result<Vec<MelodyInfo>> {
+        let mut result = vec![];
+        for older in [true, false] {
+            for rowid in self.melodies.keys().copied() {
+                let has_variations = self.variations.values().any(|(o, _)| *o == rowid);
+                if has_variations
+                    && self.is_older(rowid) == older
+                    && self.admits(rowid, min_today_pref, min_older_pref)
+                {
+                    result.push(self.info(rowid)?);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn pair_for(
+        &mut self,
+        melody_row: i64,
+        variation_row: i64,
+    ) -> anyhow::Result<(MelodyInfo, MelodyInfo, VariationStats)> {
+        match self.variations.get(&variation_row) {
+            Some((_, stats)) => Ok((
+                self.info(melody_row)?,
+                self.info(variation_row)?,
+                stats.clone(),
+            )),
+            None => bail!("{variation_row} not in database."),
+        }
+    }
+
+    fn add_snapshot(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        self.snapshots.push(snapshot.clone());
+        Ok(())
+    }
+
+    fn snapshots(&self) -> anyhow::Result<Vec<Snapshot>> {
+        let mut result = self.snapshots.clone();
+        result.sort_by_key(|snapshot| Reverse(snapshot.timestamp));
+        Ok(result)
+    }
+
+    fn add_practice_attempt(&mut self, attempt: &PracticeAttempt) -> anyhow::Result<()> {
+        self.practice.push(attempt.clone());
+        Ok(())
+    }
+
+    fn practice_attempts(&self) -> anyhow::Result<Vec<PracticeAttempt>> {
+        let mut result: Vec<PracticeAttempt> = self.practice.iter().rev().cloned().collect();
+        result.sort_by_key(|attempt| Reverse(attempt.timestamp));
+        Ok(result)
+    }
+
+    fn start_session(&mut self, settings: &str) -> anyhow::Result<i64> {
+        let start = Utc::now().timestamp();
+        for session in self.sessions.iter_mut().filter(|s| s.end.is_none()) {
+            session.end = Some(start);
+        }
+        let rowid = self.sessions.len() as i64 + 1;
+        self.sessions.push(Session {
+            rowid,
+            start,
+            end: None,
+            settings: settings.to_owned(),
+            notes: String::new(),
+        });
+        Ok(rowid)
+    }
+
+    fn end_session(&mut self, rowid: i64) -> anyhow::Result<()> {
+        let now = Utc::now().timestamp();
+        for session in self.sessions.iter_mut() {
+            if session.rowid == rowid && session.end.is_none() {
+                session.end = Some(now);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_session_notes(&mut self, rowid: i64, notes: &str) -> anyhow::Result<()> {
+        for session in self.sessions.iter_mut().filter(|s| s.rowid == rowid) {
+            session.notes = notes.to_owned();
+        }
+        Ok(())
+    }
+
+    fn sessions(&self) -> anyhow::Result<Vec<Session>> {
+        Ok(self.sessions.iter().rev().cloned().collect())
+    }
+
+    fn session_pairs(
+        &mut self,
+        rowid: i64,
+    ) -> anyhow::Result<Vec<(MelodyInfo, MelodyInfo, VariationStats)>> {
+        let session = match self.sessions.iter().find(|s| s.rowid == rowid) {
+            Some(session) => session.clone(),
+            None => bail!("No session with row id {rowid}"),
+        };
+        let end = session.end.unwrap_or(i64::MAX);
+        let ids = self
+            .variations
+            .iter()
+            .filter(|(_, (original_row, _))| {
+                self.melodies
+                    .get(original_row)
+                    .is_some_and(|info| (session.start..=end).contains(&info.timestamp()))
+            })
+            .map(|(variation_row, (original_row, _))| (*original_row, *variation_row))
+            .collect::<Vec<_>>();
+        self.pairs_for(&ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::VariationReport;
+
+    fn stats() -> VariationStats {
+        VariationStats {
+            algorithm_name: "test".to_owned(),
+            random_prob: 0.5,
+            ornament_prob: 0.0,
+            min_note_duration: 0.1,
+            whimsify: false,
+            report: VariationReport::default(),
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryStore::new();
+        let session = store.start_session("settings").unwrap();
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.8");
+        let variation = Melody::from("64,0.5,0.8,62,0.5,0.8");
+        let (m1, v1) = store
+            .add_melody_and_variation(&melody, &variation, &stats())
+            .unwrap();
+        let v2 = store.add_variation(m1.row_id(), &melody, &stats()).unwrap();
+        store.store_melody(&variation).unwrap();
+        assert_eq!(
+            store
+                .melody_pair_ids(Preference::Neutral, Preference::Neutral)
+                .unwrap(),
+            vec![(m1.row_id(), v1.row_id()), (m1.row_id(), v2.row_id())]
+        );
+
+        store
+            .update_info(v1.row_id(), Preference::Favorite)
+            .unwrap();
+        store.add_tag_for(v1.row_id(), "bright".to_owned()).unwrap();
+        let favorites = store
+            .melody_pair_ids(Preference::Favorite, Preference::Favorite)
+            .unwrap();
+        let pairs = store.pairs_for(&favorites).unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].1.rating(), Preference::Favorite);
+        assert!(pairs[0].1.tags().contains("bright"));
+        assert_eq!(pairs[0].0.melody(), &melody);
+
+        assert_eq!(
+            store
+                .get_single_melody_variations(m1.row_id())
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            store
+                .get_melodies_only(Preference::Ignore, Preference::Ignore)
+                .unwrap(),
+            vec![m1.clone()]
+        );
+        assert_eq!(store.session_pairs(session).unwrap().len(), 2);
+        store.set_session_notes(session, "warm room").unwrap();
+        store.end_session(session).unwrap();
+        let sessions = store.sessions().unwrap();
+        assert_eq!(sessions[0].notes, "warm room");
+        assert!(sessions[0].end.is_some());
+
+        for score in [0.5, 0.75] {
+            store
+                .add_practice_attempt(&PracticeAttempt::new("Run".to_owned(), score))
+                .unwrap();
+        }
+        assert_eq!(store.practice_attempts().unwrap()[0].score, 0.75);
+        assert!(store.pair_for(m1.row_id(), m1.row_id()).is_err());
+    }
+}
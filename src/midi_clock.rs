@@ -0,0 +1,320 @@
+use crate::runtime::SliderValue;
+use crate::scheduler::Scheduler;
+use crate::tempo::Tempo;
+use crate::threads::{spawn_named, CLOCK_OUTPUT_THREAD, SINGLETON};
+use crossbeam_utils::atomic::AtomicCell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// MIDI clock sends 24 Timing Clock messages per beat.
+pub const CLOCKS_PER_BEAT: u32 = 24;
+pub const TIMING_CLOCK: u8 = 0xf8;
+pub const START: u8 = 0xfa;
+pub const STOP: u8 = 0xfc;
+/// Song Position Pointer counts in sixteenth notes, each six Timing Clock messages long.
+const CLOCKS_PER_SONG_POSITION: u32 = 6;
+/// A longer wait between Timing Clock messages means the clock has stopped.
+const MAX_CLOCK_GAP_SECONDS: f64 = 0.5;
+const CLOCK_IDLE_MILLIS: u64 = 10;
+/// Timing Clock is scheduled this far ahead, so that a new tempo is heard soon after it
+/// is set.
+const CLOCK_LOOKAHEAD_MILLIS: u64 = 40;
+
+/// Estimates the tempo of incoming MIDI clock from the last beat's worth of Timing Clock
+/// messages, and keeps track of where each beat falls. Many senders keep sending Timing
+/// Clock after a Stop, so only the tempo follows it until they Start or Continue again.
+#[derive(Default)]
+pub struct ClockTempo {
+    ticks: VecDeque<Instant>,
+    /// Where the sender is in the song, in Timing Clock messages, so that beats fall where
+    /// the sender's do.
+    count: u32,
+    last_beat: Option<Instant>,
+    stopped: bool,
+}
+
+impl ClockTempo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `None` until a whole beat of clock has arrived.
+    pub fn tick_at(&mut self, when: Instant) -> Option<Tempo> {
+        if let Some(last) = self.ticks.back() {
+            if when.duration_since(*last).as_secs_f64() > MAX_CLOCK_GAP_SECONDS {
+                self.ticks.clear();
+            }
+        }
+        if !self.stopped {
+            if self.count % CLOCKS_PER_BEAT == 0 {
+                self.last_beat = Some(when);
+            }
+            self.count += 1;
+        }
+        self.ticks.push_back(when);
+        if self.ticks.len() > CLOCKS_PER_BEAT as usize + 1 {
+            self.ticks.pop_front();
+        }
+        if self.ticks.len() <= CLOCKS_PER_BEAT as usize {
+            None
+        } else {
+            let beat = when.duration_since(*self.ticks.front().unwrap());
+            Some(Tempo::from_bpm(60.0 / beat.as_secs_f64()))
+        }
+    }
+
+    /// A Start message: the next Timing Clock begins a beat.
+    pub fn start(&mut self) {
+        self.count = 0;
+        self.stopped = false;
+    }
+
+    /// A Stop message: there are no beats until the sender starts or continues.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+        self.last_beat = None;
+    }
+
+    /// A Continue message: counting resumes from where the song stopped, or from where the
+    /// last Song Position Pointer moved it.
+    pub fn resume(&mut self) {
+        self.stopped = false;
+    }
+
+    /// A Song Position Pointer: the next Timing Clock falls `sixteenths` sixteenth notes
+    /// from the start of the song.
+    pub fn relocate(&mut self, sixteenths: u16) {
+        self.count = sixteenths as u32 * CLOCKS_PER_SONG_POSITION;
+    }
+
+    pub fn last_beat(&self) -> Option<Instant> {
+        self.last_beat
+    }
+}
+
+/// Keeps the replayer in time with other MIDI equipment. When following, incoming MIDI
+/// clock sets the tempo slider and fixes the playback tempo, as tap tempo does. When
+/// sending, MIDI clock at the tempo slider's tempo goes to `output`. Either way, AI
+/// responses can wait for the next beat, so that they begin on the shared grid.
+#[derive(Clone)]
+pub struct ClockSync {
+    pub follow: Arc<AtomicCell<bool>>,
+    pub send: Arc<AtomicCell<bool>>,
+    tempo_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    fixed_tempo: Arc<AtomicCell<bool>>,
+    incoming: Arc<Mutex<ClockTempo>>,
+    output: Arc<Mutex<Option<Box<dyn FnMut(&[u8]) + Send>>>>,
+    /// When the clock followed or sent last began a beat.
+    last_beat: Arc<AtomicCell<Option<Instant>>>,
+}
+
+impl ClockSync {
+    pub fn new(
+        tempo_slider: Arc<AtomicCell<SliderValue<f64>>>,
+        fixed_tempo: Arc<AtomicCell<bool>>,
+    ) -> Self {
+        ClockSync {
+            follow: Arc::new(AtomicCell::new(false)),
+            send: Arc::new(AtomicCell::new(false)),
+            tempo_slider,
+            fixed_tempo,
+            incoming: Arc::new(Mutex::new(ClockTempo::new())),
+            output: Arc::new(Mutex::new(None)),
+            last_beat: Arc::new(AtomicCell::new(None)),
+        }
+    }
+
+    /// Where MIDI clock is sent, usually a port on a hardware sequencer.
+    pub fn set_output(&self, output: Option<Box<dyn FnMut(&[u8]) + Send>>) {
+        *self.output.lock().unwrap() = output;
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output.lock().unwrap().is_some()
+    }
+
+    /// Handles an incoming Timing Clock message.
+    pub fn receive_tick(&self) {
+        if self.follow.load() {
+            let mut incoming = self.incoming.lock().unwrap();
+            if let Some(tempo) = incoming.tick_at(Instant::now()) {
+                let sv = self.tempo_slider.load();
+                let range = sv.make_range();
+                self.tempo_slider
+                    .store(sv.slid_to(tempo.bpm().clamp(*range.start(), *range.end())));
+                self.fixed_tempo.store(true);
+            }
+            self.last_beat.store(incoming.last_beat());
+        }
+    }
+
+    /// Handles an incoming Start message.
+    pub fn receive_start(&self) {
+        self.incoming.lock().unwrap().start();
+    }
+
+    /// Handles an incoming Stop message. Responses stop waiting for beats at once.
+    pub fn receive_stop(&self) {
+        self.incoming.lock().unwrap().stop();
+        if self.follow.load() {
+            self.last_beat.store(None);
+        }
+    }
+
+    /// Handles an incoming Continue message.
+    pub fn receive_continue(&self) {
+        self.incoming.lock().unwrap().resume();
+    }
+
+    /// Handles an incoming Song Position Pointer, as sent when the sender relocates.
+    pub fn receive_song_position(&self, sixteenths: u16) {
+        self.incoming.lock().unwrap().relocate(sixteenths);
+    }
+
+    /// How long until the next beat of the clock being followed or sent, if it is running.
+    pub fn until_next_beat(&self) -> Option<f64> {
+        let beat_seconds = Tempo::from_bpm(self.tempo_slider.load().current()).beat_seconds();
+        let last_beat = self.last_beat.load()?;
+        let since = last_beat.elapsed().as_secs_f64();
+        (since < 2.0 * beat_seconds).then_some((beat_seconds - since).max(0.0))
+    }
+
+    fn send_byte(&self, byte: u8) {
+        if let Some(output) = self.output.lock().unwrap().as_mut() {
+            output(&[byte]);
+        }
+    }
+}
+
+/// While `sync.send` is set and there is an output, sends Start, then Timing Clock at the
+/// tempo slider's tempo, then Stop when sending is turned off. Timing Clock is sent by
+/// `scheduler`, a little ahead of time, and each message is timed from the one before, so
+/// the clock does not drift.
+pub fn start_clock_output_thread(
+    sync: ClockSync,
+    scheduler: Scheduler,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(CLOCK_OUTPUT_THREAD, SINGLETON, move || {
+        let source = scheduler.source();
+        let lookahead = Duration::from_millis(CLOCK_LOOKAHEAD_MILLIS);
+        let mut running: Option<(Instant, u32)> = None;
+        while !quit.load() {
+            let sending = sync.send.load() && sync.has_output();
+            match running {
+                Some((mut next, mut count)) if sending => {
+                    let horizon = Instant::now() + lookahead;
+                    while next < horizon {
+                        let beat = count % CLOCKS_PER_BEAT == 0;
+                        let sync = sync.clone();
+                        scheduler.schedule_call(source, next, move || {
+                            sync.send_byte(TIMING_CLOCK);
+                            if beat {
+                                sync.last_beat.store(Some(next));
+                            }
+                        });
+                        let beat_seconds =
+                            Tempo::from_bpm(sync.tempo_slider.load().current()).beat_seconds();
+                        next += Duration::from_secs_f64(beat_seconds / CLOCKS_PER_BEAT as f64);
+                        count += 1;
+                    }
+                    running = Some((next, count));
+                    thread::sleep(lookahead / 2);
+                }
+                None if sending => {
+                    info!("Sending MIDI clock");
+                    let now = Instant::now();
+                    let sync = sync.clone();
+                    scheduler.schedule_call(source, now, move || sync.send_byte(START));
+                    running = Some((now, 0));
+                }
+                Some(_) => {
+                    // Start and Stop are sent by the scheduler too, so that they keep their
+                    // places among the Timing Clock already due.
+                    scheduler.cancel(source);
+                    let sync = sync.clone();
+                    scheduler.schedule_call(source, Instant::now(), move || {
+                        sync.send_byte(STOP);
+                        sync.last_beat.store(None);
+                    });
+                    running = None;
+                }
+                None => thread::sleep(Duration::from_millis(CLOCK_IDLE_MILLIS)),
+            }
+        }
+        if running.is_some() {
+            scheduler.cancel(source);
+            sync.send_byte(STOP);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::BlockingQueue;
+    use crate::scheduler::start_test_scheduler_thread;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_clock_tempo() {
+        let mut clock = ClockTempo::new();
+        let start = Instant::now();
+        let tick = 0.5 / CLOCKS_PER_BEAT as f64;
+        let at = |i: u32| start + Duration::from_secs_f64(i as f64 * tick);
+        for i in 0..CLOCKS_PER_BEAT {
+            assert!(clock.tick_at(at(i)).is_none());
+        }
+        let tempo = clock.tick_at(at(CLOCKS_PER_BEAT)).unwrap();
+        assert_approx_eq!(f64, tempo.bpm(), 120.0, epsilon = 0.001);
+        assert_eq!(clock.last_beat(), Some(at(CLOCKS_PER_BEAT)));
+        clock.start();
+        clock.tick_at(at(CLOCKS_PER_BEAT + 5));
+        assert_eq!(clock.last_beat(), Some(at(CLOCKS_PER_BEAT + 5)));
+        assert!(clock.tick_at(at(CLOCKS_PER_BEAT * 3)).is_none());
+
+        // Stopped, moved on a bar and two sixteenths by Song Position Pointer, continued.
+        clock.stop();
+        assert_eq!(clock.last_beat(), None);
+        clock.tick_at(at(CLOCKS_PER_BEAT * 3 + 1));
+        assert_eq!(clock.last_beat(), None);
+        clock.relocate(18);
+        clock.resume();
+        let resumed = CLOCKS_PER_BEAT * 3 + 2;
+        for i in 0..2 * CLOCKS_PER_SONG_POSITION {
+            clock.tick_at(at(resumed + i));
+            assert_eq!(clock.last_beat(), None);
+        }
+        clock.tick_at(at(resumed + 12));
+        assert_eq!(clock.last_beat(), Some(at(resumed + 12)));
+
+        let sync = ClockSync::new(
+            Arc::new(AtomicCell::new(SliderValue::new(100.0, 40.0, 240.0))),
+            Arc::new(AtomicCell::new(false)),
+        );
+        assert!(sync.until_next_beat().is_none());
+        let sent = Arc::new(Mutex::new(vec![]));
+        let received = sent.clone();
+        sync.set_output(Some(Box::new(move |bytes: &[u8]| {
+            received.lock().unwrap().extend_from_slice(bytes)
+        })));
+        sync.send.store(true);
+        let quit = Arc::new(AtomicCell::new(false));
+        let scheduler = Scheduler::new(Arc::new(BlockingQueue::new()));
+        let scheduling = start_test_scheduler_thread(scheduler.clone(), quit.clone());
+        start_clock_output_thread(sync.clone(), scheduler, quit.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(sync.until_next_beat().unwrap() <= 0.6);
+        sync.send.store(false);
+        thread::sleep(Duration::from_millis(50));
+        quit.store(true);
+        scheduling.join().unwrap();
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.first(), Some(&START));
+        assert_eq!(sent.last(), Some(&STOP));
+        assert!(sent.iter().filter(|b| **b == TIMING_CLOCK).count() > 1);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::ai_variation::PERCUSSION_CHANNEL;
+use crate::analyzer::{Melody, MidiByte, Note};
+use anyhow::anyhow;
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind,
+};
+use std::fs;
+
+const TICKS_PER_BEAT: u16 = 480;
+/// The Standard MIDI File default of 120 beats per minute.
+pub const MICROS_PER_BEAT: u32 = 500_000;
+
+/// Reads a melody from the note events of every track of a Standard MIDI File, in the same
+/// form the recorder produces from live input: each NoteOn or NoteOff lasts until the next.
+/// Percussion is skipped, as it is when recording.
+pub fn melody_from_smf(bytes: &[u8]) -> anyhow::Result<Melody> {
+    let smf = Smf::parse(bytes)?;
+    let (events, seconds) = timed_events(&smf);
+    let mut onsets: Vec<(f64, MidiByte, MidiByte)> = vec![];
+    for (at, kind) in events {
+        match kind {
+            TrackEventKind::Midi { channel, message }
+                if channel.as_int() != PERCUSSION_CHANNEL as u8 =>
+            {
+                match message {
+                    MidiMessage::NoteOn { key, vel } => {
+                        onsets.push((at, key.as_int() as MidiByte, vel.as_int() as MidiByte))
+                    }
+                    MidiMessage::NoteOff { key, .. } => {
+                        onsets.push((at, key.as_int() as MidiByte, 0))
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if onsets.is_empty() {
+        return Err(anyhow!("No notes found"));
+    }
+
+    let mut melody = Melody::new();
+    for (i, (start, pitch, velocity)) in onsets.iter().enumerate() {
+        let end = onsets.get(i + 1).map_or(seconds, |(next, _, _)| *next);
+        let duration = end - start;
+        // A NoteOff directly followed by the next NoteOn is not a rest.
+        if *velocity > 0 || duration > 0.0 || i + 1 == onsets.len() {
+            melody.add(Note::new(*pitch, duration, *velocity));
+        }
+    }
+    Ok(melody)
+}
+
+/// Every event of every track of `smf` in order, each with the number of seconds into the
+/// file at which it happens, following the file's tempo changes. Also returns the length of
+/// the file in seconds.
+pub fn timed_events<'a>(smf: &Smf<'a>) -> (Vec<(f64, TrackEventKind<'a>)>, f64) {
+    let mut events = vec![];
+    for track in smf.tracks.iter() {
+        let mut tick = 0;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut seconds_per_tick = match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => {
+            MICROS_PER_BEAT as f64 / 1_000_000.0 / ticks_per_beat.as_int() as f64
+        }
+        Timing::Timecode(fps, subframes) => 1.0 / (fps.as_f32() as f64 * subframes as f64),
+    };
+    let mut seconds = 0.0;
+    let mut prev_tick = 0;
+    let mut timed = vec![];
+    for (tick, kind) in events {
+        seconds += (tick - prev_tick) as f64 * seconds_per_tick;
+        prev_tick = tick;
+        if let TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat)) = kind {
+            if let Timing::Metrical(ticks_per_beat) = smf.header.timing {
+                seconds_per_tick =
+                    micros_per_beat.as_int() as f64 / 1_000_000.0 / ticks_per_beat.as_int() as f64;
+            }
+        }
+        timed.push((seconds, kind));
+    }
+    (timed, seconds)
+}
+
+/// Writes `melody` as a single-track Standard MIDI File at 120 beats per minute.
+pub fn melody_to_smf(melody: &Melody) -> anyhow::Result<Vec<u8>> {
+    let seconds_per_tick = MICROS_PER_BEAT as f64 / 1_000_000.0 / TICKS_PER_BEAT as f64;
+    let mut track: Track = vec![TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(MICROS_PER_BEAT))),
+    }];
+    let mut delta = 0;
+    let mut sounding = None;
+    for note in melody.iter() {
+        if let Some(key) = sounding.take() {
+            track.push(note_event(
+                delta,
+                MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                },
+            ));
+            delta = 0;
+        }
+        if !note.is_rest() {
+            let key = u7::new(note.pitch() as u8);
+            let vel = u7::new(note.velocity() as u8);
+            track.push(note_event(delta, MidiMessage::NoteOn { key, vel }));
+            delta = 0;
+            sounding = Some(key);
+        }
+        delta += (note.duration() / seconds_per_tick).round() as u32;
+    }
+    if let Some(key) = sounding {
+        track.push(note_event(
+            delta,
+            MidiMessage::NoteOff {
+                key,
+                vel: u7::new(0),
+            },
+        ));
+        delta = 0;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    let smf = Smf {
+        header: Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+        ),
+        tracks: vec![track],
+    };
+    let mut bytes = vec![];
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn note_event(delta: u32, message: MidiMessage) -> TrackEvent<'static> {
+    TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Midi {
+            channel: u4::new(0),
+            message,
+        },
+    }
+}
+
+pub fn load_midi_file(filename: &str) -> anyhow::Result<Melody> {
+    melody_from_smf(fs::read(filename)?.as_slice())
+}
+
+pub fn save_midi_file(melody: &Melody, filename: &str) -> anyhow::Result<()> {
+    fs::write(filename, melody_to_smf(melody)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_smf_round_trip() {
+        let melody = Melody::from("60,0.5,0.8,62,0.25,0.7,64,0.25,0.7,64,0.5,0.0");
+        let bytes = melody_to_smf(&melody).unwrap();
+        let restored = melody_from_smf(bytes.as_slice()).unwrap();
+        assert_eq!(restored.len(), melody.len());
+        for (restored, original) in restored.iter().zip(melody.iter()) {
+            assert_approx_eq!(Note, *restored, *original, epsilon = 0.001);
+        }
+        assert!(melody_from_smf(&[0, 1, 2]).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+use crate::backends::MidiSource;
+use crate::midi_clock::ClockSync;
+use crate::midi_learn::MidiLearn;
+use crate::queue::BlockingQueue;
+use crate::runtime::HUMAN_SPEAKER;
+use crate::threads::{spawn_named, INPUT_THREAD, REPLAY_THREAD, SINGLETON};
+use crate::watchdog::{PanicButton, StuckNoteTracker};
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::SynthMsg;
+use midi_msg::{ChannelModeMsg, ChannelVoiceMsg, MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace, trace_span, warn};
+
+const INPUT_POLL_MILLIS: u64 = 10;
+const ACTIVE_SENSING_TIMEOUT_MILLIS: u128 = 300;
+/// Status bytes from here up are real-time messages of a single byte, which may arrive
+/// anywhere in the stream, even in the middle of another message.
+const REAL_TIME_STATUS: u8 = 0xf8;
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+/// Longer SysEx messages are dropped rather than collected.
+const MAX_SYSEX_BYTES: usize = 65536;
+
+/// Receives each complete SysEx message, from its 0xF0 through its 0xF7.
+pub type SysExHandler = Arc<Mutex<Option<Box<dyn FnMut(&[u8]) + Send>>>>;
+
+/// A single raw MIDI packet, exactly as delivered by `midir`, with its timestamp in microseconds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawMidiEvent {
+    micros: u64,
+    bytes: Vec<u8>,
+}
+
+impl RawMidiEvent {
+    pub fn new(micros: u64, bytes: &[u8]) -> Self {
+        RawMidiEvent {
+            micros,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    pub fn micros(&self) -> u64 {
+        self.micros
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// One event per line: the timestamp followed by the bytes in hex.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{}", self.micros);
+        for byte in self.bytes.iter() {
+            line.push_str(format!(" {byte:02x}").as_str());
+        }
+        line
+    }
+
+    pub fn from_line(line: &str) -> anyhow::Result<Self> {
+        let mut parts = line.split_whitespace();
+        let micros = parts
+            .next()
+            .ok_or(anyhow!("Empty MIDI capture line"))?
+            .parse::<u64>()?;
+        let mut bytes = vec![];
+        for part in parts {
+            bytes.push(u8::from_str_radix(part, 16)?);
+        }
+        Ok(RawMidiEvent { micros, bytes })
+    }
+}
+
+/// Captures raw MIDI input while `recording` is set, so that the exact byte stream behind a
+/// parsing or phrase-detection problem can be saved and replayed later. A capture can also
+/// log every event straight to a file as it arrives, so that the input leading up to a
+/// crash is not lost with the program.
+#[derive(Clone)]
+pub struct MidiCapture {
+    pub recording: Arc<AtomicCell<bool>>,
+    events: Arc<Mutex<Vec<RawMidiEvent>>>,
+    log: Arc<Mutex<Option<LineWriter<File>>>>,
+}
+
+impl MidiCapture {
+    pub fn new() -> Self {
+        MidiCapture {
+            recording: Arc::new(AtomicCell::new(false)),
+            events: Arc::new(Mutex::new(vec![])),
+            log: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Writes every event from now on to `filename`, in the format `load_capture` reads,
+    /// whether or not `recording` is set.
+    pub fn log_to(&self, filename: &str) -> anyhow::Result<()> {
+        let file = File::create(filename)?;
+        *self.log.lock().unwrap() = Some(LineWriter::new(file));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    fn capture(&self, micros: u64, bytes: &[u8]) {
+        if let Some(log) = self.log.lock().unwrap().as_mut() {
+            if let Err(e) = writeln!(log, "{}", RawMidiEvent::new(micros, bytes).to_line()) {
+                warn!("Could not log MIDI input: {e}");
+            }
+        }
+        if self.recording.load() {
+            self.events
+                .lock()
+                .unwrap()
+                .push(RawMidiEvent::new(micros, bytes));
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> anyhow::Result<()> {
+        let mut file = File::create(filename)?;
+        for event in self.events.lock().unwrap().iter() {
+            writeln!(file, "{}", event.to_line())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_capture(filename: &str) -> anyhow::Result<Vec<RawMidiEvent>> {
+    let reader = BufReader::new(File::open(filename)?);
+    let mut events = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            events.push(RawMidiEvent::from_line(line.as_str())?);
+        }
+    }
+    Ok(events)
+}
+
+/// Reassembles the incoming byte stream into whole messages, however the driver divides
+/// it into packets. Data bytes without a status byte of their own take the last channel
+/// status, as running status allows, and real-time bytes are picked out wherever they
+/// fall. SysEx is collected until its end and passed to a `SysExHandler` instead. Data
+/// bytes that belong to no message are skipped with a warning.
+#[derive(Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+    pending: Vec<u8>,
+    sysex: Option<Vec<u8>>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the messages that `bytes` completes, each beginning with its status byte.
+    pub fn parse(&mut self, bytes: &[u8], sysex: &SysExHandler) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        for byte in bytes.iter().copied() {
+            self.parse_byte(byte, sysex, &mut messages);
+        }
+        messages
+    }
+
+    fn parse_byte(&mut self, byte: u8, sysex: &SysExHandler, messages: &mut Vec<Vec<u8>>) {
+        if byte >= REAL_TIME_STATUS {
+            messages.push(vec![byte]);
+            return;
+        }
+        if let Some(mut collected) = self.sysex.take() {
+            if byte == SYSEX_END {
+                collected.push(byte);
+                Self::send_sysex(&collected, sysex);
+                return;
+            } else if byte < 0x80 {
+                if collected.len() <= MAX_SYSEX_BYTES {
+                    collected.push(byte);
+                }
+                self.sysex = Some(collected);
+                return;
+            }
+            warn!("SysEx cut short by status byte {byte:02x}");
+        }
+        if byte < 0x80 {
+            if self.pending.is_empty() {
+                match self.running_status {
+                    Some(status) => self.pending.push(status),
+                    None => {
+                        warn!("Skipping MIDI data byte {byte:02x} with no status byte");
+                        return;
+                    }
+                }
+            }
+            self.pending.push(byte);
+        } else {
+            if !self.pending.is_empty() {
+                warn!("Skipping incomplete MIDI message {:02x?}", self.pending);
+            }
+            // Only channel messages may be continued by running status.
+            self.running_status = (byte < SYSEX_START).then_some(byte);
+            if byte == SYSEX_START {
+                self.pending.clear();
+                self.sysex = Some(vec![byte]);
+                return;
+            }
+            self.pending = vec![byte];
+        }
+        if self.pending.len() == message_len(self.pending[0]) {
+            messages.push(std::mem::take(&mut self.pending));
+        }
+    }
+
+    fn send_sysex(collected: &[u8], sysex: &SysExHandler) {
+        if collected.len() > MAX_SYSEX_BYTES {
+            warn!("Dropping a SysEx message longer than {MAX_SYSEX_BYTES} bytes");
+        } else if let Some(handler) = sysex.lock().unwrap().as_mut() {
+            handler(collected);
+        } else {
+            debug!("Ignoring a SysEx message of {} bytes", collected.len());
+        }
+    }
+}
+
+/// How many bytes a message with this status byte has, including the status byte.
+fn message_len(status: u8) -> usize {
+    match status {
+        0xc0..=0xdf | 0xf1 | 0xf3 => 2,
+        0x80..=0xef | 0xf2 => 3,
+        _ => 1,
+    }
+}
+
+/// Handles the system messages that older keyboards rely on. A device that has sent Active
+/// Sensing promises to send something at least every 300ms, so a longer silence means it was
+/// unplugged or switched off. Either that or a System Reset releases every note still held.
+/// Control changes bound through `learn` set their sliders instead of reaching the synth,
+/// and program changes are left in `program_change` for the GUI to select a synthesizer.
+/// All Sound Off and All Notes Off press `panic_button`, MIDI clock goes to `clock`, and
+/// SysEx to `sysex`.
+struct InputMonitor {
+    learn: Arc<Mutex<MidiLearn>>,
+    program_change: Arc<AtomicCell<Option<u8>>>,
+    panic_button: PanicButton,
+    clock: ClockSync,
+    sysex: SysExHandler,
+    parser: MidiParser,
+    held: StuckNoteTracker,
+    sensing: bool,
+    last_message: Instant,
+}
+
+impl InputMonitor {
+    fn new(
+        learn: Arc<Mutex<MidiLearn>>,
+        program_change: Arc<AtomicCell<Option<u8>>>,
+        panic_button: PanicButton,
+        clock: ClockSync,
+        sysex: SysExHandler,
+    ) -> Self {
+        InputMonitor {
+            learn,
+            program_change,
+            panic_button,
+            clock,
+            sysex,
+            parser: MidiParser::new(),
+            held: StuckNoteTracker::new(),
+            sensing: false,
+            last_message: Instant::now(),
+        }
+    }
+
+    fn receive(&mut self, input2ai: &BlockingQueue<SynthMsg>, bytes: &[u8]) {
+        self.last_message = Instant::now();
+        for message in self.parser.parse(bytes, &self.sysex) {
+            self.receive_message(input2ai, &message);
+        }
+    }
+
+    fn receive_message(&mut self, input2ai: &BlockingQueue<SynthMsg>, bytes: &[u8]) {
+        if self.learn.lock().unwrap().handle_midi(bytes) {
+            return;
+        }
+        match MidiMsg::from_midi(bytes) {
+            Ok((msg, _)) => {
+                trace!("{msg:?}");
+                match msg {
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::ActiveSensing,
+                    } => self.sensing = true,
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::SystemReset,
+                    } => {
+                        info!("MIDI System Reset received");
+                        self.sensing = false;
+                        self.release_all(input2ai);
+                    }
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::TimingClock,
+                    } => self.clock.receive_tick(),
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::Start,
+                    } => self.clock.receive_start(),
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::Continue,
+                    } => self.clock.receive_continue(),
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::Stop,
+                    } => self.clock.receive_stop(),
+                    MidiMsg::SystemCommon {
+                        msg: SystemCommonMsg::SongPosition(sixteenths),
+                    } => self.clock.receive_song_position(sixteenths),
+                    MidiMsg::ChannelVoice {
+                        msg: ChannelVoiceMsg::ProgramChange { program },
+                        ..
+                    } => self.program_change.store(Some(program)),
+                    MidiMsg::ChannelMode {
+                        msg: ChannelModeMsg::AllSoundOff | ChannelModeMsg::AllNotesOff,
+                        ..
+                    } => {
+                        info!("MIDI All Notes Off received");
+                        self.held = StuckNoteTracker::new();
+                        self.panic_button.press();
+                    }
+                    msg => {
+                        let synth_msg = SynthMsg {
+                            msg,
+                            speaker: HUMAN_SPEAKER,
+                        };
+                        self.held.observe(&synth_msg);
+                        input2ai.push(synth_msg);
+                    }
+                }
+            }
+            Err(e) => warn!("Skipping MIDI message {bytes:02x?}: {e:?}"),
+        }
+    }
+
+    fn timed_out(&self) -> bool {
+        self.sensing && self.last_message.elapsed().as_millis() > ACTIVE_SENSING_TIMEOUT_MILLIS
+    }
+
+    fn release_all(&mut self, input2ai: &BlockingQueue<SynthMsg>) {
+        for release in self.held.release_all() {
+            input2ai.push(release);
+        }
+        input2ai.push(SynthMsg::all_notes_off(HUMAN_SPEAKER));
+    }
+}
+
+pub fn start_input_thread(
+    input2ai: Arc<BlockingQueue<SynthMsg>>,
+    source: Box<dyn MidiSource>,
+    capture: MidiCapture,
+    learn: Arc<Mutex<MidiLearn>>,
+    program_change: Arc<AtomicCell<Option<u8>>>,
+    panic_button: PanicButton,
+    clock: ClockSync,
+    sysex: SysExHandler,
+    connected: Arc<AtomicCell<bool>>,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(INPUT_THREAD, SINGLETON, move || {
+        let monitor = InputMonitor::new(learn, program_change, panic_button, clock, sysex);
+        let monitor = Arc::new(Mutex::new(monitor));
+        let name = source.name();
+        let span = info_span!("midi input", source = %name);
+        let _entered = span.enter();
+        let conn_in = {
+            let input2ai = input2ai.clone();
+            let monitor = monitor.clone();
+            let connected = connected.clone();
+            let span = span.clone();
+            source.connect(Box::new(move |stamp, message| {
+                // The callback runs on the MIDI driver's thread, outside the input thread's span.
+                let _receipt = trace_span!(parent: &span, "midi receipt", stamp).entered();
+                capture.capture(stamp, message);
+                monitor.lock().unwrap().receive(&input2ai, message);
+                connected.store(true);
+            }))
+        };
+        let conn_in = match conn_in {
+            Ok(conn_in) => conn_in,
+            Err(e) => {
+                error!("Could not connect to {name}: {e}");
+                return;
+            }
+        };
+        while !quit.load() {
+            {
+                let mut monitor = monitor.lock().unwrap();
+                if monitor.timed_out() {
+                    warn!(
+                        "MIDI device stopped sending Active Sensing; treating it as disconnected"
+                    );
+                    monitor.sensing = false;
+                    monitor.release_all(&input2ai);
+                    connected.store(false);
+                }
+            }
+            thread::sleep(Duration::from_millis(INPUT_POLL_MILLIS));
+        }
+        conn_in.close();
+        info!("MIDI input closed");
+    })
+}
+
+/// Re-injects a saved capture into the input queue, preserving the original timing between
+/// events, as if it were being played live.
+pub fn start_replay_thread(
+    input2ai: Arc<BlockingQueue<SynthMsg>>,
+    events: Vec<RawMidiEvent>,
+    learn: Arc<Mutex<MidiLearn>>,
+    program_change: Arc<AtomicCell<Option<u8>>>,
+    panic_button: PanicButton,
+    clock: ClockSync,
+    sysex: SysExHandler,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(REPLAY_THREAD, SINGLETON, move || {
+        let _span = info_span!("midi replay", events = events.len()).entered();
+        let mut monitor = InputMonitor::new(learn, program_change, panic_button, clock, sysex);
+        let mut prev_micros = events.first().map_or(0, |e| e.micros);
+        for event in events.iter() {
+            if quit.load() {
+                break;
+            }
+            thread::sleep(Duration::from_micros(
+                event.micros.saturating_sub(prev_micros),
+            ));
+            prev_micros = event.micros;
+            monitor.receive(&input2ai, event.bytes());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::SliderValue;
+
+    #[test]
+    fn test_event_lines() {
+        let event = RawMidiEvent::new(123456, &[0x90, 60, 0x7f]);
+        assert_eq!(event.to_line(), "123456 90 3c 7f");
+        assert_eq!(
+            RawMidiEvent::from_line(event.to_line().as_str()).unwrap(),
+            event
+        );
+        assert!(RawMidiEvent::from_line("").is_err());
+        assert!(RawMidiEvent::from_line("12 zz").is_err());
+    }
+
+    #[test]
+    fn test_capture_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("midi_log.txt");
+        let filename = filename.to_str().unwrap();
+        let capture = MidiCapture::new();
+        capture.log_to(filename).unwrap();
+        capture.capture(100, &[0x90, 60, 0x7f]);
+        capture.recording.store(true);
+        capture.capture(250, &[0x80, 60, 0]);
+        assert_eq!(capture.len(), 1);
+        let logged = load_capture(filename).unwrap();
+        assert_eq!(
+            logged,
+            vec![
+                RawMidiEvent::new(100, &[0x90, 60, 0x7f]),
+                RawMidiEvent::new(250, &[0x80, 60, 0])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_input_monitor() {
+        let input2ai = BlockingQueue::new();
+        let program_change = Arc::new(AtomicCell::new(None));
+        let panic_button = PanicButton::new();
+        let clock = ClockSync::new(
+            Arc::new(AtomicCell::new(SliderValue::new(100.0, 40.0, 240.0))),
+            Arc::new(AtomicCell::new(false)),
+        );
+        clock.follow.store(true);
+        let mut monitor = InputMonitor::new(
+            Arc::new(Mutex::new(MidiLearn::new())),
+            program_change.clone(),
+            panic_button.clone(),
+            clock.clone(),
+            Arc::new(Mutex::new(None)),
+        );
+        monitor.receive(&input2ai, &[0xfe]);
+        assert!(monitor.sensing);
+        assert!(!monitor.timed_out());
+        assert!(input2ai.is_empty());
+
+        monitor.receive(&input2ai, &[0xfa]);
+        monitor.receive(&input2ai, &[0xf8]);
+        assert!(input2ai.is_empty());
+        assert!(clock.until_next_beat().is_some());
+
+        monitor.receive(&input2ai, &[0xc0, 3]);
+        assert_eq!(program_change.take(), Some(3));
+        assert!(input2ai.is_empty());
+
+        monitor.receive(&input2ai, &[0x90, 60, 0x7f]);
+        monitor.receive(&input2ai, &[0xff]);
+        assert!(!monitor.sensing);
+        assert_eq!(input2ai.len(), 3);
+        input2ai.pop();
+        match input2ai.pop().unwrap().msg {
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { note, .. },
+                ..
+            } => assert_eq!(note, 60),
+            _ => panic!("Expected a NoteOff"),
+        }
+
+        monitor.receive(&input2ai, &[0x90, 62, 0x7f]);
+        input2ai.pop();
+        monitor.receive(&input2ai, &[0xb0, 123, 0]);
+        assert!(input2ai.is_empty());
+        assert_eq!(monitor.held.num_held(), 0);
+        assert!(panic_button.take());
+    }
+
+    #[test]
+    fn test_midi_parser() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let handler: Box<dyn FnMut(&[u8]) + Send> = {
+            let received = received.clone();
+            Box::new(move |bytes: &[u8]| received.lock().unwrap().push(bytes.to_vec()))
+        };
+        let sysex: SysExHandler = Arc::new(Mutex::new(Some(handler)));
+        let mut parser = MidiParser::new();
+        assert!(parser.parse(&[60, 0x7f], &sysex).is_empty());
+        assert_eq!(
+            parser.parse(&[0x90, 60, 0x7f, 62], &sysex),
+            vec![vec![0x90, 60, 0x7f]]
+        );
+        assert_eq!(
+            parser.parse(&[0xf8, 0x7f, 64, 0], &sysex),
+            vec![vec![0xf8], vec![0x90, 62, 0x7f], vec![0x90, 64, 0]]
+        );
+        assert_eq!(
+            parser.parse(&[0xf0, 0x7e, 0x7f, 0xf8], &sysex),
+            vec![vec![0xf8]]
+        );
+        assert_eq!(
+            parser.parse(&[0x06, 0x01, 0xf7, 0xc0, 3], &sysex),
+            vec![vec![0xc0, 3]]
+        );
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![vec![0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7]]
+        );
+        assert_eq!(
+            parser.parse(&[0xf0, 1, 2, 0x80, 60, 0], &sysex),
+            vec![vec![0x80, 60, 0]]
+        );
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
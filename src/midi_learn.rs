@@ -0,0 +1,252 @@
+use crate::pitch;
+use crate::runtime::SliderValue;
+use crate::tempo::TapTempo;
+use crossbeam_utils::atomic::AtomicCell;
+use std::collections::btree_map::BTreeMap;
+use std::sync::Arc;
+
+pub const TAP_TEMPO: &str = "Tap Tempo";
+
+const NOTE_OFF_STATUS: u8 = 0x80;
+const NOTE_ON_STATUS: u8 = 0x90;
+const CONTROL_CHANGE_STATUS: u8 = 0xb0;
+const MAX_CC_VALUE: f64 = 127.0;
+/// Controllers 120 and above are channel mode messages, such as All Notes Off.
+const FIRST_CHANNEL_MODE_CONTROL: u8 = 120;
+
+/// Maps controller knobs to sliders. Once a slider is armed, the next control change
+/// received is bound to it, and thereafter that controller sets the slider. Arming
+/// `TAP_TEMPO` instead binds the next key pressed, which then acts as a tap-tempo pad.
+#[derive(Default)]
+pub struct MidiLearn {
+    sliders: Vec<(String, Arc<AtomicCell<SliderValue<f64>>>)>,
+    control2slider: BTreeMap<u8, String>,
+    armed: Option<String>,
+    tap_tempo: TapTempo,
+    tempo: Option<(Arc<AtomicCell<SliderValue<f64>>>, Arc<AtomicCell<bool>>)>,
+    tap_note: Option<u8>,
+}
+
+impl MidiLearn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: &str, slider: Arc<AtomicCell<SliderValue<f64>>>) {
+        self.sliders.push((name.to_owned(), slider));
+    }
+
+    /// Taps set `tempo_slider` and turn on `fixed_tempo`.
+    pub fn register_tap_tempo(
+        &mut self,
+        tempo_slider: Arc<AtomicCell<SliderValue<f64>>>,
+        fixed_tempo: Arc<AtomicCell<bool>>,
+    ) {
+        self.tempo = Some((tempo_slider, fixed_tempo));
+    }
+
+    pub fn binding_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.sliders.iter().map(|(name, _)| name.clone()).collect();
+        if self.tempo.is_some() {
+            names.push(TAP_TEMPO.to_owned());
+        }
+        names
+    }
+
+    pub fn binding_label(&self, name: &str) -> String {
+        let binding = if name == TAP_TEMPO {
+            self.tap_note
+                .map(|note| format!("Note {}", pitch::name(note)))
+        } else {
+            self.control_for(name)
+                .map(|control| format!("CC {control}"))
+        };
+        binding.unwrap_or_else(|| "unmapped".to_owned())
+    }
+
+    pub fn tap(&mut self) {
+        if let Some((tempo_slider, fixed_tempo)) = &self.tempo {
+            if let Some(tempo) = self.tap_tempo.tap() {
+                let sv = tempo_slider.load();
+                let range = sv.make_range();
+                tempo_slider.store(sv.slid_to(tempo.bpm().clamp(*range.start(), *range.end())));
+                fixed_tempo.store(true);
+            }
+        }
+    }
+
+    pub fn arm(&mut self, name: &str) {
+        self.armed = Some(name.to_owned());
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed = None;
+    }
+
+    pub fn is_armed(&self, name: &str) -> bool {
+        self.armed.as_deref() == Some(name)
+    }
+
+    pub fn control_for(&self, name: &str) -> Option<u8> {
+        self.control2slider
+            .iter()
+            .find(|(_, slider)| slider.as_str() == name)
+            .map(|(control, _)| *control)
+    }
+
+    pub fn forget(&mut self, name: &str) {
+        if name == TAP_TEMPO {
+            self.tap_note = None;
+        } else {
+            self.control2slider.retain(|_, slider| slider != name);
+        }
+    }
+
+    /// Returns true if `bytes` was a control change bound to a slider or a press of the
+    /// tap-tempo key, in which case it should not be passed along to the synthesizer.
+    pub fn handle_midi(&mut self, bytes: &[u8]) -> bool {
+        if bytes.len() < 3 {
+            return false;
+        }
+        let (status, data, value) = (bytes[0] & 0xf0, bytes[1], bytes[2]);
+        match status {
+            NOTE_ON_STATUS | NOTE_OFF_STATUS => {
+                self.handle_note(data, status == NOTE_ON_STATUS && value > 0)
+            }
+            CONTROL_CHANGE_STATUS if data < FIRST_CHANNEL_MODE_CONTROL => {
+                self.handle_control(data, value)
+            }
+            _ => false,
+        }
+    }
+
+    fn handle_note(&mut self, note: u8, pressed: bool) -> bool {
+        if pressed && self.is_armed(TAP_TEMPO) {
+            self.armed = None;
+            self.tap_note = Some(note);
+        }
+        if self.tap_note == Some(note) {
+            if pressed {
+                self.tap();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_control(&mut self, control: u8, value: u8) -> bool {
+        if self.armed.is_some() && !self.is_armed(TAP_TEMPO) {
+            let name = self.armed.take().unwrap();
+            self.control2slider.retain(|_, slider| *slider != name);
+            self.control2slider.insert(control, name);
+        }
+        match self.control2slider.get(&control) {
+            Some(name) => {
+                if let Some((_, slider)) = self.sliders.iter().find(|(n, _)| n == name) {
+                    let sv = slider.load();
+                    let range = sv.make_range();
+                    let fraction = value as f64 / MAX_CC_VALUE;
+                    slider.store(
+                        sv.slid_to(range.start() + fraction * (range.end() - range.start())),
+                    );
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The controller bound to each slider by name, and the key bound to `TAP_TEMPO`, as
+    /// `Config::midi_mappings` keeps them.
+    pub fn mappings(&self) -> BTreeMap<String, u8> {
+        let mut mappings: BTreeMap<String, u8> = self
+            .control2slider
+            .iter()
+            .map(|(control, name)| (name.clone(), *control))
+            .collect();
+        if let Some(note) = self.tap_note {
+            mappings.insert(TAP_TEMPO.to_owned(), note);
+        }
+        mappings
+    }
+
+    /// Replaces every binding with those in `mappings`, as returned by `mappings()`.
+    pub fn set_mappings(&mut self, mappings: &BTreeMap<String, u8>) {
+        self.control2slider.clear();
+        self.tap_note = None;
+        for (name, value) in mappings.iter() {
+            if name == TAP_TEMPO {
+                self.tap_note = Some(*value);
+            } else {
+                self.control2slider.insert(*value, name.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::prob_slider;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_midi_learn() {
+        let slider = Arc::new(AtomicCell::new(prob_slider(0.5)));
+        let mut learn = MidiLearn::new();
+        learn.register("Randomization", slider.clone());
+        assert!(!learn.handle_midi(&[0xb0, 7, 127]));
+
+        learn.arm("Randomization");
+        assert!(learn.is_armed("Randomization"));
+        assert!(learn.handle_midi(&[0xb1, 7, 0]));
+        assert!(!learn.is_armed("Randomization"));
+        assert_eq!(learn.control_for("Randomization"), Some(7));
+        assert_approx_eq!(f64, slider.load().current(), 0.0);
+        assert!(learn.handle_midi(&[0xb0, 7, 127]));
+        assert_approx_eq!(f64, slider.load().current(), 1.0);
+
+        let mappings = learn.mappings();
+        assert_eq!(mappings.get("Randomization"), Some(&7));
+        let mut restored = MidiLearn::new();
+        restored.register("Randomization", slider.clone());
+        restored.set_mappings(&mappings);
+        assert_eq!(restored.control_for("Randomization"), Some(7));
+        assert!(restored.handle_midi(&[0xb0, 7, 0]));
+        assert_approx_eq!(f64, slider.load().current(), 0.0);
+
+        assert!(!learn.handle_midi(&[0xb0, 123, 0]));
+        assert!(!learn.handle_midi(&[0x90, 7, 100]));
+
+        learn.forget("Randomization");
+        assert_eq!(learn.control_for("Randomization"), None);
+        assert!(!learn.handle_midi(&[0xb0, 7, 64]));
+    }
+
+    #[test]
+    fn test_tap_note() {
+        let tempo_slider = Arc::new(AtomicCell::new(SliderValue::new(100.0, 40.0, 240.0)));
+        let fixed_tempo = Arc::new(AtomicCell::new(false));
+        let mut learn = MidiLearn::new();
+        learn.register_tap_tempo(tempo_slider.clone(), fixed_tempo.clone());
+        assert!(learn.binding_names().contains(&TAP_TEMPO.to_owned()));
+        assert_eq!(learn.binding_label(TAP_TEMPO), "unmapped");
+
+        learn.arm(TAP_TEMPO);
+        assert!(!learn.handle_midi(&[0xb0, 7, 64]));
+        assert!(learn.is_armed(TAP_TEMPO));
+        assert!(learn.handle_midi(&[0x90, 36, 100]));
+        assert_eq!(learn.binding_label(TAP_TEMPO), "Note C2");
+        assert_eq!(learn.mappings().get(TAP_TEMPO), Some(&36));
+        assert!(learn.handle_midi(&[0x80, 36, 0]));
+        assert!(!learn.handle_midi(&[0x90, 60, 100]));
+
+        for _ in 0..3 {
+            learn.handle_midi(&[0x90, 36, 100]);
+            learn.handle_midi(&[0x90, 36, 0]);
+        }
+        assert!(fixed_tempo.load());
+        assert_approx_eq!(f64, tempo_slider.load().current(), 240.0);
+    }
+}
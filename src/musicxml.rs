@@ -0,0 +1,227 @@
+use crate::analyzer::{Melody, MidiByte, MusicMode};
+use crate::pitch::octave;
+use crate::tempo::Tempo;
+use std::cmp::min;
+use std::fmt::Write;
+
+/// Durations are quantized to sixteenth notes, in measures of 4/4.
+const DIVISIONS_PER_BEAT: usize = 4;
+const BEATS_PER_MEASURE: usize = 4;
+const MEASURE_DIVISIONS: usize = DIVISIONS_PER_BEAT * BEATS_PER_MEASURE;
+const DEFAULT_BPM: f64 = 120.0;
+const NOTES_PER_OCTAVE: MidiByte = 12;
+const MIDDLE_C: MidiByte = 60;
+
+/// The note values that need no tie, in sixteenths, longest first: (length, type, dotted).
+const NOTE_VALUES: [(usize, &str, bool); 8] = [
+    (16, "whole", false),
+    (12, "half", true),
+    (8, "half", false),
+    (6, "quarter", true),
+    (4, "quarter", false),
+    (3, "eighth", true),
+    (2, "eighth", false),
+    (1, "16th", false),
+];
+
+impl Melody {
+    /// Produces a MusicXML score with this melody as the human part and `variation` as
+    /// the variation part, so that the pair can be engraved in MuseScore. Both parts are
+    /// written in 4/4 and in this melody's key, quantized to sixteenth notes at this
+    /// melody's tempo, or at 120 beats per minute if there are too few notes to tell.
+    pub fn to_musicxml(&self, variation: &Melody) -> String {
+        let tempo = Tempo::estimate(self).unwrap_or_else(|| Tempo::from_bpm(DEFAULT_BPM));
+        let scale = self.best_scale_for();
+        let mut xml = String::new();
+        writeln!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n\
+             <!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+             \"http://www.musicxml.org/dtds/partwise.dtd\">\n\
+             <score-partwise version=\"4.0\">\n<part-list>"
+        )
+        .unwrap();
+        for (id, name) in [("P1", "Human"), ("P2", "Variation")] {
+            writeln!(
+                xml,
+                "<score-part id=\"{id}\"><part-name>{name}</part-name></score-part>"
+            )
+            .unwrap();
+        }
+        writeln!(xml, "</part-list>").unwrap();
+        write_part(&mut xml, "P1", self, tempo, &scale, true);
+        write_part(&mut xml, "P2", variation, tempo, &scale, false);
+        writeln!(xml, "</score-partwise>").unwrap();
+        xml
+    }
+}
+
+/// Returns each note of `melody` as its pitch, or `None` for a rest, and its length in
+/// sixteenths. Onsets are rounded to the grid rather than lengths, so that rounding
+/// errors do not accumulate. Notes that round to nothing are dropped, as is the final
+/// rest.
+fn quantized(melody: &Melody, tempo: Tempo) -> Vec<(Option<MidiByte>, usize)> {
+    let sixteenth = tempo.beat_seconds() / DIVISIONS_PER_BEAT as f64;
+    let mut events: Vec<(Option<MidiByte>, usize)> = vec![];
+    let mut seconds = 0.0;
+    let mut start = 0;
+    for note in melody.iter() {
+        seconds += note.duration();
+        let end = (seconds / sixteenth).round() as usize;
+        if end > start {
+            let pitch = (!note.is_rest()).then_some(note.pitch());
+            match events.last_mut() {
+                Some((None, length)) if pitch.is_none() => *length += end - start,
+                _ => events.push((pitch, end - start)),
+            }
+        }
+        start = end;
+    }
+    if let Some((None, _)) = events.last() {
+        events.pop();
+    }
+    events
+}
+
+fn write_part(
+    xml: &mut String,
+    id: &str,
+    melody: &Melody,
+    tempo: Tempo,
+    scale: &MusicMode,
+    show_tempo: bool,
+) {
+    let events = quantized(melody, tempo);
+    let pitches = events.iter().filter_map(|(pitch, _)| *pitch);
+    let (sum, count) = pitches.fold((0, 0), |(sum, count), p| (sum + p as i64, count + 1));
+    let (sign, line) = if count > 0 && sum / count < MIDDLE_C as i64 {
+        ("F", 4)
+    } else {
+        ("G", 2)
+    };
+    let key = scale.key_signature();
+    let fifths = key.len() as MidiByte * key.symbol().pitch_shift();
+
+    writeln!(xml, "<part id=\"{id}\">\n<measure number=\"1\">").unwrap();
+    writeln!(
+        xml,
+        "<attributes><divisions>{DIVISIONS_PER_BEAT}</divisions>\
+         <key><fifths>{fifths}</fifths></key>\
+         <time><beats>{BEATS_PER_MEASURE}</beats><beat-type>4</beat-type></time>\
+         <clef><sign>{sign}</sign><line>{line}</line></clef></attributes>"
+    )
+    .unwrap();
+    if show_tempo {
+        let bpm = tempo.bpm().round();
+        writeln!(
+            xml,
+            "<direction placement=\"above\"><direction-type><metronome>\
+             <beat-unit>quarter</beat-unit><per-minute>{bpm}</per-minute>\
+             </metronome></direction-type><sound tempo=\"{bpm}\"/></direction>"
+        )
+        .unwrap();
+    }
+
+    let mut measure = 1;
+    let mut filled = 0;
+    // Rests fill out the last measure, or the only one if there are no notes.
+    let used = events.iter().map(|(_, length)| length).sum::<usize>();
+    let padding = if used == 0 {
+        MEASURE_DIVISIONS
+    } else {
+        (MEASURE_DIVISIONS - used % MEASURE_DIVISIONS) % MEASURE_DIVISIONS
+    };
+    for (pitch, mut length) in events.iter().copied().chain(Some((None, padding))) {
+        let mut tie_stop = false;
+        while length > 0 {
+            if filled == MEASURE_DIVISIONS {
+                measure += 1;
+                filled = 0;
+                writeln!(xml, "</measure>\n<measure number=\"{measure}\">").unwrap();
+            }
+            let room = min(length, MEASURE_DIVISIONS - filled);
+            let value = NOTE_VALUES.iter().find(|(v, _, _)| *v <= room).unwrap();
+            length -= value.0;
+            filled += value.0;
+            let tie_start = length > 0 && pitch.is_some();
+            write_note(xml, pitch, *value, scale, tie_stop, tie_start);
+            tie_stop = tie_start;
+        }
+    }
+    writeln!(xml, "</measure>\n</part>").unwrap();
+}
+
+fn write_note(
+    xml: &mut String,
+    pitch: Option<MidiByte>,
+    (length, note_type, dotted): (usize, &str, bool),
+    scale: &MusicMode,
+    tie_stop: bool,
+    tie_start: bool,
+) {
+    write!(xml, "<note>").unwrap();
+    match pitch {
+        Some(pitch) => {
+            // `MusicMode` expects pitches at or above its root, as in `pitch::spelled_name`.
+            let (letter, acc) = scale.note_name(pitch + NOTES_PER_OCTAVE);
+            write!(xml, "<pitch><step>{letter:?}</step>").unwrap();
+            if acc.pitch_shift() != 0 {
+                write!(xml, "<alter>{}</alter>", acc.pitch_shift()).unwrap();
+            }
+            write!(xml, "<octave>{}</octave></pitch>", octave(pitch, acc)).unwrap();
+        }
+        None => write!(xml, "<rest/>").unwrap(),
+    }
+    write!(xml, "<duration>{length}</duration>").unwrap();
+    let ties = [("stop", tie_stop), ("start", tie_start)];
+    for (tie, _) in ties.iter().filter(|(_, on)| *on) {
+        write!(xml, "<tie type=\"{tie}\"/>").unwrap();
+    }
+    write!(xml, "<type>{note_type}</type>").unwrap();
+    if dotted {
+        write!(xml, "<dot/>").unwrap();
+    }
+    if tie_stop || tie_start {
+        write!(xml, "<notations>").unwrap();
+        for (tie, _) in ties.iter().filter(|(_, on)| *on) {
+            write!(xml, "<tied type=\"{tie}\"/>").unwrap();
+        }
+        write!(xml, "</notations>").unwrap();
+    }
+    writeln!(xml, "</note>").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantized() {
+        // At 120 bpm a sixteenth lasts 0.125 seconds.
+        let melody = Melody::from("60,0.5,0.8,62,0.26,0.8,0,0.24,0.0,64,1.25,0.8,64,2.0,0.0");
+        assert_eq!(
+            quantized(&melody, Tempo::from_bpm(120.0)),
+            vec![(Some(60), 4), (Some(62), 2), (None, 2), (Some(64), 10)]
+        );
+    }
+
+    #[test]
+    fn test_musicxml() {
+        let melody = Melody::from("60,0.5,0.8,65,0.5,0.8,71,0.5,0.8,72,1.5,0.8,0,1.0,0.0");
+        let variation = Melody::from("67,0.5,0.8,65,0.5,0.8,64,0.5,0.8,62,0.5,0.8");
+        let xml = melody.to_musicxml(&variation);
+        assert_eq!(xml.matches("<part id=").count(), 2);
+        assert!(xml.contains("<fifths>0</fifths>"));
+        assert!(xml.contains("<per-minute>120</per-minute>"));
+        // The held C5 crosses the barline, so it is split into a quarter tied to a half.
+        assert!(xml.contains(
+            "<pitch><step>C</step><octave>5</octave></pitch><duration>4</duration>\
+             <tie type=\"start\"/><type>quarter</type>"
+        ));
+        assert!(xml.contains("<duration>8</duration><tie type=\"stop\"/><type>half</type>"));
+        // The human part is padded with a half rest to fill its second measure, while the
+        // variation fills exactly one.
+        assert!(xml.contains("<rest/><duration>8</duration>"));
+        assert_eq!(xml.matches("<measure number=\"2\">").count(), 1);
+    }
+}
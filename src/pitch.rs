@@ -0,0 +1,109 @@
+use crate::analyzer::{Accidental, KeySignature, MidiByte, MusicMode, NoteLetter, MAX_MIDI_VALUE};
+use enum_iterator::all;
+
+const NOTES_PER_OCTAVE: MidiByte = 12;
+const A4: MidiByte = 69;
+
+/// Returns the scientific pitch name of `pitch`, such as "C#4" for MIDI note 61.
+/// Accidentals are spelled as sharps.
+pub fn name(pitch: u8) -> String {
+    spelled_name(&KeySignature::c_major(), pitch as MidiByte)
+}
+
+/// Returns the scientific pitch name of `pitch`, with accidentals spelled
+/// according to `scale`. For example, MIDI note 70 is "Bb4" in F major.
+pub fn spelled_name(scale: &MusicMode, pitch: MidiByte) -> String {
+    // Names repeat every octave, and `MusicMode` expects pitches at or above its root.
+    let (letter, acc) = scale.note_name(pitch + NOTES_PER_OCTAVE);
+    format!("{letter:?}{}{}", acc.ascii(), octave(pitch, acc))
+}
+
+/// Returns the MIDI pitch named by `name`, the inverse of `name` and `spelled_name`.
+/// Accidentals may be sharps or flats, so both "C#4" and "Db4" give 61.
+pub fn from_name(name: &str) -> Option<MidiByte> {
+    let mut chars = name.chars();
+    let first = chars.next()?.to_string();
+    let letter = all::<NoteLetter>().find(|l| format!("{l:?}") == first)?;
+    let rest = chars.as_str();
+    let (acc, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (Accidental::Sharp, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (Accidental::Flat, octave)
+    } else {
+        (Accidental::Natural, rest)
+    };
+    let pitch = octave
+        .parse::<MidiByte>()
+        .ok()?
+        .checked_add(1)?
+        .checked_mul(NOTES_PER_OCTAVE)?
+        .checked_add(letter.natural_pitch() + acc.pitch_shift())?;
+    (0..=MAX_MIDI_VALUE).contains(&pitch).then_some(pitch)
+}
+
+/// Returns the frequency in Hz of `pitch` in equal temperament, with A4 tuned to
+/// `concert_pitch` Hz.
+pub fn frequency(pitch: MidiByte, concert_pitch: f64) -> f64 {
+    concert_pitch * 2.0_f64.powf((pitch - A4) as f64 / NOTES_PER_OCTAVE as f64)
+}
+
+/// Returns the octave number of `pitch` when spelled with `acc`. The octave
+/// follows the letter name, so Cb4 and B3 are both MIDI note 59.
+pub fn octave(pitch: MidiByte, acc: Accidental) -> MidiByte {
+    num::integer::div_floor(pitch - acc.pitch_shift(), NOTES_PER_OCTAVE) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyzer::{Accidental, MidiByte, MusicMode};
+    use crate::pitch::{frequency, from_name, name, octave, spelled_name};
+    use bare_metal_modulo::ModNumC;
+    use float_cmp::assert_approx_eq;
+
+    #[test]
+    fn test_names() {
+        for (pitch, expected) in [
+            (0, "C-1"),
+            (21, "A0"),
+            (60, "C4"),
+            (61, "C#4"),
+            (69, "A4"),
+            (70, "A#4"),
+            (127, "G9"),
+        ] {
+            assert_eq!(name(pitch), expected);
+        }
+    }
+
+    #[test]
+    fn test_spelled_names() {
+        let f_major = MusicMode::new(ModNumC::new(0), 5);
+        assert_eq!(spelled_name(&f_major, 70), "Bb4");
+        assert_eq!(spelled_name(&f_major, 65), "F4");
+        assert_eq!(octave(59, Accidental::Flat), 4);
+        assert_eq!(octave(59, Accidental::Natural), 3);
+    }
+
+    #[test]
+    fn test_from_name() {
+        for pitch in 0..=127 {
+            assert_eq!(from_name(name(pitch).as_str()), Some(pitch as MidiByte));
+        }
+        assert_eq!(from_name("Bb4"), Some(70));
+        assert_eq!(from_name("Cb4"), Some(59));
+        assert_eq!(from_name("H4"), None);
+        assert_eq!(from_name("C"), None);
+        assert_eq!(from_name("G#9"), None);
+        for huge in ["C9999", "C-9999", "B2730", "Cb-2731", "C32767", "C-32768"] {
+            assert_eq!(from_name(huge), None);
+        }
+    }
+
+    #[test]
+    fn test_frequency() {
+        assert_approx_eq!(f64, frequency(69, 442.0), 442.0);
+        assert_approx_eq!(f64, frequency(81, 440.0), 880.0);
+        assert_approx_eq!(f64, frequency(57, 432.0), 216.0);
+        assert_approx_eq!(f64, frequency(60, 440.0), 261.6256, epsilon = 0.0001);
+    }
+}
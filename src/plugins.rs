@@ -0,0 +1,239 @@
+use crate::ai_variation::{AIFuncType, AITable};
+use crate::analyzer::{Melody, MelodyMaker, MidiByte, Note};
+use crate::runtime::ChooserTable;
+use libloading::Library;
+use std::ffi::{c_char, CStr};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+pub const PLUGIN_DIR: &str = "plugins";
+/// Plugins built for any other version are not loaded.
+pub const PLUGIN_API_VERSION: u32 = 1;
+/// Room for this many output notes per input note on the first call to a plugin.
+const OUTPUT_NOTES_PER_INPUT: usize = 4;
+/// A plugin asking for room for more than this many output notes per input note is
+/// assumed to be broken.
+const MAX_OUTPUT_NOTES_PER_INPUT: usize = 64;
+
+/// A variation algorithm from outside the crate. Programs built on the crate can implement
+/// it directly; compiled plugins are loaded from a directory by `load_plugins`.
+pub trait VariationPlugin: Send + Sync {
+    /// The name shown among the variation algorithms.
+    fn name(&self) -> String;
+
+    /// Varies `melody`, where `p` is the Randomization slider, from 0.0 to 1.0.
+    fn vary(&self, maker: &MelodyMaker, melody: &Melody, p: f64) -> Melody;
+}
+
+/// How a plugin library sees a note: a MIDI pitch, a duration in seconds and a MIDI
+/// velocity, with velocity 0 for rests.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PluginNote {
+    pub pitch: MidiByte,
+    pub duration: f64,
+    pub velocity: MidiByte,
+}
+
+/// `musicserver_plugin_api_version`: returns the `PLUGIN_API_VERSION` it was built for.
+type ApiVersionFn = unsafe extern "C" fn() -> u32;
+/// `musicserver_plugin_name`: returns a nul-terminated name that lives as long as the library.
+type NameFn = unsafe extern "C" fn() -> *const c_char;
+/// `musicserver_plugin_vary`: reads `input_len` notes from `input`, and writes at most
+/// `output_capacity` notes of its variation to `output`. It returns the length of the whole
+/// variation, and is called again with more room if that is more than `output_capacity`.
+type VaryFn = unsafe extern "C" fn(
+    input: *const PluginNote,
+    input_len: usize,
+    p: f64,
+    output: *mut PluginNote,
+    output_capacity: usize,
+) -> usize;
+
+/// A plugin in a dynamic library exporting the three `musicserver_plugin_` functions.
+struct LibraryPlugin {
+    name: String,
+    vary: VaryFn,
+    // Dropped last, since `vary` points into it.
+    _library: Library,
+}
+
+impl LibraryPlugin {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        // SAFETY: a plugin runs with the replayer's privileges, so only libraries the user
+        // placed in the plugin directory are loaded, and they are trusted to follow the API.
+        unsafe {
+            let library = Library::new(path)?;
+            let api_version = *library.get::<ApiVersionFn>(b"musicserver_plugin_api_version")?;
+            if api_version() != PLUGIN_API_VERSION {
+                anyhow::bail!(
+                    "built for plugin API {}, not {PLUGIN_API_VERSION}",
+                    api_version()
+                );
+            }
+            let name_fn = *library.get::<NameFn>(b"musicserver_plugin_name")?;
+            let name = CStr::from_ptr(name_fn()).to_string_lossy().into_owned();
+            let vary = *library.get::<VaryFn>(b"musicserver_plugin_vary")?;
+            Ok(LibraryPlugin {
+                name,
+                vary,
+                _library: library,
+            })
+        }
+    }
+}
+
+impl VariationPlugin for LibraryPlugin {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Plugins are trusted not to crash, but whatever they return is checked. A plugin
+    /// asking for too much room, or returning notes that are not valid MIDI, is reported
+    /// and the melody is played back unchanged.
+    fn vary(&self, _maker: &MelodyMaker, melody: &Melody, p: f64) -> Melody {
+        let input: Vec<PluginNote> = melody
+            .iter()
+            .map(|n| PluginNote {
+                pitch: n.pitch(),
+                duration: n.duration(),
+                velocity: n.velocity(),
+            })
+            .collect();
+        let max_len = input.len().max(1) * MAX_OUTPUT_NOTES_PER_INPUT;
+        let mut output = vec![PluginNote::default(); input.len() * OUTPUT_NOTES_PER_INPUT];
+        // A second call is all a plugin needs, once it has said how much room it wants.
+        for _ in 0..2 {
+            // SAFETY: both buffers are valid for the lengths given.
+            let len = unsafe {
+                (self.vary)(
+                    input.as_ptr(),
+                    input.len(),
+                    p,
+                    output.as_mut_ptr(),
+                    output.len(),
+                )
+            };
+            if len <= output.len() {
+                output.truncate(len);
+                return checked_variation(self.name.as_str(), &output, melody);
+            } else if len > max_len {
+                warn!("Plugin {} asked for room for {len} notes", self.name);
+                return melody.clone();
+            }
+            output.resize(len, PluginNote::default());
+        }
+        warn!("Plugin {} kept asking for more room", self.name);
+        melody.clone()
+    }
+}
+
+/// The variation in `output`, or `melody` if any of its notes is not valid MIDI.
+fn checked_variation(name: &str, output: &[PluginNote], melody: &Melody) -> Melody {
+    let mut variation = Melody::new();
+    for n in output {
+        let note = Note::new(n.pitch, n.duration, n.velocity);
+        if !note.is_valid() {
+            warn!("Plugin {name} returned {n:?}, which is not a valid MIDI note");
+            return melody.clone();
+        }
+        variation.add(note);
+    }
+    variation
+}
+
+/// Loads every dynamic library in `dir` as a plugin. Libraries that fail to load are
+/// reported and skipped; a missing directory just means there are no plugins.
+pub fn load_plugins(dir: &str) -> Vec<Arc<dyn VariationPlugin>> {
+    let mut plugins: Vec<Arc<dyn VariationPlugin>> = vec![];
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return plugins,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) == Some(std::env::consts::DLL_EXTENSION) {
+            match LibraryPlugin::load(path.as_path()) {
+                Ok(plugin) => {
+                    info!(
+                        "Loaded variation plugin {} from {}",
+                        plugin.name,
+                        path.display()
+                    );
+                    plugins.push(Arc::new(plugin));
+                }
+                Err(e) => warn!("Skipping plugin {}: {e}", path.display()),
+            }
+        }
+    }
+    plugins
+}
+
+/// Returns `table` with each of `plugins` added after the built-in algorithms. A plugin
+/// whose name is already taken is skipped.
+pub fn with_plugins(table: &AITable, plugins: &[Arc<dyn VariationPlugin>]) -> AITable {
+    let mut choices = table.choice_vec();
+    for plugin in plugins {
+        let name = plugin.name();
+        if choices.iter().any(|(n, _)| *n == name) {
+            warn!("Skipping plugin {name}: an algorithm of that name exists");
+        } else {
+            let plugin = plugin.clone();
+            let func: Arc<AIFuncType> = Arc::new(move |maker: &MelodyMaker, melody: &Melody, p| {
+                plugin.vary(maker, melody, p)
+            });
+            choices.push((name, func));
+        }
+    }
+    ChooserTable::from(&choices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_variation::make_ai_table;
+
+    struct Reverser;
+
+    impl VariationPlugin for Reverser {
+        fn name(&self) -> String {
+            "Reverser".to_owned()
+        }
+
+        fn vary(&self, _maker: &MelodyMaker, melody: &Melody, _p: f64) -> Melody {
+            melody.retrograde()
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let table = make_ai_table();
+        let plugins: Vec<Arc<dyn VariationPlugin>> = vec![Arc::new(Reverser), Arc::new(Reverser)];
+        let mut table = with_plugins(&table, &plugins);
+        assert_eq!(table.name_vec().len(), make_ai_table().name_vec().len() + 1);
+        table.choose("Reverser");
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.8,64,1.0,0.8");
+        let variation = table.current_choice()(&MelodyMaker::new(), &melody, 0.5);
+        assert_eq!(variation, melody.retrograde());
+        assert!(load_plugins("no such plugin directory").is_empty());
+
+        let note = |pitch, duration, velocity| PluginNote {
+            pitch,
+            duration,
+            velocity,
+        };
+        let good = [note(60, 0.5, 100), note(0, 0.25, 0)];
+        assert_eq!(checked_variation("Test", &good, &melody).len(), 2);
+        for bad in [
+            note(128, 0.5, 100),
+            note(-1, 0.5, 100),
+            note(60, 0.5, 200),
+            note(60, -0.5, 100),
+            note(60, f64::NAN, 100),
+            note(60, f64::INFINITY, 100),
+        ] {
+            assert_eq!(checked_variation("Test", &[bad], &melody), melody);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crossbeam_queue::SegQueue;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many of the most recent waits `LatencyStats` keeps.
+pub const LATENCY_SAMPLES: usize = 1000;
+
+/// A `SegQueue` whose consumer can wait for the next item instead of polling, so that
+/// threads with nothing to do sleep rather than spin. Producers never block. Each item
+/// is timed from push to pop, so that slow stages of the pipeline can be found.
+pub struct BlockingQueue<T> {
+    queue: SegQueue<(T, Instant)>,
+    lock: Mutex<()>,
+    ready: Condvar,
+    latency: LatencyStats,
+}
+
+impl<T> Default for BlockingQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> BlockingQueue<T> {
+    pub fn new() -> Self {
+        BlockingQueue {
+            queue: SegQueue::new(),
+            lock: Mutex::new(()),
+            ready: Condvar::new(),
+            latency: LatencyStats::new(),
+        }
+    }
+
+    pub fn push(&self, item: T) {
+        self.queue.push((item, Instant::now()));
+        // Taking the lock ensures a consumer between its check and its wait is not missed.
+        let _guard = self.lock.lock().unwrap();
+        self.ready.notify_all();
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop().map(|(item, pushed)| {
+            self.latency.record(pushed.elapsed());
+            item
+        })
+    }
+
+    /// Returns the next item, waiting up to `timeout` for one to arrive. It may return
+    /// `None` sooner, so callers should be prepared to wait again.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        if let Some(item) = self.pop() {
+            return Some(item);
+        }
+        let guard = self.lock.lock().unwrap();
+        if let Some(item) = self.pop() {
+            return Some(item);
+        }
+        let _wait = self.ready.wait_timeout(guard, timeout).unwrap();
+        self.pop()
+    }
+
+    /// Discards every waiting item, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut discarded = 0;
+        while self.queue.pop().is_some() {
+            discarded += 1;
+        }
+        discarded
+    }
+
+    /// How long recent items waited between being pushed and being popped.
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Keeps the most recent `capacity` items, discarding the oldest as new ones arrive.
+#[derive(Clone, Debug)]
+pub struct RingBuffer<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.capacity > 0 {
+            if self.items.len() == self.capacity {
+                self.items.pop_front();
+            }
+            self.items.push_back(item);
+        }
+    }
+
+    /// The item `back` places before the newest, so that `recent(0)` is the newest.
+    pub fn recent(&self, back: usize) -> Option<&T> {
+        let index = self.items.len().checked_sub(back + 1)?;
+        self.items.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
+
+/// Percentiles of the waits recorded by a `LatencyStats`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub median: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// The most recent `LATENCY_SAMPLES` waits, shared between the threads that record them
+/// and the GUI that reports them.
+pub struct LatencyStats {
+    waits: Mutex<RingBuffer<Duration>>,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        LatencyStats {
+            waits: Mutex::new(RingBuffer::new(LATENCY_SAMPLES)),
+        }
+    }
+
+    pub fn record(&self, wait: Duration) {
+        self.waits.lock().unwrap().push(wait);
+    }
+
+    pub fn clear(&self) {
+        self.waits.lock().unwrap().clear();
+    }
+
+    /// Nearest-rank percentiles, or `None` if nothing has been recorded.
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut waits = self
+            .waits
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        waits.sort();
+        let percentile = |p: usize| waits[(waits.len() * p).div_ceil(100).max(1) - 1];
+        (!waits.is_empty()).then(|| LatencySummary {
+            samples: waits.len(),
+            median: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_pop_timeout() {
+        let queue = Arc::new(BlockingQueue::new());
+        assert_eq!(queue.pop_timeout(Duration::from_millis(20)), None);
+
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                queue.push(7);
+            })
+        };
+        assert_eq!(queue.pop_timeout(Duration::from_secs(10)), Some(7));
+        producer.join().unwrap();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut ring = RingBuffer::new(3);
+        assert_eq!(ring.recent(0), None);
+        for i in 1..=5 {
+            ring.push(i);
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.recent(0), Some(&5));
+        assert_eq!(ring.recent(2), Some(&3));
+        assert_eq!(ring.recent(3), None);
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        let stats = LatencyStats::new();
+        assert_eq!(stats.summary(), None);
+        for millis in (1..=200).rev() {
+            stats.record(Duration::from_millis(millis));
+        }
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.samples, 200);
+        assert_eq!(summary.median, Duration::from_millis(100));
+        assert_eq!(summary.p90, Duration::from_millis(180));
+        assert_eq!(summary.p99, Duration::from_millis(198));
+        assert_eq!(summary.max, Duration::from_millis(200));
+
+        let queue = BlockingQueue::new();
+        queue.push(1);
+        queue.pop();
+        assert_eq!(queue.latency().summary().unwrap().samples, 1);
+        queue.push(2);
+        queue.push(3);
+        assert_eq!(queue.clear(), 2);
+        assert!(queue.is_empty());
+        stats.clear();
+        assert_eq!(stats.summary(), None);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::analyzer::Melody;
+use crate::database::{MelodyInfo, VariationStats};
+use crate::pitch;
+use std::fmt::Write;
+
+const SAMPLE_RATE: u32 = 22050;
+const PREVIEW_AMPLITUDE: f64 = 0.3;
+const ENVELOPE_SECONDS: f64 = 0.01;
+const PIXELS_PER_SECOND: f64 = 20.0;
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+.timeline { display: flex; gap: 4px; overflow-x: auto; padding: 8px 0; }
+.block { display: block; height: 24px; border-radius: 2px; flex: none; }
+.human { background: black; }
+.variation { background: red; }
+.phrase { border-top: 1px solid #ccc; margin-top: 1em; }";
+
+/// Produces a standalone HTML page for the given melody/variation pairs: a clickable
+/// timeline, per-phrase metrics, and an embedded audio preview of each melody, tuned to
+/// `concert_pitch`.
+pub fn session_html(
+    pairs: &[(MelodyInfo, MelodyInfo, VariationStats)],
+    concert_pitch: f64,
+) -> String {
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+    )
+    .unwrap();
+    writeln!(
+        html,
+        "<title>Session Report</title>\n<style>\n{STYLE}\n</style>"
+    )
+    .unwrap();
+    writeln!(html, "</head>\n<body>\n<h1>Session Report</h1>").unwrap();
+
+    writeln!(html, "<div class=\"timeline\">").unwrap();
+    for (i, (melody_info, variation_info, _)) in pairs.iter().enumerate() {
+        for (info, class) in [(melody_info, "human"), (variation_info, "variation")] {
+            writeln!(
+                html,
+                "<a class=\"block {class}\" href=\"#phrase-{i}\" style=\"width: {:.0}px\" title=\"Phrase {i} ({class})\"></a>",
+                info.melody().duration() * PIXELS_PER_SECOND
+            )
+            .unwrap();
+        }
+    }
+    writeln!(html, "</div>").unwrap();
+
+    for (i, (melody_info, variation_info, stats)) in pairs.iter().enumerate() {
+        writeln!(html, "<div class=\"phrase\" id=\"phrase-{i}\">").unwrap();
+        writeln!(
+            html,
+            "<h2>Phrase {i}: {}</h2>",
+            escaped(melody_info.date_time_stamp().as_str())
+        )
+        .unwrap();
+        for (info, label) in [(melody_info, "Human"), (variation_info, "Variation")] {
+            writeln!(
+                html,
+                "<p><b>{label}</b>: {} notes, {:.2}s, {}, rated {}</p>",
+                info.melody().onset_indices().len(),
+                info.melody().duration(),
+                escaped(info.scale_name().as_str()),
+                info.rating()
+            )
+            .unwrap();
+            writeln!(
+                html,
+                "<audio controls src=\"data:audio/wav;base64,{}\"></audio>",
+                base64(&preview_wav(info.melody(), concert_pitch))
+            )
+            .unwrap();
+        }
+        writeln!(
+            html,
+            "<p>{}: randomization {:.2}, ornamentation {:.2}; {}</p>",
+            escaped(stats.algorithm_name.as_str()),
+            stats.random_prob,
+            stats.ornament_prob,
+            escaped(stats.report.to_string().as_str())
+        )
+        .unwrap();
+        writeln!(html, "</div>").unwrap();
+    }
+    writeln!(html, "</body>\n</html>").unwrap();
+    html
+}
+
+/// Renders `melody` as a mono 16-bit WAV file using plain sine tones, with A4 at
+/// `concert_pitch` Hz. This is only a preview, since the actual synthesizers run in the
+/// live output thread.
+pub fn preview_wav(melody: &Melody, concert_pitch: f64) -> Vec<u8> {
+    let mut samples: Vec<i16> = vec![];
+    for note in melody.iter() {
+        let num_samples = (note.duration() * SAMPLE_RATE as f64) as usize;
+        if note.is_rest() {
+            samples.extend(std::iter::repeat(0).take(num_samples));
+        } else {
+            let frequency = pitch::frequency(note.pitch(), concert_pitch);
+            let amplitude = PREVIEW_AMPLITUDE * note.velocity() as f64 / i8::MAX as f64;
+            let ramp = ENVELOPE_SECONDS * SAMPLE_RATE as f64;
+            for s in 0..num_samples {
+                let t = s as f64 / SAMPLE_RATE as f64;
+                let envelope = (s as f64 / ramp)
+                    .min((num_samples - s) as f64 / ramp)
+                    .min(1.0);
+                let value = (2.0 * std::f64::consts::PI * frequency * t).sin();
+                samples.push((value * amplitude * envelope * i16::MAX as f64) as i16);
+            }
+        }
+    }
+
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = vec![];
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes());
+    wav.extend_from_slice(&1_u16.to_le_bytes());
+    wav.extend_from_slice(&1_u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2_u16.to_le_bytes());
+    wav.extend_from_slice(&16_u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn escaped(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Note;
+    use crate::transpose::STANDARD_CONCERT_PITCH;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_preview_wav() {
+        let mut melody = Melody::new();
+        melody.add(Note::new(69, 0.5, 100));
+        melody.add(Note::new(69, 0.5, 0));
+        let wav = preview_wav(&melody, STANDARD_CONCERT_PITCH);
+        let samples_per_event = SAMPLE_RATE as usize / 2;
+        assert_eq!(wav.len(), 44 + 2 * samples_per_event * 2);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[36..40], b"data");
+        assert_ne!(preview_wav(&melody, 442.0), wav);
+    }
+}
@@ -1,23 +1,29 @@
-use crate::analyzer::Melody;
+use crate::analyzer::{
+    Chord, ChordSource, ChordTrack, DynamicShaping, EnabledOrnaments, Humanize, Melody,
+    MelodyMaker, MidiByte, Ornament, OrnamentSettings, OrnamentTiming, RhythmPreservation,
+    VariationReport,
+};
 use crate::database::VariationStats;
-use crossbeam_queue::SegQueue;
+use crate::dynamics::{Mix, PressureRoute};
+use crate::scheduler::Scheduler;
+use crate::tempo::{BeatMelody, Tempo};
+use crate::transpose::STANDARD_CONCERT_PITCH;
 use crossbeam_utils::atomic::AtomicCell;
-use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_fundsp::io::Speaker;
 use midi_fundsp::sounds::favorites;
 use midi_fundsp::SynthFunc;
-use midi_msg::MidiMsg;
 use read_input::prelude::input;
 use read_input::InputBuild;
-use std::collections::VecDeque;
 use std::collections::btree_map::BTreeMap;
 use std::ops::RangeInclusive;
-use std::sync::Arc;
-use std::time::Instant;
-
-pub const SHOW_MIDI_MSG: bool = false;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug_span;
 
 pub const HUMAN_SPEAKER: Speaker = Speaker::Left;
 pub const VARIATION_SPEAKER: Speaker = Speaker::Right;
+/// Trading counts bars of 4/4.
+const BEATS_PER_BAR: f64 = 4.0;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum SynthChoice {
@@ -106,12 +112,69 @@ macro_rules! arc_vec {
     ($( ($s:expr, $f:expr)),* ) => {vec![$(($s.to_owned(), Arc::new($f)),)*]}
 }
 
+/// How the human's and the variation's voices are balanced in the output, and what the
+/// player's aftertouch does to them.
+#[derive(Clone)]
+pub struct MixControls {
+    pub master_volume_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub human_pan_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub variation_pan_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub pressure_route: Arc<AtomicCell<PressureRoute>>,
+    pub pressure_depth_slider: Arc<AtomicCell<SliderValue<f64>>>,
+}
+
+impl MixControls {
+    pub fn new() -> Self {
+        let mix = Mix::default();
+        Self {
+            master_volume_slider: Arc::new(AtomicCell::new(master_volume_slider())),
+            human_pan_slider: Arc::new(AtomicCell::new(pan_slider(mix.human_pan))),
+            variation_pan_slider: Arc::new(AtomicCell::new(pan_slider(mix.variation_pan))),
+            pressure_route: Arc::new(AtomicCell::new(mix.pressure_route)),
+            pressure_depth_slider: Arc::new(AtomicCell::new(prob_slider(mix.pressure_depth))),
+        }
+    }
+
+    pub fn mix(&self) -> Mix {
+        Mix {
+            master_volume: self.master_volume_slider.load().current(),
+            human_pan: self.human_pan_slider.load().current(),
+            variation_pan: self.variation_pan_slider.load().current(),
+            pressure_route: self.pressure_route.load(),
+            pressure_depth: self.pressure_depth_slider.load().current(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct VariationControls {
     pub p_random_slider: Arc<AtomicCell<SliderValue<f64>>>,
     pub p_ornament_slider: Arc<AtomicCell<SliderValue<f64>>>,
     pub whimsify: Arc<AtomicCell<bool>>,
     pub shortest_note_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub p_respond_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub listen_only: Arc<AtomicCell<bool>>,
+    pub tempo_relative_ornaments: Arc<AtomicCell<bool>>,
+    pub ornament_beat_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub velocity_sensitive_ornaments: Arc<AtomicCell<bool>>,
+    /// Whether each kind of ornament may be inserted.
+    pub ornament_switches: Vec<(Ornament, Arc<AtomicCell<bool>>)>,
+    pub overlap: Arc<AtomicCell<bool>>,
+    pub fixed_tempo: Arc<AtomicCell<bool>>,
+    pub tempo_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub timing_jitter_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub velocity_jitter_slider: Arc<AtomicCell<SliderValue<MidiByte>>>,
+    pub swing: Arc<AtomicCell<bool>>,
+    pub thin_input: Arc<AtomicCell<bool>>,
+    pub max_density_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    pub preserve_gestures: Arc<AtomicCell<bool>>,
+    pub rhythm_preservation: Arc<AtomicCell<RhythmPreservation>>,
+    pub dynamics: Arc<AtomicCell<DynamicShaping>>,
+    pub chord_source: Arc<AtomicCell<ChordSource>>,
+    /// The progression used when `chord_source` is `ChordSource::Progression`.
+    pub chord_progression: Arc<Mutex<Vec<Chord>>>,
+    pub trade_bars: Arc<AtomicCell<bool>>,
+    pub bars_per_turn_slider: Arc<AtomicCell<SliderValue<usize>>>,
 }
 
 impl VariationControls {
@@ -121,6 +184,100 @@ impl VariationControls {
             p_ornament_slider: Arc::new(AtomicCell::new(prob_slider(0.2))),
             whimsify: Arc::new(AtomicCell::new(false)),
             shortest_note_slider: Arc::new(AtomicCell::new(SliderValue::new(0.1, 0.0, 0.2))),
+            p_respond_slider: Arc::new(AtomicCell::new(prob_slider(1.0))),
+            listen_only: Arc::new(AtomicCell::new(false)),
+            tempo_relative_ornaments: Arc::new(AtomicCell::new(false)),
+            ornament_beat_slider: Arc::new(AtomicCell::new(SliderValue::new(0.25, 0.0625, 1.0))),
+            velocity_sensitive_ornaments: Arc::new(AtomicCell::new(false)),
+            ornament_switches: enum_iterator::all::<Ornament>()
+                .map(|o| (o, Arc::new(AtomicCell::new(true))))
+                .collect(),
+            overlap: Arc::new(AtomicCell::new(false)),
+            fixed_tempo: Arc::new(AtomicCell::new(false)),
+            tempo_slider: Arc::new(AtomicCell::new(SliderValue::new(100.0, 40.0, 240.0))),
+            timing_jitter_slider: Arc::new(AtomicCell::new(SliderValue::new(0.0, 0.0, 0.05))),
+            velocity_jitter_slider: Arc::new(AtomicCell::new(SliderValue::new(0, 0, 30))),
+            swing: Arc::new(AtomicCell::new(false)),
+            thin_input: Arc::new(AtomicCell::new(false)),
+            max_density_slider: Arc::new(AtomicCell::new(SliderValue::new(12.0, 4.0, 40.0))),
+            preserve_gestures: Arc::new(AtomicCell::new(false)),
+            rhythm_preservation: Arc::new(AtomicCell::new(RhythmPreservation::Free)),
+            dynamics: Arc::new(AtomicCell::new(DynamicShaping::AsPlayed)),
+            chord_source: Arc::new(AtomicCell::new(ChordSource::Off)),
+            chord_progression: Arc::new(Mutex::new(vec![])),
+            trade_bars: Arc::new(AtomicCell::new(false)),
+            bars_per_turn_slider: Arc::new(AtomicCell::new(SliderValue::new(4, 1, 8))),
+        }
+    }
+
+    pub fn ornament_settings(&self) -> OrnamentSettings {
+        let timing = if self.tempo_relative_ornaments.load() {
+            OrnamentTiming::BeatFraction(self.ornament_beat_slider.load().current)
+        } else {
+            OrnamentTiming::Absolute
+        };
+        OrnamentSettings {
+            timing,
+            velocity_sensitive: self.velocity_sensitive_ornaments.load(),
+            enabled: self
+                .ornament_switches
+                .iter()
+                .fold(EnabledOrnaments::none(), |e, (o, on)| e.with(*o, on.load())),
+        }
+    }
+
+    /// The chords that variations of `melody` should fit, if any.
+    pub fn chord_track(&self, melody: &Melody) -> Option<ChordTrack> {
+        match self.chord_source.load() {
+            ChordSource::Off => None,
+            ChordSource::Progression => {
+                let chords = self.chord_progression.lock().unwrap();
+                (!chords.is_empty()).then(|| ChordTrack::evenly(&chords))
+            }
+            ChordSource::Implied => Some(ChordTrack::implied_by(melody)),
+        }
+    }
+
+    /// Decides whether the AI should respond to the phrase just played. In listen-only
+    /// mode it never responds; otherwise it responds with probability `p_respond_slider`,
+    /// as decided by `maker`.
+    pub fn will_respond(&self, maker: &MelodyMaker) -> bool {
+        !self.listen_only.load() && maker.chance(self.p_respond_slider.load().current())
+    }
+
+    /// Counts `variation` in beats of the tempo at which `original` was played. Should that
+    /// tempo be impossible to tell, the beat is that of `tempo_slider`.
+    pub fn in_beats(&self, original: &Melody, variation: &Melody) -> BeatMelody {
+        let tempo = Tempo::estimate(original)
+            .unwrap_or_else(|| Tempo::from_bpm(self.tempo_slider.load().current));
+        BeatMelody::new(variation, tempo)
+    }
+
+    /// Returns `variation` in seconds: at the tempo of `tempo_slider` when a fixed playback
+    /// tempo is set, so that it keeps the rhythmic proportions the player gave it, and
+    /// otherwise at the tempo it was counted at.
+    pub fn performed(&self, variation: &BeatMelody) -> Melody {
+        if self.fixed_tempo.load() {
+            variation.at(Tempo::from_bpm(self.tempo_slider.load().current))
+        } else {
+            variation.as_played()
+        }
+    }
+
+    /// When trading bars, returns how long each turn lasts in seconds, counting
+    /// `bars_per_turn_slider` bars at the tempo of `tempo_slider`.
+    pub fn turn_seconds(&self) -> Option<f64> {
+        self.trade_bars.load().then(|| {
+            let beat_seconds = Tempo::from_bpm(self.tempo_slider.load().current).beat_seconds();
+            self.bars_per_turn_slider.load().current as f64 * BEATS_PER_BAR * beat_seconds
+        })
+    }
+
+    pub fn humanize(&self) -> Humanize {
+        Humanize {
+            timing_jitter: self.timing_jitter_slider.load().current,
+            velocity_jitter: self.velocity_jitter_slider.load().current,
+            swing: self.swing.load(),
         }
     }
 
@@ -131,6 +288,7 @@ impl VariationControls {
             ornament_prob: self.p_ornament_slider.load().current,
             min_note_duration: self.shortest_note_slider.load().current,
             whimsify: self.whimsify.load(),
+            report: VariationReport::default(),
         }
     }
 
@@ -193,6 +351,39 @@ pub fn replay_slider() -> SliderValue<f64> {
     SliderValue::new(1.5, 1.0, 5.0)
 }
 
+/// Sustained playing rings on through the gaps between phrases, so the silence that ends a
+/// phrase while the pedal is held is set separately, and defaults to longer.
+pub fn pedal_replay_slider() -> SliderValue<f64> {
+    SliderValue::new(3.0, 1.0, 10.0)
+}
+
+pub fn stuck_note_slider() -> SliderValue<f64> {
+    SliderValue::new(10.0, 2.0, 60.0)
+}
+
+/// Scales the velocity of every note. Above 1.0, only notes played softer than full velocity
+/// get louder.
+pub fn master_volume_slider() -> SliderValue<f64> {
+    SliderValue::new(1.0, 0.0, 1.5)
+}
+
+/// From -1.0, hard left, to 1.0, hard right.
+pub fn pan_slider(start_pan: f64) -> SliderValue<f64> {
+    SliderValue::new(start_pan, -1.0, 1.0)
+}
+
+pub fn transpose_slider() -> SliderValue<MidiByte> {
+    SliderValue::new(0, -24, 24)
+}
+
+/// Tunings that ensembles commonly settle on, offered as quick choices.
+pub const COMMON_CONCERT_PITCHES: [f64; 3] = [432.0, 440.0, 442.0];
+
+/// From baroque pitch, a semitone below A4 = 440 Hz, to a semitone above.
+pub fn concert_pitch_slider() -> SliderValue<f64> {
+    SliderValue::new(STANDARD_CONCERT_PITCH, 415.0, 466.0)
+}
+
 pub fn prob_slider(start_prob: f64) -> SliderValue<f64> {
     SliderValue::new(start_prob, 0.0, 1.0)
 }
@@ -212,118 +403,147 @@ pub fn user_pick_element<T: Clone, S: Fn(&T) -> String>(
     choices[choice - 1].clone()
 }
 
-#[derive(Clone)]
+#[derive(Default)]
+struct RunState {
+    num_running: usize,
+    stop: bool,
+}
+
+/// Lets any thread stop every melody playing, and wait until they have all stopped. The
+/// condition variable is signalled whenever a stop is sent and whenever the last melody
+/// stops, so that neither the players nor the waiters have to spin.
+#[derive(Clone, Default)]
 pub struct MelodyRunStatus {
-    num_running: Arc<AtomicCell<usize>>,
-    stop: Arc<AtomicCell<bool>>,
+    state: Arc<(Mutex<RunState>, Condvar)>,
 }
 
 impl MelodyRunStatus {
     pub fn new() -> Self {
-        MelodyRunStatus {
-            num_running: Arc::new(AtomicCell::new(0)),
-            stop: Arc::new(AtomicCell::new(false)),
-        }
+        Self::default()
     }
 
     pub fn is_stopping(&self) -> bool {
-        self.stop.load()
+        self.state.0.lock().unwrap().stop
     }
 
     pub fn send_stop(&self) {
-        if self.num_running.load() > 0 {
-            self.stop.store(true);
+        let (state, changed) = &*self.state;
+        let mut state = state.lock().unwrap();
+        if state.num_running > 0 {
+            state.stop = true;
+            changed.notify_all();
         }
     }
 
     pub fn report_start(&self) {
-        self.num_running.fetch_add(1);
+        self.state.0.lock().unwrap().num_running += 1;
     }
 
     pub fn report_stop(&self) {
-        assert!(self.num_running.load() > 0);
-        self.num_running.fetch_sub(1);
-        if self.num_running.load() == 0 {
-            self.stop.store(false);
+        let (state, changed) = &*self.state;
+        let mut state = state.lock().unwrap();
+        assert!(state.num_running > 0);
+        state.num_running -= 1;
+        if state.num_running == 0 {
+            state.stop = false;
+            changed.notify_all();
         }
     }
+
+    /// Blocks until any stop sent has been carried out by every melody playing.
+    pub fn wait_until_stopped(&self) {
+        let (state, changed) = &*self.state;
+        let state = state.lock().unwrap();
+        let _stopped = changed.wait_while(state, |s| s.stop).unwrap();
+    }
+
+    /// Blocks for up to `timeout`, returning early with `true` if a stop is sent.
+    pub fn wait_for_stop(&self, timeout: Duration) -> bool {
+        let (state, changed) = &*self.state;
+        let state = state.lock().unwrap();
+        let (state, _) = changed
+            .wait_timeout_while(state, timeout, |s| !s.stop)
+            .unwrap();
+        state.stop
+    }
 }
 
+/// How often a playing melody reports its progress. It stops as soon as asked, between
+/// reports.
+const PROGRESS_UPDATE_MILLIS: u64 = 20;
+
+/// Schedules `melody` on `speaker` to begin at `start`, and returns once it has finished
+/// or been stopped. Any humanizing has already been done, so that it can be seeded.
 pub fn send_recorded_melody(
     melody: &Melody,
     speaker: Speaker,
-    ai2output: Arc<SegQueue<SynthMsg>>,
+    start: Instant,
+    scheduler: Scheduler,
     melody_progress: Arc<AtomicCell<Option<f32>>>,
     melody_run_status: MelodyRunStatus,
 ) {
+    let _span = debug_span!("playback", notes = melody.len(), speaker = ?speaker).entered();
     melody_run_status.report_start();
-    let total_start = Instant::now();
-    let total_duration = melody.duration() as f32;
-    'outer: for note in melody.iter() {
-        let (midi, duration) = note.to_midi();
-        ai2output.push(SynthMsg { msg: midi, speaker });
-        let note_start = Instant::now();
-        while note_start.elapsed().as_secs_f64() < duration {
-            let progress = Some(total_start.elapsed().as_secs_f32() / total_duration);
-            melody_progress.store(progress);
-            if melody_run_status.is_stopping() {
-                break 'outer;
-            }
-        }
-    }
-    ai2output.push(SynthMsg::all_notes_off(speaker));
+    let source = scheduler.source();
+    let end = scheduler.schedule_melody(source, melody, speaker, start);
+    follow_playback(
+        &scheduler,
+        source,
+        start,
+        end,
+        melody_progress,
+        &melody_run_status,
+    );
     melody_run_status.report_stop();
-    melody_progress.store(None);
 }
 
 pub fn send_two_melodies(
     melody_left: &Melody,
     melody_right: &Melody,
-    ai2output: Arc<SegQueue<SynthMsg>>,
+    scheduler: Scheduler,
     melody_progress: Arc<AtomicCell<Option<f32>>>,
     melody_run_status: MelodyRunStatus,
 ) {
+    let _span = debug_span!("playback", notes = melody_left.len() + melody_right.len()).entered();
     melody_run_status.report_start();
-    let elapsed = Instant::now();
-    let total_duration = if melody_left.duration() > melody_right.duration() {melody_left.duration()} else {melody_right.duration()};
-    let mut left_queue = note_times_from(melody_left);
-    let mut right_queue = note_times_from(melody_right);
-    let mut left_next = 0.0;
-    let mut right_next = 0.0;
-    loop {
-        check_get_next(ai2output.clone(), elapsed, &mut left_next, &mut left_queue, Speaker::Left);
-        check_get_next(ai2output.clone(), elapsed, &mut right_next, &mut right_queue, Speaker::Right);
-        if left_queue.is_empty() && right_queue.is_empty() {
-            break;
-        } else {
-            let progress = Some(elapsed.elapsed().as_secs_f32() / total_duration as f32);
-            melody_progress.store(progress);
-            if melody_run_status.is_stopping() {
-                break;
-            }
-        }
-    }   
-    ai2output.push(SynthMsg::all_notes_off(Speaker::Both));
+    let source = scheduler.source();
+    let start = Instant::now();
+    let left_end = scheduler.schedule_melody(source, melody_left, Speaker::Left, start);
+    let right_end = scheduler.schedule_melody(source, melody_right, Speaker::Right, start);
+    follow_playback(
+        &scheduler,
+        source,
+        start,
+        left_end.max(right_end),
+        melody_progress,
+        &melody_run_status,
+    );
     melody_run_status.report_stop();
-    melody_progress.store(None);
 }
 
-fn check_get_next(ai2output: Arc<SegQueue<SynthMsg>>, elapsed: Instant, next: &mut f64, queue: &mut VecDeque<(MidiMsg, f64)>, speaker: Speaker) {
-    if *next < elapsed.elapsed().as_secs_f64() {
-        if let Some((msg, time)) = queue.pop_front() {
-            *next = time;
-            ai2output.push(SynthMsg { msg, speaker });
+/// Reports the progress of what `source` scheduled until `end`. If playback is stopped
+/// first, the rest of it is cancelled and the notes it left sounding are released.
+fn follow_playback(
+    scheduler: &Scheduler,
+    source: u64,
+    start: Instant,
+    end: Instant,
+    melody_progress: Arc<AtomicCell<Option<f32>>>,
+    melody_run_status: &MelodyRunStatus,
+) {
+    let total_duration = end.saturating_duration_since(start).as_secs_f32();
+    let update = Duration::from_millis(PROGRESS_UPDATE_MILLIS);
+    loop {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        let elapsed = now.saturating_duration_since(start).as_secs_f32();
+        melody_progress.store(Some(elapsed / total_duration));
+        if melody_run_status.wait_for_stop((end - now).min(update)) {
+            scheduler.release(source);
+            break;
         }
-    } 
-}
-
-fn note_times_from(melody: &Melody) -> VecDeque<(MidiMsg,f64)> {
-    let mut total_time = 0.0;
-    let mut result = VecDeque::new();
-    for note in melody.iter() {
-        let (msg, duration) = note.to_midi();
-        total_time += duration;
-        result.push_back((msg, total_time));
     }
-    result
-}
\ No newline at end of file
+    melody_progress.store(None);
+}
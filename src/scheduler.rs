@@ -0,0 +1,392 @@
+use crate::analyzer::Melody;
+use crate::queue::BlockingQueue;
+use crate::threads::{spawn_named, SCHEDULER_THREAD, SINGLETON};
+use crate::watchdog::StuckNoteTracker;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+/// With nothing due sooner, the scheduler thread still wakes this often to check `quit`.
+const SCHEDULER_IDLE_MILLIS: u64 = 10;
+
+/// What happens when a scheduled message falls due.
+enum Delivery {
+    Synth(SynthMsg, Arc<BlockingQueue<SynthMsg>>),
+    /// For messages that go somewhere other than a synthesizer, such as MIDI clock.
+    Call(Box<dyn FnOnce() + Send>),
+}
+
+impl Delivery {
+    fn deliver(self) {
+        match self {
+            Delivery::Synth(msg, output) => {
+                trace!("{:?}", msg.msg);
+                output.push(msg);
+            }
+            Delivery::Call(call) => call(),
+        }
+    }
+}
+
+/// A message waiting to be sent. Messages due at the same instant are sent in the order
+/// they were scheduled, so that a note ends before the same key is struck again.
+struct TimedMsg {
+    at: Instant,
+    order: u64,
+    source: u64,
+    delivery: Delivery,
+}
+
+impl PartialEq for TimedMsg {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimedMsg {}
+
+impl PartialOrd for TimedMsg {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimedMsg {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+/// Sends synthesizer messages to `output` at the times they were scheduled for. Every
+/// melody played, the backing track and outgoing MIDI clock are all scheduled in advance
+/// and sent from the one scheduler thread, so that timing holds up however busy the
+/// threads producing them are, and voices that start together stay together. Each
+/// producer takes its own number from `source()`, so that whatever it has yet to play can
+/// be cancelled, and whatever it left sounding released. Copies made by `with_output()`
+/// share the schedule, but send to another queue.
+#[derive(Clone)]
+pub struct Scheduler {
+    output: Arc<BlockingQueue<SynthMsg>>,
+    pending: Arc<(Mutex<BinaryHeap<Reverse<TimedMsg>>>, Condvar)>,
+    sounding: Arc<Mutex<HashMap<u64, Sounding>>>,
+    count: Arc<AtomicCell<u64>>,
+}
+
+/// The notes one source has started and not yet ended, and where their NoteOffs go.
+struct Sounding {
+    output: Arc<BlockingQueue<SynthMsg>>,
+    tracker: StuckNoteTracker,
+}
+
+impl Scheduler {
+    pub fn new(output: Arc<BlockingQueue<SynthMsg>>) -> Self {
+        Scheduler {
+            output,
+            pending: Arc::new((Mutex::new(BinaryHeap::new()), Condvar::new())),
+            sounding: Arc::new(Mutex::new(HashMap::new())),
+            count: Arc::new(AtomicCell::new(0)),
+        }
+    }
+
+    pub fn with_output(&self, output: Arc<BlockingQueue<SynthMsg>>) -> Self {
+        Scheduler {
+            output,
+            ..self.clone()
+        }
+    }
+
+    /// For messages that should be heard right away, such as the player's own notes.
+    pub fn output(&self) -> Arc<BlockingQueue<SynthMsg>> {
+        self.output.clone()
+    }
+
+    pub fn source(&self) -> u64 {
+        self.count.fetch_add(1)
+    }
+
+    pub fn schedule(&self, source: u64, at: Instant, msg: SynthMsg) {
+        self.schedule_delivery(source, at, Delivery::Synth(msg, self.output.clone()));
+    }
+
+    /// Calls `call` from the scheduler thread at `at`. It should return quickly, and must
+    /// not schedule anything itself.
+    pub fn schedule_call<F: FnOnce() + Send + 'static>(&self, source: u64, at: Instant, call: F) {
+        self.schedule_delivery(source, at, Delivery::Call(Box::new(call)));
+    }
+
+    fn schedule_delivery(&self, source: u64, at: Instant, delivery: Delivery) {
+        let order = self.count.fetch_add(1);
+        let (pending, wakeup) = &*self.pending;
+        pending.lock().unwrap().push(Reverse(TimedMsg {
+            at,
+            order,
+            source,
+            delivery,
+        }));
+        wakeup.notify_one();
+    }
+
+    /// Schedules each note of `melody` on `speaker` from `start`, then releases whatever
+    /// it left sounding when it ends. Returns when that will be. Only this melody's own
+    /// notes are released, so that others playing on the same speaker carry on.
+    pub fn schedule_melody(
+        &self,
+        source: u64,
+        melody: &Melody,
+        speaker: Speaker,
+        start: Instant,
+    ) -> Instant {
+        let mut at = start;
+        let mut sounding = vec![];
+        for note in melody.iter() {
+            let (msg, duration) = note.to_midi();
+            let pitch = note.pitch() as u8;
+            sounding.retain(|p| *p != pitch);
+            if !note.is_rest() {
+                sounding.push(pitch);
+            }
+            self.schedule(source, at, SynthMsg { msg, speaker });
+            at += Duration::from_secs_f64(duration.max(0.0));
+        }
+        for note in sounding {
+            let msg = MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOff { note, velocity: 0 },
+            };
+            self.schedule(source, at, SynthMsg { msg, speaker });
+        }
+        at
+    }
+
+    /// Drops every message from `source` that has yet to be sent. Any notes it left
+    /// sounding are the caller's to release.
+    pub fn cancel(&self, source: u64) {
+        let (pending, _) = &*self.pending;
+        pending
+            .lock()
+            .unwrap()
+            .retain(|Reverse(timed)| timed.source != source);
+        self.sounding.lock().unwrap().remove(&source);
+    }
+
+    /// Cancels what `source` has yet to send, then releases the notes it left sounding,
+    /// and only those, so that whatever else is playing on the same speaker carries on.
+    pub fn release(&self, source: u64) {
+        let (pending, _) = &*self.pending;
+        let mut pending = pending.lock().unwrap();
+        pending.retain(|Reverse(timed)| timed.source != source);
+        if let Some(mut sounding) = self.sounding.lock().unwrap().remove(&source) {
+            for msg in sounding.tracker.release_all() {
+                sounding.output.push(msg);
+            }
+        }
+    }
+
+    /// Keeps track of the notes `source` has sounding, as each of its messages is sent.
+    fn observe(&self, source: u64, delivery: &Delivery) {
+        if let Delivery::Synth(msg, output) = delivery {
+            let mut sounding = self.sounding.lock().unwrap();
+            let entry = sounding.entry(source).or_insert_with(|| Sounding {
+                output: output.clone(),
+                tracker: StuckNoteTracker::new(),
+            });
+            entry.tracker.observe(msg);
+            if entry.tracker.num_held() == 0 {
+                sounding.remove(&source);
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.0.lock().unwrap().len()
+    }
+}
+
+/// Sends each message scheduled on `scheduler` as it falls due, until `quit` is set.
+pub fn start_scheduler_thread(
+    scheduler: Scheduler,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(SCHEDULER_THREAD, SINGLETON, move || {
+        run_scheduler(&scheduler, &quit)
+    })
+}
+
+/// Runs the scheduler on a thread of its own, outside the program's thread registry, so
+/// that tests running side by side do not refuse each other's scheduler. Join the
+/// returned handle after setting `quit`.
+#[cfg(test)]
+pub(crate) fn start_test_scheduler_thread(
+    scheduler: Scheduler,
+    quit: Arc<AtomicCell<bool>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || run_scheduler(&scheduler, &quit))
+}
+
+fn run_scheduler(scheduler: &Scheduler, quit: &AtomicCell<bool>) {
+    let idle = Duration::from_millis(SCHEDULER_IDLE_MILLIS);
+    let (lock, wakeup) = &*scheduler.pending;
+    let mut pending = lock.lock().unwrap();
+    while !quit.load() {
+        let now = Instant::now();
+        let wait = match pending.peek() {
+            Some(Reverse(next)) if next.at <= now => {
+                let Reverse(due) = pending.pop().unwrap();
+                scheduler.observe(due.source, &due.delivery);
+                match due.delivery {
+                    // Sent with the schedule still locked, so that `release()` cannot slip
+                    // its NoteOffs in ahead of a note it has just seen start.
+                    synth @ Delivery::Synth(..) => synth.deliver(),
+                    call => {
+                        drop(pending);
+                        call.deliver();
+                        pending = lock.lock().unwrap();
+                    }
+                }
+                continue;
+            }
+            Some(Reverse(next)) => (next.at - now).min(idle),
+            None => idle,
+        };
+        pending = wakeup.wait_timeout(pending, wait).unwrap().0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn {
+                    note,
+                    velocity: 100,
+                },
+            },
+            speaker: Speaker::Both,
+        }
+    }
+
+    fn note_of(msg: &SynthMsg) -> Option<u8> {
+        match msg.msg {
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOn { note, .. },
+                ..
+            } => Some(note),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let output = Arc::new(BlockingQueue::new());
+        let scheduler = Scheduler::new(output.clone());
+        let quit = Arc::new(AtomicCell::new(false));
+        let start = Instant::now() + Duration::from_millis(20);
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let kept = scheduler.source();
+        let cancelled = scheduler.source();
+        scheduler.schedule(kept, at(20), note_on(62));
+        scheduler.schedule(cancelled, at(10), note_on(70));
+        scheduler.schedule(kept, at(0), note_on(60));
+        scheduler.schedule(kept, at(20), note_on(64));
+        let elsewhere = Arc::new(BlockingQueue::new());
+        scheduler
+            .with_output(elsewhere.clone())
+            .schedule(kept, at(10), note_on(67));
+        let called = Arc::new(BlockingQueue::new());
+        let calls = called.clone();
+        scheduler.schedule_call(kept, at(5), move || calls.push(Instant::now()));
+        scheduler.schedule_call(cancelled, at(5), || panic!("Cancelled call was made"));
+        scheduler.cancel(cancelled);
+        assert_eq!(scheduler.pending(), 5);
+        let scheduling = start_test_scheduler_thread(scheduler.clone(), quit.clone());
+        for (note, due) in [(60, at(0)), (62, at(20)), (64, at(20))] {
+            let msg = output.pop_timeout(Duration::from_secs(1)).unwrap();
+            assert!(Instant::now() >= due);
+            assert_eq!(note_of(&msg), Some(note));
+        }
+        let msg = elsewhere.pop_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(note_of(&msg), Some(67));
+        assert!(called.pop_timeout(Duration::from_secs(1)).unwrap() >= at(5));
+        assert_eq!(scheduler.pending(), 0);
+        quit.store(true);
+        scheduling.join().unwrap();
+    }
+
+    #[test]
+    fn test_schedule_melody() {
+        let output = Arc::new(BlockingQueue::new());
+        let scheduler = Scheduler::new(output.clone());
+        let quit = Arc::new(AtomicCell::new(false));
+        // 64 is left sounding, and released when the melody ends; 60 already was.
+        let melody = Melody::from("60,0.01,0.8,64,0.01,0.8,60,0.01,0.0");
+        let source = scheduler.source();
+        scheduler.schedule_melody(source, &melody, Speaker::Right, Instant::now());
+        assert_eq!(scheduler.pending(), 4);
+        let scheduling = start_test_scheduler_thread(scheduler.clone(), quit.clone());
+        let sent = (0..4)
+            .map(|_| output.pop_timeout(Duration::from_secs(1)).unwrap().msg)
+            .collect::<Vec<_>>();
+        quit.store(true);
+        scheduling.join().unwrap();
+        assert!(matches!(
+            sent[2],
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { note: 60, .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            sent[3],
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { note: 64, .. },
+                ..
+            }
+        ));
+    }
+    #[test]
+    fn test_release() {
+        let output = Arc::new(BlockingQueue::new());
+        let scheduler = Scheduler::new(output.clone());
+        let quit = Arc::new(AtomicCell::new(false));
+        let scheduling = start_test_scheduler_thread(scheduler.clone(), quit.clone());
+        // Each melody leaves all three of its notes sounding until it ends.
+        let replay = scheduler.source();
+        let other = scheduler.source();
+        let start = Instant::now();
+        let melody = Melody::from("60,0.01,0.8,64,0.01,0.8,67,5.0,0.8");
+        scheduler.schedule_melody(replay, &melody, Speaker::Left, start);
+        let melody = Melody::from("48,0.01,0.8,52,0.01,0.8,55,5.0,0.8");
+        scheduler.schedule_melody(other, &melody, Speaker::Left, start);
+        for _ in 0..6 {
+            let msg = output.pop_timeout(Duration::from_secs(1)).unwrap();
+            assert!(note_of(&msg).is_some());
+        }
+        scheduler.release(replay);
+        let released = (0..3)
+            .map(|_| output.pop_timeout(Duration::from_secs(1)).unwrap())
+            .map(|msg| match msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOff { note, .. },
+                    ..
+                } if matches!(msg.speaker, Speaker::Left) => note,
+                _ => panic!("Expected a NoteOff on the left"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(released, vec![60, 64, 67]);
+        assert!(output.pop_timeout(Duration::from_millis(50)).is_none());
+        // The other melody still has its own NoteOffs to come.
+        assert_eq!(scheduler.pending(), 3);
+        scheduler.cancel(other);
+        quit.store(true);
+        scheduling.join().unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use crate::analyzer::{Melody, MelodyMaker, MidiByte, Note};
+use crate::plugins::VariationPlugin;
+use anyhow::anyhow;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{error, info};
+
+pub const SCRIPT_DIR: &str = "scripts";
+const SCRIPT_EXTENSION: &str = "rhai";
+const VARY_FUNCTION: &str = "vary";
+/// Limits on each run of a script, so that an endless loop or runaway recursion fails
+/// with an error instead of holding up the AI thread.
+const MAX_SCRIPT_OPERATIONS: u64 = 1_000_000;
+const MAX_SCRIPT_CALL_LEVELS: usize = 64;
+const MAX_SCRIPT_EXPR_DEPTH: usize = 64;
+const MAX_SCRIPT_FUNCTION_EXPR_DEPTH: usize = 32;
+
+/// A variation algorithm written as a Rhai script, named after its file. The script
+/// defines `fn vary(notes, p)`, where `notes` is an array of maps with `pitch`,
+/// `duration` and `velocity` entries (velocity 0 for rests) and `p` is the Randomization
+/// slider; it returns the variation in the same form. For example:
+///
+/// ```text
+/// fn vary(notes, p) {
+///     notes.reverse();
+///     notes
+/// }
+/// ```
+///
+/// The script is compiled again whenever its file changes, so it can be edited while the
+/// replayer runs. If it fails, runs for too long, or returns a note that is not valid MIDI,
+/// the error is printed and the AI stays silent for the phrase.
+pub struct ScriptedVariation {
+    name: String,
+    path: PathBuf,
+    engine: Engine,
+    compiled: Mutex<Option<(SystemTime, AST)>>,
+}
+
+impl ScriptedVariation {
+    pub fn new(path: &Path) -> Self {
+        ScriptedVariation {
+            name: path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            path: path.to_path_buf(),
+            engine: limited_engine(),
+            compiled: Mutex::new(None),
+        }
+    }
+
+    fn script(&self) -> anyhow::Result<AST> {
+        let modified = fs::metadata(self.path.as_path())?.modified()?;
+        let mut compiled = self.compiled.lock().unwrap();
+        match compiled.as_ref() {
+            Some((when, ast)) if *when == modified => Ok(ast.clone()),
+            _ => {
+                let ast = self
+                    .engine
+                    .compile_file(self.path.clone())
+                    .map_err(|e| anyhow!("{e}"))?;
+                *compiled = Some((modified, ast.clone()));
+                Ok(ast)
+            }
+        }
+    }
+
+    fn run(&self, melody: &Melody, p: f64) -> anyhow::Result<Melody> {
+        let ast = self.script()?;
+        let notes: Array = melody.iter().map(note_to_script).collect();
+        let result: Array = self
+            .engine
+            .call_fn(&mut Scope::new(), &ast, VARY_FUNCTION, (notes, p))
+            .map_err(|e| anyhow!("{e}"))?;
+        let mut variation = Melody::new();
+        for value in result {
+            variation.add(note_from_script(value)?);
+        }
+        Ok(variation)
+    }
+}
+
+impl VariationPlugin for ScriptedVariation {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn vary(&self, _maker: &MelodyMaker, melody: &Melody, p: f64) -> Melody {
+        self.run(melody, p).unwrap_or_else(|e| {
+            error!("Script {} failed: {e}", self.path.display());
+            Melody::new()
+        })
+    }
+}
+
+fn limited_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+    engine.set_max_call_levels(MAX_SCRIPT_CALL_LEVELS);
+    engine.set_max_expr_depths(MAX_SCRIPT_EXPR_DEPTH, MAX_SCRIPT_FUNCTION_EXPR_DEPTH);
+    engine
+}
+
+fn note_to_script(note: &Note) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("pitch".into(), Dynamic::from(note.pitch() as i64));
+    map.insert("duration".into(), Dynamic::from(note.duration()));
+    map.insert("velocity".into(), Dynamic::from(note.velocity() as i64));
+    Dynamic::from(map)
+}
+
+fn note_from_script(value: Dynamic) -> anyhow::Result<Note> {
+    let map = value
+        .try_cast::<Map>()
+        .ok_or(anyhow!("vary must return an array of notes"))?;
+    let int = |key: &str| -> anyhow::Result<MidiByte> {
+        let value = map.get(key).ok_or(anyhow!("A note has no {key}"))?;
+        let value = value.as_int().map_err(|t| anyhow!("{key} is a {t}"))?;
+        MidiByte::try_from(value).map_err(|_| anyhow!("{key} {value} is out of range"))
+    };
+    let duration = map
+        .get("duration")
+        .ok_or(anyhow!("A note has no duration"))?;
+    let duration = duration
+        .as_float()
+        .or_else(|_| duration.as_int().map(|d| d as f64))
+        .map_err(|t| anyhow!("duration is a {t}"))?;
+    let note = Note::new(int("pitch")?, duration, int("velocity")?);
+    if note.is_valid() {
+        Ok(note)
+    } else {
+        Err(anyhow!("{note:?} is not a valid MIDI note"))
+    }
+}
+
+/// Every `.rhai` script in `dir`, as a variation algorithm.
+pub fn load_scripts(dir: &str) -> Vec<Arc<dyn VariationPlugin>> {
+    let mut scripts: Vec<Arc<dyn VariationPlugin>> = vec![];
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return scripts,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) == Some(SCRIPT_EXTENSION) {
+            info!("Found variation script {}", path.display());
+            scripts.push(Arc::new(ScriptedVariation::new(path.as_path())));
+        }
+    }
+    scripts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_variation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Backwards.rhai");
+        fs::write(
+            path.as_path(),
+            "fn vary(notes, p) { notes.reverse(); notes }",
+        )
+        .unwrap();
+        let scripts = load_scripts(dir.path().to_str().unwrap());
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts[0].name(), "Backwards");
+
+        let maker = MelodyMaker::new();
+        let melody = Melody::from("60,0.5,0.8,0,0.25,0,64,1.0,0.8");
+        let variation = scripts[0].vary(&maker, &melody, 0.5);
+        let pitches: Vec<MidiByte> = variation.iter().map(|n| n.pitch()).collect();
+        assert_eq!(pitches, vec![64, 0, 60]);
+        assert_eq!(variation[0].duration(), 1.0);
+
+        fs::write(path.as_path(), "fn vary(notes, p) { 42 }").unwrap();
+        let broken = ScriptedVariation::new(path.as_path());
+        assert!(broken.run(&melody, 0.5).is_err());
+        assert_eq!(broken.vary(&maker, &melody, 0.5).len(), 0);
+
+        fs::write(path.as_path(), "fn vary(notes, p) { loop {} }").unwrap();
+        assert!(ScriptedVariation::new(path.as_path())
+            .run(&melody, 0.5)
+            .is_err());
+        fs::write(
+            path.as_path(),
+            "fn deeper(n) { deeper(n + 1) } fn vary(notes, p) { deeper(0) }",
+        )
+        .unwrap();
+        assert!(ScriptedVariation::new(path.as_path())
+            .run(&melody, 0.5)
+            .is_err());
+        fs::write(
+            path.as_path(),
+            "fn vary(notes, p) { notes[0].pitch = 200; notes }",
+        )
+        .unwrap();
+        assert!(ScriptedVariation::new(path.as_path())
+            .run(&melody, 0.5)
+            .is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::database::{MelodyInfo, Preference, VariationStats};
+use crate::midi_file::save_midi_file;
+use crate::report::preview_wav;
+use crate::threads::{spawn_named, SHARE_CONNECTION_THREAD, SHARE_SERVER_THREAD, SINGLETON};
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use qrcode::{Color, QrCode};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
+
+pub const SHARE_FOLDER: &str = "shared";
+pub const SHARE_PORT: u16 = 8642;
+
+const ACCEPT_POLL_MILLIS: u64 = 100;
+/// A visitor that sends or reads nothing for this long is disconnected.
+const SHARE_TIMEOUT_SECONDS: u64 = 5;
+/// Visitors served at once; more are turned away until one finishes.
+const MAX_SHARE_CONNECTIONS: usize = 4;
+
+fn rank(pref: Preference) -> u8 {
+    match pref {
+        Preference::Favorite => 2,
+        Preference::Neutral => 1,
+        Preference::Ignore => 0,
+    }
+}
+
+/// The exchange worth sharing: the best-rated variation, breaking ties by the rating of
+/// the human phrase and then by taking the most recent.
+pub fn best_exchange(
+    pairs: &[(MelodyInfo, MelodyInfo, VariationStats)],
+) -> Option<&(MelodyInfo, MelodyInfo, VariationStats)> {
+    pairs.iter().max_by_key(|(melody_info, variation_info, _)| {
+        (
+            rank(variation_info.rating()),
+            rank(melody_info.rating()),
+            melody_info.timestamp(),
+        )
+    })
+}
+
+/// Writes the human phrase followed by its variation into `SHARE_FOLDER`, as a WAV file
+/// that phones can play, tuned to `concert_pitch`, and as a MIDI file. Returns the name of
+/// the WAV file.
+pub fn save_exchange(
+    melody_info: &MelodyInfo,
+    variation_info: &MelodyInfo,
+    concert_pitch: f64,
+) -> anyhow::Result<String> {
+    fs::create_dir_all(SHARE_FOLDER)?;
+    let mut exchange = melody_info.melody().clone();
+    exchange.append(variation_info.melody());
+    let stem = format!("exchange-{}", melody_info.row_id());
+    let folder = Path::new(SHARE_FOLDER);
+    save_midi_file(
+        &exchange,
+        folder
+            .join(format!("{stem}.mid"))
+            .to_string_lossy()
+            .as_ref(),
+    )?;
+    let wav_name = format!("{stem}.wav");
+    fs::write(
+        folder.join(wav_name.as_str()),
+        preview_wav(&exchange, concert_pitch),
+    )?;
+    Ok(wav_name)
+}
+
+/// This machine's address on the local network, found by asking which interface would be
+/// used to reach the outside world. No packets are sent.
+pub fn local_address() -> IpAddr {
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("8.8.8.8:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+pub fn share_url(file_name: &str) -> String {
+    format!("http://{}:{SHARE_PORT}/{file_name}", local_address())
+}
+
+/// Returns the width of the QR code for `text`, and whether each of its modules is dark,
+/// row by row.
+pub fn qr_modules(text: &str) -> anyhow::Result<(usize, Vec<bool>)> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| anyhow!("No QR code: {e:?}"))?;
+    let dark = code.to_colors().iter().map(|c| *c == Color::Dark).collect();
+    Ok((code.width(), dark))
+}
+
+/// Returns the file named by an HTTP GET request line, provided it names a file directly
+/// within the share folder.
+fn requested_file(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split_whitespace();
+    if parts.next() != Some("GET") {
+        return None;
+    }
+    let name = parts.next()?.strip_prefix('/')?;
+    let safe = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    safe.then_some(name)
+}
+
+fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".wav") {
+        "audio/wav"
+    } else if name.ends_with(".mid") {
+        "audio/midi"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+fn serve(mut stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    let timeout = Some(Duration::from_secs(SHARE_TIMEOUT_SECONDS));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let file = requested_file(request_line.as_str())
+        .and_then(|name| Some((name, fs::read(Path::new(SHARE_FOLDER).join(name)).ok()?)));
+    match file {
+        Some((name, bytes)) => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type(name),
+                bytes.len()
+            )?;
+            stream.write_all(bytes.as_slice())?;
+        }
+        None => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?,
+    }
+    Ok(())
+}
+
+/// Serves the files in `SHARE_FOLDER` over HTTP, so that visitors can download their
+/// exchange by scanning its QR code. Each visitor is served on its own thread, so that
+/// one slow phone does not hold up the rest.
+pub fn start_share_server_thread(quit: Arc<AtomicCell<bool>>) -> anyhow::Result<()> {
+    spawn_named(SHARE_SERVER_THREAD, SINGLETON, move || {
+        let listener = match TcpListener::bind(("0.0.0.0", SHARE_PORT)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Cannot share on port {SHARE_PORT}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Cannot share on port {SHARE_PORT}: {e}");
+            return;
+        }
+        while !quit.load() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let started =
+                        spawn_named(SHARE_CONNECTION_THREAD, MAX_SHARE_CONNECTIONS, move || {
+                            if let Err(e) = serve(stream) {
+                                warn!("Share request failed: {e}");
+                            }
+                        });
+                    if let Err(e) = started {
+                        warn!("Turning away a share request: {e}");
+                    }
+                }
+                Err(_) => thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested_file() {
+        assert_eq!(
+            requested_file("GET /exchange-12.wav HTTP/1.1\r\n"),
+            Some("exchange-12.wav")
+        );
+        assert_eq!(requested_file("GET /../secret.db HTTP/1.1"), None);
+        assert_eq!(requested_file("GET /sub/file.wav HTTP/1.1"), None);
+        assert_eq!(requested_file("GET / HTTP/1.1"), None);
+        assert_eq!(requested_file("POST /exchange-12.wav HTTP/1.1"), None);
+        assert_eq!(content_type("exchange-12.mid"), "audio/midi");
+    }
+
+    #[test]
+    fn test_qr_modules() {
+        let (width, dark) = qr_modules("http://192.168.1.20:8642/exchange-12.wav").unwrap();
+        assert!(width >= 21);
+        assert_eq!(dark.len(), width * width);
+        assert!(dark.iter().any(|d| *d));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::analyzer::{Melody, Note};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+const SECONDS_PER_MINUTE: f64 = 60.0;
+const MIN_BEAT_SECONDS: f64 = 0.3;
+const MAX_BEAT_SECONDS: f64 = 1.0;
+const MIN_ONSETS_FOR_TEMPO: usize = 3;
+const MIN_TAPS: usize = 4;
+const MAX_TAPS: usize = 8;
+const MAX_TAP_GAP_SECONDS: f64 = 2.0;
+
+/// A tempo, represented by the length of one beat in seconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tempo {
+    beat_seconds: f64,
+}
+
+impl Tempo {
+    pub fn from_bpm(bpm: f64) -> Self {
+        Tempo {
+            beat_seconds: SECONDS_PER_MINUTE / bpm,
+        }
+    }
+
+    pub fn bpm(&self) -> f64 {
+        SECONDS_PER_MINUTE / self.beat_seconds
+    }
+
+    pub fn beat_seconds(&self) -> f64 {
+        self.beat_seconds
+    }
+
+    /// Estimates the player's tempo from the inter-onset intervals of `melody`. The median
+    /// interval is doubled or halved until it falls between 60 and 200 beats per minute.
+    /// Returns `None` if there are too few notes to tell.
+    pub fn estimate(melody: &Melody) -> Option<Self> {
+        if melody.onset_indices().len() < MIN_ONSETS_FOR_TEMPO {
+            return None;
+        }
+        let mut beat_seconds = melody.median_duration_note_on().into_inner();
+        if beat_seconds <= 0.0 {
+            return None;
+        }
+        while beat_seconds < MIN_BEAT_SECONDS {
+            beat_seconds *= 2.0;
+        }
+        while beat_seconds > MAX_BEAT_SECONDS {
+            beat_seconds /= 2.0;
+        }
+        Some(Tempo { beat_seconds })
+    }
+
+    /// Returns `melody`, played at this tempo, retimed so that it keeps the same
+    /// durations in beats when played at `target`.
+    pub fn retimed(&self, melody: &Melody, target: Tempo) -> Melody {
+        BeatMelody::new(melody, *self).at(target)
+    }
+}
+
+/// A melody whose note lengths are counted in beats rather than seconds, along with the
+/// tempo it was counted at. Variations are kept this way until they start, so that they
+/// are heard in proportion at whatever tempo is set by then.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BeatMelody {
+    notes: Vec<Note>,
+    tempo: Tempo,
+}
+
+impl BeatMelody {
+    /// Counts the notes of `melody`, as played at `tempo`, in beats.
+    pub fn new(melody: &Melody, tempo: Tempo) -> Self {
+        BeatMelody {
+            notes: melody
+                .iter()
+                .map(|n| n.retimed(n.duration() / tempo.beat_seconds))
+                .collect(),
+            tempo,
+        }
+    }
+
+    /// The tempo the melody was counted at.
+    pub fn tempo(&self) -> Tempo {
+        self.tempo
+    }
+
+    /// The length of each note in beats.
+    pub fn beats(&self) -> Vec<f64> {
+        self.notes.iter().map(|n| n.duration()).collect()
+    }
+
+    pub fn total_beats(&self) -> f64 {
+        self.notes.iter().map(|n| n.duration()).sum()
+    }
+
+    /// The melody in seconds, played at `tempo`.
+    pub fn at(&self, tempo: Tempo) -> Melody {
+        let mut melody = Melody::new();
+        for note in self.notes.iter() {
+            melody.add(note.retimed(note.duration() * tempo.beat_seconds));
+        }
+        melody
+    }
+
+    /// The melody in seconds, played at the tempo it was counted at.
+    pub fn as_played(&self) -> Melody {
+        self.at(self.tempo)
+    }
+}
+
+/// Estimates a tempo from the performer's taps by averaging the intervals between the
+/// last several of them. A pause longer than two seconds starts over.
+#[derive(Default)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tap(&mut self) -> Option<Tempo> {
+        self.tap_at(Instant::now())
+    }
+
+    /// Returns `None` until there have been enough taps to smooth over.
+    pub fn tap_at(&mut self, when: Instant) -> Option<Tempo> {
+        if let Some(last) = self.taps.back() {
+            if when.duration_since(*last).as_secs_f64() > MAX_TAP_GAP_SECONDS {
+                self.taps.clear();
+            }
+        }
+        self.taps.push_back(when);
+        if self.taps.len() > MAX_TAPS {
+            self.taps.pop_front();
+        }
+        if self.taps.len() < MIN_TAPS {
+            None
+        } else {
+            let span = when.duration_since(*self.taps.front().unwrap());
+            Some(Tempo {
+                beat_seconds: span.as_secs_f64() / (self.taps.len() - 1) as f64,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Note;
+    use float_cmp::assert_approx_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tempo() {
+        let mut melody = Melody::new();
+        for pitch in [60, 62, 64, 65, 67] {
+            melody.add(Note::new(pitch, 0.2, 100));
+            melody.add(Note::new(pitch, 0.05, 0));
+        }
+        let tempo = Tempo::estimate(&melody).unwrap();
+        assert_approx_eq!(f64, tempo.beat_seconds(), 0.5);
+        assert_approx_eq!(f64, tempo.bpm(), 120.0);
+        let counted = BeatMelody::new(&melody, tempo);
+        assert_approx_eq!(f64, counted.beats()[0], 0.4);
+        assert_approx_eq!(f64, counted.total_beats(), 2.5);
+        assert_eq!(counted.tempo(), tempo);
+        for (played, original) in counted.as_played().iter().zip(melody.iter()) {
+            assert_approx_eq!(Note, *played, *original);
+        }
+
+        let slower = tempo.retimed(&melody, Tempo::from_bpm(60.0));
+        assert_approx_eq!(f64, slower.duration(), melody.duration() * 2.0);
+        let slower_beats = BeatMelody::new(&slower, Tempo::from_bpm(60.0)).beats();
+        for (before, after) in counted.beats().iter().zip(slower_beats.iter()) {
+            assert_approx_eq!(f64, *before, *after);
+        }
+
+        assert!(Tempo::estimate(&Melody::new()).is_none());
+    }
+
+    #[test]
+    fn test_tap_tempo() {
+        let mut tapper = TapTempo::new();
+        let start = Instant::now();
+        let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+        for i in 0..MIN_TAPS - 1 {
+            assert!(tapper.tap_at(at(i as f64 * 0.5)).is_none());
+        }
+        let tempo = tapper.tap_at(at((MIN_TAPS - 1) as f64 * 0.5)).unwrap();
+        assert_approx_eq!(f64, tempo.bpm(), 120.0, epsilon = 0.001);
+
+        let mut last = 0.0;
+        for i in 0..MAX_TAPS * 2 {
+            last = 10.0 + i as f64 * 0.25;
+            tapper.tap_at(at(last));
+        }
+        assert_eq!(tapper.taps.len(), MAX_TAPS);
+        let tempo = tapper.tap_at(at(last + 0.25)).unwrap();
+        assert_approx_eq!(f64, tempo.bpm(), 240.0, epsilon = 0.001);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::runtime::MelodyRunStatus;
+use crossbeam_queue::SegQueue;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::{Speaker, SynthMsg};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Playback threads may pile up when melodies are started faster than earlier ones stop.
+pub const MAX_PLAYBACK_THREADS: usize = 4;
+/// For the long-running threads that exist once per program run.
+pub const SINGLETON: usize = 1;
+
+pub const AI_THREAD: &str = "ai";
+pub const AUTOMATION_THREAD: &str = "automation";
+pub const BACKING_THREAD: &str = "backing track";
+pub const CLOCK_OUTPUT_THREAD: &str = "midi clock output";
+pub const DATABASE_THREAD: &str = "database";
+pub const DRAIN_THREAD: &str = "output drain";
+pub const FOLDER_WATCH_THREAD: &str = "folder watch";
+pub const INPUT_THREAD: &str = "midi input";
+pub const MOCK_MIDI_THREAD: &str = "mock midi";
+pub const REPLAY_THREAD: &str = "midi replay";
+pub const PLAYBACK_THREAD: &str = "playback";
+pub const SCHEDULER_THREAD: &str = "scheduler";
+pub const SHARE_CONNECTION_THREAD: &str = "share connection";
+pub const SHARE_SERVER_THREAD: &str = "share server";
+pub const GUI_LISTENER_THREAD: &str = "gui listener";
+pub const WATCHDOG_THREAD: &str = "watchdog";
+
+const SHUTDOWN_TIMEOUT_MILLIS: u128 = 2000;
+const SHUTDOWN_POLL_MILLIS: u64 = 10;
+
+/// The refusal `spawn_named` gives when enough threads of that name are already running,
+/// as distinct from the operating system failing to start one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AlreadyRunning {
+    pub name: &'static str,
+    pub max_running: usize,
+}
+
+impl Display for AlreadyRunning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Not starting {} thread: {} already running",
+            self.name, self.max_running
+        )
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+struct Entry {
+    id: u64,
+    name: &'static str,
+    started: Instant,
+}
+
+/// Keeps track of the threads started through it, so that their numbers can be limited
+/// and shutdown can wait for them. The program's threads all belong to `THREADS`; tests
+/// can keep theirs apart.
+struct ThreadRegistry {
+    running: Mutex<Vec<Entry>>,
+    next_id: AtomicU64,
+}
+
+static THREADS: ThreadRegistry = ThreadRegistry::new();
+
+/// Removes its thread from the registry when the thread ends, even by panicking.
+struct Registration(&'static ThreadRegistry, u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.0.running.lock().unwrap().retain(|e| e.id != self.1);
+    }
+}
+
+impl ThreadRegistry {
+    const fn new() -> Self {
+        ThreadRegistry {
+            running: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(
+        &'static self,
+        name: &'static str,
+        max_running: usize,
+        f: F,
+    ) -> anyhow::Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.iter().filter(|e| e.name == name).count() >= max_running {
+                return Err(AlreadyRunning { name, max_running }.into());
+            }
+            running.push(Entry {
+                id,
+                name,
+                started: Instant::now(),
+            });
+        }
+        let registration = Registration(self, id);
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let _registration = registration;
+                f();
+            })?;
+        Ok(())
+    }
+
+    fn running(&self) -> Vec<(&'static str, f64)> {
+        self.running
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.name, e.started.elapsed().as_secs_f64()))
+            .collect()
+    }
+}
+
+/// Spawns `f` on a thread called `name`, unless `max_running` threads of that name are
+/// already running. Callers decide what a refusal means: whether to tell the user, or
+/// to carry on because the thread they wanted is already there. A refusal is an
+/// `AlreadyRunning` error.
+pub fn spawn_named<F: FnOnce() + Send + 'static>(
+    name: &'static str,
+    max_running: usize,
+    f: F,
+) -> anyhow::Result<()> {
+    THREADS.spawn(name, max_running, f)
+}
+
+/// Returns the name of each running thread along with how many seconds it has run.
+pub fn running_threads() -> Vec<(&'static str, f64)> {
+    THREADS.running()
+}
+
+/// Brings the program down in order, so that nothing is lost or left sounding. Threads
+/// started by `spawn_named` are asked to quit first: the input thread closes its MIDI
+/// connection, the AI thread keeps any melody in progress, and the database thread writes
+/// everything queued before it closes. Only then are the synthesizers silenced and the
+/// output thread stopped.
+#[derive(Clone)]
+pub struct Shutdown {
+    pub quit_threads: Arc<AtomicCell<bool>>,
+    pub quit_output: Arc<AtomicCell<bool>>,
+    melody_run_status: MelodyRunStatus,
+    to_output: Arc<SegQueue<SynthMsg>>,
+    threads: &'static ThreadRegistry,
+}
+
+impl Shutdown {
+    pub fn new(melody_run_status: MelodyRunStatus, to_output: Arc<SegQueue<SynthMsg>>) -> Self {
+        Shutdown {
+            quit_threads: Arc::new(AtomicCell::new(false)),
+            quit_output: Arc::new(AtomicCell::new(false)),
+            melody_run_status,
+            to_output,
+            threads: &THREADS,
+        }
+    }
+
+    /// Waits for the threads of `threads` instead of the program's.
+    #[cfg(test)]
+    fn with_threads(self, threads: &'static ThreadRegistry) -> Self {
+        Shutdown { threads, ..self }
+    }
+
+    /// Returns once every thread has finished, or after a timeout. Only the first call has
+    /// any effect.
+    pub fn run(&self) {
+        if self.quit_threads.swap(true) {
+            return;
+        }
+        info!("Shutting down...");
+        let start = Instant::now();
+        while !self.threads.running().is_empty()
+            && start.elapsed().as_millis() < SHUTDOWN_TIMEOUT_MILLIS
+        {
+            // Repeated in case the AI thread starts a variation after the first request.
+            self.melody_run_status.send_stop();
+            thread::sleep(Duration::from_millis(SHUTDOWN_POLL_MILLIS));
+        }
+        let stragglers = self.threads.running();
+        if !stragglers.is_empty() {
+            warn!("Still running at shutdown: {stragglers:?}");
+        }
+        self.to_output.push(SynthMsg::all_notes_off(Speaker::Both));
+        while !self.to_output.is_empty() && start.elapsed().as_millis() < SHUTDOWN_TIMEOUT_MILLIS {
+            thread::sleep(Duration::from_millis(SHUTDOWN_POLL_MILLIS));
+        }
+        self.quit_output.store(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_thread_limit() {
+        const TEST_THREAD: &str = "test limit";
+        let (finish, wait) = channel::<()>();
+        let (started, ready) = channel::<()>();
+        assert!(spawn_named(TEST_THREAD, 1, move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        })
+        .is_ok());
+        ready.recv().unwrap();
+        assert_eq!(
+            spawn_named(TEST_THREAD, 1, || {})
+                .unwrap_err()
+                .downcast_ref::<AlreadyRunning>(),
+            Some(&AlreadyRunning {
+                name: TEST_THREAD,
+                max_running: 1
+            })
+        );
+        let count = |name: &str| running_threads().iter().filter(|(n, _)| *n == name).count();
+        assert_eq!(count(TEST_THREAD), 1);
+        finish.send(()).unwrap();
+        let started = Instant::now();
+        while count(TEST_THREAD) > 0 && started.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count(TEST_THREAD), 0);
+        assert!(spawn_named(TEST_THREAD, 1, || {}).is_ok());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // Apart from the program's threads, which other tests start and stop meanwhile.
+        static TEST_THREADS: ThreadRegistry = ThreadRegistry::new();
+        const TEST_THREAD: &str = "test shutdown";
+        let to_output = Arc::new(SegQueue::new());
+        let shutdown =
+            Shutdown::new(MelodyRunStatus::new(), to_output.clone()).with_threads(&TEST_THREADS);
+        let quit = shutdown.quit_threads.clone();
+        let finished = Arc::new(AtomicCell::new(false));
+        {
+            let finished = finished.clone();
+            TEST_THREADS
+                .spawn(TEST_THREAD, 1, move || {
+                    let started = Instant::now();
+                    while !quit.load() && started.elapsed() < Duration::from_secs(2) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    finished.store(quit.load());
+                })
+                .unwrap();
+        }
+        // Nothing consumes the output queue here, so the all-notes-off stays in it.
+        shutdown.run();
+        assert!(finished.load());
+        assert!(shutdown.quit_output.load());
+        assert_eq!(to_output.len(), 1);
+        shutdown.run();
+        assert_eq!(to_output.len(), 1);
+    }
+}
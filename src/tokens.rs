@@ -0,0 +1,81 @@
+use crate::analyzer::{Melody, MidiByte};
+use crate::tempo::Tempo;
+
+/// The REMI convention of sixteen positions in a bar of four beats.
+pub const POSITIONS_PER_BEAT: usize = 4;
+pub const BEATS_PER_BAR: usize = 4;
+pub const VELOCITY_BINS: usize = 32;
+/// Longer notes are truncated to two bars, so that the vocabulary stays finite.
+pub const MAX_DURATION_POSITIONS: usize = 2 * POSITIONS_PER_BEAT * BEATS_PER_BAR;
+
+const DEFAULT_BPM: f64 = 120.0;
+const MIDI_VALUES: usize = 128;
+
+/// Encodes `melody` as REMI-style event tokens for training symbolic-music models:
+/// a `Tempo` token, then `Bar` at the start of every bar, and for each note
+/// `Position`, `Pitch`, `Velocity` and `Duration`. Positions and durations are in
+/// sixteenths on a grid at the estimated tempo, or 120 beats per minute if there are
+/// too few notes to estimate one. Rests are implied by the gaps between notes.
+pub fn remi_tokens(melody: &Melody) -> Vec<String> {
+    let tempo = Tempo::estimate(melody).unwrap_or_else(|| Tempo::from_bpm(DEFAULT_BPM));
+    let position_seconds = tempo.beat_seconds() / POSITIONS_PER_BEAT as f64;
+    let positions_per_bar = POSITIONS_PER_BEAT * BEATS_PER_BAR;
+    let mut tokens = vec![format!("Tempo_{}", tempo.bpm().round() as i64)];
+    let mut bars = 0;
+    let mut start = 0.0;
+    for note in melody.iter() {
+        if !note.is_rest() {
+            let position = (start / position_seconds).round() as usize;
+            while bars <= position / positions_per_bar {
+                tokens.push("Bar".to_string());
+                bars += 1;
+            }
+            let duration = ((note.duration() / position_seconds).round() as usize)
+                .clamp(1, MAX_DURATION_POSITIONS);
+            let velocity = note.velocity().clamp(0, MIDI_VALUES as MidiByte - 1) as usize
+                * VELOCITY_BINS
+                / MIDI_VALUES;
+            tokens.push(format!("Position_{}", position % positions_per_bar));
+            tokens.push(format!("Pitch_{}", note.pitch()));
+            tokens.push(format!("Velocity_{velocity}"));
+            tokens.push(format!("Duration_{duration}"));
+        }
+        start += note.duration();
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remi_tokens() {
+        // At 120 bpm a sixteenth lasts 0.125 seconds.
+        let melody = Melody::from("60,0.5,0.8,62,0.5,0.5,64,1.0,1.0,0,1.0,0.0,67,0.25,0.8");
+        assert_eq!(
+            remi_tokens(&melody),
+            vec![
+                "Tempo_120",
+                "Bar",
+                "Position_0",
+                "Pitch_60",
+                "Velocity_25",
+                "Duration_4",
+                "Position_4",
+                "Pitch_62",
+                "Velocity_15",
+                "Duration_4",
+                "Position_8",
+                "Pitch_64",
+                "Velocity_31",
+                "Duration_8",
+                "Bar",
+                "Position_8",
+                "Pitch_67",
+                "Velocity_25",
+                "Duration_2",
+            ]
+        );
+    }
+}
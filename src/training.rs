@@ -0,0 +1,126 @@
+use crate::analyzer::{MelodicFigure, Melody, MidiByte, MusicMode, Note};
+use crate::database::PracticeAttempt;
+use bare_metal_modulo::ModNumC;
+use enum_iterator::all;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use std::collections::BTreeMap;
+
+/// Targets start on middle C, in C major.
+const TARGET_START_PITCH: MidiByte = 60;
+/// How long each note of a target lasts when it is played for the player.
+const TARGET_NOTE_SECONDS: f64 = 0.5;
+const TARGET_VELOCITY: MidiByte = 100;
+/// How many of the latest attempts at a figure are averaged to show current progress.
+const RECENT_ATTEMPTS: usize = 5;
+
+/// A melodic figure from the figure library for the player to play back by ear. Only the
+/// shape of an attempt is scored, so it may be played in any key and at any tempo.
+#[derive(Clone, Debug)]
+pub struct PracticeTarget {
+    figure: MelodicFigure,
+    melody: Melody,
+}
+
+impl PracticeTarget {
+    pub fn new(figure: MelodicFigure, scale: &MusicMode, start_pitch: MidiByte) -> Self {
+        let mut melody = Melody::new();
+        for pitch in figure.make_pitches(start_pitch, scale) {
+            melody.add(Note::new(pitch, TARGET_NOTE_SECONDS, TARGET_VELOCITY));
+        }
+        PracticeTarget { figure, melody }
+    }
+
+    /// Any figure from the library, chosen by `rng`, starting on middle C.
+    pub fn random<R: Rng>(rng: &mut R) -> Self {
+        let figure = all::<MelodicFigure>().choose(rng).unwrap();
+        let scale = MusicMode::new(ModNumC::new(0), TARGET_START_PITCH);
+        Self::new(figure, &scale, TARGET_START_PITCH)
+    }
+
+    pub fn name(&self) -> String {
+        self.figure.name()
+    }
+
+    pub fn melody(&self) -> &Melody {
+        &self.melody
+    }
+
+    /// How closely `attempt` followed this figure, from 0.0 for nothing in common to 1.0
+    /// for the same sequence of intervals, by `Melody::interval_distance`.
+    pub fn score(&self, attempt: &Melody) -> f64 {
+        1.0 - self.melody.interval_distance(attempt)
+    }
+
+    pub fn attempt(&self, attempt: &Melody) -> PracticeAttempt {
+        PracticeAttempt::new(self.name(), self.score(attempt))
+    }
+}
+
+/// How the player has done at one figure.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FigureProgress {
+    pub figure: String,
+    pub attempts: usize,
+    pub best: f64,
+    /// The mean score of the latest `RECENT_ATTEMPTS` attempts.
+    pub recent: f64,
+}
+
+/// Sums up `attempts`, given most recent first, figure by figure in alphabetical order.
+pub fn progress(attempts: &[PracticeAttempt]) -> Vec<FigureProgress> {
+    let mut by_figure: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for attempt in attempts {
+        by_figure
+            .entry(attempt.figure.as_str())
+            .or_default()
+            .push(attempt.score);
+    }
+    by_figure
+        .iter()
+        .map(|(figure, scores)| {
+            let recent = &scores[..scores.len().min(RECENT_ATTEMPTS)];
+            FigureProgress {
+                figure: figure.to_string(),
+                attempts: scores.len(),
+                best: scores.iter().copied().fold(0.0, f64::max),
+                recent: recent.iter().sum::<f64>() / recent.len() as f64,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_practice() {
+        let target = PracticeTarget::random(&mut rand::thread_rng());
+        assert!(target.melody().len() >= 3);
+        assert_eq!(target.melody()[0].pitch(), TARGET_START_PITCH);
+        assert_eq!(target.score(target.melody()), 1.0);
+        let mut transposed = Melody::new();
+        for note in target.melody().iter() {
+            transposed.add(note.repitched(note.pitch() + 5));
+        }
+        assert_eq!(target.score(&transposed), 1.0);
+        let mut wrong = Melody::new();
+        for pitch in [60, 60, 60, 60] {
+            wrong.add(Note::new(pitch, 0.5, 100));
+            wrong.add(Note::new(pitch, 0.0, 0));
+        }
+        assert!(target.score(&wrong) < 1.0);
+
+        let attempts = [("Run", 1.0), ("Arpeggio", 0.25), ("Run", 0.5)]
+            .iter()
+            .map(|(figure, score)| PracticeAttempt::new(figure.to_string(), *score))
+            .collect::<Vec<_>>();
+        let summary = progress(&attempts);
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].figure, "Arpeggio");
+        assert_eq!(summary[1].attempts, 2);
+        assert_eq!(summary[1].best, 1.0);
+        assert_eq!(summary[1].recent, 0.75);
+    }
+}
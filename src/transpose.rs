@@ -0,0 +1,577 @@
+use crate::analyzer::MidiByte;
+use crate::watchdog::{same_speaker, silences};
+use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_msg::{Channel, ChannelModeMsg, ChannelVoiceMsg, ControlChange, MidiMsg};
+
+const HIGHEST_MIDI_NOTE: MidiByte = 127;
+
+/// The frequency of A4 that MIDI note numbers are tuned to.
+pub const STANDARD_CONCERT_PITCH: f64 = 440.0;
+
+const CENTERED_BEND: u16 = 8192;
+const HIGHEST_BEND: u16 = 16383;
+/// The pitch-bend range, either way, that each channel is set to (RPN 0, pitch-bend
+/// sensitivity) before its first note, so that concert pitch does not depend on the patch.
+/// Two semitones is the General MIDI default, so player bends still sound as expected.
+const BEND_RANGE_SEMITONES: u8 = 2;
+const RPN_MSB_CONTROL: u8 = 101;
+const RPN_LSB_CONTROL: u8 = 100;
+const DATA_ENTRY_MSB_CONTROL: u8 = 6;
+const DATA_ENTRY_LSB_CONTROL: u8 = 38;
+const PITCH_BEND_SENSITIVITY_RPN: u8 = 0;
+/// Deselects the RPN, so that stray data entry changes nothing.
+const NULL_RPN: u8 = 127;
+const MOD_WHEEL_CONTROL: u8 = 1;
+const HIGHEST_CONTROL_VALUE: u8 = 127;
+const VIBRATO_HZ: f64 = 5.5;
+/// How far either way the pitch swings with the mod wheel all the way up.
+const MAX_VIBRATO_SEMITONES: f64 = 0.5;
+
+/// Shifts every note on its way to the synthesizers by a number of semitones, for playing
+/// along with a recording in another key or reading a part for a transposing instrument.
+/// Each note is released where it was started, even if the transposition changed while it
+/// was held. Notes shifted beyond the MIDI range are dropped.
+///
+/// A concert pitch other than A4 = 440 Hz is reached by bending each channel before its
+/// next note starts. A channel is only retuned while none of its notes are held, so that
+/// no held note changes pitch. Pitch bends from the player are kept, offset by the same
+/// amount.
+///
+/// The mod wheel (CC1) adds vibrato to every note on its channel, by swinging the
+/// channel's bend back and forth as `vibrato()` is called, further the higher the wheel.
+#[derive(Default)]
+pub struct Transposer {
+    sounding: Vec<(Speaker, Channel, u8, u8)>,
+    bends: Vec<ChannelBend>,
+}
+
+struct ChannelBend {
+    speaker: Speaker,
+    channel: Channel,
+    /// The player's own bend.
+    bend: u16,
+    /// The concert pitch last sent with it.
+    tuned_to: f64,
+    modulation: u8,
+}
+
+impl ChannelBend {
+    fn voice(&self, msg: ChannelVoiceMsg) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: self.channel,
+                msg,
+            },
+            speaker: self.speaker,
+        }
+    }
+}
+
+impl Transposer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns what to send in place of `synth_msg`: nothing if it is dropped, and more
+    /// than one message where a note is struck again or the channel must be retuned first.
+    pub fn transpose(
+        &mut self,
+        semitones: MidiByte,
+        concert_pitch: f64,
+        synth_msg: SynthMsg,
+    ) -> Vec<SynthMsg> {
+        let speaker = synth_msg.speaker;
+        let voice = |channel, msg| SynthMsg {
+            msg: MidiMsg::ChannelVoice { channel, msg },
+            speaker,
+        };
+        match synth_msg.msg {
+            MidiMsg::ChannelVoice { channel, msg } => {
+                let mut sent = vec![];
+                let msg = match msg {
+                    ChannelVoiceMsg::NoteOn { note, velocity } if velocity > 0 => {
+                        if let Some(old) = self.release(speaker, channel, note) {
+                            sent.push(voice(
+                                channel,
+                                ChannelVoiceMsg::NoteOff {
+                                    note: old,
+                                    velocity: 0,
+                                },
+                            ));
+                        }
+                        let shifted = note as MidiByte + semitones;
+                        if !(0..=HIGHEST_MIDI_NOTE).contains(&shifted) {
+                            return sent;
+                        }
+                        if let Some(bend) = self.retune(speaker, channel, concert_pitch, &mut sent)
+                        {
+                            sent.push(voice(channel, ChannelVoiceMsg::PitchBend { bend }));
+                        }
+                        self.sounding.push((speaker, channel, note, shifted as u8));
+                        ChannelVoiceMsg::NoteOn {
+                            note: shifted as u8,
+                            velocity,
+                        }
+                    }
+                    ChannelVoiceMsg::PitchBend { bend } => {
+                        let quiet = self.is_quiet(speaker, channel);
+                        let i = self.channel_bend(speaker, channel, &mut sent);
+                        let channel_bend = &mut self.bends[i];
+                        channel_bend.bend = bend;
+                        if quiet {
+                            channel_bend.tuned_to = concert_pitch;
+                        }
+                        ChannelVoiceMsg::PitchBend {
+                            bend: tuned_bend(bend, channel_bend.tuned_to),
+                        }
+                    }
+                    ChannelVoiceMsg::ControlChange {
+                        control: ControlChange::ModWheel(value),
+                    } => {
+                        self.modulate(speaker, channel, (value >> 7) as u8, &mut sent);
+                        msg
+                    }
+                    ChannelVoiceMsg::ControlChange {
+                        control:
+                            ControlChange::CC {
+                                control: MOD_WHEEL_CONTROL,
+                                value,
+                            },
+                    } => {
+                        self.modulate(speaker, channel, value, &mut sent);
+                        msg
+                    }
+                    ChannelVoiceMsg::NoteOn { note, velocity } => {
+                        match self.release(speaker, channel, note) {
+                            Some(note) => ChannelVoiceMsg::NoteOn { note, velocity },
+                            None => return sent,
+                        }
+                    }
+                    ChannelVoiceMsg::NoteOff { note, velocity } => {
+                        match self.release(speaker, channel, note) {
+                            Some(note) => ChannelVoiceMsg::NoteOff { note, velocity },
+                            None => return sent,
+                        }
+                    }
+                    ChannelVoiceMsg::PolyPressure { note, pressure } => {
+                        match self.sounding_as(speaker, channel, note) {
+                            Some(note) => ChannelVoiceMsg::PolyPressure { note, pressure },
+                            None => return sent,
+                        }
+                    }
+                    msg => msg,
+                };
+                sent.push(voice(channel, msg));
+                sent
+            }
+            MidiMsg::ChannelMode {
+                msg: ChannelModeMsg::AllNotesOff | ChannelModeMsg::AllSoundOff,
+                ..
+            } => {
+                self.sounding.retain(|(s, _, _, _)| !silences(speaker, *s));
+                vec![synth_msg]
+            }
+            _ => vec![synth_msg],
+        }
+    }
+
+    /// Forgets every sounding note, once they have all been silenced some other way.
+    /// Channels stay bent as they were.
+    pub fn clear(&mut self) {
+        self.sounding.clear();
+    }
+
+    /// Forgets the note that `release`, sent by someone else after the transposition,
+    /// ends, so that a stuck note released by the watchdog no longer holds its channel's
+    /// tuning, and its late NoteOff, if one comes, is dropped.
+    pub fn forget(&mut self, release: &SynthMsg) {
+        if let MidiMsg::ChannelVoice {
+            channel,
+            msg: ChannelVoiceMsg::NoteOff { note, .. },
+        } = release.msg
+        {
+            self.sounding.retain(|(s, c, _, shifted)| {
+                !(same_speaker(release.speaker, *s) && *c == channel && *shifted == note)
+            });
+        }
+    }
+
+    /// Whether any channel has its mod wheel up, so that `vibrato()` should be called often.
+    pub fn is_vibrating(&self) -> bool {
+        self.bends.iter().any(|b| b.modulation > 0)
+    }
+
+    /// The bend, `seconds` into the vibrato, for each channel whose mod wheel is up.
+    pub fn vibrato(&self, seconds: f64) -> Vec<SynthMsg> {
+        let swing = (2.0 * std::f64::consts::PI * VIBRATO_HZ * seconds).sin();
+        self.bends
+            .iter()
+            .filter(|b| b.modulation > 0)
+            .map(|b| {
+                let depth = b.modulation as f64 / HIGHEST_CONTROL_VALUE as f64;
+                let semitones = depth * MAX_VIBRATO_SEMITONES * swing;
+                let offset = semitones / BEND_RANGE_SEMITONES as f64 * CENTERED_BEND as f64;
+                let bend = (tuned_bend(b.bend, b.tuned_to) as f64 + offset)
+                    .round()
+                    .clamp(0.0, HIGHEST_BEND as f64) as u16;
+                b.voice(ChannelVoiceMsg::PitchBend { bend })
+            })
+            .collect()
+    }
+
+    /// Sets the vibrato depth on `channel`. Once the mod wheel is all the way down, the
+    /// channel is returned to its bend without vibrato.
+    fn modulate(
+        &mut self,
+        speaker: Speaker,
+        channel: Channel,
+        modulation: u8,
+        sent: &mut Vec<SynthMsg>,
+    ) {
+        let i = self.channel_bend(speaker, channel, sent);
+        let channel_bend = &mut self.bends[i];
+        if modulation == 0 && channel_bend.modulation > 0 {
+            let bend = tuned_bend(channel_bend.bend, channel_bend.tuned_to);
+            sent.push(channel_bend.voice(ChannelVoiceMsg::PitchBend { bend }));
+        }
+        channel_bend.modulation = modulation;
+    }
+
+    /// Returns the bend to send on `channel` before its next note, if the concert pitch
+    /// has changed since it was last bent and none of the channel's notes are held.
+    fn retune(
+        &mut self,
+        speaker: Speaker,
+        channel: Channel,
+        concert_pitch: f64,
+        sent: &mut Vec<SynthMsg>,
+    ) -> Option<u16> {
+        let quiet = self.is_quiet(speaker, channel);
+        let i = self.channel_bend(speaker, channel, sent);
+        let channel_bend = &mut self.bends[i];
+        if channel_bend.tuned_to == concert_pitch || !quiet {
+            return None;
+        }
+        channel_bend.tuned_to = concert_pitch;
+        Some(tuned_bend(channel_bend.bend, concert_pitch))
+    }
+
+    /// Where `channel`'s bend is kept. A channel not seen before has its bend range set
+    /// first, by adding the messages for it to `sent`.
+    fn channel_bend(
+        &mut self,
+        speaker: Speaker,
+        channel: Channel,
+        sent: &mut Vec<SynthMsg>,
+    ) -> usize {
+        match self
+            .bends
+            .iter()
+            .position(|b| same_speaker(speaker, b.speaker) && b.channel == channel)
+        {
+            Some(i) => i,
+            None => {
+                sent.extend(bend_range(speaker, channel));
+                self.bends.push(ChannelBend {
+                    speaker,
+                    channel,
+                    bend: CENTERED_BEND,
+                    tuned_to: STANDARD_CONCERT_PITCH,
+                    modulation: 0,
+                });
+                self.bends.len() - 1
+            }
+        }
+    }
+
+    fn is_quiet(&self, speaker: Speaker, channel: Channel) -> bool {
+        !self
+            .sounding
+            .iter()
+            .any(|(s, c, _, _)| same_speaker(speaker, *s) && *c == channel)
+    }
+
+    fn sounding_as(&self, speaker: Speaker, channel: Channel, note: u8) -> Option<u8> {
+        self.sounding
+            .iter()
+            .find(|(s, c, n, _)| same_speaker(speaker, *s) && *c == channel && *n == note)
+            .map(|(_, _, _, shifted)| *shifted)
+    }
+
+    fn release(&mut self, speaker: Speaker, channel: Channel, note: u8) -> Option<u8> {
+        let i = self
+            .sounding
+            .iter()
+            .position(|(s, c, n, _)| same_speaker(speaker, *s) && *c == channel && *n == note)?;
+        Some(self.sounding.remove(i).3)
+    }
+}
+
+/// The controller messages that set `channel`'s pitch-bend range to
+/// `BEND_RANGE_SEMITONES`.
+fn bend_range(speaker: Speaker, channel: Channel) -> Vec<SynthMsg> {
+    [
+        (RPN_MSB_CONTROL, PITCH_BEND_SENSITIVITY_RPN),
+        (RPN_LSB_CONTROL, PITCH_BEND_SENSITIVITY_RPN),
+        (DATA_ENTRY_MSB_CONTROL, BEND_RANGE_SEMITONES),
+        (DATA_ENTRY_LSB_CONTROL, 0),
+        (RPN_MSB_CONTROL, NULL_RPN),
+        (RPN_LSB_CONTROL, NULL_RPN),
+    ]
+    .iter()
+    .map(|(control, value)| SynthMsg {
+        msg: MidiMsg::ChannelVoice {
+            channel,
+            msg: ChannelVoiceMsg::ControlChange {
+                control: ControlChange::CC {
+                    control: *control,
+                    value: *value,
+                },
+            },
+        },
+        speaker,
+    })
+    .collect()
+}
+
+/// Offsets the player's `bend` so that A4 sounds at `concert_pitch`.
+fn tuned_bend(bend: u16, concert_pitch: f64) -> u16 {
+    let semitones = 12.0 * (concert_pitch / STANDARD_CONCERT_PITCH).log2();
+    let offset = semitones / BEND_RANGE_SEMITONES as f64 * CENTERED_BEND as f64;
+    (bend as f64 + offset)
+        .round()
+        .clamp(0.0, HIGHEST_BEND as f64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice(msg: ChannelVoiceMsg) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg,
+            },
+            speaker: Speaker::Left,
+        }
+    }
+
+    fn notes_of(sent: Vec<SynthMsg>) -> Vec<u8> {
+        sent.iter()
+            .filter_map(|synth_msg| match synth_msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg:
+                        ChannelVoiceMsg::NoteOn { note, .. } | ChannelVoiceMsg::NoteOff { note, .. },
+                    ..
+                } => Some(note),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn bends_of(sent: Vec<SynthMsg>) -> Vec<u16> {
+        sent.iter()
+            .filter_map(|synth_msg| match synth_msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::PitchBend { bend },
+                    ..
+                } => Some(bend),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transposer() {
+        let mut transposer = Transposer::new();
+        let on = |note| {
+            voice(ChannelVoiceMsg::NoteOn {
+                note,
+                velocity: 100,
+            })
+        };
+        let off = |note| voice(ChannelVoiceMsg::NoteOff { note, velocity: 0 });
+        let a4 = STANDARD_CONCERT_PITCH;
+        assert_eq!(notes_of(transposer.transpose(2, a4, on(60))), vec![62]);
+        // Released where it started, though the transposition has changed.
+        assert_eq!(notes_of(transposer.transpose(-3, a4, off(60))), vec![62]);
+        assert!(transposer.transpose(-3, a4, off(60)).is_empty());
+        assert!(transposer.transpose(12, a4, on(120)).is_empty());
+        assert!(transposer.transpose(12, a4, off(120)).is_empty());
+
+        // Struck again after the transposition changed: the old pitch is released first.
+        transposer.transpose(2, a4, on(60));
+        assert_eq!(notes_of(transposer.transpose(5, a4, on(60))), vec![62, 65]);
+        assert_eq!(notes_of(transposer.transpose(0, a4, off(60))), vec![65]);
+
+        transposer.transpose(0, a4, on(64));
+        transposer.transpose(0, a4, SynthMsg::all_notes_off(Speaker::Both));
+        assert!(transposer.sounding.is_empty());
+    }
+
+    fn controls_of(sent: Vec<SynthMsg>) -> Vec<(u8, u8)> {
+        sent.iter()
+            .filter_map(|synth_msg| match synth_msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg:
+                        ChannelVoiceMsg::ControlChange {
+                            control: ControlChange::CC { control, value },
+                        },
+                    ..
+                } => Some((control, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_concert_pitch() {
+        let mut transposer = Transposer::new();
+        let on = |note| {
+            voice(ChannelVoiceMsg::NoteOn {
+                note,
+                velocity: 100,
+            })
+        };
+        let off = |note| voice(ChannelVoiceMsg::NoteOff { note, velocity: 0 });
+        let bend = |bend| voice(ChannelVoiceMsg::PitchBend { bend });
+        let a4 = STANDARD_CONCERT_PITCH;
+        let semitone_up = a4 * 2.0_f64.powf(1.0 / 12.0);
+        // The channel's bend range is set before its first note, and only then.
+        let sent = transposer.transpose(0, a4, on(60));
+        assert_eq!(
+            controls_of(sent.clone()),
+            vec![(101, 0), (100, 0), (6, 2), (38, 0), (101, 127), (100, 127)]
+        );
+        assert!(bends_of(sent).is_empty());
+        transposer.transpose(0, a4, off(60));
+        assert!(controls_of(transposer.transpose(0, a4, on(60))).is_empty());
+        transposer.transpose(0, a4, off(60));
+        // A semitone up is half the bend range.
+        let sent = transposer.transpose(0, semitone_up, on(62));
+        assert_eq!(bends_of(sent), vec![CENTERED_BEND + 4096]);
+        assert!(bends_of(transposer.transpose(0, semitone_up, on(64))).is_empty());
+        // The player's own bend is offset by the same amount.
+        let sent = transposer.transpose(0, semitone_up, bend(10000));
+        assert_eq!(bends_of(sent), vec![14096]);
+        transposer.transpose(0, semitone_up, off(62));
+        transposer.transpose(0, semitone_up, off(64));
+        assert_eq!(bends_of(transposer.transpose(0, a4, on(65))), vec![10000]);
+        assert_eq!(tuned_bend(HIGHEST_BEND, 466.0), HIGHEST_BEND);
+        // The full range, either way.
+        let range_up = a4 * 2.0_f64.powf(BEND_RANGE_SEMITONES as f64 / 12.0);
+        assert_eq!(tuned_bend(CENTERED_BEND, range_up), HIGHEST_BEND);
+        assert_eq!(tuned_bend(CENTERED_BEND, a4 * a4 / range_up), 0);
+    }
+
+    #[test]
+    fn test_retune_while_held() {
+        let mut transposer = Transposer::new();
+        let on = |note| {
+            voice(ChannelVoiceMsg::NoteOn {
+                note,
+                velocity: 100,
+            })
+        };
+        let off = |note| voice(ChannelVoiceMsg::NoteOff { note, velocity: 0 });
+        let bend = |bend| voice(ChannelVoiceMsg::PitchBend { bend });
+        let a4 = STANDARD_CONCERT_PITCH;
+        let semitone_up = a4 * 2.0_f64.powf(1.0 / 12.0);
+        transposer.transpose(0, a4, on(60));
+        // Retuning now would move the held note, so the channel keeps its tuning...
+        assert!(bends_of(transposer.transpose(0, semitone_up, on(64))).is_empty());
+        assert_eq!(
+            bends_of(transposer.transpose(0, semitone_up, bend(9000))),
+            vec![9000]
+        );
+        transposer.transpose(0, semitone_up, off(60));
+        assert!(bends_of(transposer.transpose(0, semitone_up, on(67))).is_empty());
+        // ...until all of its notes are released.
+        transposer.transpose(0, semitone_up, off(64));
+        transposer.transpose(0, semitone_up, off(67));
+        assert_eq!(
+            bends_of(transposer.transpose(0, semitone_up, on(60))),
+            vec![9000 + 4096]
+        );
+        // Another channel is retuned at once.
+        let other = SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: Channel::Ch2,
+                msg: ChannelVoiceMsg::NoteOn {
+                    note: 62,
+                    velocity: 100,
+                },
+            },
+            speaker: Speaker::Left,
+        };
+        assert_eq!(
+            bends_of(transposer.transpose(0, a4 / 2.0_f64.powf(1.0 / 12.0), other)),
+            vec![CENTERED_BEND - 4096]
+        );
+    }
+
+    #[test]
+    fn test_vibrato() {
+        let mut transposer = Transposer::new();
+        let a4 = STANDARD_CONCERT_PITCH;
+        let mod_wheel = |value| {
+            voice(ChannelVoiceMsg::ControlChange {
+                control: ControlChange::CC {
+                    control: MOD_WHEEL_CONTROL,
+                    value,
+                },
+            })
+        };
+        let quarter_cycle = 0.25 / VIBRATO_HZ;
+        assert!(transposer.vibrato(quarter_cycle).is_empty());
+        let sent = transposer.transpose(0, a4, mod_wheel(127));
+        assert_eq!(controls_of(sent).last(), Some(&(MOD_WHEEL_CONTROL, 127)));
+        assert!(transposer.is_vibrating());
+        // Half a semitone either way is a quarter of the bend range.
+        assert_eq!(bends_of(transposer.vibrato(0.0)), vec![CENTERED_BEND]);
+        assert_eq!(
+            bends_of(transposer.vibrato(quarter_cycle)),
+            vec![CENTERED_BEND + 2048]
+        );
+        assert_eq!(
+            bends_of(transposer.vibrato(3.0 * quarter_cycle)),
+            vec![CENTERED_BEND - 2048]
+        );
+        transposer.transpose(0, a4, mod_wheel(64));
+        assert_eq!(
+            bends_of(transposer.vibrato(quarter_cycle)),
+            vec![CENTERED_BEND + 1032]
+        );
+        // With the wheel down, the channel goes back to its bend.
+        let sent = transposer.transpose(0, a4, mod_wheel(0));
+        assert_eq!(bends_of(sent), vec![CENTERED_BEND]);
+        assert!(!transposer.is_vibrating());
+        assert!(transposer.vibrato(quarter_cycle).is_empty());
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut transposer = Transposer::new();
+        let on = |note| {
+            voice(ChannelVoiceMsg::NoteOn {
+                note,
+                velocity: 100,
+            })
+        };
+        let off = |note| voice(ChannelVoiceMsg::NoteOff { note, velocity: 0 });
+        let a4 = STANDARD_CONCERT_PITCH;
+        let semitone_up = a4 * 2.0_f64.powf(1.0 / 12.0);
+        transposer.transpose(2, a4, on(60));
+        // The watchdog releases the stuck note as it was sent, transposed.
+        transposer.forget(&off(60));
+        assert!(!transposer.is_quiet(Speaker::Left, Channel::Ch1));
+        transposer.forget(&off(62));
+        assert!(transposer.is_quiet(Speaker::Left, Channel::Ch1));
+        assert!(transposer.transpose(2, a4, off(60)).is_empty());
+        assert_eq!(
+            bends_of(transposer.transpose(0, semitone_up, on(64))),
+            vec![CENTERED_BEND + 4096]
+        );
+    }
+}
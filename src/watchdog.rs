@@ -0,0 +1,329 @@
+use crate::analyzer::MidiByte;
+use crate::dynamics::{Dynamics, OutputLevel};
+use crate::pitch;
+use crate::queue::BlockingQueue;
+use crate::runtime::{MelodyRunStatus, MixControls, SliderValue, HUMAN_SPEAKER, VARIATION_SPEAKER};
+use crate::threads::{spawn_named, SINGLETON, WATCHDOG_THREAD};
+use crate::transpose::Transposer;
+use crossbeam_queue::SegQueue;
+use crossbeam_utils::atomic::AtomicCell;
+use midi_fundsp::io::{Speaker, SynthMsg};
+use midi_msg::{Channel, ChannelModeMsg, ChannelVoiceMsg, MidiMsg};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const SWEEP_MILLIS: u128 = 250;
+const IDLE_MILLIS: u64 = 50;
+/// How often the bend is updated while a mod wheel is up, for a smooth vibrato.
+const VIBRATO_STEP_MILLIS: u64 = 10;
+pub const NUM_MIDI_NOTES: usize = 128;
+
+/// The velocity of every note now sounding, indexed by MIDI note number, with 0 for
+/// silent notes, separately for the human and the variation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HeldKeys {
+    pub human: [u8; NUM_MIDI_NOTES],
+    pub variation: [u8; NUM_MIDI_NOTES],
+}
+
+impl HeldKeys {
+    pub const NONE: HeldKeys = HeldKeys {
+        human: [0; NUM_MIDI_NOTES],
+        variation: [0; NUM_MIDI_NOTES],
+    };
+}
+
+/// Asks the watchdog thread to silence everything at once: it stops any melody being
+/// played, discards the messages still waiting for the output thread, and releases every
+/// note. Pressed from the GUI, or by an All Sound Off or All Notes Off from the keyboard.
+#[derive(Clone, Default)]
+pub struct PanicButton {
+    pressed: Arc<AtomicCell<bool>>,
+}
+
+impl PanicButton {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&self) {
+        self.pressed.store(true);
+    }
+
+    pub(crate) fn take(&self) -> bool {
+        self.pressed.swap(false)
+    }
+}
+
+struct HeldNote {
+    speaker: Speaker,
+    channel: Channel,
+    note: u8,
+    velocity: u8,
+    started: Instant,
+}
+
+impl HeldNote {
+    fn note_off(&self) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: self.channel,
+                msg: ChannelVoiceMsg::NoteOff {
+                    note: self.note,
+                    velocity: 0,
+                },
+            },
+            speaker: self.speaker,
+        }
+    }
+}
+
+/// Tracks every note passing through to the output thread, so that notes whose NoteOff
+/// was lost can be released before they pile up.
+#[derive(Default)]
+pub struct StuckNoteTracker {
+    held: Vec<HeldNote>,
+}
+
+impl StuckNoteTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn num_held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn level(&self) -> OutputLevel {
+        OutputLevel::of(self.held.iter().map(|h| h.velocity))
+    }
+
+    /// Notes sent to both speakers count for the human and the variation alike.
+    pub fn held_keys(&self) -> HeldKeys {
+        let mut keys = HeldKeys::NONE;
+        for h in self.held.iter() {
+            let note = h.note as usize % NUM_MIDI_NOTES;
+            if silences(h.speaker, HUMAN_SPEAKER) {
+                keys.human[note] = keys.human[note].max(h.velocity);
+            }
+            if silences(h.speaker, VARIATION_SPEAKER) {
+                keys.variation[note] = keys.variation[note].max(h.velocity);
+            }
+        }
+        keys
+    }
+
+    pub fn observe(&mut self, synth_msg: &SynthMsg) {
+        match synth_msg.msg {
+            MidiMsg::ChannelVoice { channel, msg } => match msg {
+                ChannelVoiceMsg::NoteOn { note, velocity } if velocity > 0 => {
+                    self.release(synth_msg.speaker, channel, note);
+                    self.held.push(HeldNote {
+                        speaker: synth_msg.speaker,
+                        channel,
+                        note,
+                        velocity,
+                        started: Instant::now(),
+                    });
+                }
+                ChannelVoiceMsg::NoteOn { note, .. } | ChannelVoiceMsg::NoteOff { note, .. } => {
+                    self.release(synth_msg.speaker, channel, note);
+                }
+                _ => {}
+            },
+            MidiMsg::ChannelMode {
+                msg: ChannelModeMsg::AllNotesOff | ChannelModeMsg::AllSoundOff,
+                ..
+            } => {
+                self.held
+                    .retain(|h| !silences(synth_msg.speaker, h.speaker));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns a NoteOff for each note held longer than `max_seconds`, and stops tracking them.
+    pub fn sweep(&mut self, max_seconds: f64) -> Vec<SynthMsg> {
+        let mut releases = vec![];
+        self.held.retain(|h| {
+            let held_seconds = h.started.elapsed().as_secs_f64();
+            if held_seconds >= max_seconds {
+                warn!(
+                    "Watchdog: releasing note {} on {:?}/{:?} after {held_seconds:.1}s",
+                    pitch::name(h.note),
+                    h.speaker,
+                    h.channel
+                );
+                releases.push(h.note_off());
+                false
+            } else {
+                true
+            }
+        });
+        releases
+    }
+
+    /// Returns a NoteOff for every held note, and stops tracking them.
+    pub fn release_all(&mut self) -> Vec<SynthMsg> {
+        self.held.drain(..).map(|h| h.note_off()).collect()
+    }
+
+    fn release(&mut self, speaker: Speaker, channel: Channel, note: u8) {
+        self.held.retain(|h| {
+            !(same_speaker(speaker, h.speaker) && h.channel == channel && h.note == note)
+        });
+    }
+}
+
+pub(crate) fn same_speaker(a: Speaker, b: Speaker) -> bool {
+    matches!(
+        (a, b),
+        (Speaker::Left, Speaker::Left)
+            | (Speaker::Right, Speaker::Right)
+            | (Speaker::Both, Speaker::Both)
+    )
+}
+
+/// Whether an all-notes-off sent to `off` silences notes held on `held`.
+pub(crate) fn silences(off: Speaker, held: Speaker) -> bool {
+    matches!(off, Speaker::Both) || same_speaker(off, held)
+}
+
+/// Relays every message from `ai2watchdog` to `watchdog2output`, transposed by
+/// `transpose_slider` semitones, tuned to the A4 given by `concert_pitch_slider`, with
+/// vibrato from the mod wheel, and shaped by the player's breath and expression
+/// controllers and by `mix_controls`, periodically releasing notes held longer than the
+/// maximum duration given by `max_note_slider`. The notes still sounding, and how loud
+/// they are, are kept in `held_keys` and `output_level` for display.
+pub fn start_watchdog_thread(
+    ai2watchdog: Arc<BlockingQueue<SynthMsg>>,
+    watchdog2output: Arc<SegQueue<SynthMsg>>,
+    max_note_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    transpose_slider: Arc<AtomicCell<SliderValue<MidiByte>>>,
+    concert_pitch_slider: Arc<AtomicCell<SliderValue<f64>>>,
+    mix_controls: MixControls,
+    held_keys: Arc<AtomicCell<HeldKeys>>,
+    output_level: Arc<AtomicCell<OutputLevel>>,
+    panic_button: PanicButton,
+    melody_run_status: MelodyRunStatus,
+    quit: Arc<AtomicCell<bool>>,
+) -> anyhow::Result<()> {
+    spawn_named(WATCHDOG_THREAD, SINGLETON, move || {
+        let mut tracker = StuckNoteTracker::new();
+        let mut transposer = Transposer::new();
+        let mut dynamics = Dynamics::new();
+        let mut last_sweep = Instant::now();
+        let started = Instant::now();
+        let mut last_vibrato = started;
+        while !quit.load() {
+            if panic_button.take() {
+                warn!("Panic: silencing every note");
+                melody_run_status.send_stop();
+                let discarded = ai2watchdog.clear();
+                while watchdog2output.pop().is_some() {}
+                for release in tracker.release_all() {
+                    watchdog2output.push(release);
+                }
+                watchdog2output.push(SynthMsg::all_notes_off(Speaker::Both));
+                transposer.clear();
+                info!("Panic: discarded {discarded} queued messages");
+            }
+            let idle = if transposer.is_vibrating() {
+                VIBRATO_STEP_MILLIS
+            } else {
+                IDLE_MILLIS
+            };
+            if let Some(synth_msg) = ai2watchdog.pop_timeout(Duration::from_millis(idle)) {
+                let semitones = transpose_slider.load().current();
+                let concert_pitch = concert_pitch_slider.load().current();
+                let mix = mix_controls.mix();
+                // Shaped first, so that aftertouch routed to the mod wheel gives vibrato.
+                for synth_msg in dynamics.shape(synth_msg, mix, tracker.level()) {
+                    for synth_msg in transposer.transpose(semitones, concert_pitch, synth_msg) {
+                        tracker.observe(&synth_msg);
+                        watchdog2output.push(synth_msg);
+                    }
+                }
+            }
+            if last_vibrato.elapsed() >= Duration::from_millis(VIBRATO_STEP_MILLIS) {
+                for bend in transposer.vibrato(started.elapsed().as_secs_f64()) {
+                    watchdog2output.push(bend);
+                }
+                last_vibrato = Instant::now();
+            }
+            if last_sweep.elapsed().as_millis() >= SWEEP_MILLIS {
+                for release in tracker.sweep(max_note_slider.load().current()) {
+                    transposer.forget(&release);
+                    watchdog2output.push(release);
+                }
+                last_sweep = Instant::now();
+            }
+            held_keys.store(tracker.held_keys());
+            output_level.store(tracker.level());
+        }
+        held_keys.store(HeldKeys::NONE);
+        output_level.store(OutputLevel::default());
+        while let Some(synth_msg) = ai2watchdog.pop() {
+            watchdog2output.push(synth_msg);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8, velocity: u8, speaker: Speaker) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity },
+            },
+            speaker,
+        }
+    }
+
+    #[test]
+    fn test_stuck_notes() {
+        let mut tracker = StuckNoteTracker::new();
+        tracker.observe(&note_on(60, 100, Speaker::Left));
+        tracker.observe(&note_on(64, 100, Speaker::Left));
+        tracker.observe(&note_on(60, 100, Speaker::Right));
+        assert_eq!(tracker.num_held(), 3);
+        let keys = tracker.held_keys();
+        assert_eq!(
+            (keys.human[60], keys.human[64], keys.variation[60]),
+            (100, 100, 100)
+        );
+        assert_eq!(keys.variation[64], 0);
+        tracker.observe(&note_on(64, 0, Speaker::Left));
+        assert_eq!(tracker.num_held(), 2);
+        assert_eq!(tracker.held_keys().human[64], 0);
+        assert!(tracker.sweep(60.0).is_empty());
+        assert_eq!(tracker.num_held(), 2);
+
+        tracker.observe(&SynthMsg::all_notes_off(Speaker::Right));
+        assert_eq!(tracker.num_held(), 1);
+        let releases = tracker.sweep(0.0);
+        assert_eq!(releases.len(), 1);
+        assert_eq!(tracker.num_held(), 0);
+        match releases[0].msg {
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { note, .. },
+                ..
+            } => assert_eq!(note, 60),
+            _ => panic!("Expected a NoteOff"),
+        }
+
+        tracker.observe(&note_on(62, 100, Speaker::Left));
+        tracker.observe(&SynthMsg {
+            msg: MidiMsg::ChannelMode {
+                channel: Channel::Ch1,
+                msg: ChannelModeMsg::AllSoundOff,
+            },
+            speaker: Speaker::Both,
+        });
+        assert_eq!(tracker.num_held(), 0);
+    }
+}